@@ -0,0 +1,45 @@
+use std::{error::Error, path::PathBuf};
+
+use super::{AttachmentExtractor, AttachmentOptions, ExtractedAttachment, PreparedContent};
+
+// Transcribed with the local speech model while in the tray, the audio itself is never sent
+pub struct AudioExtractor;
+
+impl AttachmentExtractor for AudioExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["wav", "mp3", "ogg", "oga"]
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["audio/wav", "audio/x-wav", "audio/mpeg", "audio/ogg"]
+    }
+
+    fn extract(
+        &self,
+        _file_path: &PathBuf,
+        options: &AttachmentOptions,
+    ) -> Result<ExtractedAttachment, Box<dyn Error>> {
+        // Transcribing takes too long to do while sending
+        let transcript = match &options.prepared_content {
+            Some(PreparedContent::Transcript(transcript)) => transcript,
+            Some(PreparedContent::Failed(err)) => return Err(err.clone().into()),
+            _ => return Err("it wasn't transcribed before sending".into()),
+        };
+        Ok(ExtractedAttachment::Text(format!(
+            "Transcript of the audio:\n{}",
+            transcript
+        )))
+    }
+}
+
+pub fn is_audio_file(file_path: &PathBuf) -> bool {
+    file_path
+        .extension()
+        .map(|extension| {
+            AudioExtractor
+                .extensions()
+                .iter()
+                .any(|audio_extension| extension.eq_ignore_ascii_case(audio_extension))
+        })
+        .unwrap_or(false)
+}
@@ -0,0 +1,110 @@
+use quick_xml::{events::Event, Reader};
+use std::{collections::HashMap, error::Error, fs::File, io::Read, path::PathBuf};
+use zip::ZipArchive;
+
+use super::{html::html_to_text, AttachmentExtractor, AttachmentOptions, ExtractedAttachment};
+
+fn attribute(
+    element: &quick_xml::events::BytesStart,
+    attribute_name: &str,
+) -> Result<Option<String>, Box<dyn Error>> {
+    Ok(match element.try_get_attribute(attribute_name)? {
+        Some(attribute) => Some(attribute.unescape_value()?.to_string()),
+        None => None,
+    })
+}
+
+// META-INF/container.xml points at the package file listing the book's contents
+fn package_path(container_xml: &str) -> Result<String, Box<dyn Error>> {
+    let mut reader = Reader::from_str(container_xml);
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element)
+                if element.local_name().as_ref() == b"rootfile" =>
+            {
+                if let Some(full_path) = attribute(&element, "full-path")? {
+                    return Ok(full_path);
+                }
+            }
+            Event::Eof => return Err("The EPUB has no package file".into()),
+            _ => {}
+        }
+    }
+}
+
+// The spine gives the reading order as ids, the manifest maps those ids to files
+fn chapter_paths(package_xml: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut reader = Reader::from_str(package_xml);
+    let mut manifest = HashMap::new();
+    let mut spine = vec![];
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) | Event::Empty(element) => match element.local_name().as_ref() {
+                b"item" => {
+                    if let (Some(id), Some(href)) =
+                        (attribute(&element, "id")?, attribute(&element, "href")?)
+                    {
+                        manifest.insert(id, href);
+                    }
+                }
+                b"itemref" => {
+                    if let Some(idref) = attribute(&element, "idref")? {
+                        spine.push(idref);
+                    }
+                }
+                _ => {}
+            },
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(spine
+        .iter()
+        .filter_map(|idref| manifest.get(idref).cloned())
+        .collect())
+}
+
+// Chapters are XHTML, read in spine order and converted like HTML attachments
+pub struct EpubExtractor;
+
+impl AttachmentExtractor for EpubExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["epub"]
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/epub+zip"]
+    }
+
+    fn extract(
+        &self,
+        file_path: &PathBuf,
+        _options: &AttachmentOptions,
+    ) -> Result<ExtractedAttachment, Box<dyn Error>> {
+        let mut archive = ZipArchive::new(File::open(file_path)?)?;
+        let mut read_entry = |entry_name: &str| -> Result<Vec<u8>, Box<dyn Error>> {
+            let mut entry = archive.by_name(entry_name)?;
+            let mut entry_bytes = vec![];
+            entry.read_to_end(&mut entry_bytes)?;
+            Ok(entry_bytes)
+        };
+        let package_path =
+            package_path(&String::from_utf8(read_entry("META-INF/container.xml")?)?)?;
+        let package_folder = package_path
+            .rsplit_once('/')
+            .map(|(package_folder, _)| format!("{}/", package_folder))
+            .unwrap_or_default();
+        let chapters = chapter_paths(&String::from_utf8(read_entry(&package_path)?)?)?
+            .into_iter()
+            .filter_map(|chapter_path| {
+                let chapter_path = format!("{0}{1}", package_folder, chapter_path);
+                read_entry(&chapter_path)
+                    .map_err(|err| println!("Error reading {}: {:?}", chapter_path, err))
+                    .ok()
+            })
+            .map(|chapter_html| html_to_text(&chapter_html))
+            .filter(|chapter_text| !chapter_text.trim().is_empty())
+            .collect::<Vec<String>>();
+        Ok(ExtractedAttachment::Text(chapters.join("\n\n")))
+    }
+}
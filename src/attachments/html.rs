@@ -0,0 +1,33 @@
+use std::{error::Error, fs, path::PathBuf};
+
+use super::{AttachmentExtractor, AttachmentOptions, ExtractedAttachment};
+
+// Wide enough that html2text doesn't wrap paragraphs the model would read as separate lines
+const TEXT_WIDTH: usize = 10_000;
+
+// Readable text only, scripts, styles and markup are dropped and links are kept as footnotes
+pub fn html_to_text(html: &[u8]) -> String {
+    html2text::from_read(html, TEXT_WIDTH)
+}
+
+pub struct HtmlExtractor;
+
+impl AttachmentExtractor for HtmlExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["html", "htm", "xhtml"]
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/html", "application/xhtml+xml"]
+    }
+
+    fn extract(
+        &self,
+        file_path: &PathBuf,
+        _options: &AttachmentOptions,
+    ) -> Result<ExtractedAttachment, Box<dyn Error>> {
+        Ok(ExtractedAttachment::Text(html_to_text(&fs::read(
+            file_path,
+        )?)))
+    }
+}
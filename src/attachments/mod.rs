@@ -0,0 +1,320 @@
+use gtk::{gio, glib};
+use serde::{Deserialize, Serialize};
+use std::{error::Error, ffi::OsStr, fs, path::PathBuf};
+
+use crate::{
+    models::{B64Image, Message},
+    settings::Settings,
+    utils::{self, get_root_folder},
+};
+
+use self::{
+    audio::AudioExtractor,
+    epub::EpubExtractor,
+    html::HtmlExtractor,
+    office::{DocxExtractor, OdtExtractor},
+    pdf::{PageRange, PdfDocument, PdfExtractor},
+    spreadsheet::SpreadsheetExtractor,
+};
+
+pub mod audio;
+pub mod epub;
+pub mod html;
+pub mod office;
+pub mod pdf;
+pub mod spreadsheet;
+
+// A rough average for English text, good enough to budget attachments against a context window
+pub const CHARS_PER_TOKEN: usize = 4;
+
+// Half the context is left for the conversation and the answer
+pub fn max_attachment_chars(context_window_tokens: usize) -> usize {
+    context_window_tokens * CHARS_PER_TOKEN / 2
+}
+
+// Cuts plain text attachments on a char boundary with a note saying how much was left out
+pub fn truncate_text(text: &str, max_chars: usize) -> String {
+    if text.len() <= max_chars {
+        return text.to_string();
+    }
+    let cut_at = text
+        .char_indices()
+        .map(|(char_index, _)| char_index)
+        .take_while(|char_index| *char_index <= max_chars)
+        .last()
+        .unwrap_or(0);
+    format!(
+        "{0}\n[Truncated to fit the model's context, {1} of {2} characters were left out]\n",
+        &text[..cut_at],
+        text.len() - cut_at,
+        text.len()
+    )
+}
+
+// Pasted text past this length is offered as an attachment rather than put in the prompt
+pub const LONG_PASTE_CHARS: usize = 2000;
+
+// Where pasted images and text are written so they can be attached like any other file
+pub fn pasted_file_path(description: &str, extension: &str) -> Result<PathBuf, Box<dyn Error>> {
+    let pasted_folder = get_root_folder().join("pasted");
+    fs::create_dir_all(&pasted_folder)?;
+    let timestamp = glib::DateTime::now_local()?.format("%Y-%m-%d %H.%M.%S")?;
+    let file_path = pasted_folder.join(format!("{0} {1}.{2}", description, timestamp, extension));
+    if file_path.exists() {
+        Ok(pasted_folder.join(utils::generate_unique_filename(extension)))
+    } else {
+        Ok(file_path)
+    }
+}
+
+// Read while the file waited in the tray, so sending doesn't have to do the slow part again
+#[derive(Clone, Debug)]
+pub enum PreparedContent {
+    Pdf(PdfDocument),
+    Transcript(String),
+    // Documents and text files, not cut to fit yet as the model can change before sending
+    Text(String),
+    // Decoded, turned upright and scaled down, which is slow for full size photos
+    Image(B64Image),
+    // Sent as the attachment's error rather than tried again
+    Failed(String),
+}
+
+// What the user chose for this attachment and how much of it fits the model
+#[derive(Clone, Debug)]
+pub struct AttachmentOptions {
+    pub page_range: Option<PageRange>,
+    pub max_chars: usize,
+    pub prepared_content: Option<PreparedContent>,
+}
+
+/*
+A file sent with a message, kept with it so a reopened conversation shows what was attached
+- The extracted text is sent ahead of the prompt but never shown as part of it
+- Images have no text, they go in the message's images
+- The content type is guessed when attaching, so the icon still shows if the file is gone
+*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Attachment {
+    pub file_name: String,
+    pub file_path: PathBuf,
+    pub size_bytes: u64,
+    pub content_type: String,
+    #[serde(default)]
+    pub text: Option<String>,
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl Attachment {
+    pub fn new(file_path: &PathBuf) -> Self {
+        Self {
+            file_name: file_name(file_path),
+            file_path: file_path.clone(),
+            size_bytes: fs::metadata(file_path)
+                .map(|metadata| metadata.len())
+                .unwrap_or(0),
+            content_type: content_type(file_path),
+            text: None,
+            error: None,
+        }
+    }
+}
+
+pub fn file_name(file_path: &PathBuf) -> String {
+    file_path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_string())
+        .unwrap_or_default()
+}
+
+pub fn content_type(file_path: &PathBuf) -> String {
+    gio::content_type_guess(Some(file_path), &[]).0.to_string()
+}
+
+pub enum ExtractedAttachment {
+    Text(String),
+    Image(B64Image),
+}
+
+/*
+Turns one kind of file into something a model can read
+- Matched on extension, the MIME types are only used for the file chooser filter
+- Add an extractor to extractors() to support a new kind of file for every backend
+*/
+pub trait AttachmentExtractor {
+    fn extensions(&self) -> &'static [&'static str];
+
+    fn mime_types(&self) -> &'static [&'static str];
+
+    fn extract(
+        &self,
+        file_path: &PathBuf,
+        options: &AttachmentOptions,
+    ) -> Result<ExtractedAttachment, Box<dyn Error>>;
+}
+
+pub struct ImageExtractor;
+
+impl AttachmentExtractor for ImageExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["jpg", "jpeg", "png", "webp", "gif", "bmp"]
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &[
+            "image/png",
+            "image/jpeg",
+            "image/webp",
+            "image/gif",
+            "image/bmp",
+        ]
+    }
+
+    fn extract(
+        &self,
+        file_path: &PathBuf,
+        _options: &AttachmentOptions,
+    ) -> Result<ExtractedAttachment, Box<dyn Error>> {
+        let max_dimension = Settings::load().image_max_dimension;
+        Ok(ExtractedAttachment::Image(B64Image::new(
+            utils::image_to_b64(file_path, max_dimension)?,
+        )))
+    }
+}
+
+// Anything not claimed by another extractor is read as UTF-8 text
+pub struct PlainTextExtractor;
+
+impl AttachmentExtractor for PlainTextExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &[]
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["text/*"]
+    }
+
+    fn extract(
+        &self,
+        file_path: &PathBuf,
+        _options: &AttachmentOptions,
+    ) -> Result<ExtractedAttachment, Box<dyn Error>> {
+        let file_text = fs::read_to_string(file_path)
+            .map_err(|err| format!("it isn't a supported document or a text file ({})", err))?;
+        Ok(ExtractedAttachment::Text(file_text))
+    }
+}
+
+pub fn extractors() -> Vec<Box<dyn AttachmentExtractor>> {
+    vec![
+        Box::new(PdfExtractor),
+        Box::new(ImageExtractor),
+        Box::new(DocxExtractor),
+        Box::new(OdtExtractor),
+        Box::new(SpreadsheetExtractor),
+        Box::new(EpubExtractor),
+        Box::new(HtmlExtractor),
+        Box::new(AudioExtractor),
+    ]
+}
+
+fn extractor_for(file_path: &PathBuf) -> Box<dyn AttachmentExtractor> {
+    let extension = file_path
+        .extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("")
+        .to_lowercase();
+    extractors()
+        .into_iter()
+        .find(|extractor| extractor.extensions().contains(&extension.as_str()))
+        .unwrap_or_else(|| Box::new(PlainTextExtractor))
+}
+
+// Extracts a file on the tray's thread, so parsing documents or resizing images never holds up sending
+pub fn prepare(file_path: &PathBuf) -> PreparedContent {
+    let options = AttachmentOptions {
+        page_range: None,
+        max_chars: usize::MAX,
+        prepared_content: None,
+    };
+    match extractor_for(file_path).extract(file_path, &options) {
+        Ok(ExtractedAttachment::Text(file_text)) => PreparedContent::Text(file_text),
+        Ok(ExtractedAttachment::Image(image)) => PreparedContent::Image(image),
+        Err(err) => PreparedContent::Failed(err.to_string()),
+    }
+}
+
+// Suffixes are added too, as desktops don't always know the MIME type of EPUB or ODS files
+pub fn add_to_file_filter(file_filter: &gtk::FileFilter) {
+    let mut extractors = extractors();
+    extractors.push(Box::new(PlainTextExtractor));
+    for extractor in extractors {
+        extractor
+            .mime_types()
+            .iter()
+            .for_each(|mime_type| file_filter.add_mime_type(mime_type));
+        extractor
+            .extensions()
+            .iter()
+            .for_each(|extension| file_filter.add_suffix(extension));
+    }
+}
+
+// Shared by every backend, text is kept on the attachment and images are added to the message
+pub fn process_file_for_prompt(
+    mut chat_message: Message,
+    file_path: PathBuf,
+    options: &AttachmentOptions,
+) -> Message {
+    let mut attachment = Attachment::new(&file_path);
+    let extract_result = match &options.prepared_content {
+        Some(PreparedContent::Text(file_text)) => Ok(ExtractedAttachment::Text(file_text.clone())),
+        Some(PreparedContent::Image(image)) => Ok(ExtractedAttachment::Image(image.clone())),
+        Some(PreparedContent::Failed(err)) => Err(err.clone().into()),
+        _ => extractor_for(&file_path).extract(&file_path, options),
+    };
+    match extract_result {
+        Ok(ExtractedAttachment::Text(file_text)) => {
+            attachment.text = Some(truncate_text(file_text.trim(), options.max_chars));
+        }
+        Ok(ExtractedAttachment::Image(image)) => {
+            chat_message.images.get_or_insert_with(Vec::new).push(image);
+        }
+        Err(err) => {
+            println!("Error reading attachment {:?}: {:?}", file_path, err);
+            attachment.error = Some(err.to_string());
+        }
+    }
+    chat_message
+        .attachments
+        .get_or_insert_with(Vec::new)
+        .push(attachment);
+    chat_message
+}
+
+// What the model reads, each attachment's text goes before the prompt as it was typed
+pub fn content_with_attachments(chat_message: &Message) -> String {
+    let attachment_texts = chat_message
+        .attachments
+        .iter()
+        .flatten()
+        .filter_map(|attachment| match (&attachment.text, &attachment.error) {
+            (Some(text), _) => Some(format!("[{0}]\n{1}", attachment.file_name, text)),
+            (None, Some(err)) => Some(format!(
+                "[{0} couldn't be read: {1}]",
+                attachment.file_name, err
+            )),
+            (None, None) => None,
+        })
+        .collect::<Vec<String>>();
+    if attachment_texts.is_empty() {
+        chat_message.content.clone()
+    } else {
+        format!(
+            "{0}\n\n{1}",
+            attachment_texts.join("\n\n"),
+            chat_message.content
+        )
+    }
+}
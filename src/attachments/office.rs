@@ -0,0 +1,114 @@
+use quick_xml::{events::Event, Reader};
+use std::{
+    error::Error,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+};
+use zip::ZipArchive;
+
+use super::{AttachmentExtractor, AttachmentOptions, ExtractedAttachment};
+
+// Office formats are zip archives with the document body in one XML file
+pub fn read_zip_entry(file_path: &Path, entry_name: &str) -> Result<String, Box<dyn Error>> {
+    let mut archive = ZipArchive::new(File::open(file_path)?)?;
+    let mut entry = archive.by_name(entry_name)?;
+    let mut entry_text = String::new();
+    entry.read_to_string(&mut entry_text)?;
+    Ok(entry_text)
+}
+
+/*
+Collects the text of an XML document one paragraph per line
+- Text is only kept inside paragraph elements, which skips styles and metadata
+- Tab and line break elements become a tab and a new line
+*/
+fn xml_paragraphs(
+    xml: &str,
+    paragraph_tags: &[&[u8]],
+    tab_tags: &[&[u8]],
+    break_tags: &[&[u8]],
+) -> Result<String, Box<dyn Error>> {
+    let mut reader = Reader::from_str(xml);
+    let mut document_text = String::new();
+    let mut paragraph_depth = 0;
+    loop {
+        match reader.read_event()? {
+            Event::Start(element) if paragraph_tags.contains(&element.name().as_ref()) => {
+                paragraph_depth += 1;
+            }
+            Event::End(element) if paragraph_tags.contains(&element.name().as_ref()) => {
+                paragraph_depth -= 1;
+                document_text.push('\n');
+            }
+            Event::Empty(element) if paragraph_tags.contains(&element.name().as_ref()) => {
+                document_text.push('\n');
+            }
+            Event::Empty(element) if paragraph_depth > 0 => {
+                if tab_tags.contains(&element.name().as_ref()) {
+                    document_text.push('\t');
+                } else if break_tags.contains(&element.name().as_ref()) {
+                    document_text.push('\n');
+                }
+            }
+            Event::Text(text) if paragraph_depth > 0 => {
+                document_text.push_str(&text.unescape()?);
+            }
+            Event::Eof => break,
+            _ => {}
+        }
+    }
+    Ok(document_text)
+}
+
+pub struct DocxExtractor;
+
+impl AttachmentExtractor for DocxExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["docx"]
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/vnd.openxmlformats-officedocument.wordprocessingml.document"]
+    }
+
+    fn extract(
+        &self,
+        file_path: &PathBuf,
+        _options: &AttachmentOptions,
+    ) -> Result<ExtractedAttachment, Box<dyn Error>> {
+        let document_xml = read_zip_entry(file_path, "word/document.xml")?;
+        Ok(ExtractedAttachment::Text(xml_paragraphs(
+            &document_xml,
+            &[b"w:p"],
+            &[b"w:tab"],
+            &[b"w:br", b"w:cr"],
+        )?))
+    }
+}
+
+pub struct OdtExtractor;
+
+impl AttachmentExtractor for OdtExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["odt"]
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/vnd.oasis.opendocument.text"]
+    }
+
+    fn extract(
+        &self,
+        file_path: &PathBuf,
+        _options: &AttachmentOptions,
+    ) -> Result<ExtractedAttachment, Box<dyn Error>> {
+        let content_xml = read_zip_entry(file_path, "content.xml")?;
+        Ok(ExtractedAttachment::Text(xml_paragraphs(
+            &content_xml,
+            &[b"text:p", b"text:h"],
+            &[b"text:tab"],
+            &[b"text:line-break"],
+        )?))
+    }
+}
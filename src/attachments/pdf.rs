@@ -0,0 +1,224 @@
+use std::{error::Error, ops::RangeInclusive, panic, path::PathBuf};
+
+use super::{AttachmentExtractor, AttachmentOptions, ExtractedAttachment, PreparedContent};
+
+// Pages with less text than this are treated as images, e.g. a scanned page with a page number
+const MIN_PAGE_TEXT_CHARS: usize = 20;
+// Room kept for the notes saying what was left out
+const TRUNCATION_NOTE_CHARS: usize = 100;
+
+// Pages the user picked, e.g. "1-3, 7", numbered from 1 like a PDF viewer
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageRange {
+    ranges: Vec<RangeInclusive<usize>>,
+}
+
+impl PageRange {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let ranges = text
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let (start, end) = part.split_once('-').unwrap_or((part, part));
+                let start = start.trim().parse::<usize>();
+                let end = end.trim().parse::<usize>();
+                match (start, end) {
+                    (Ok(start), Ok(end)) if start >= 1 && start <= end => Ok(start..=end),
+                    _ => Err(format!("\"{}\" isn't a page or range like 2-5", part)),
+                }
+            })
+            .collect::<Result<Vec<RangeInclusive<usize>>, String>>()?;
+        if ranges.is_empty() {
+            return Err(String::from("Enter pages like 1-3, 7"));
+        }
+        Ok(Self { ranges })
+    }
+
+    pub fn contains(&self, page_number: usize) -> bool {
+        self.ranges.iter().any(|range| range.contains(&page_number))
+    }
+}
+
+/*
+Text of a PDF split by page
+- Extracted with pdf-extract, which can panic on malformed files so that's caught and reported as an error
+- Scanned PDFs have no text layer, has_text_layer lets callers warn instead of sending nothing
+*/
+#[derive(Clone, Debug)]
+pub struct PdfDocument {
+    pub file_path: PathBuf,
+    pub pages: Vec<String>,
+}
+
+impl PdfDocument {
+    pub fn open(file_path: &PathBuf) -> Result<Self, Box<dyn Error>> {
+        let extract_path = file_path.clone();
+        let pages = panic::catch_unwind(move || pdf_extract::extract_text_by_pages(extract_path))
+            .map_err(|_| format!("{:?} couldn't be parsed as a PDF", file_path))??;
+        Ok(Self {
+            file_path: file_path.clone(),
+            pages,
+        })
+    }
+
+    pub fn has_text_layer(&self) -> bool {
+        self.pages
+            .iter()
+            .any(|page| page.trim().chars().count() >= MIN_PAGE_TEXT_CHARS)
+    }
+
+    pub fn file_name(&self) -> String {
+        self.file_path
+            .file_name()
+            .map(|file_name| file_name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    }
+
+    /*
+    The selected pages with a marker before each so answers can point at a page
+    - Whole pages are added until max_chars is reached, a first page that's too long on its own is cut
+    - A note at the end says which pages were left out, so the model and user know the text is partial
+    */
+    pub fn to_prompt_text(&self, page_range: Option<&PageRange>, max_chars: usize) -> String {
+        if !self.has_text_layer() {
+            return format!(
+                "[{} has no text layer, it looks like a scanned document that needs OCR]",
+                self.file_name()
+            );
+        }
+        let selected_pages = self
+            .pages
+            .iter()
+            .enumerate()
+            .map(|(page_index, page)| (page_index + 1, page))
+            .filter(|(page_number, _)| {
+                page_range
+                    .map(|page_range| page_range.contains(*page_number))
+                    .unwrap_or(true)
+            })
+            .collect::<Vec<(usize, &String)>>();
+
+        let max_chars = max_chars.saturating_sub(TRUNCATION_NOTE_CHARS);
+        let mut prompt_text = String::new();
+        let mut omitted_pages = vec![];
+        let mut is_full = false;
+        for (page_number, page) in selected_pages {
+            let page_text = format!("\n[Page {0}]\n{1}\n", page_number, page.trim());
+            if !is_full && prompt_text.len() + page_text.len() <= max_chars {
+                prompt_text.push_str(&page_text);
+                continue;
+            }
+            if !is_full && prompt_text.len() < max_chars / 2 {
+                let cut_at = page_text
+                    .char_indices()
+                    .map(|(char_index, _)| char_index)
+                    .take_while(|char_index| prompt_text.len() + char_index < max_chars)
+                    .last()
+                    .unwrap_or(0);
+                prompt_text.push_str(&page_text[..cut_at]);
+                prompt_text.push_str("\n[Rest of the page left out]\n");
+            } else {
+                omitted_pages.push(page_number);
+            }
+            is_full = true;
+        }
+        if let (Some(first_omitted), Some(last_omitted)) =
+            (omitted_pages.first(), omitted_pages.last())
+        {
+            prompt_text.push_str(&format!(
+                "\n[Truncated to fit the model's context, pages {0} to {1} were left out]\n",
+                first_omitted, last_omitted
+            ));
+        }
+        prompt_text
+    }
+}
+
+pub struct PdfExtractor;
+
+impl AttachmentExtractor for PdfExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["pdf"]
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &["application/pdf"]
+    }
+
+    fn extract(
+        &self,
+        file_path: &PathBuf,
+        options: &AttachmentOptions,
+    ) -> Result<ExtractedAttachment, Box<dyn Error>> {
+        // The tray parses PDFs when they're added, they're only opened here if that was skipped
+        let pdf_document = match &options.prepared_content {
+            Some(PreparedContent::Pdf(pdf_document)) => pdf_document.clone(),
+            Some(PreparedContent::Failed(err)) => return Err(err.clone().into()),
+            _ => PdfDocument::open(file_path)?,
+        };
+        Ok(ExtractedAttachment::Text(pdf_document.to_prompt_text(
+            options.page_range.as_ref(),
+            options.max_chars,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pdf_document(pages: &[&str]) -> PdfDocument {
+        PdfDocument {
+            file_path: PathBuf::from("/tmp/report.pdf"),
+            pages: pages.iter().map(|page| page.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn page_range_parses_pages_and_ranges() {
+        let page_range = PageRange::parse("1-3, 7").unwrap();
+        assert!(page_range.contains(1));
+        assert!(page_range.contains(3));
+        assert!(page_range.contains(7));
+        assert!(!page_range.contains(4));
+        assert!(!page_range.contains(0));
+        assert_eq!(
+            PageRange::parse(" 2 - 4 ,").unwrap(),
+            PageRange::parse("2-4").unwrap()
+        );
+    }
+
+    #[test]
+    fn page_range_rejects_invalid_and_empty_input() {
+        assert!(PageRange::parse("").is_err());
+        assert!(PageRange::parse(" , ").is_err());
+        assert!(PageRange::parse("0").is_err());
+        assert!(PageRange::parse("5-2").is_err());
+        assert!(PageRange::parse("one").is_err());
+        assert!(PageRange::parse("1-3, x").is_err());
+    }
+
+    #[test]
+    fn prompt_text_only_includes_selected_pages() {
+        let pdf_document = pdf_document(&[
+            "First page with enough text to count",
+            "Second page with enough text to count",
+            "Third page with enough text to count",
+        ]);
+        let page_range = PageRange::parse("1, 3").unwrap();
+        let prompt_text = pdf_document.to_prompt_text(Some(&page_range), 10_000);
+        assert!(prompt_text.contains("[Page 1]"));
+        assert!(!prompt_text.contains("[Page 2]"));
+        assert!(prompt_text.contains("[Page 3]"));
+    }
+
+    #[test]
+    fn prompt_text_without_text_layer_asks_for_ocr() {
+        let pdf_document = pdf_document(&["1", ""]);
+        assert!(!pdf_document.has_text_layer());
+        assert!(pdf_document
+            .to_prompt_text(None, 10_000)
+            .contains("report.pdf has no text layer"));
+    }
+}
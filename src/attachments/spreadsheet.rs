@@ -0,0 +1,67 @@
+use calamine::{open_workbook_auto, Data, Range, Reader};
+use std::{error::Error, path::PathBuf};
+
+use super::{AttachmentExtractor, AttachmentOptions, ExtractedAttachment};
+
+// Pipes would end the cell early and new lines would end the row
+fn markdown_cell(cell: &Data) -> String {
+    cell.to_string()
+        .replace('|', "\\|")
+        .replace(['\r', '\n'], " ")
+}
+
+// The first row is used as the header, as that's how most sheets are laid out
+fn markdown_table(range: &Range<Data>) -> String {
+    let mut rows = range.rows().map(|row| {
+        format!(
+            "| {} |",
+            row.iter()
+                .map(markdown_cell)
+                .collect::<Vec<String>>()
+                .join(" | ")
+        )
+    });
+    let Some(header_row) = rows.next() else {
+        return String::from("(empty sheet)");
+    };
+    let separator_row = format!("|{}", " --- |".repeat(range.width()));
+    [header_row, separator_row]
+        .into_iter()
+        .chain(rows)
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// XLSX, XLS and ODS workbooks, every sheet becomes a markdown table under its name
+pub struct SpreadsheetExtractor;
+
+impl AttachmentExtractor for SpreadsheetExtractor {
+    fn extensions(&self) -> &'static [&'static str] {
+        &["xlsx", "xls", "ods"]
+    }
+
+    fn mime_types(&self) -> &'static [&'static str] {
+        &[
+            "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+            "application/vnd.ms-excel",
+            "application/vnd.oasis.opendocument.spreadsheet",
+        ]
+    }
+
+    fn extract(
+        &self,
+        file_path: &PathBuf,
+        _options: &AttachmentOptions,
+    ) -> Result<ExtractedAttachment, Box<dyn Error>> {
+        let mut workbook = open_workbook_auto(file_path)?;
+        let sheets = workbook
+            .sheet_names()
+            .into_iter()
+            .map(|sheet_name| {
+                let range = workbook.worksheet_range(&sheet_name)?;
+                Ok(format!("## {0}\n\n{1}", sheet_name, markdown_table(&range)))
+            })
+            .collect::<Result<Vec<String>, Box<dyn Error>>>()?;
+        Ok(ExtractedAttachment::Text(sheets.join("\n\n")))
+    }
+}
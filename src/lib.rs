@@ -1,16 +1,23 @@
+pub mod attachments;
 pub mod config;
 pub mod models;
+pub mod rag;
+pub mod settings;
+pub mod speech;
 pub mod utils;
 pub mod widgets;
 pub mod window;
 
-use std::path::PathBuf;
-
+use rag::{
+    conversation_search::ConversationSearchConfig, document_index::DocumentIndexConfig,
+    manifest::RagManifest,
+};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Debug)]
 pub enum ModelMessageState {
     UserTurn,
+    RetrievingContext,
     RunningAssistant,
     StartAssistant,
     LoadingFromFile,
@@ -31,7 +38,9 @@ pub enum AppState {
 #[derive(Clone, Debug)]
 pub enum RagSource {
     NoRag,
-    BashScript(PathBuf),
+    Script(RagManifest),
+    DocumentIndex(DocumentIndexConfig),
+    Conversations(ConversationSearchConfig),
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -57,9 +66,21 @@ impl RagSource {
     pub fn name(&self) -> String {
         match self {
             RagSource::NoRag => String::from("No Rag"),
-            RagSource::BashScript(file_path) => {
-                file_path.file_stem().unwrap().to_str().unwrap().to_string()
+            RagSource::Script(manifest) => manifest.name.clone(),
+            RagSource::DocumentIndex(config) => config.name.clone(),
+            RagSource::Conversations(config) => config.name(),
+        }
+    }
+
+    // Stable across renames, used to keep settings for a source
+    pub fn key(&self) -> String {
+        match self {
+            RagSource::NoRag => String::from("none"),
+            RagSource::Script(manifest) => {
+                format!("script:{}", manifest.manifest_path.to_string_lossy())
             }
+            RagSource::DocumentIndex(config) => format!("index:{}", config.id),
+            RagSource::Conversations(config) => format!("conversations:{}", config.name()),
         }
     }
 }
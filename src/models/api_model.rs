@@ -1,18 +1,27 @@
-use crate::utils;
 use async_openai::{
-    config::OpenAIConfig,
+    config::{OpenAIConfig, OPENAI_API_BASE},
+    error::OpenAIError,
     types::{
         ChatCompletionRequestAssistantMessageArgs, ChatCompletionRequestMessage,
+        ChatCompletionRequestMessageContentPart, ChatCompletionRequestMessageContentPartImageArgs,
         ChatCompletionRequestMessageContentPartTextArgs, ChatCompletionRequestSystemMessageArgs,
-        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs,
+        ChatCompletionRequestUserMessageArgs, CreateChatCompletionRequestArgs, ImageUrlArgs,
     },
     Client,
 };
 use serde::{Deserialize, Serialize};
-use std::{error::Error, fs::File, io::Read, path::PathBuf};
+use std::{collections::BTreeMap, error::Error, path::PathBuf, sync::atomic::AtomicBool};
 use tokio_stream::StreamExt;
 
-use super::{CoreLLM, Message, SavedConversation, SavedModel, UtilsLLM};
+use crate::{
+    attachments::{self, AttachmentOptions},
+    rag::RetrievedResult,
+};
+
+use super::{
+    prompt_template, request_inspector::RequestRecord, CoreLLM, Message, SavedConversation,
+    SavedModel, UtilsLLM,
+};
 use async_trait::async_trait;
 
 #[derive(Clone)]
@@ -24,17 +33,7 @@ pub struct ApiModel {
 
 impl ApiModel {
     pub fn new(model_name: String, api_key: String, api_type_from_saved: ApiTypeForSaving) -> Self {
-        let api_type = match api_type_from_saved {
-            ApiTypeForSaving::OpenAI => {
-                ApiType::OpenAI(OpenAIModel::new(model_name, api_key.clone()))
-            }
-            ApiTypeForSaving::Generic => todo!(),
-        };
-        Self {
-            api_key,
-            message_history: vec![],
-            api_type,
-        }
+        Self::new_from_conversation_and_model_name(vec![], model_name, api_key, api_type_from_saved)
     }
 
     pub fn new_from_conversation_and_model_name(
@@ -43,116 +42,249 @@ impl ApiModel {
         api_key: String,
         api_type_from_saved: ApiTypeForSaving,
     ) -> Self {
-        let api_type = match api_type_from_saved {
-            ApiTypeForSaving::OpenAI => {
-                ApiType::OpenAI(OpenAIModel::new(model_name, api_key.clone()))
-            }
-            ApiTypeForSaving::Generic => todo!(),
-        };
+        let api_type = ApiType::OpenAI(OpenAIModel::new(
+            model_name,
+            api_key.clone(),
+            &api_type_from_saved,
+        ));
         Self {
             api_key,
             message_history,
             api_type,
         }
     }
+
+    pub async fn list_available_models(
+        api_key: String,
+        api_type_from_saved: ApiTypeForSaving,
+    ) -> Result<Vec<String>, Box<dyn Error>> {
+        let client = OpenAIModel::create_client(api_key, &api_type_from_saved);
+        let mut model_names = client
+            .models()
+            .list()
+            .await?
+            .data
+            .into_iter()
+            .map(|model| model.id)
+            .collect::<Vec<String>>();
+        model_names.sort();
+        Ok(model_names)
+    }
+
+    pub async fn test_connection(
+        model_name: String,
+        api_key: String,
+        api_type_from_saved: ApiTypeForSaving,
+    ) -> Result<String, Box<dyn Error>> {
+        let client = OpenAIModel::create_client(api_key, &api_type_from_saved);
+        let request = CreateChatCompletionRequestArgs::default()
+            .model(&model_name)
+            .max_tokens(5_u16)
+            .messages(vec![ChatCompletionRequestUserMessageArgs::default()
+                .content("Say hello")
+                .build()?
+                .into()])
+            .build()?;
+        let response = client.chat().create(request).await?;
+        Ok(response
+            .choices
+            .first()
+            .and_then(|chat_choice| chat_choice.message.content.clone())
+            .unwrap_or_default())
+    }
 }
 
 #[derive(Clone)]
 enum ApiType {
     OpenAI(OpenAIModel),
-    Generic(GenericApi),
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq)]
+#[serde(try_from = "SavedApiType")]
 pub enum ApiTypeForSaving {
     OpenAI,
-    Generic,
+    // Any server speaking the OpenAI API, like vLLM or llama.cpp, with its base URL
+    Generic(String),
+}
+
+// Older api_models.json files have a plain "Generic" from before it took a base URL
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum SavedApiType {
+    Name(String),
+    Generic {
+        #[serde(rename = "Generic")]
+        base_url: String,
+    },
+}
+
+impl TryFrom<SavedApiType> for ApiTypeForSaving {
+    type Error = String;
+
+    fn try_from(saved_api_type: SavedApiType) -> Result<Self, Self::Error> {
+        match saved_api_type {
+            SavedApiType::Name(name) if name == "OpenAI" => Ok(ApiTypeForSaving::OpenAI),
+            // The base URL gets filled in by editing the model
+            SavedApiType::Name(name) if name == "Generic" => {
+                Ok(ApiTypeForSaving::Generic(String::new()))
+            }
+            SavedApiType::Name(name) => Err(format!("Unknown API type: {}", name)),
+            SavedApiType::Generic { base_url } => Ok(ApiTypeForSaving::Generic(base_url)),
+        }
+    }
+}
+
+impl ApiTypeForSaving {
+    pub fn name(&self) -> &str {
+        match self {
+            ApiTypeForSaving::OpenAI => "OpenAI",
+            ApiTypeForSaving::Generic(_) => "OpenAI compatible",
+        }
+    }
 }
 
 #[derive(Clone)]
 struct OpenAIModel {
     model_name: String,
     client: Client<OpenAIConfig>,
+    // Kept for the request inspector, the key only so it can be redacted
+    api_base: String,
+    api_key: String,
 }
 
 impl OpenAIModel {
-    pub fn new(model_name: String, api_key: String) -> Self {
-        let config = OpenAIConfig::new().with_api_key(api_key);
+    pub fn new(model_name: String, api_key: String, api_type: &ApiTypeForSaving) -> Self {
+        let client = Self::create_client(api_key.clone(), api_type);
+        let api_base = match api_type {
+            ApiTypeForSaving::OpenAI => OPENAI_API_BASE.to_owned(),
+            ApiTypeForSaving::Generic(base_url) => base_url.clone(),
+        };
 
-        let client = Client::with_config(config);
+        Self {
+            client,
+            model_name,
+            api_base,
+            api_key,
+        }
+    }
 
-        Self { client, model_name }
+    fn create_client(api_key: String, api_type: &ApiTypeForSaving) -> Client<OpenAIConfig> {
+        let config = match api_type {
+            ApiTypeForSaving::OpenAI => OpenAIConfig::new().with_api_key(api_key),
+            ApiTypeForSaving::Generic(base_url) => OpenAIConfig::new()
+                .with_api_key(api_key)
+                .with_api_base(base_url),
+        };
+
+        Client::with_config(config)
+    }
+
+    fn to_request_message(message: &Message) -> Result<ChatCompletionRequestMessage, OpenAIError> {
+        Ok(match message.role {
+            super::Role::User => {
+                let mut content_parts: Vec<ChatCompletionRequestMessageContentPart> =
+                    vec![ChatCompletionRequestMessageContentPartTextArgs::default()
+                        .text(&message.content)
+                        .build()?
+                        .into()];
+                // Images go as data URLs, as the API has no other way to take a local file
+                for image in message.images.iter().flatten() {
+                    content_parts.push(
+                        ChatCompletionRequestMessageContentPartImageArgs::default()
+                            .image_url(ImageUrlArgs::default().url(image.to_data_url()).build()?)
+                            .build()?
+                            .into(),
+                    );
+                }
+                ChatCompletionRequestUserMessageArgs::default()
+                    .content(content_parts)
+                    .build()?
+                    .into()
+            }
+            super::Role::Assistant => ChatCompletionRequestAssistantMessageArgs::default()
+                .content(&message.content)
+                .build()?
+                .into(),
+            super::Role::System => ChatCompletionRequestSystemMessageArgs::default()
+                .content(&message.content)
+                .build()?
+                .into(),
+        })
     }
 
     pub async fn stream_call(
         &self,
         conversation: Vec<Message>,
         list_sender: std::sync::mpsc::Sender<Message>,
-    ) -> String {
+        sources: Option<Vec<RetrievedResult>>,
+    ) -> Result<(String, Option<String>), Box<dyn Error + Send + Sync>> {
         let request = CreateChatCompletionRequestArgs::default()
             .model(&self.model_name)
             .messages(
                 conversation
                     .iter()
-                    .map(|message| match message.role {
-                        super::Role::User => ChatCompletionRequestUserMessageArgs::default()
-                            .content(vec![
-                                ChatCompletionRequestMessageContentPartTextArgs::default()
-                                    .text(&message.content)
-                                    .build()
-                                    .unwrap()
-                                    .into(),
-                            ])
-                            .build()
-                            .unwrap()
-                            .into(),
-                        super::Role::Assistant => {
-                            ChatCompletionRequestAssistantMessageArgs::default()
-                                .content(&message.content)
-                                .build()
-                                .unwrap()
-                                .into()
-                        }
-                        super::Role::System => ChatCompletionRequestSystemMessageArgs::default()
-                            .content(&message.content)
-                            .build()
-                            .unwrap()
-                            .into(),
-                    })
-                    .collect::<Vec<ChatCompletionRequestMessage>>(),
+                    .map(Self::to_request_message)
+                    .collect::<Result<Vec<ChatCompletionRequestMessage>, OpenAIError>>()?,
             )
-            .build()
-            .unwrap();
-        let mut stream = self.client.chat().create_stream(request).await.unwrap();
+            .build()?;
+        let mut request_value = serde_json::to_value(&request)?;
+        // The stream flag is only set once the request is sent
+        request_value["stream"] = serde_json::Value::Bool(true);
+        let mut request_record = RequestRecord::start(
+            "OpenAI",
+            format!("{}/chat/completions", self.api_base),
+            self.model_name.clone(),
+            BTreeMap::from([(
+                String::from("Authorization"),
+                format!("Bearer {}", self.api_key),
+            )]),
+            request_value,
+            vec![self.api_key.clone()],
+        );
+        let mut stream = match self.client.chat().create_stream(request).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                request_record.finish(Some(err.to_string()));
+                return Err(err.into());
+            }
+        };
         let mut response_text = String::new();
         while let Some(result) = stream.next().await {
-            match result {
-                Ok(response) => {
-                    response.choices.iter().for_each(|chat_choice| {
-                        if let Some(ref content) = chat_choice.delta.content {
-                            response_text += content.as_str();
-                            list_sender
-                                .send(Message {
-                                    role: super::Role::Assistant,
-                                    content: response_text.clone(),
-                                    images: None,
-                                })
-                                .unwrap();
-                        }
-                    });
-                }
+            let chunk = match result {
+                Ok(chunk) => chunk,
                 Err(err) => {
-                    println!("error: {err}");
+                    request_record.finish(Some(err.to_string()));
+                    return Err(err.into());
                 }
-            }
+            };
+            request_record.record_chunk(serde_json::to_string(&chunk).unwrap_or_default());
+            chunk.choices.iter().for_each(|chat_choice| {
+                if let Some(ref content) = chat_choice.delta.content {
+                    response_text += content.as_str();
+                    list_sender
+                        .send(Message {
+                            role: super::Role::Assistant,
+                            content: response_text.clone(),
+                            images: None,
+                            model_name: Some(self.model_name.clone()),
+                            request_id: request_record.saved_id(),
+                            original_content: None,
+                            rag_context: None,
+                            sources: sources.clone(),
+                            rag_error: None,
+                            rag_query: None,
+                            attachments: None,
+                        })
+                        .unwrap();
+                }
+            });
         }
-        response_text
+        let request_id = request_record.saved_id();
+        request_record.finish(None);
+        Ok((response_text, request_id))
     }
 }
 
-#[derive(Clone)]
-struct GenericApi {}
-
 #[async_trait]
 impl CoreLLM for ApiModel {
     fn reset_conversation(&mut self, conversation_file_path: PathBuf) {
@@ -167,39 +299,77 @@ impl CoreLLM for ApiModel {
         self.message_history = loaded_conversation.conversation;
     }
 
-    async fn ask(&mut self, user_message: Message, list_sender: std::sync::mpsc::Sender<Message>) {
-        self.message_history.push(user_message);
-        let response: String = match &self.api_type {
+    async fn ask(
+        &mut self,
+        user_message: Message,
+        list_sender: std::sync::mpsc::Sender<Message>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut conversation = self.message_history.clone();
+        conversation.push(user_message.clone());
+        let conversation = prompt_template::with_context_messages(conversation, &self.model_name());
+        let (response, request_id) = match &self.api_type {
             ApiType::OpenAI(openai) => {
                 openai
-                    .stream_call(self.message_history.clone(), list_sender)
-                    .await
+                    .stream_call(conversation, list_sender, user_message.sources.clone())
+                    .await?
             }
-            ApiType::Generic(_) => todo!(),
         };
+        let sources = user_message.sources.clone();
+        // Only keep the turn once it's answered, so a failed ask can be retried with the same history
+        self.message_history.push(user_message);
         self.message_history.push(Message {
             role: super::Role::Assistant,
             content: response,
             images: None,
+            model_name: Some(self.model_name()),
+            request_id,
+            original_content: None,
+            rag_context: None,
+            sources,
+            rag_error: None,
+            rag_query: None,
+            attachments: None,
         });
+        Ok(())
     }
 
     fn get_conversation(&mut self) -> Vec<Message> {
         self.message_history.clone()
     }
 
+    fn set_conversation(&mut self, conversation: Vec<Message>) {
+        self.message_history = conversation;
+    }
+
+    fn model_name(&self) -> String {
+        match &self.api_type {
+            ApiType::OpenAI(openai) => openai.model_name.clone(),
+        }
+    }
+
+    fn clone_box(&self) -> Box<dyn CoreLLM> {
+        Box::new(self.clone())
+    }
+
+    async fn context_window_tokens(&self) -> usize {
+        prompt_template::api_context_window_tokens(&self.model_name())
+    }
+
     fn export_conversation(&mut self, file_path: std::path::PathBuf) {
         if !self.message_history.is_empty() {
             let archived;
             let starred;
             let name;
+            let fallback_models;
             if let Some(loaded_conversation) = SavedConversation::load(&file_path) {
                 archived = loaded_conversation.archived;
                 starred = loaded_conversation.starred;
                 name = loaded_conversation.name;
+                fallback_models = loaded_conversation.fallback_models;
             } else {
                 archived = false;
                 starred = false;
+                fallback_models = None;
                 name = file_path
                     .file_stem()
                     .unwrap()
@@ -212,6 +382,7 @@ impl CoreLLM for ApiModel {
                 archived,
                 starred,
                 name,
+                fallback_models,
             };
             saved_conversation.save(&file_path);
         } else {
@@ -225,35 +396,46 @@ impl UtilsLLM for ApiModel {
         todo!()
     }
 
-    fn format_prompt(prompt: &str, rag_source: crate::RagSource) -> String {
-        todo!()
+    async fn format_prompt(
+        chat_message: Message,
+        rag_source: crate::RagSource,
+        conversation: Vec<Message>,
+        conversation_file_path: PathBuf,
+        model_name: String,
+        cancel_flag: &AtomicBool,
+    ) -> Message {
+        prompt_template::format_prompt(
+            chat_message,
+            rag_source,
+            conversation,
+            conversation_file_path,
+            model_name,
+            cancel_flag,
+        )
+        .await
     }
 
-    fn unformat_prompt(prompt: &str) -> String {
-        todo!()
+    fn unformat_prompt(chat_message: &Message) -> String {
+        prompt_template::unformat_prompt(chat_message)
     }
 
-    async fn pull_model(model_name: String, download_progress_bar: &gtk::ProgressBar) {
+    async fn pull_model(
+        model_name: String,
+        download_progress_bar: &gtk::ProgressBar,
+    ) -> Result<(), Box<dyn Error>> {
         todo!()
     }
 
     fn list_models() -> Result<Vec<SavedModel>, Box<dyn Error>> {
-        let file_path = utils::get_root_folder().join(PathBuf::from("models/api_models.json"));
-        if file_path.exists() {
-            let mut model_file = File::open(&file_path)?;
-
-            let mut json_data = String::new();
-            model_file.read_to_string(&mut json_data)?;
-
-            let loaded_models: Vec<SavedModel> = serde_json::from_str(&json_data)?;
-            Ok(loaded_models)
-        } else {
-            Ok(vec![])
-        }
+        SavedModel::read_from_file(PathBuf::from("api_models.json"))
     }
 
-    fn process_file_for_prompt(chat_message: Message, file_path: std::path::PathBuf) -> Message {
-        todo!()
+    fn process_file_for_prompt(
+        chat_message: Message,
+        file_path: std::path::PathBuf,
+        options: &AttachmentOptions,
+    ) -> Message {
+        attachments::process_file_for_prompt(chat_message, file_path, options)
     }
 
     async fn delete_model(model_name: String) {
@@ -0,0 +1,361 @@
+use argon2::Argon2;
+use base64::prelude::*;
+use chacha20poly1305::{
+    aead::{rand_core::RngCore, Aead, AeadCore, KeyInit, OsRng},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{Read, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path::PathBuf,
+    sync::Mutex,
+};
+use uuid::Uuid;
+
+use crate::utils::get_root_folder;
+
+const KEYRING_SERVICE: &str = "com.github.leo030303.Comhra";
+const VAULT_CHECK_VALUE: &str = "comhra-vault";
+// ChaCha20-Poly1305 nonces are 96 bits
+const NONCE_BYTES: usize = 12;
+
+// The passphrase is only held in memory for the lifetime of the app
+static VAULT_PASSPHRASE: Mutex<Option<String>> = Mutex::new(None);
+
+/*
+Where API keys live, SavedModel only keeps an ApiKeyRef pointing in here
+- Secret Service (GNOME Keyring, KWallet) when the desktop provides it
+- Otherwise a local file encrypted with a key derived from the user's passphrase
+*/
+pub trait CredentialBackend {
+    fn name(&self) -> &str;
+
+    fn store(&self, credential_id: &str, secret: &str) -> Result<(), Box<dyn Error>>;
+
+    fn retrieve(&self, credential_id: &str) -> Result<Option<String>, Box<dyn Error>>;
+
+    fn delete(&self, credential_id: &str) -> Result<(), Box<dyn Error>>;
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum ApiKeyRef {
+    Stored { credential_id: String },
+    // Raw keys from before the credential store, these get migrated on startup
+    Plaintext(String),
+}
+
+impl ApiKeyRef {
+    pub fn resolve(&self) -> Result<String, Box<dyn Error>> {
+        match self {
+            ApiKeyRef::Stored { credential_id } => CredentialStore::open()?
+                .retrieve(credential_id)?
+                .ok_or(format!("No credential stored for {}", credential_id).into()),
+            ApiKeyRef::Plaintext(api_key) => Ok(api_key.clone()),
+        }
+    }
+}
+
+pub struct SecretServiceBackend {}
+
+impl SecretServiceBackend {
+    pub fn is_available() -> bool {
+        match keyring::Entry::new(KEYRING_SERVICE, "availability-check") {
+            Ok(entry) => matches!(entry.get_password(), Ok(_) | Err(keyring::Error::NoEntry)),
+            Err(_) => false,
+        }
+    }
+}
+
+impl CredentialBackend for SecretServiceBackend {
+    fn name(&self) -> &str {
+        "Secret Service"
+    }
+
+    fn store(&self, credential_id: &str, secret: &str) -> Result<(), Box<dyn Error>> {
+        keyring::Entry::new(KEYRING_SERVICE, credential_id)?.set_password(secret)?;
+        Ok(())
+    }
+
+    fn retrieve(&self, credential_id: &str) -> Result<Option<String>, Box<dyn Error>> {
+        match keyring::Entry::new(KEYRING_SERVICE, credential_id)?.get_password() {
+            Ok(secret) => Ok(Some(secret)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn delete(&self, credential_id: &str) -> Result<(), Box<dyn Error>> {
+        match keyring::Entry::new(KEYRING_SERVICE, credential_id)?.delete_password() {
+            Ok(()) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct EncryptedValue {
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct VaultFile {
+    salt: String,
+    check: EncryptedValue,
+    entries: HashMap<String, EncryptedValue>,
+}
+
+pub struct EncryptedFileBackend {
+    passphrase: String,
+}
+
+impl EncryptedFileBackend {
+    pub fn new(passphrase: String) -> Self {
+        Self { passphrase }
+    }
+
+    pub fn vault_path() -> PathBuf {
+        get_root_folder().join(PathBuf::from("./credentials/vault.json"))
+    }
+
+    fn derive_cipher(&self, salt: &[u8]) -> Result<ChaCha20Poly1305, Box<dyn Error>> {
+        let mut key = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(self.passphrase.as_bytes(), salt, &mut key)
+            .map_err(|err| format!("Error deriving vault key: {}", err))?;
+        Ok(ChaCha20Poly1305::new(Key::from_slice(&key)))
+    }
+
+    fn encrypt(cipher: &ChaCha20Poly1305, secret: &str) -> Result<EncryptedValue, Box<dyn Error>> {
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, secret.as_bytes())
+            .map_err(|_| "Error encrypting credential")?;
+        Ok(EncryptedValue {
+            nonce: BASE64_STANDARD.encode(nonce),
+            ciphertext: BASE64_STANDARD.encode(ciphertext),
+        })
+    }
+
+    fn decrypt(
+        cipher: &ChaCha20Poly1305,
+        encrypted_value: &EncryptedValue,
+    ) -> Result<String, Box<dyn Error>> {
+        let nonce = BASE64_STANDARD.decode(&encrypted_value.nonce)?;
+        // from_slice panics on any other length, which a hand-edited vault could have
+        if nonce.len() != NONCE_BYTES {
+            return Err("Corrupted vault, a nonce has the wrong length".into());
+        }
+        let ciphertext = BASE64_STANDARD.decode(&encrypted_value.ciphertext)?;
+        let plaintext = cipher
+            .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| "Wrong passphrase or corrupted vault")?;
+        Ok(String::from_utf8(plaintext)?)
+    }
+
+    // Loads the vault, creating an empty one with a fresh salt if there isn't one yet
+    fn load(&self) -> Result<(VaultFile, ChaCha20Poly1305), Box<dyn Error>> {
+        let vault_path = Self::vault_path();
+        if vault_path.exists() {
+            let mut vault_file = File::open(&vault_path)?;
+            let mut json_data = String::new();
+            vault_file.read_to_string(&mut json_data)?;
+            let vault: VaultFile = serde_json::from_str(&json_data)?;
+            let cipher = self.derive_cipher(&BASE64_STANDARD.decode(&vault.salt)?)?;
+            Self::decrypt(&cipher, &vault.check)?;
+            Ok((vault, cipher))
+        } else {
+            let mut salt = [0u8; 16];
+            OsRng.fill_bytes(&mut salt);
+            let cipher = self.derive_cipher(&salt)?;
+            let vault = VaultFile {
+                salt: BASE64_STANDARD.encode(salt),
+                check: Self::encrypt(&cipher, VAULT_CHECK_VALUE)?,
+                entries: HashMap::new(),
+            };
+            Ok((vault, cipher))
+        }
+    }
+
+    fn save(vault: &VaultFile) -> Result<(), Box<dyn Error>> {
+        let vault_path = Self::vault_path();
+        if let Some(parent) = vault_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Only readable by the user, the salt and check value help anyone guessing the passphrase
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&vault_path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(serde_json::to_string(vault)?.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn check_passphrase(&self) -> Result<(), Box<dyn Error>> {
+        self.load().map(|_| ())
+    }
+}
+
+impl CredentialBackend for EncryptedFileBackend {
+    fn name(&self) -> &str {
+        "Encrypted file"
+    }
+
+    fn store(&self, credential_id: &str, secret: &str) -> Result<(), Box<dyn Error>> {
+        let (mut vault, cipher) = self.load()?;
+        vault
+            .entries
+            .insert(credential_id.to_owned(), Self::encrypt(&cipher, secret)?);
+        Self::save(&vault)
+    }
+
+    fn retrieve(&self, credential_id: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let (vault, cipher) = self.load()?;
+        match vault.entries.get(credential_id) {
+            Some(encrypted_value) => Ok(Some(Self::decrypt(&cipher, encrypted_value)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn delete(&self, credential_id: &str) -> Result<(), Box<dyn Error>> {
+        let (mut vault, _) = self.load()?;
+        vault.entries.remove(credential_id);
+        Self::save(&vault)
+    }
+}
+
+#[derive(Debug)]
+pub enum CredentialStoreError {
+    // The encrypted file is in use and nobody has entered the passphrase yet
+    Locked,
+}
+
+impl fmt::Display for CredentialStoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CredentialStoreError::Locked => write!(f, "The credential vault is locked"),
+        }
+    }
+}
+
+impl Error for CredentialStoreError {}
+
+pub struct CredentialStore {
+    backend: Box<dyn CredentialBackend>,
+}
+
+impl CredentialStore {
+    pub fn open() -> Result<Self, CredentialStoreError> {
+        let backend: Box<dyn CredentialBackend> = if SecretServiceBackend::is_available() {
+            Box::new(SecretServiceBackend {})
+        } else {
+            let Some(passphrase) = VAULT_PASSPHRASE.lock().unwrap().clone() else {
+                return Err(CredentialStoreError::Locked);
+            };
+            Box::new(EncryptedFileBackend::new(passphrase))
+        };
+        Ok(Self { backend })
+    }
+
+    pub fn needs_passphrase() -> bool {
+        !SecretServiceBackend::is_available() && VAULT_PASSPHRASE.lock().unwrap().is_none()
+    }
+
+    pub fn unlock(passphrase: String) -> Result<(), Box<dyn Error>> {
+        EncryptedFileBackend::new(passphrase.clone()).check_passphrase()?;
+        *VAULT_PASSPHRASE.lock().unwrap() = Some(passphrase);
+        Ok(())
+    }
+
+    pub fn backend_name(&self) -> &str {
+        self.backend.name()
+    }
+
+    pub fn store_new(&self, secret: &str) -> Result<ApiKeyRef, Box<dyn Error>> {
+        let credential_id = Uuid::new_v4().to_string();
+        self.backend.store(&credential_id, secret)?;
+        Ok(ApiKeyRef::Stored { credential_id })
+    }
+
+    pub fn store(&self, credential_id: &str, secret: &str) -> Result<(), Box<dyn Error>> {
+        self.backend.store(credential_id, secret)
+    }
+
+    pub fn retrieve(&self, credential_id: &str) -> Result<Option<String>, Box<dyn Error>> {
+        self.backend.retrieve(credential_id)
+    }
+
+    pub fn delete(&self, credential_id: &str) -> Result<(), Box<dyn Error>> {
+        self.backend.delete(credential_id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_SALT: [u8; 16] = [7; 16];
+
+    fn test_cipher(passphrase: &str) -> ChaCha20Poly1305 {
+        EncryptedFileBackend::new(String::from(passphrase))
+            .derive_cipher(&TEST_SALT)
+            .unwrap()
+    }
+
+    #[test]
+    fn encrypted_secret_decrypts_with_the_same_passphrase() {
+        let encrypted_value =
+            EncryptedFileBackend::encrypt(&test_cipher("correct horse"), "sk-test-key").unwrap();
+        assert_ne!(encrypted_value.ciphertext, "sk-test-key");
+        let decrypted =
+            EncryptedFileBackend::decrypt(&test_cipher("correct horse"), &encrypted_value).unwrap();
+        assert_eq!(decrypted, "sk-test-key");
+    }
+
+    #[test]
+    fn wrong_passphrase_fails_to_decrypt() {
+        let encrypted_value =
+            EncryptedFileBackend::encrypt(&test_cipher("correct horse"), VAULT_CHECK_VALUE)
+                .unwrap();
+        assert!(
+            EncryptedFileBackend::decrypt(&test_cipher("battery staple"), &encrypted_value)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn each_encryption_uses_a_new_nonce() {
+        let cipher = test_cipher("correct horse");
+        let first = EncryptedFileBackend::encrypt(&cipher, "sk-test-key").unwrap();
+        let second = EncryptedFileBackend::encrypt(&cipher, "sk-test-key").unwrap();
+        assert_ne!(first.nonce, second.nonce);
+        assert_ne!(first.ciphertext, second.ciphertext);
+    }
+
+    #[test]
+    fn nonce_with_the_wrong_length_is_an_error() {
+        let cipher = test_cipher("correct horse");
+        let mut encrypted_value = EncryptedFileBackend::encrypt(&cipher, "sk-test-key").unwrap();
+        encrypted_value.nonce = BASE64_STANDARD.encode([0u8; 8]);
+        assert!(EncryptedFileBackend::decrypt(&cipher, &encrypted_value).is_err());
+    }
+
+    #[test]
+    fn tampered_ciphertext_is_rejected() {
+        let cipher = test_cipher("correct horse");
+        let mut encrypted_value = EncryptedFileBackend::encrypt(&cipher, "sk-test-key").unwrap();
+        let mut ciphertext = BASE64_STANDARD.decode(&encrypted_value.ciphertext).unwrap();
+        ciphertext[0] ^= 1;
+        encrypted_value.ciphertext = BASE64_STANDARD.encode(ciphertext);
+        assert!(EncryptedFileBackend::decrypt(&cipher, &encrypted_value).is_err());
+    }
+}
@@ -0,0 +1,242 @@
+use core::time;
+use std::{
+    error::Error,
+    path::PathBuf,
+    sync::{mpsc::Sender, Arc, Mutex},
+};
+
+use gtk::glib;
+
+use crate::settings::Settings;
+
+use super::{CoreLLM, Message, SavedConversation, SavedModel};
+
+// The conversation's own list if it has one, otherwise the global one
+pub fn fallback_models_for_conversation(conversation_file_path: &PathBuf) -> Vec<SavedModel> {
+    SavedConversation::load(conversation_file_path)
+        .and_then(|saved_conversation| saved_conversation.fallback_models)
+        .unwrap_or_else(|| Settings::load().fallback_models)
+}
+
+// Past this the backoff would wait for hours, and 1 << 64 overflows
+const MAX_RETRY_ATTEMPTS: u32 = 8;
+
+// Phrases the APIs use for rate limits, server errors and dropped connections when the status code is lost
+const TRANSIENT_ERROR_PHRASES: [&str; 13] = [
+    "429",
+    "too many requests",
+    "rate limit",
+    "rate_limit",
+    "status code: 5",
+    "internal server error",
+    "bad gateway",
+    "service unavailable",
+    "gateway timeout",
+    "overloaded",
+    "error sending request",
+    "error reading response from",
+    "connection",
+];
+
+/*
+Only rate limits, server errors and connection errors are worth retrying
+Anything else (a bad key, an unknown model) fails the same way every time, so the next model is asked instead
+*/
+fn is_transient(err: &(dyn Error + 'static)) -> bool {
+    let mut current_error = Some(err);
+    while let Some(error) = current_error {
+        if let Some(reqwest_error) = error.downcast_ref::<reqwest::Error>() {
+            if reqwest_error.is_timeout() || reqwest_error.is_connect() {
+                return true;
+            }
+            if let Some(status) = reqwest_error.status() {
+                return status.as_u16() == 429 || status.is_server_error();
+            }
+        }
+        let message = error.to_string().to_lowercase();
+        if TRANSIENT_ERROR_PHRASES
+            .iter()
+            .any(|phrase| message.contains(phrase))
+        {
+            return true;
+        }
+        current_error = error.source();
+    }
+    false
+}
+
+async fn ask_with_retries(
+    chat_model: &mut Box<dyn CoreLLM>,
+    user_message: &Message,
+    list_sender: &Sender<Message>,
+    retry_attempts: u32,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut attempt = 0;
+    loop {
+        match chat_model
+            .ask(user_message.clone(), list_sender.clone())
+            .await
+        {
+            Ok(()) => return Ok(()),
+            Err(err) if attempt + 1 < retry_attempts && is_transient(err.as_ref()) => {
+                let delay = time::Duration::from_secs(2u64.saturating_pow(attempt));
+                println!(
+                    "{0} failed: {1}, retrying in {2:?}",
+                    chat_model.model_name(),
+                    err,
+                    delay
+                );
+                glib::timeout_future(delay).await;
+                attempt += 1;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Streamed messages hold the whole answer so far, an empty one wipes what a failed model got through
+fn clear_partial_answer(list_sender: &Sender<Message>) {
+    list_sender
+        .send(Message {
+            role: super::Role::Assistant,
+            content: String::new(),
+            images: None,
+            model_name: None,
+            request_id: None,
+            original_content: None,
+            rag_context: None,
+            sources: None,
+            rag_error: None,
+            rag_query: None,
+            attachments: None,
+        })
+        .expect("List channel needs to be open.");
+}
+
+/*
+Asks the selected model, retrying rate limits, server and connection errors with exponential backoff
+If it still fails, each fallback model is asked in order with the same history
+The selected model stays selected, it's given the fallback's history so the next turn can go back to it
+The shared model is only locked to copy it out and to write the history back, never across an await
+Returns the name of the model that answered, or every error on the way
+*/
+pub async fn ask_with_fallback(
+    chat_model: &Arc<Mutex<Box<dyn CoreLLM>>>,
+    user_message: Message,
+    list_sender: Sender<Message>,
+    fallback_models: Vec<SavedModel>,
+) -> Result<String, String> {
+    let retry_attempts = Settings::load()
+        .fallback_retry_attempts
+        .clamp(1, MAX_RETRY_ATTEMPTS);
+    let mut errors = vec![];
+
+    let (mut primary_model, conversation) = {
+        let mut chat_model = chat_model.lock().unwrap();
+        (chat_model.clone_box(), chat_model.get_conversation())
+    };
+    let primary_model_name = primary_model.model_name();
+    match ask_with_retries(
+        &mut primary_model,
+        &user_message,
+        &list_sender,
+        retry_attempts,
+    )
+    .await
+    {
+        Ok(()) => {
+            write_back_conversation(
+                chat_model,
+                &primary_model_name,
+                conversation.len(),
+                primary_model.get_conversation(),
+            );
+            return Ok(primary_model_name);
+        }
+        Err(err) => errors.push(format!("{0}: {1}", primary_model_name, err)),
+    }
+
+    for saved_model in fallback_models {
+        if saved_model.name == primary_model_name {
+            continue;
+        }
+        let mut fallback_model = match saved_model.load_model(conversation.clone()) {
+            Ok(fallback_model) => fallback_model,
+            Err(err) => {
+                errors.push(format!("{0}: {1}", saved_model.display_name(), err));
+                continue;
+            }
+        };
+        println!("Falling back to {}", saved_model.display_name());
+        clear_partial_answer(&list_sender);
+        match ask_with_retries(
+            &mut fallback_model,
+            &user_message,
+            &list_sender,
+            retry_attempts,
+        )
+        .await
+        {
+            Ok(()) => {
+                write_back_conversation(
+                    chat_model,
+                    &primary_model_name,
+                    conversation.len(),
+                    fallback_model.get_conversation(),
+                );
+                return Ok(saved_model.name);
+            }
+            Err(err) => errors.push(format!("{0}: {1}", saved_model.display_name(), err)),
+        }
+    }
+    Err(errors.join("\n"))
+}
+
+// Skipped if another model or conversation was opened while this one was answering
+fn write_back_conversation(
+    chat_model: &Arc<Mutex<Box<dyn CoreLLM>>>,
+    model_name: &str,
+    conversation_length: usize,
+    answered_conversation: Vec<Message>,
+) {
+    let mut chat_model = chat_model.lock().unwrap();
+    if chat_model.model_name() == model_name
+        && chat_model.get_conversation().len() == conversation_length
+    {
+        chat_model.set_conversation(answered_conversation);
+    } else {
+        println!("The conversation changed while answering, the answer wasn't kept");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(message: &str) -> Box<dyn Error + Send + Sync> {
+        message.into()
+    }
+
+    #[test]
+    fn rate_limits_and_server_errors_are_retried() {
+        assert!(is_transient(
+            error("Invalid status code: 429 Too Many Requests").as_ref()
+        ));
+        assert!(is_transient(
+            error("Invalid status code: 503 Service Unavailable").as_ref()
+        ));
+        assert!(is_transient(
+            error("Error reading response from llama3").as_ref()
+        ));
+    }
+
+    #[test]
+    fn auth_and_model_errors_go_to_the_next_model() {
+        assert!(!is_transient(
+            error("Invalid status code: 401 Unauthorized").as_ref()
+        ));
+        assert!(!is_transient(
+            error("The model `gpt-9` does not exist").as_ref()
+        ));
+    }
+}
@@ -1,22 +1,34 @@
 use async_trait::async_trait;
+use base64::prelude::*;
 use serde::{Deserialize, Serialize};
 use std::{
     error::Error,
     fs::{self, File},
     io::{Read, Write},
     path::PathBuf,
-    sync::mpsc::Sender,
+    sync::{atomic::AtomicBool, mpsc::Sender},
 };
 
-use crate::{utils::get_root_folder, RagSource};
+use crate::{
+    attachments::{Attachment, AttachmentOptions},
+    rag::RetrievedResult,
+    utils::get_root_folder,
+    RagSource,
+};
 
 use self::{
     api_model::{ApiModel, ApiTypeForSaving},
+    credential_store::{ApiKeyRef, CredentialStore},
     ollama_model::OllamaModel,
 };
 
 pub mod api_model;
+pub mod credential_store;
+pub mod fallback;
+pub mod model_registry;
 pub mod ollama_model;
+pub mod prompt_template;
+pub mod request_inspector;
 
 #[async_trait]
 pub trait CoreLLM {
@@ -24,27 +36,55 @@ pub trait CoreLLM {
 
     fn load_conversation_file(&mut self, file_path: PathBuf);
 
-    async fn ask(&mut self, user_message: Message, list_sender: Sender<Message>);
+    async fn ask(
+        &mut self,
+        user_message: Message,
+        list_sender: Sender<Message>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>>;
 
     fn get_conversation(&mut self) -> Vec<Message>;
 
+    fn set_conversation(&mut self, conversation: Vec<Message>);
+
+    fn model_name(&self) -> String;
+
+    // How many tokens the model reads at once, attachments are cut to fit in it
+    async fn context_window_tokens(&self) -> usize;
+
     fn export_conversation(&mut self, file_path: PathBuf);
+
+    // A copy to ask without keeping the shared model locked while it answers
+    fn clone_box(&self) -> Box<dyn CoreLLM>;
 }
 
 pub trait UtilsLLM {
     fn default_model_string() -> String;
 
-    fn format_prompt(prompt: &str, rag_source: RagSource) -> String;
+    async fn format_prompt(
+        chat_message: Message,
+        rag_source: RagSource,
+        conversation: Vec<Message>,
+        conversation_file_path: PathBuf,
+        model_name: String,
+        cancel_flag: &AtomicBool,
+    ) -> Message;
 
-    fn unformat_prompt(prompt: &str) -> String;
+    fn unformat_prompt(chat_message: &Message) -> String;
 
-    async fn pull_model(model_name: String, download_progress_bar: &gtk::ProgressBar);
+    async fn pull_model(
+        model_name: String,
+        download_progress_bar: &gtk::ProgressBar,
+    ) -> Result<(), Box<dyn Error>>;
 
     async fn delete_model(model_name: String);
 
     fn list_models() -> Result<Vec<SavedModel>, Box<dyn Error>>;
 
-    fn process_file_for_prompt(chat_message: Message, file_path: PathBuf) -> Message;
+    fn process_file_for_prompt(
+        chat_message: Message,
+        file_path: PathBuf,
+        options: &AttachmentOptions,
+    ) -> Message;
 }
 
 #[derive(Serialize, Deserialize)]
@@ -53,6 +93,9 @@ pub struct SavedConversation {
     pub archived: bool,
     pub starred: bool,
     pub name: String,
+    // Overrides the global fallback list from Settings when set
+    #[serde(default)]
+    pub fallback_models: Option<Vec<SavedModel>>,
 }
 impl SavedConversation {
     pub fn load(file_path: &PathBuf) -> Option<Self> {
@@ -107,11 +150,63 @@ pub struct B64Image {
     b64_string: String,
 }
 
+impl B64Image {
+    pub fn new(b64_string: String) -> Self {
+        Self { b64_string }
+    }
+
+    // From the file signature, new attachments are always PNG or JPEG but saved ones may be older
+    pub fn mime_type(&self) -> &'static str {
+        if self.b64_string.starts_with("iVBORw") {
+            "image/png"
+        } else if self.b64_string.starts_with("R0lGOD") {
+            "image/gif"
+        } else if self.b64_string.starts_with("UklGR") {
+            "image/webp"
+        } else {
+            "image/jpeg"
+        }
+    }
+
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        BASE64_STANDARD.decode(&self.b64_string).ok()
+    }
+
+    // For backends that take images as URLs, like OpenAI's
+    pub fn to_data_url(&self) -> String {
+        format!("data:{0};base64,{1}", self.mime_type(), self.b64_string)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct Message {
     pub role: Role,
     pub content: String,
     pub images: Option<Vec<B64Image>>,
+    // Which model actually answered, as a fallback model may have stepped in
+    #[serde(default)]
+    pub model_name: Option<String>,
+    // Links an answer to its record in the request inspector
+    #[serde(default)]
+    pub request_id: Option<String>,
+    // What the user typed, before any context was added to content
+    #[serde(default)]
+    pub original_content: Option<String>,
+    // Raw text retrieved from the RAG source, sent as its own context and shown in the UI
+    #[serde(default)]
+    pub rag_context: Option<String>,
+    // The results behind rag_context, answers keep them too so their [n] citations can be opened
+    #[serde(default)]
+    pub sources: Option<Vec<RetrievedResult>>,
+    // Why the RAG source gave nothing, e.g. a script's stderr, shown where the context would be
+    #[serde(default)]
+    pub rag_error: Option<String>,
+    // The standalone search query the prompt was rewritten into, shown with the retrieved context
+    #[serde(default)]
+    pub rag_query: Option<String>,
+    // Files sent with the message, their text is only joined to content when it's sent
+    #[serde(default)]
+    pub attachments: Option<Vec<Attachment>>,
 }
 
 pub trait FromMessage {
@@ -122,7 +217,7 @@ pub trait ToMessage {
     fn to_message(&self) -> Message;
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub struct SavedModel {
     pub name: String,
     pub model_type: ModelType,
@@ -130,43 +225,126 @@ pub struct SavedModel {
 
 impl SavedModel {
     pub fn write_to_file(file_path: PathBuf, models_list: Vec<SavedModel>) {
-        if !models_list.is_empty() {
-            let mut model_folder_path = get_root_folder().join(PathBuf::from("./models"));
-            fs::create_dir_all(&model_folder_path).expect("Failed to create parent directories");
-            model_folder_path.push(file_path);
-            let serialised_models =
-                serde_json::to_string(&models_list).expect("Error converting conversation to JSON");
-            println!(
-                "Writing models to file: {}",
-                model_folder_path.to_str().unwrap()
-            );
-            let mut file = File::create(model_folder_path).expect("Failed to create file");
+        let mut model_folder_path = get_root_folder().join(PathBuf::from("./models"));
+        fs::create_dir_all(&model_folder_path).expect("Failed to create parent directories");
+        model_folder_path.push(file_path);
+        let serialised_models =
+            serde_json::to_string(&models_list).expect("Error converting conversation to JSON");
+        println!(
+            "Writing models to file: {}",
+            model_folder_path.to_str().unwrap()
+        );
+        let mut file = File::create(model_folder_path).expect("Failed to create file");
+
+        // Write the JSON data to the file, an empty list is written too so removed models stay removed
+        file.write_all(serialised_models.as_bytes())
+            .expect("Failed to write data to file");
+    }
+    pub fn read_from_file(file_path: PathBuf) -> Result<Vec<SavedModel>, Box<dyn Error>> {
+        let model_file_path = get_root_folder()
+            .join(PathBuf::from("./models"))
+            .join(file_path);
+        if model_file_path.exists() {
+            let mut model_file = File::open(&model_file_path)?;
+
+            let mut json_data = String::new();
+            model_file.read_to_string(&mut json_data)?;
 
-            // Write the JSON data to the file
-            file.write_all(serialised_models.as_bytes())
-                .expect("Failed to write data to file");
+            let loaded_models: Vec<SavedModel> = serde_json::from_str(&json_data)?;
+            Ok(loaded_models)
         } else {
-            println!("Model list empty.");
+            Ok(vec![])
         }
     }
-    pub fn get_all() -> Vec<SavedModel> {
-        let mut saved_models_list = vec![];
-        saved_models_list.append(&mut OllamaModel::list_models().unwrap_or_else(|err| {
-            println!("Error: {:?}", err);
-            vec![]
-        }));
-        saved_models_list.append(&mut ApiModel::list_models().unwrap_or_else(|err| {
-            println!("Error: {:?}", err);
-            vec![]
-        }));
-        saved_models_list
+
+    pub fn load_model(
+        &self,
+        conversation: Vec<Message>,
+    ) -> Result<Box<dyn CoreLLM>, Box<dyn Error>> {
+        Ok(match &self.model_type {
+            ModelType::Ollama => Box::new(OllamaModel::new_from_conversation_and_model_name(
+                conversation,
+                self.name.clone(),
+            )),
+            ModelType::Api(api_key_ref, api_type) => {
+                Box::new(ApiModel::new_from_conversation_and_model_name(
+                    conversation,
+                    self.name.clone(),
+                    api_key_ref.resolve()?,
+                    api_type.clone(),
+                ))
+            }
+        })
+    }
+
+    pub fn display_name(&self) -> String {
+        format!("{0}: {1}", self.model_type.name(), &self.name)
+    }
+
+    // Moves any API keys still saved in plain text into the credential store
+    pub fn migrate_plaintext_api_keys() {
+        let api_models = match ApiModel::list_models() {
+            Ok(api_models) => api_models,
+            Err(err) => {
+                println!("Error loading API models for migration: {:?}", err);
+                return;
+            }
+        };
+        if !api_models.iter().any(|saved_model| {
+            matches!(
+                saved_model.model_type,
+                ModelType::Api(ApiKeyRef::Plaintext(_), _)
+            )
+        }) {
+            return;
+        }
+        let credential_store = match CredentialStore::open() {
+            Ok(credential_store) => credential_store,
+            Err(err) => {
+                // Runs again once the vault is unlocked
+                println!("Error opening credential store for migration: {}", err);
+                return;
+            }
+        };
+        println!(
+            "Migrating API keys to credential store: {}",
+            credential_store.backend_name()
+        );
+        let mut migrated_models = vec![];
+        let mut migration_errors = vec![];
+        for saved_model in api_models {
+            let model_type = match saved_model.model_type {
+                ModelType::Api(ApiKeyRef::Plaintext(api_key), api_type) => {
+                    match credential_store.store_new(&api_key) {
+                        Ok(api_key_ref) => ModelType::Api(api_key_ref, api_type),
+                        Err(err) => {
+                            // Keep the plaintext key so it can be migrated on the next start
+                            migration_errors.push(format!("{}: {}", saved_model.name, err));
+                            ModelType::Api(ApiKeyRef::Plaintext(api_key), api_type)
+                        }
+                    }
+                }
+                model_type => model_type,
+            };
+            migrated_models.push(SavedModel {
+                name: saved_model.name,
+                model_type,
+            });
+        }
+        if !migration_errors.is_empty() {
+            println!(
+                "Error migrating API keys, these were left in place: {}",
+                migration_errors.join(", ")
+            );
+        }
+        SavedModel::write_to_file(PathBuf::from("api_models.json"), migrated_models);
     }
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 pub enum ModelType {
     Ollama,
-    Api(String, ApiTypeForSaving),
+    Api(ApiKeyRef, ApiTypeForSaving),
 }
 
 impl ModelType {
@@ -0,0 +1,131 @@
+use core::time;
+use std::{
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use gtk::glib;
+
+use super::{api_model::ApiModel, ollama_model::OllamaModel, SavedModel, UtilsLLM};
+
+const MODEL_CACHE_FILE: &str = "model_cache.json";
+const REFRESH_INTERVAL_SECONDS: u64 = 30;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ModelRegistryUpdate {
+    pub models: Vec<SavedModel>,
+    pub ollama_available: bool,
+}
+
+/*
+Keeps the list of usable models without blocking the UI thread
+- Starts from the last known list cached on disk
+- Discovers Ollama and API models asynchronously on the GTK main context
+- Sends every change to subscribed widgets, which poll their receivers
+- Refreshed periodically, and after pulls, deletes and API model edits
+*/
+#[derive(Clone)]
+pub struct ModelRegistry {
+    state: Arc<Mutex<ModelRegistryUpdate>>,
+    subscribers: Arc<Mutex<Vec<Sender<ModelRegistryUpdate>>>>,
+}
+
+impl ModelRegistry {
+    pub fn new() -> Self {
+        let cached_models = SavedModel::read_from_file(PathBuf::from(MODEL_CACHE_FILE))
+            .unwrap_or_else(|err| {
+                println!("Error loading model cache: {:?}", err);
+                vec![]
+            });
+        Self {
+            state: Arc::new(Mutex::new(ModelRegistryUpdate {
+                models: cached_models,
+                ollama_available: true,
+            })),
+            subscribers: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    // For code without the registry at hand, e.g. finding the type of the model in use by its name
+    pub fn find_cached(model_name: &str) -> Option<SavedModel> {
+        SavedModel::read_from_file(PathBuf::from(MODEL_CACHE_FILE))
+            .unwrap_or_default()
+            .into_iter()
+            .find(|saved_model| saved_model.name == model_name)
+    }
+
+    pub fn models(&self) -> Vec<SavedModel> {
+        self.state.lock().unwrap().models.clone()
+    }
+
+    pub fn ollama_available(&self) -> bool {
+        self.state.lock().unwrap().ollama_available
+    }
+
+    // The new receiver gets the current state straight away
+    pub fn subscribe(&self) -> Receiver<ModelRegistryUpdate> {
+        let (update_sender, update_receiver) = mpsc::channel();
+        update_sender
+            .send(self.state.lock().unwrap().clone())
+            .expect("Registry channel needs to be open.");
+        self.subscribers.lock().unwrap().push(update_sender);
+        update_receiver
+    }
+
+    pub fn start(&self) {
+        let model_registry = self.clone();
+        glib::MainContext::default().spawn_local(async move {
+            loop {
+                model_registry.discover().await;
+                glib::timeout_future(time::Duration::from_secs(REFRESH_INTERVAL_SECONDS)).await;
+            }
+        });
+    }
+
+    pub fn refresh(&self) {
+        let model_registry = self.clone();
+        glib::MainContext::default().spawn_local(async move {
+            model_registry.discover().await;
+        });
+    }
+
+    async fn discover(&self) {
+        let (mut models, ollama_available) = match OllamaModel::list_models_async().await {
+            Ok(ollama_models) => (ollama_models, true),
+            Err(err) => {
+                println!("Error listing Ollama models: {:?}", err);
+                (vec![], false)
+            }
+        };
+        models.append(&mut ApiModel::list_models().unwrap_or_else(|err| {
+            println!("Error: {:?}", err);
+            vec![]
+        }));
+        self.publish(ModelRegistryUpdate {
+            models,
+            ollama_available,
+        });
+    }
+
+    fn publish(&self, update: ModelRegistryUpdate) {
+        if *self.state.lock().unwrap() == update {
+            return;
+        }
+        println!("Model list changed, {} models", update.models.len());
+        SavedModel::write_to_file(PathBuf::from(MODEL_CACHE_FILE), update.models.clone());
+        *self.state.lock().unwrap() = update.clone();
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|update_sender| update_sender.send(update.clone()).is_ok());
+    }
+}
+
+impl Default for ModelRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
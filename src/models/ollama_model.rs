@@ -3,13 +3,15 @@ use async_trait::async_trait;
 use futures::executor::block_on;
 use ollama_rs::error::OllamaError;
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
 use std::error::Error;
 use std::process::Command;
 use std::{
-    ffi::OsStr,
-    fs::{self},
+    collections::BTreeMap,
+    env,
     path::PathBuf,
-    sync::mpsc::Sender,
+    sync::{atomic::AtomicBool, mpsc::Sender},
 };
 
 use ollama_rs::{
@@ -22,14 +24,17 @@ use ollama_rs::{
 use tokio_stream::StreamExt;
 
 use crate::{
-    utils::{self, run_bash_search_script},
+    attachments::{self, AttachmentOptions},
     RagSource,
 };
 
 use super::{
-    B64Image, CoreLLM, FromMessage, Message, SavedConversation, SavedModel, ToMessage, UtilsLLM,
+    prompt_template, request_inspector::RequestRecord, CoreLLM, FromMessage, Message,
+    SavedConversation, SavedModel, ToMessage, UtilsLLM,
 };
 
+pub const DEFAULT_OLLAMA_REGISTRY: &str = "https://registry.ollama.ai";
+
 #[derive(Clone)]
 pub struct OllamaModel {
     model_name: String,
@@ -43,12 +48,26 @@ pub struct ModelInfo {
     pub size_in_b: f64,
     pub description: String,
     pub is_downloaded: bool,
+    #[serde(default)]
+    pub is_outdated: bool,
+}
+
+#[derive(Deserialize)]
+struct LocalModelTags {
+    models: Vec<LocalModelTag>,
+}
+
+#[derive(Deserialize)]
+struct LocalModelTag {
+    name: String,
+    digest: String,
 }
 
+// Keeps the app's own Message next to what's sent to Ollama so nothing is lost converting back
 #[derive(Clone)]
 pub struct ChatMessageWithB64Image {
     pub chat_message: ChatMessage,
-    pub b64_image_vec: Option<Vec<B64Image>>,
+    pub message: Message,
 }
 
 impl Default for OllamaModel {
@@ -59,24 +78,18 @@ impl Default for OllamaModel {
 
 impl FromMessage for ChatMessageWithB64Image {
     fn from_message(message: Message) -> Self {
-        let content = message.content;
+        let content = message.content.clone();
         let role = match message.role {
             super::Role::User => MessageRole::User,
             super::Role::Assistant => MessageRole::Assistant,
             super::Role::System => MessageRole::System,
         };
-        let images = match message.images {
-            Some(_) => Some(
-                message
-                    .images
-                    .clone()
-                    .unwrap()
-                    .iter()
-                    .map(|message_image| Image::from_base64(&message_image.b64_string))
-                    .collect::<Vec<Image>>(),
-            ),
-            None => None,
-        };
+        let images = message.images.as_ref().map(|message_images| {
+            message_images
+                .iter()
+                .map(|message_image| Image::from_base64(&message_image.b64_string))
+                .collect::<Vec<Image>>()
+        });
         let chat_message = ChatMessage {
             role,
             content,
@@ -84,24 +97,14 @@ impl FromMessage for ChatMessageWithB64Image {
         };
         Self {
             chat_message,
-            b64_image_vec: message.images.clone(),
+            message,
         }
     }
 }
 
 impl ToMessage for ChatMessageWithB64Image {
     fn to_message(&self) -> Message {
-        let content = self.chat_message.content.clone();
-        let role = match self.chat_message.role {
-            MessageRole::User => super::Role::User,
-            MessageRole::Assistant => super::Role::Assistant,
-            MessageRole::System => super::Role::System,
-        };
-        Message {
-            role,
-            content,
-            images: self.b64_image_vec.clone(),
-        }
+        self.message.clone()
     }
 }
 
@@ -130,6 +133,135 @@ impl OllamaModel {
         self.model_name = new_model;
     }
 
+    pub async fn list_models_async() -> Result<Vec<SavedModel>, Box<dyn Error>> {
+        Ok(Ollama::default()
+            .list_local_models()
+            .await?
+            .iter()
+            .map(|local_model| SavedModel {
+                name: local_model.name.clone(),
+                model_type: super::ModelType::Ollama,
+            })
+            .collect::<Vec<SavedModel>>())
+    }
+
+    pub fn registry_url() -> String {
+        env::var("COMHRA_OLLAMA_REGISTRY").unwrap_or(String::from(DEFAULT_OLLAMA_REGISTRY))
+    }
+
+    // Splits a model name like "llama3:8b" or "user/model" into the registry namespace, model and tag
+    fn split_model_name(model_name: &str) -> (String, String, String) {
+        let (repository, tag) = model_name
+            .rsplit_once(':')
+            .unwrap_or((model_name, "latest"));
+        let (namespace, model) = repository
+            .rsplit_once('/')
+            .unwrap_or(("library", repository));
+        let namespace = namespace.rsplit('/').next().unwrap_or("library");
+        (namespace.to_owned(), model.to_owned(), tag.to_owned())
+    }
+
+    async fn local_digest(model_name: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let tags_url = format!("{}/api/tags", Ollama::default().uri());
+        let local_tags: LocalModelTags = reqwest::get(tags_url).await?.json().await?;
+        let (namespace, model, tag) = Self::split_model_name(model_name);
+        Ok(local_tags
+            .models
+            .into_iter()
+            .find(|local_tag| {
+                Self::split_model_name(&local_tag.name)
+                    == (namespace.clone(), model.clone(), tag.clone())
+            })
+            .map(|local_tag| local_tag.digest))
+    }
+
+    async fn registry_digest(model_name: &str) -> Result<String, Box<dyn Error>> {
+        let (namespace, model, tag) = Self::split_model_name(model_name);
+        let manifest_url = format!(
+            "{0}/v2/{1}/{2}/manifests/{3}",
+            Self::registry_url(),
+            namespace,
+            model,
+            tag
+        );
+        let manifest = reqwest::Client::new()
+            .get(manifest_url)
+            .header(
+                "Accept",
+                "application/vnd.docker.distribution.manifest.v2+json",
+            )
+            .send()
+            .await?
+            .error_for_status()?
+            .bytes()
+            .await?;
+        // Ollama stores the digest of the manifest it pulled, so hashing the current one is enough to compare
+        Ok(format!("{:x}", Sha256::digest(&manifest)))
+    }
+
+    pub async fn is_model_outdated(model_name: String) -> Result<bool, Box<dyn Error>> {
+        let local_digest = Self::local_digest(&model_name).await?;
+        Self::is_digest_outdated(&model_name, local_digest).await
+    }
+
+    // Models that aren't pulled can't be outdated, so the registry is only asked about pulled ones
+    async fn is_digest_outdated(
+        model_name: &str,
+        local_digest: Option<String>,
+    ) -> Result<bool, Box<dyn Error>> {
+        match local_digest {
+            Some(local_digest) => {
+                let registry_digest = Self::registry_digest(model_name).await?;
+                Ok(local_digest.trim_start_matches("sha256:") != registry_digest)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub async fn update_model(
+        model_name: String,
+        download_progress_bar: &gtk::ProgressBar,
+    ) -> Result<(), Box<dyn Error>> {
+        Self::download_model(model_name, download_progress_bar).await
+    }
+
+    async fn download_model(
+        model_name: String,
+        download_progress_bar: &gtk::ProgressBar,
+    ) -> Result<(), Box<dyn Error>> {
+        download_progress_bar.show();
+        println!("Downloading model: {}", &model_name);
+        let download_result = Self::stream_download(&model_name, download_progress_bar).await;
+        download_progress_bar.hide();
+        download_result
+    }
+
+    async fn stream_download(
+        model_name: &str,
+        download_progress_bar: &gtk::ProgressBar,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut res = Ollama::default()
+            .pull_model_stream(model_name.to_owned(), false)
+            .await?;
+
+        while let Some(res) = res.next().await {
+            let res = res?;
+            if let (Some(total), Some(completed)) = (res.total, res.completed) {
+                let fraction = completed as f64 / total as f64;
+                download_progress_bar.set_fraction(fraction);
+                download_progress_bar.set_text(Some(
+                    format!(
+                        "Downloading model: {0} {1:.1}%",
+                        model_name,
+                        (fraction * 100.0)
+                    )
+                    .as_str(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
     fn list_els() -> Result<Vec<SavedModel>, OllamaError> {
         Command::new("ls")
             .arg("-a")
@@ -150,67 +282,41 @@ impl UtilsLLM for OllamaModel {
         String::from("phi3:latest")
     }
 
-    fn format_prompt(prompt: &str, rag_source: RagSource) -> String {
-        println!("\n\nRag Source: {:?}\n\n", rag_source);
-        match rag_source {
-            RagSource::NoRag => prompt.to_string(),
-            RagSource::BashScript(script_path) => {
-                let rag_content = run_bash_search_script(script_path, prompt);
-                println!("{}", rag_content);
-                todo!(); //fix the format below, probably will make the model return nothing atm
-                         //format!("<|context|>/n This is additional context to answer the users query:/n {0}<|end|>/n <|user|>/n {1}<|end|>/n <|assistant|>", rag_content, prompt)
-            }
-        }
+    async fn format_prompt(
+        chat_message: Message,
+        rag_source: RagSource,
+        conversation: Vec<Message>,
+        conversation_file_path: PathBuf,
+        model_name: String,
+        cancel_flag: &AtomicBool,
+    ) -> Message {
+        prompt_template::format_prompt(
+            chat_message,
+            rag_source,
+            conversation,
+            conversation_file_path,
+            model_name,
+            cancel_flag,
+        )
+        .await
     }
 
-    fn unformat_prompt(prompt: &str) -> String {
-        let end_split = prompt
-            .char_indices()
-            .nth_back("<|end|>/n <|assistant|><|end|>/n".len())
-            .unwrap()
-            .0;
-        String::from(
-            prompt
-                .split_at("<|user|>/n ".len() - 1)
-                .1
-                .split_at(end_split)
-                .0,
-        )
+    fn unformat_prompt(chat_message: &Message) -> String {
+        prompt_template::unformat_prompt(chat_message)
     }
 
-    async fn pull_model(model_name: String, download_progress_bar: &gtk::ProgressBar) {
+    async fn pull_model(
+        model_name: String,
+        download_progress_bar: &gtk::ProgressBar,
+    ) -> Result<(), Box<dyn Error>> {
         let ollama = Ollama::default();
-        let res = ollama.list_local_models().await.unwrap();
+        let res = ollama.list_local_models().await?;
         if res.iter().any(|local_model| local_model.name == model_name) {
             println!("Model found: {}", model_name);
+            Ok(())
         } else {
-            download_progress_bar.show();
-            println!("Downloading model: {}", &model_name);
-            let mut res = ollama.pull_model_stream(model_name, false).await.unwrap();
-
-            while let Some(res) = res.next().await {
-                match res {
-                    Ok(res) => {
-                        if let (Some(total), Some(completed)) = (res.total, res.completed) {
-                            let fraction = completed as f64 / total as f64;
-                            println!("{}", fraction);
-                            download_progress_bar.set_fraction(fraction);
-                            download_progress_bar.set_text(Some(
-                                format!(
-                                    "Downloading model: {0} {1:.1}%",
-                                    OllamaModel::default_model_string(),
-                                    (fraction * 100.0)
-                                )
-                                .as_str(),
-                            ));
-                        }
-                        println!("{:?}", res);
-                    }
-                    Err(e) => panic!("{:?}", e),
-                }
-            }
+            OllamaModel::download_model(model_name, download_progress_bar).await
         }
-        download_progress_bar.hide();
     }
 
     async fn delete_model(model_name: String) {
@@ -227,41 +333,12 @@ impl UtilsLLM for OllamaModel {
             .collect::<Vec<SavedModel>>())
     }
 
-    fn process_file_for_prompt(mut chat_message: Message, file_path: PathBuf) -> Message {
-        match file_path
-            .extension()
-            .unwrap_or(OsStr::new("error"))
-            .to_str()
-            .unwrap_or("error")
-        {
-            "pdf" => {
-                let mut pdf_content = utils::pdf_to_string(&file_path);
-                pdf_content.push_str(&chat_message.content);
-                chat_message.content = pdf_content;
-            }
-            "jpg" | "jpeg" | "png" => {
-                chat_message.images = if let Some(mut images) = chat_message.images {
-                    images.push(B64Image {
-                        b64_string: utils::image_to_b64(&file_path),
-                    });
-                    Some(images)
-                } else {
-                    Some(vec![B64Image {
-                        b64_string: utils::image_to_b64(&file_path),
-                    }])
-                };
-            }
-            "error" => {
-                println!("Error with file")
-            }
-            _ => {
-                let mut file_content =
-                    fs::read_to_string(file_path).unwrap_or(String::from("Error reading file"));
-                file_content.push_str(&chat_message.content);
-                chat_message.content = file_content;
-            }
-        }
-        chat_message
+    fn process_file_for_prompt(
+        chat_message: Message,
+        file_path: PathBuf,
+        options: &AttachmentOptions,
+    ) -> Message {
+        attachments::process_file_for_prompt(chat_message, file_path, options)
     }
 }
 
@@ -283,23 +360,53 @@ impl CoreLLM for OllamaModel {
             .collect();
     }
 
-    async fn ask(&mut self, user_message: Message, list_sender: Sender<Message>) {
-        self.message_history
-            .push(ChatMessageWithB64Image::from_message(user_message));
-        let parsed_conversation = self
+    async fn ask(
+        &mut self,
+        user_message: Message,
+        list_sender: Sender<Message>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let conversation = self
             .message_history
             .iter()
-            .map(|chat_message_with_b64_image| chat_message_with_b64_image.chat_message.clone())
-            .collect::<Vec<ChatMessage>>();
-        let mut stream: ChatMessageResponseStream = Ollama::default()
-            .send_chat_messages_stream(ChatMessageRequest::new(
-                self.model_name.clone(),
-                parsed_conversation,
-            ))
-            .await
-            .unwrap();
+            .map(|chat_message_with_b64_image| chat_message_with_b64_image.to_message())
+            .chain(std::iter::once(user_message.clone()))
+            .collect::<Vec<Message>>();
+        let parsed_conversation =
+            prompt_template::with_context_messages(conversation, &self.model_name)
+                .into_iter()
+                .map(|message| ChatMessageWithB64Image::from_message(message).chat_message)
+                .collect::<Vec<ChatMessage>>();
+        let ollama = Ollama::default();
+        let request = ChatMessageRequest::new(self.model_name.clone(), parsed_conversation);
+        let mut request_value = serde_json::to_value(&request).unwrap_or(Value::Null);
+        // The stream flag is only set once the request is sent
+        request_value["stream"] = Value::Bool(true);
+        let mut request_record = RequestRecord::start(
+            "Ollama",
+            format!("{}/api/chat", ollama.uri()),
+            self.model_name.clone(),
+            BTreeMap::new(),
+            request_value,
+            vec![],
+        );
+        let mut stream: ChatMessageResponseStream =
+            match ollama.send_chat_messages_stream(request).await {
+                Ok(stream) => stream,
+                Err(err) => {
+                    request_record.finish(Some(err.to_string()));
+                    return Err(err.into());
+                }
+            };
+        // The answer lists the same sources as the prompt, so its citations can be followed
+        let sources = user_message.sources.clone();
         let mut response = String::new();
-        while let Some(Ok(res)) = stream.next().await {
+        while let Some(res) = stream.next().await {
+            let Ok(res) = res else {
+                let error = format!("Error reading response from {}", self.model_name);
+                request_record.finish(Some(error.clone()));
+                return Err(error.into());
+            };
+            request_record.record_chunk(serde_json::to_string(&res).unwrap_or_default());
             if let Some(assistant_message) = res.message {
                 response += assistant_message.content.as_str();
                 list_sender
@@ -307,14 +414,38 @@ impl CoreLLM for OllamaModel {
                         role: super::Role::Assistant,
                         content: response.clone(),
                         images: None,
+                        model_name: Some(self.model_name.clone()),
+                        request_id: request_record.saved_id(),
+                        original_content: None,
+                        rag_context: None,
+                        sources: sources.clone(),
+                        rag_error: None,
+                        rag_query: None,
+                        attachments: None,
                     })
                     .unwrap();
             }
         }
-        self.message_history.push(ChatMessageWithB64Image {
-            chat_message: ChatMessage::assistant(response),
-            b64_image_vec: None,
-        });
+        let request_id = request_record.saved_id();
+        request_record.finish(None);
+        // Only keep the turn once it's answered, so a failed ask can be retried with the same history
+        self.message_history
+            .push(ChatMessageWithB64Image::from_message(user_message));
+        self.message_history
+            .push(ChatMessageWithB64Image::from_message(Message {
+                role: super::Role::Assistant,
+                content: response,
+                images: None,
+                model_name: Some(self.model_name.clone()),
+                request_id,
+                original_content: None,
+                rag_context: None,
+                sources,
+                rag_error: None,
+                rag_query: None,
+                attachments: None,
+            }));
+        Ok(())
     }
 
     fn get_conversation(&mut self) -> Vec<Message> {
@@ -324,18 +455,54 @@ impl CoreLLM for OllamaModel {
             .collect::<Vec<Message>>()
     }
 
+    fn set_conversation(&mut self, conversation: Vec<Message>) {
+        self.message_history = conversation
+            .into_iter()
+            .map(ChatMessageWithB64Image::from_message)
+            .collect();
+    }
+
+    fn model_name(&self) -> String {
+        self.model_name.clone()
+    }
+
+    fn clone_box(&self) -> Box<dyn CoreLLM> {
+        Box::new(self.clone())
+    }
+
+    // Reads num_ctx from the model's parameters, which is only there when its Modelfile sets it
+    async fn context_window_tokens(&self) -> usize {
+        Ollama::default()
+            .show_model_info(self.model_name.clone())
+            .await
+            .ok()
+            .and_then(|model_info| {
+                model_info.parameters.lines().find_map(|parameter_line| {
+                    let mut parameter = parameter_line.split_whitespace();
+                    match (parameter.next(), parameter.next()) {
+                        (Some("num_ctx"), Some(value)) => value.parse::<usize>().ok(),
+                        _ => None,
+                    }
+                })
+            })
+            .unwrap_or(prompt_template::OLLAMA_DEFAULT_CONTEXT_TOKENS)
+    }
+
     fn export_conversation(&mut self, file_path: PathBuf) {
         if !self.message_history.is_empty() {
             let archived;
             let starred;
             let name;
+            let fallback_models;
             if let Some(loaded_conversation) = SavedConversation::load(&file_path) {
                 archived = loaded_conversation.archived;
                 starred = loaded_conversation.starred;
                 name = loaded_conversation.name;
+                fallback_models = loaded_conversation.fallback_models;
             } else {
                 archived = false;
                 starred = false;
+                fallback_models = None;
                 name = file_path
                     .file_stem()
                     .unwrap()
@@ -352,6 +519,7 @@ impl CoreLLM for OllamaModel {
                 archived,
                 starred,
                 name,
+                fallback_models,
             };
             saved_conversation.save(&file_path);
         } else {
@@ -359,3 +527,93 @@ impl CoreLLM for OllamaModel {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    const FAKE_MANIFEST: &str =
+        r#"{"schemaVersion":2,"config":{"digest":"sha256:abc"},"layers":[]}"#;
+
+    // Serves FAKE_MANIFEST for llama3:8b and a 404 for anything else
+    fn start_mock_registry() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let registry_url = format!("http://{}", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut request = [0u8; 4096];
+                let request_length = stream.read(&mut request).unwrap();
+                let request = String::from_utf8_lossy(&request[..request_length]);
+                let response = if request.starts_with("GET /v2/library/llama3/manifests/8b ") {
+                    format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {0}\r\nConnection: close\r\n\r\n{1}",
+                        FAKE_MANIFEST.len(),
+                        FAKE_MANIFEST
+                    )
+                } else {
+                    String::from(
+                        "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                    )
+                };
+                stream.write_all(response.as_bytes()).unwrap();
+            }
+        });
+        registry_url
+    }
+
+    #[test]
+    fn splits_model_names_like_the_registry() {
+        assert_eq!(
+            OllamaModel::split_model_name("llama3:8b"),
+            (
+                String::from("library"),
+                String::from("llama3"),
+                String::from("8b")
+            )
+        );
+        assert_eq!(
+            OllamaModel::split_model_name("user/model"),
+            (
+                String::from("user"),
+                String::from("model"),
+                String::from("latest")
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn compares_local_digest_with_registry_manifest() {
+        env::set_var("COMHRA_OLLAMA_REGISTRY", start_mock_registry());
+        let registry_digest = format!("sha256:{:x}", Sha256::digest(FAKE_MANIFEST.as_bytes()));
+
+        let is_outdated = OllamaModel::is_digest_outdated("llama3:8b", Some(registry_digest))
+            .await
+            .unwrap();
+        assert!(!is_outdated);
+
+        let is_outdated =
+            OllamaModel::is_digest_outdated("llama3:8b", Some(String::from("sha256:0123")))
+                .await
+                .unwrap();
+        assert!(is_outdated);
+
+        // Models that aren't pulled never reach the registry
+        let is_outdated = OllamaModel::is_digest_outdated("missing:latest", None)
+            .await
+            .unwrap();
+        assert!(!is_outdated);
+
+        assert!(OllamaModel::is_digest_outdated(
+            "missing:latest",
+            Some(String::from("sha256:0123"))
+        )
+        .await
+        .is_err());
+    }
+}
@@ -0,0 +1,294 @@
+use std::{path::PathBuf, sync::atomic::AtomicBool};
+
+use crate::{
+    attachments,
+    rag::{self, pipeline},
+    RagSource,
+};
+
+use super::{Message, Role};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ModelFamily {
+    Phi3,
+    Llama3,
+    Mistral,
+    Gemma,
+    Qwen,
+    Other,
+}
+
+impl ModelFamily {
+    // Ollama names look like "namespace/llama3:8b", API names like "gpt-4o"
+    pub fn from_model_name(model_name: &str) -> Self {
+        let base_name = model_name
+            .rsplit('/')
+            .next()
+            .unwrap_or(model_name)
+            .split(':')
+            .next()
+            .unwrap_or(model_name)
+            .to_lowercase();
+        if base_name.starts_with("phi3") || base_name.starts_with("phi-3") {
+            ModelFamily::Phi3
+        } else if base_name.starts_with("llama3") || base_name.starts_with("llama-3") {
+            ModelFamily::Llama3
+        } else if base_name.starts_with("mistral") || base_name.starts_with("mixtral") {
+            ModelFamily::Mistral
+        } else if base_name.starts_with("gemma") {
+            ModelFamily::Gemma
+        } else if base_name.starts_with("qwen") {
+            ModelFamily::Qwen
+        } else {
+            ModelFamily::Other
+        }
+    }
+}
+
+// The numbers match the ones rag::format_results gives each result
+const CITATION_INSTRUCTION: &str =
+    "When you use a numbered source, cite it with its number in square brackets, like [1].";
+
+// Ollama serves every model with this much context unless num_ctx is raised in its Modelfile
+pub const OLLAMA_DEFAULT_CONTEXT_TOKENS: usize = 2048;
+
+// Context windows of common hosted models by name prefix, unknown models get a conservative size
+pub fn api_context_window_tokens(model_name: &str) -> usize {
+    let model_name = model_name.to_lowercase();
+    if model_name.starts_with("gpt-4o") || model_name.starts_with("gpt-4-turbo") {
+        128_000
+    } else if model_name.starts_with("gpt-4-32k") {
+        32_768
+    } else if model_name.starts_with("gpt-4") {
+        8_192
+    } else if model_name.starts_with("gpt-3.5") {
+        16_385
+    } else if model_name.starts_with("claude") {
+        200_000
+    } else if model_name.starts_with("gemini") {
+        1_000_000
+    } else {
+        8_192
+    }
+}
+
+/*
+How context and prompts are laid out for a model family
+- Both the Ollama chat endpoint and OpenAI style APIs take structured messages and apply the model's own chat template, so no special tokens are written here
+- What differs between families is whether a system message is accepted, Gemma and Mistral templates drop or reject it
+- Retrieved context goes in its own system message where possible, otherwise it's folded into the user message only when sending
+*/
+#[derive(Clone, Copy, Debug)]
+pub struct PromptTemplate {
+    pub family: ModelFamily,
+}
+
+impl PromptTemplate {
+    pub fn for_model(model_name: &str) -> Self {
+        Self {
+            family: ModelFamily::from_model_name(model_name),
+        }
+    }
+
+    pub fn supports_system_role(&self) -> bool {
+        !matches!(self.family, ModelFamily::Gemma | ModelFamily::Mistral)
+    }
+
+    pub fn context_message(&self, context: &str) -> String {
+        format!(
+            "Use the following context to answer the user's next question if it's relevant. {0}\n\nContext:\n{1}",
+            CITATION_INSTRUCTION,
+            context.trim()
+        )
+    }
+
+    pub fn wrap_context(&self, context: &str, prompt: &str) -> String {
+        format!(
+            "Use the following context to answer the question if it's relevant. {0}\n\nContext:\n{1}\n\nQuestion:\n{2}",
+            CITATION_INSTRUCTION,
+            context.trim(),
+            prompt
+        )
+    }
+}
+
+/*
+Runs the RAG source, the retrieved text is kept with the message rather than spliced into it
+- conversation is the history before this message, used when the source rewrites the query
+- conversation_file_path is where it's saved, so searching past conversations leaves it out
+- model_name is the model that will answer, which rewrites and re-ranks unless a helper model is set
+*/
+pub async fn format_prompt(
+    mut chat_message: Message,
+    rag_source: RagSource,
+    conversation: Vec<Message>,
+    conversation_file_path: PathBuf,
+    model_name: String,
+    cancel_flag: &AtomicBool,
+) -> Message {
+    println!("\n\nRag Source: {:?}\n\n", rag_source.name());
+    chat_message.original_content = Some(chat_message.content.clone());
+    if let RagSource::NoRag = rag_source {
+        return chat_message;
+    }
+    let pipeline_results = pipeline::retrieve(
+        &rag_source,
+        &chat_message.content,
+        &conversation,
+        Some(&conversation_file_path),
+        Some(&model_name),
+        cancel_flag,
+    )
+    .await;
+    match pipeline_results {
+        Ok(pipeline_results)
+            if pipeline_results
+                .results
+                .iter()
+                .any(|result| !result.text.trim().is_empty()) =>
+        {
+            let rag_content = rag::format_results(&pipeline_results.results);
+            println!("{}", rag_content);
+            chat_message.rag_context = Some(rag_content);
+            chat_message.sources = Some(pipeline_results.results);
+            chat_message.rag_query = pipeline_results.rewritten_query;
+        }
+        Ok(pipeline_results) => {
+            println!("RAG source returned no context");
+            chat_message.rag_query = pipeline_results.rewritten_query;
+        }
+        Err(err) => {
+            println!("Error retrieving RAG context: {:?}", err);
+            chat_message.rag_error = Some(err.to_string());
+        }
+    }
+    chat_message
+}
+
+pub fn unformat_prompt(chat_message: &Message) -> String {
+    chat_message
+        .original_content
+        .clone()
+        .unwrap_or_else(|| chat_message.content.clone())
+}
+
+// The messages as they're sent to a model, with attachments and context laid out for its family
+pub fn with_context_messages(conversation: Vec<Message>, model_name: &str) -> Vec<Message> {
+    let prompt_template = PromptTemplate::for_model(model_name);
+    conversation
+        .into_iter()
+        .map(|chat_message| Message {
+            content: attachments::content_with_attachments(&chat_message),
+            ..chat_message
+        })
+        .flat_map(|chat_message| {
+            let Some(rag_context) = chat_message.rag_context.clone() else {
+                return vec![chat_message];
+            };
+            if prompt_template.supports_system_role() {
+                vec![
+                    Message {
+                        role: Role::System,
+                        content: prompt_template.context_message(&rag_context),
+                        images: None,
+                        model_name: None,
+                        request_id: None,
+                        original_content: None,
+                        rag_context: None,
+                        sources: None,
+                        rag_error: None,
+                        rag_query: None,
+                        attachments: None,
+                    },
+                    chat_message,
+                ]
+            } else {
+                let content = prompt_template.wrap_context(&rag_context, &chat_message.content);
+                vec![Message {
+                    content,
+                    ..chat_message
+                }]
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_message(content: &str, rag_context: Option<&str>) -> Message {
+        Message {
+            role: Role::User,
+            content: String::from(content),
+            images: None,
+            model_name: None,
+            request_id: None,
+            original_content: None,
+            rag_context: rag_context.map(String::from),
+            sources: None,
+            rag_error: None,
+            rag_query: None,
+            attachments: None,
+        }
+    }
+
+    #[test]
+    fn families_are_found_from_ollama_and_api_names() {
+        assert_eq!(ModelFamily::from_model_name("phi3:mini"), ModelFamily::Phi3);
+        assert_eq!(
+            ModelFamily::from_model_name("namespace/llama3:8b"),
+            ModelFamily::Llama3
+        );
+        assert_eq!(
+            ModelFamily::from_model_name("Llama-3.1-70B"),
+            ModelFamily::Llama3
+        );
+        assert_eq!(
+            ModelFamily::from_model_name("mixtral:8x7b"),
+            ModelFamily::Mistral
+        );
+        assert_eq!(
+            ModelFamily::from_model_name("gemma2:9b"),
+            ModelFamily::Gemma
+        );
+        assert_eq!(ModelFamily::from_model_name("qwen2:7b"), ModelFamily::Qwen);
+        assert_eq!(ModelFamily::from_model_name("gpt-4o"), ModelFamily::Other);
+    }
+
+    #[test]
+    fn context_goes_in_a_system_message_when_supported() {
+        let messages = with_context_messages(
+            vec![user_message("What changed?", Some("Release notes"))],
+            "llama3:8b",
+        );
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(messages[0].role, Role::System));
+        assert!(messages[0].content.contains("Context:\nRelease notes"));
+        assert!(messages[0].content.contains(CITATION_INSTRUCTION));
+        assert!(matches!(messages[1].role, Role::User));
+        assert_eq!(messages[1].content, "What changed?");
+    }
+
+    #[test]
+    fn context_is_folded_into_the_prompt_without_system_role() {
+        for model_name in ["gemma:2b", "mistral:7b"] {
+            let messages = with_context_messages(
+                vec![user_message("What changed?", Some("Release notes"))],
+                model_name,
+            );
+            assert_eq!(messages.len(), 1);
+            assert!(matches!(messages[0].role, Role::User));
+            assert!(messages[0]
+                .content
+                .ends_with("Context:\nRelease notes\n\nQuestion:\nWhat changed?"));
+        }
+    }
+
+    #[test]
+    fn messages_without_context_are_unchanged() {
+        let messages = with_context_messages(vec![user_message("Hello", None)], "gemma:2b");
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].content, "Hello");
+    }
+}
@@ -0,0 +1,365 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs::{self, File},
+    io::{Read, Write},
+    path::PathBuf,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+use crate::{settings::Settings, utils::get_root_folder};
+
+const INSPECTOR_FOLDER: &str = "./inspector";
+// Older records are removed so the folder doesn't grow forever
+const MAX_SAVED_RECORDS: usize = 100;
+const REDACTED: &str = "[REDACTED]";
+// Ollama sends images as a list of base64 strings, OpenAI as data URLs
+const IMAGES_KEY_NAME: &str = "images";
+const DATA_URL_PREFIX: &str = "data:";
+const BASE64_MARKER: &str = ";base64,";
+const SECRET_KEY_NAMES: [&str; 6] = [
+    "api_key",
+    "apikey",
+    "authorization",
+    "password",
+    "secret",
+    "token",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StreamChunk {
+    pub elapsed_ms: u128,
+    pub raw: String,
+}
+
+/*
+Everything sent to and received from a provider for one answer
+- Endpoint, headers, parameters and the full message list as they were sent
+- Every raw streamed chunk with the time it arrived
+- Only kept while record_requests is on in Settings, otherwise nothing is written
+- Secrets are redacted and images replaced with their size before anything is written to disk
+*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RequestRecord {
+    pub id: String,
+    pub provider: String,
+    pub endpoint: String,
+    pub model_name: String,
+    pub started_at_unix_ms: u128,
+    pub headers: BTreeMap<String, String>,
+    pub parameters: Value,
+    pub messages: Value,
+    pub chunks: Vec<StreamChunk>,
+    pub first_chunk_ms: Option<u128>,
+    pub total_ms: Option<u128>,
+    pub error: Option<String>,
+    #[serde(skip)]
+    secrets: Vec<String>,
+    #[serde(skip)]
+    started_at: Option<Instant>,
+    #[serde(skip)]
+    is_recording: bool,
+}
+
+impl RequestRecord {
+    // The request is split into its messages and everything else, the parameters
+    pub fn start(
+        provider: &str,
+        endpoint: String,
+        model_name: String,
+        headers: BTreeMap<String, String>,
+        request: Value,
+        secrets: Vec<String>,
+    ) -> Self {
+        let (messages, parameters) = match request {
+            Value::Object(mut request_fields) => {
+                let messages = request_fields.remove("messages").unwrap_or(Value::Null);
+                (messages, Value::Object(request_fields))
+            }
+            request => (Value::Null, request),
+        };
+        Self {
+            id: Uuid::new_v4().to_string(),
+            provider: provider.to_owned(),
+            endpoint,
+            model_name,
+            started_at_unix_ms: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|duration| duration.as_millis())
+                .unwrap_or(0),
+            headers,
+            parameters,
+            messages,
+            chunks: vec![],
+            first_chunk_ms: None,
+            total_ms: None,
+            error: None,
+            secrets: secrets
+                .into_iter()
+                .filter(|secret| !secret.is_empty())
+                .collect(),
+            started_at: Some(Instant::now()),
+            is_recording: Settings::load().record_requests,
+        }
+    }
+
+    // Put on the answer so it can be inspected, None when the record won't be saved
+    pub fn saved_id(&self) -> Option<String> {
+        self.is_recording.then(|| self.id.clone())
+    }
+
+    fn elapsed_ms(&self) -> u128 {
+        self.started_at
+            .map(|started_at| started_at.elapsed().as_millis())
+            .unwrap_or(0)
+    }
+
+    pub fn record_chunk(&mut self, raw: String) {
+        if !self.is_recording {
+            return;
+        }
+        let elapsed_ms = self.elapsed_ms();
+        self.first_chunk_ms.get_or_insert(elapsed_ms);
+        self.chunks.push(StreamChunk { elapsed_ms, raw });
+    }
+
+    // Saves the record whether the request worked or not, failed ones are the interesting ones
+    pub fn finish(mut self, error: Option<String>) {
+        if !self.is_recording {
+            return;
+        }
+        self.total_ms = Some(self.elapsed_ms());
+        self.error = error;
+        let redacted_record = self.redacted();
+        if let Err(err) = redacted_record.save() {
+            println!("Error saving request record: {:?}", err);
+        }
+    }
+
+    fn redacted(&self) -> Self {
+        let mut redacted_record = self.clone();
+        redacted_record.headers = self
+            .headers
+            .iter()
+            .map(|(header_name, header_value)| {
+                let header_value = if Self::is_secret_key_name(header_name) {
+                    match header_value.split_once(' ') {
+                        Some((scheme, _)) => format!("{} {}", scheme, REDACTED),
+                        None => REDACTED.to_owned(),
+                    }
+                } else {
+                    self.redact_string(header_value)
+                };
+                (header_name.clone(), header_value)
+            })
+            .collect();
+        redacted_record.endpoint = self.redact_string(&self.endpoint);
+        self.redact_value(&mut redacted_record.parameters);
+        self.redact_value(&mut redacted_record.messages);
+        Self::strip_images(&mut redacted_record.messages);
+        redacted_record
+            .chunks
+            .iter_mut()
+            .for_each(|chunk| chunk.raw = self.redact_string(&chunk.raw));
+        redacted_record.error = self.error.as_ref().map(|error| self.redact_string(error));
+        redacted_record
+    }
+
+    fn is_secret_key_name(key_name: &str) -> bool {
+        let key_name = key_name.to_lowercase().replace('-', "_");
+        SECRET_KEY_NAMES
+            .iter()
+            .any(|secret_key_name| key_name.contains(secret_key_name))
+    }
+
+    fn redact_string(&self, text: &str) -> String {
+        self.secrets.iter().fold(text.to_owned(), |text, secret| {
+            text.replace(secret, REDACTED)
+        })
+    }
+
+    fn redact_value(&self, value: &mut Value) {
+        match value {
+            Value::String(text) => *text = self.redact_string(text),
+            Value::Array(values) => values.iter_mut().for_each(|value| self.redact_value(value)),
+            Value::Object(fields) => fields.iter_mut().for_each(|(key_name, value)| {
+                if Self::is_secret_key_name(key_name) && value.is_string() {
+                    *value = Value::String(REDACTED.to_owned());
+                } else {
+                    self.redact_value(value);
+                }
+            }),
+            _ => {}
+        }
+    }
+
+    fn image_placeholder(base64_length: usize) -> Value {
+        Value::String(format!(
+            "[image, {} bytes of base64 left out]",
+            base64_length
+        ))
+    }
+
+    // Images are what makes records big, and the inspector can't show them anyway
+    fn strip_images(value: &mut Value) {
+        match value {
+            Value::String(text) if text.starts_with(DATA_URL_PREFIX) => {
+                if let Some((_, base64_data)) = text.split_once(BASE64_MARKER) {
+                    *value = Self::image_placeholder(base64_data.len());
+                }
+            }
+            Value::Array(values) => values.iter_mut().for_each(Self::strip_images),
+            Value::Object(fields) => fields.iter_mut().for_each(|(key_name, value)| match value {
+                Value::Array(images) if key_name == IMAGES_KEY_NAME => {
+                    images.iter_mut().for_each(|image| {
+                        if let Value::String(base64_data) = image {
+                            *image = Self::image_placeholder(base64_data.len());
+                        }
+                    })
+                }
+                value => Self::strip_images(value),
+            }),
+            _ => {}
+        }
+    }
+
+    fn folder() -> PathBuf {
+        get_root_folder().join(PathBuf::from(INSPECTOR_FOLDER))
+    }
+
+    fn save(&self) -> Result<(), Box<dyn Error>> {
+        let inspector_folder = Self::folder();
+        fs::create_dir_all(&inspector_folder)?;
+        let mut file = File::create(inspector_folder.join(format!("{}.json", self.id)))?;
+        file.write_all(serde_json::to_string(&self)?.as_bytes())?;
+        Self::prune_old_records(&inspector_folder)?;
+        Ok(())
+    }
+
+    fn prune_old_records(inspector_folder: &PathBuf) -> Result<(), Box<dyn Error>> {
+        let mut record_files = fs::read_dir(inspector_folder)?
+            .filter_map(|dir_entry| dir_entry.ok())
+            .filter_map(|dir_entry| {
+                let modified = dir_entry.metadata().ok()?.modified().ok()?;
+                Some((modified, dir_entry.path()))
+            })
+            .collect::<Vec<(SystemTime, PathBuf)>>();
+        if record_files.len() <= MAX_SAVED_RECORDS {
+            return Ok(());
+        }
+        record_files.sort();
+        let remove_count = record_files.len() - MAX_SAVED_RECORDS;
+        record_files
+            .into_iter()
+            .take(remove_count)
+            .for_each(|(_, record_file)| {
+                if let Err(err) = fs::remove_file(&record_file) {
+                    println!("Error removing {:?}: {:?}", record_file, err);
+                }
+            });
+        Ok(())
+    }
+
+    pub fn load(id: &str) -> Option<Self> {
+        let record_file_path = Self::folder().join(format!("{}.json", id));
+        let mut record_file = File::open(record_file_path).ok()?;
+        let mut json_data = String::new();
+        record_file.read_to_string(&mut json_data).ok()?;
+        serde_json::from_str(&json_data)
+            .map_err(|err| println!("Error reading request record: {:?}", err))
+            .ok()
+    }
+
+    pub fn to_pretty_json(&self) -> String {
+        serde_json::to_string_pretty(&self).unwrap_or_else(|err| format!("{:?}", err))
+    }
+
+    pub fn export(&self, file_path: &PathBuf) -> Result<(), Box<dyn Error>> {
+        let mut file = File::create(file_path)?;
+        file.write_all(self.to_pretty_json().as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const API_KEY: &str = "sk-test-1234567890";
+    const IMAGE_BASE64: &str = "iVBORw0KGgoAAAANSUhEUgAAAAEAAAABCAYAAAAfFcSJAAAADUlEQVR42mNk";
+
+    // What save() writes to disk
+    fn saved_json(request_record: &RequestRecord) -> String {
+        serde_json::to_string(&request_record.redacted()).unwrap()
+    }
+
+    #[test]
+    fn saved_record_has_no_api_key() {
+        let mut request_record = RequestRecord::start(
+            "OpenAI",
+            format!(
+                "https://api.example.com/v1/chat/completions?key={}",
+                API_KEY
+            ),
+            String::from("gpt-4o"),
+            BTreeMap::from([(String::from("Authorization"), format!("Bearer {}", API_KEY))]),
+            json!({
+                "model": "gpt-4o",
+                "api_key": "not-in-the-secrets-list",
+                "messages": [{"role": "user", "content": format!("my key is {}", API_KEY)}],
+            }),
+            vec![API_KEY.to_owned()],
+        );
+        request_record.chunks.push(StreamChunk {
+            elapsed_ms: 10,
+            raw: format!("{{\"echo\":\"{}\"}}", API_KEY),
+        });
+        request_record.error = Some(format!("401 for key {}", API_KEY));
+
+        let redacted_record = request_record.redacted();
+        assert_eq!(
+            redacted_record.headers["Authorization"],
+            format!("Bearer {}", REDACTED)
+        );
+        assert_eq!(redacted_record.parameters["api_key"], REDACTED);
+        let saved_json = saved_json(&request_record);
+        assert!(!saved_json.contains(API_KEY));
+        assert!(!saved_json.contains("not-in-the-secrets-list"));
+    }
+
+    #[test]
+    fn saved_record_has_no_image_data() {
+        let request_record = RequestRecord::start(
+            "Ollama",
+            String::from("http://localhost:11434/api/chat"),
+            String::from("llava"),
+            BTreeMap::new(),
+            json!({
+                "model": "llava",
+                "messages": [
+                    {"role": "user", "content": "What is this?", "images": [IMAGE_BASE64]},
+                    {"role": "user", "content": [
+                        {"type": "text", "text": "And this?"},
+                        {"type": "image_url", "image_url": {"url": format!("data:image/png;base64,{}", IMAGE_BASE64)}},
+                    ]},
+                ],
+            }),
+            vec![],
+        );
+
+        let redacted_record = request_record.redacted();
+        let placeholder = RequestRecord::image_placeholder(IMAGE_BASE64.len());
+        assert_eq!(redacted_record.messages[0]["images"][0], placeholder);
+        assert_eq!(
+            redacted_record.messages[1]["content"][1]["image_url"]["url"],
+            placeholder
+        );
+        assert_eq!(redacted_record.messages[0]["content"], "What is this?");
+        assert!(!saved_json(&request_record).contains(IMAGE_BASE64));
+    }
+}
@@ -0,0 +1,204 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    ffi::OsStr,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use crate::utils;
+
+use super::RetrievedResult;
+
+const CHUNK_SIZE_CHARS: usize = 1500;
+const CHUNK_OVERLAP_CHARS: usize = 200;
+// Bigger files are usually generated or data dumps, not documents
+pub const MAX_FILE_SIZE_BYTES: u64 = 5 * 1024 * 1024;
+
+const TEXT_EXTENSIONS: [&str; 6] = ["txt", "md", "markdown", "rst", "org", "adoc"];
+const CODE_EXTENSIONS: [&str; 25] = [
+    "rs", "py", "js", "ts", "tsx", "jsx", "c", "h", "cpp", "hpp", "cc", "java", "kt", "go", "rb",
+    "php", "cs", "swift", "sh", "toml", "yaml", "yml", "json", "html", "css",
+];
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TextChunk {
+    pub source: PathBuf,
+    // 0 when lines don't apply, like in rendered man pages
+    pub start_line: usize,
+    pub text: String,
+    // Only set for code, where a chunk is a definition with a known last line
+    #[serde(default)]
+    pub end_line: Option<usize>,
+    #[serde(default)]
+    pub symbol: Option<String>,
+    // Where the chunk is when a file name and line can't say it, e.g. "ls(1), OPTIONS"
+    #[serde(default)]
+    pub title: Option<String>,
+}
+
+pub fn is_supported_file(file_path: &Path) -> bool {
+    let extension = file_path
+        .extension()
+        .and_then(OsStr::to_str)
+        .unwrap_or("")
+        .to_lowercase();
+    extension == "pdf"
+        || TEXT_EXTENSIONS.contains(&extension.as_str())
+        || CODE_EXTENSIONS.contains(&extension.as_str())
+}
+
+// Walks the folders recursively, hidden files and folders are skipped
+pub fn collect_files(folders: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = vec![];
+    let mut folders_to_visit = folders.to_vec();
+    while let Some(folder) = folders_to_visit.pop() {
+        let Ok(read_dir) = fs::read_dir(&folder) else {
+            println!("Error reading folder: {:?}", folder);
+            continue;
+        };
+        for dir_entry in read_dir.filter_map(|dir_entry| dir_entry.ok()) {
+            let entry_path = dir_entry.path();
+            if dir_entry.file_name().to_string_lossy().starts_with('.') {
+                continue;
+            }
+            match dir_entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => folders_to_visit.push(entry_path),
+                Ok(file_type) if file_type.is_file() && is_supported_file(&entry_path) => {
+                    let is_small_enough = dir_entry
+                        .metadata()
+                        .map(|metadata| metadata.len() <= MAX_FILE_SIZE_BYTES)
+                        .unwrap_or(false);
+                    if is_small_enough {
+                        files.push(entry_path);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+// 0 when it can't be read, which never matches a real time so the file is read again
+pub fn modified_unix_secs(file_path: &Path) -> u64 {
+    fs::metadata(file_path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_secs())
+        .unwrap_or(0)
+}
+
+// Catches files that were touched or copied without their content changing
+pub fn content_hash(file_path: &Path) -> Option<String> {
+    fs::read(file_path)
+        .map(|file_bytes| format!("{:x}", Sha256::digest(&file_bytes)))
+        .ok()
+}
+
+pub fn read_document(file_path: &PathBuf) -> Option<String> {
+    let is_pdf = file_path
+        .extension()
+        .map(|extension| extension.eq_ignore_ascii_case("pdf"))
+        .unwrap_or(false);
+    if is_pdf {
+        Some(utils::pdf_to_string(file_path))
+    } else {
+        fs::read_to_string(file_path)
+            .map_err(|err| println!("Error reading {:?}: {:?}", file_path, err))
+            .ok()
+    }
+}
+
+/*
+Splits text into overlapping chunks on line boundaries
+- Chunks are roughly CHUNK_SIZE_CHARS long, lines longer than that become their own chunk
+- The last few lines of a chunk start the next one so an answer split across the boundary isn't lost
+*/
+pub fn chunk_text(source: &Path, text: &str) -> Vec<TextChunk> {
+    let lines = text.lines().collect::<Vec<&str>>();
+    let mut chunks = vec![];
+    let mut chunk_start = 0;
+    while chunk_start < lines.len() {
+        let mut chunk_end = chunk_start;
+        let mut chunk_length = 0;
+        while chunk_end < lines.len()
+            && (chunk_end == chunk_start
+                || chunk_length + lines[chunk_end].len() <= CHUNK_SIZE_CHARS)
+        {
+            chunk_length += lines[chunk_end].len() + 1;
+            chunk_end += 1;
+        }
+        let chunk_text = lines[chunk_start..chunk_end].join("\n");
+        if !chunk_text.trim().is_empty() {
+            chunks.push(TextChunk {
+                source: source.to_path_buf(),
+                start_line: chunk_start + 1,
+                text: chunk_text,
+                end_line: None,
+                symbol: None,
+                title: None,
+            });
+        }
+        if chunk_end >= lines.len() {
+            break;
+        }
+        let mut overlap_start = chunk_end;
+        let mut overlap_length = 0;
+        while overlap_start > chunk_start + 1
+            && overlap_length + lines[overlap_start - 1].len() <= CHUNK_OVERLAP_CHARS
+        {
+            overlap_length += lines[overlap_start - 1].len() + 1;
+            overlap_start -= 1;
+        }
+        chunk_start = overlap_start;
+    }
+    chunks
+}
+
+impl TextChunk {
+    /*
+    PDF text has a [Page n] marker before each page, see PdfDocument::to_prompt_text
+    - A chunk starting with a marker is on that page
+    - A chunk with a marker further in started on the page before it
+    */
+    fn page(&self) -> Option<usize> {
+        let marker_start = self.text.find("[Page ")?;
+        let page_number = self.text[marker_start + "[Page ".len()..]
+            .split(']')
+            .next()?
+            .parse::<usize>()
+            .ok()?;
+        if self.text[..marker_start].trim().is_empty() {
+            Some(page_number)
+        } else {
+            Some(page_number.saturating_sub(1).max(1))
+        }
+    }
+
+    // Titled with where the chunk is, or for code what it defines, e.g. "retrieve in mod.rs"
+    pub fn to_result(&self, score: f32) -> RetrievedResult {
+        let page = self.page();
+        let title = self.title.clone().or_else(|| {
+            let symbol = self.symbol.as_ref()?;
+            let file_name = self
+                .source
+                .file_name()
+                .map(|file_name| file_name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            Some(format!("{0} in {1}", symbol, file_name))
+        });
+        RetrievedResult {
+            text: self.text.clone(),
+            title,
+            source: Some(self.source.to_string_lossy().to_string()),
+            score: Some(score),
+            page,
+            line: (page.is_none() && self.start_line > 0).then_some(self.start_line),
+            end_line: self.end_line,
+        }
+    }
+}
@@ -0,0 +1,474 @@
+use ignore::WalkBuilder;
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+};
+use tree_sitter::{Language, Node, Parser};
+
+use super::chunking::{self, TextChunk};
+
+// Definitions longer than this are split, into their methods when they have any
+const MAX_DEFINITION_CHARS: usize = 3000;
+// Small neighbouring definitions are merged up to this size so one-line items don't each get a chunk
+const MERGE_SIZE_CHARS: usize = 1500;
+const MAX_SYMBOLS_IN_TITLE: usize = 3;
+
+/*
+Languages with a syntax parser, other code and text files in a repository are chunked by lines
+- Definitions are the syntax nodes that become chunks of their own
+- Definitions that hold others, like impl blocks and classes, are split into them when too long
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CodeLanguage {
+    Rust,
+    Python,
+    JavaScript,
+    TypeScript,
+    Tsx,
+    Go,
+    C,
+    Java,
+}
+
+impl CodeLanguage {
+    fn from_path(file_path: &Path) -> Option<Self> {
+        let extension = file_path
+            .extension()
+            .and_then(OsStr::to_str)
+            .unwrap_or("")
+            .to_lowercase();
+        match extension.as_str() {
+            "rs" => Some(CodeLanguage::Rust),
+            "py" => Some(CodeLanguage::Python),
+            "js" | "jsx" | "mjs" | "cjs" => Some(CodeLanguage::JavaScript),
+            "ts" => Some(CodeLanguage::TypeScript),
+            "tsx" => Some(CodeLanguage::Tsx),
+            "go" => Some(CodeLanguage::Go),
+            "c" | "h" => Some(CodeLanguage::C),
+            "java" => Some(CodeLanguage::Java),
+            _ => None,
+        }
+    }
+
+    fn grammar(&self) -> Language {
+        match self {
+            CodeLanguage::Rust => tree_sitter_rust::language(),
+            CodeLanguage::Python => tree_sitter_python::language(),
+            CodeLanguage::JavaScript => tree_sitter_javascript::language(),
+            CodeLanguage::TypeScript => tree_sitter_typescript::language_typescript(),
+            CodeLanguage::Tsx => tree_sitter_typescript::language_tsx(),
+            CodeLanguage::Go => tree_sitter_go::language(),
+            CodeLanguage::C => tree_sitter_c::language(),
+            CodeLanguage::Java => tree_sitter_java::language(),
+        }
+    }
+
+    fn is_definition(&self, kind: &str) -> bool {
+        let definition_kinds: &[&str] = match self {
+            CodeLanguage::Rust => &[
+                "function_item",
+                "impl_item",
+                "struct_item",
+                "enum_item",
+                "union_item",
+                "trait_item",
+                "mod_item",
+                "macro_definition",
+                "const_item",
+                "static_item",
+                "type_item",
+            ],
+            CodeLanguage::Python => &[
+                "function_definition",
+                "class_definition",
+                "decorated_definition",
+            ],
+            CodeLanguage::JavaScript | CodeLanguage::TypeScript | CodeLanguage::Tsx => &[
+                "function_declaration",
+                "generator_function_declaration",
+                "class_declaration",
+                "abstract_class_declaration",
+                "method_definition",
+                "lexical_declaration",
+                "export_statement",
+                "interface_declaration",
+                "type_alias_declaration",
+                "enum_declaration",
+            ],
+            CodeLanguage::Go => &[
+                "function_declaration",
+                "method_declaration",
+                "type_declaration",
+            ],
+            CodeLanguage::C => &[
+                "function_definition",
+                "struct_specifier",
+                "enum_specifier",
+                "type_definition",
+            ],
+            CodeLanguage::Java => &[
+                "class_declaration",
+                "interface_declaration",
+                "enum_declaration",
+                "record_declaration",
+                "method_declaration",
+                "constructor_declaration",
+            ],
+        };
+        definition_kinds.contains(&kind)
+    }
+
+    // Comments and attributes right above a definition belong to its chunk
+    fn is_preamble(&self, kind: &str) -> bool {
+        kind.contains("comment") || kind == "attribute_item" || kind == "decorator"
+    }
+}
+
+// Walks the folders like git would see them, .gitignore and hidden files are skipped
+pub fn collect_repository_files(folders: &[PathBuf]) -> Vec<PathBuf> {
+    let Some((first_folder, other_folders)) = folders.split_first() else {
+        return vec![];
+    };
+    let mut walk_builder = WalkBuilder::new(first_folder);
+    other_folders.iter().for_each(|folder| {
+        walk_builder.add(folder);
+    });
+    let mut files = walk_builder
+        .build()
+        .filter_map(|dir_entry| {
+            dir_entry
+                .map_err(|err| println!("Error reading repository: {:?}", err))
+                .ok()
+        })
+        .filter(|dir_entry| {
+            dir_entry
+                .file_type()
+                .map(|file_type| file_type.is_file())
+                .unwrap_or(false)
+        })
+        .filter(|dir_entry| chunking::is_supported_file(dir_entry.path()))
+        .filter(|dir_entry| {
+            dir_entry
+                .metadata()
+                .map(|metadata| metadata.len() <= chunking::MAX_FILE_SIZE_BYTES)
+                .unwrap_or(false)
+        })
+        .map(|dir_entry| dir_entry.into_path())
+        .collect::<Vec<PathBuf>>();
+    files.sort();
+    files.dedup();
+    files
+}
+
+// Lines are 0-based and inclusive here, chunks get 1-based line numbers
+#[derive(Clone, Debug)]
+struct Span {
+    start_row: usize,
+    end_row: usize,
+    symbols: Vec<String>,
+}
+
+fn node_text<'a>(node: Node, source_text: &'a str) -> &'a str {
+    node.utf8_text(source_text.as_bytes()).unwrap_or("")
+}
+
+// A node ending at column 0 ends on the line before, after its trailing newline
+fn end_row(node: Node) -> usize {
+    let end_position = node.end_position();
+    if end_position.column == 0 && end_position.row > node.start_position().row {
+        end_position.row - 1
+    } else {
+        end_position.row
+    }
+}
+
+// The name a definition introduces, found through the fields the grammars use for it
+fn symbol_name(node: Node, source_text: &str) -> Option<String> {
+    if let Some(name_node) = node.child_by_field_name("name") {
+        return Some(node_text(name_node, source_text).to_string());
+    }
+    if node.kind() == "impl_item" {
+        let type_node = node.child_by_field_name("type")?;
+        let trait_name = node
+            .child_by_field_name("trait")
+            .map(|trait_node| format!("{} for ", node_text(trait_node, source_text)))
+            .unwrap_or_default();
+        return Some(format!(
+            "impl {0}{1}",
+            trait_name,
+            node_text(type_node, source_text)
+        ));
+    }
+    for field_name in ["declarator", "declaration", "definition", "type"] {
+        if let Some(child) = node.child_by_field_name(field_name) {
+            if child.kind().ends_with("identifier") {
+                return Some(node_text(child, source_text).to_string());
+            }
+            if let Some(name) = symbol_name(child, source_text) {
+                return Some(name);
+            }
+        }
+    }
+    // let and const declarations, and Go's type declarations, name their first declarator
+    let mut cursor = node.walk();
+    let first_declarator = node.named_children(&mut cursor).find(|child| {
+        matches!(
+            child.kind(),
+            "variable_declarator" | "type_spec" | "type_alias"
+        )
+    });
+    first_declarator.and_then(|declarator| symbol_name(declarator, source_text))
+}
+
+/*
+The definitions directly under a node, each with the comments and attributes above it
+- A definition that is too long and holds other definitions is replaced by them
+- What's left between definitions, like imports, is filled in later by line chunks
+*/
+fn definition_spans(node: Node, language: CodeLanguage, source_text: &str, spans: &mut Vec<Span>) {
+    let mut cursor = node.walk();
+    let mut preamble_start: Option<usize> = None;
+    for child in node.named_children(&mut cursor) {
+        let kind = child.kind();
+        if language.is_preamble(kind) {
+            preamble_start.get_or_insert(child.start_position().row);
+            continue;
+        }
+        if !language.is_definition(kind) {
+            preamble_start = None;
+            if child.named_child_count() > 0 && child.byte_range().len() > MAX_DEFINITION_CHARS {
+                // Class bodies, declaration lists and the like
+                definition_spans(child, language, source_text, spans);
+            }
+            continue;
+        }
+        let start_row = preamble_start.take().unwrap_or(child.start_position().row);
+        let previous_count = spans.len();
+        if child.byte_range().len() > MAX_DEFINITION_CHARS {
+            definition_spans(child, language, source_text, spans);
+        }
+        if spans.len() == previous_count {
+            spans.push(Span {
+                start_row,
+                end_row: end_row(child),
+                symbols: symbol_name(child, source_text).into_iter().collect(),
+            });
+        } else if let Some(symbol) = symbol_name(child, source_text) {
+            // The header of a split definition, e.g. "impl Foo {", goes in its own span
+            let first_inner_row = spans[previous_count].start_row;
+            if first_inner_row > start_row {
+                spans.insert(
+                    previous_count,
+                    Span {
+                        start_row,
+                        end_row: first_inner_row - 1,
+                        symbols: vec![symbol],
+                    },
+                );
+            }
+        }
+    }
+}
+
+// Text between definitions, or a definition that's still too long, chunked by lines
+fn line_chunks(source: &Path, lines: &[&str], span: &Span) -> Vec<TextChunk> {
+    let span_text = lines[span.start_row..=span.end_row].join("\n");
+    chunking::chunk_text(source, &span_text)
+        .into_iter()
+        .map(|mut chunk| {
+            chunk.start_line += span.start_row;
+            chunk.end_line = Some(chunk.start_line + chunk.text.lines().count().max(1) - 1);
+            chunk.symbol = span.symbols.first().cloned();
+            chunk
+        })
+        .collect()
+}
+
+fn span_chars(lines: &[&str], span: &Span) -> usize {
+    lines[span.start_row..=span.end_row]
+        .iter()
+        .map(|line| line.len() + 1)
+        .sum()
+}
+
+/*
+Splits a source file along function and class boundaries
+- Falls back to line chunks for languages without a parser, or when parsing fails
+- Every chunk keeps its first and last line so answers can point at them
+- Neighbouring small spans are merged, a chunk's symbol lists the first few definitions in it
+*/
+pub fn chunk_source_file(source: &Path, source_text: &str) -> Vec<TextChunk> {
+    let lines = source_text.lines().collect::<Vec<&str>>();
+    if lines.is_empty() {
+        return vec![];
+    }
+    let whole_file = Span {
+        start_row: 0,
+        end_row: lines.len() - 1,
+        symbols: vec![],
+    };
+    let Some(language) = CodeLanguage::from_path(source) else {
+        return line_chunks(source, &lines, &whole_file);
+    };
+    let mut parser = Parser::new();
+    if let Err(err) = parser.set_language(&language.grammar()) {
+        println!("Error loading the {:?} parser: {:?}", language, err);
+        return line_chunks(source, &lines, &whole_file);
+    }
+    let Some(tree) = parser.parse(source_text, None) else {
+        return line_chunks(source, &lines, &whole_file);
+    };
+
+    let mut definitions = vec![];
+    definition_spans(tree.root_node(), language, source_text, &mut definitions);
+    definitions.sort_by_key(|span| span.start_row);
+
+    // Fill the gaps so nothing in the file is left out of the index
+    let mut spans: Vec<Span> = vec![];
+    let mut next_row = 0;
+    for definition in definitions {
+        if definition.start_row < next_row || definition.end_row >= lines.len() {
+            continue;
+        }
+        if definition.start_row > next_row {
+            spans.push(Span {
+                start_row: next_row,
+                end_row: definition.start_row - 1,
+                symbols: vec![],
+            });
+        }
+        next_row = definition.end_row + 1;
+        spans.push(definition);
+    }
+    if next_row < lines.len() {
+        spans.push(Span {
+            start_row: next_row,
+            end_row: lines.len() - 1,
+            symbols: vec![],
+        });
+    }
+
+    let mut merged_spans: Vec<Span> = vec![];
+    for span in spans {
+        if let Some(last_span) = merged_spans.last_mut() {
+            let merged_chars = span_chars(&lines, last_span) + span_chars(&lines, &span);
+            if merged_chars <= MERGE_SIZE_CHARS {
+                last_span.end_row = span.end_row;
+                last_span.symbols.extend(span.symbols);
+                continue;
+            }
+        }
+        merged_spans.push(span);
+    }
+
+    merged_spans
+        .iter()
+        .filter(|span| {
+            !lines[span.start_row..=span.end_row]
+                .iter()
+                .all(|line| line.trim().is_empty())
+        })
+        .flat_map(|span| {
+            if span_chars(&lines, span) > MAX_DEFINITION_CHARS {
+                return line_chunks(source, &lines, span);
+            }
+            let symbol = match span.symbols.len() {
+                0 => None,
+                symbol_count if symbol_count <= MAX_SYMBOLS_IN_TITLE => {
+                    Some(span.symbols.join(", "))
+                }
+                _ => Some(format!(
+                    "{}, ...",
+                    span.symbols[..MAX_SYMBOLS_IN_TITLE].join(", ")
+                )),
+            };
+            vec![TextChunk {
+                source: source.to_path_buf(),
+                start_line: span.start_row + 1,
+                text: lines[span.start_row..=span.end_row].join("\n"),
+                end_line: Some(span.end_row + 1),
+                symbol,
+                title: None,
+            }]
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Enough assignments that one function is too big to be merged with another
+    fn long_body(indent: &str, line_count: usize, line_end: &str) -> String {
+        (0..line_count)
+            .map(|index| format!("{0}value_{1} = {1}{2}\n", indent, index, line_end))
+            .collect()
+    }
+
+    fn line_of(source_text: &str, line: &str) -> usize {
+        source_text
+            .lines()
+            .position(|source_line| source_line == line)
+            .unwrap()
+            + 1
+    }
+
+    #[test]
+    fn rust_functions_become_chunks_with_their_comments() {
+        let source_text = format!(
+            "use std::fmt;\n\n/// Adds things\nfn first() {{\n{0}}}\n\nfn second() {{\n{0}}}\n",
+            long_body("    let ", 60, ";")
+        );
+        let chunks = chunk_source_file(Path::new("lib.rs"), &source_text);
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].symbol.as_deref(), Some("first"));
+        assert_eq!(chunks[0].start_line, 1);
+        assert!(chunks[0].text.contains("/// Adds things"));
+        assert_eq!(chunks[1].symbol.as_deref(), Some("second"));
+        assert_eq!(chunks[1].start_line, line_of(&source_text, "fn second() {"));
+        assert_eq!(chunks[1].end_line, Some(source_text.lines().count()));
+        assert!(chunks[1].text.starts_with("fn second() {"));
+    }
+
+    #[test]
+    fn small_rust_definitions_are_merged() {
+        let source_text = "struct Foo;\n\nimpl fmt::Display for Foo {\n    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {\n        write!(f, \"foo\")\n    }\n}\n\nfn helper() {}\n";
+        let chunks = chunk_source_file(Path::new("lib.rs"), source_text);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(
+            chunks[0].symbol.as_deref(),
+            Some("Foo, impl fmt::Display for Foo, helper")
+        );
+        assert_eq!(chunks[0].start_line, 1);
+        assert_eq!(chunks[0].end_line, Some(9));
+    }
+
+    #[test]
+    fn long_python_class_is_split_into_methods() {
+        let source_text = format!(
+            "class Foo:\n    def first(self):\n{0}\n    def second(self):\n{0}",
+            long_body("        ", 90, "")
+        );
+        let chunks = chunk_source_file(Path::new("foo.py"), &source_text);
+        let symbols = chunks
+            .iter()
+            .map(|chunk| chunk.symbol.as_deref())
+            .collect::<Vec<Option<&str>>>();
+        assert_eq!(symbols, vec![Some("Foo"), Some("first"), Some("second")]);
+        assert_eq!(chunks[0].text, "class Foo:");
+        assert_eq!(
+            chunks[2].start_line,
+            line_of(&source_text, "    def second(self):")
+        );
+    }
+
+    #[test]
+    fn unknown_languages_are_chunked_by_lines() {
+        let chunks = chunk_source_file(Path::new("notes.toml"), "[package]\nname = \"comhra\"\n");
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].start_line, 1);
+        assert_eq!(chunks[0].end_line, Some(2));
+        assert_eq!(chunks[0].symbol, None);
+        assert!(chunk_source_file(Path::new("empty.rs"), "").is_empty());
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    attachments::truncate_text,
+    models::{prompt_template, Role, SavedConversation},
+    utils::{get_filenames_from_folder, get_root_folder},
+};
+
+use super::{self as rag, chunking::TextChunk, keyword_index::KeywordIndex, RetrievedResult};
+
+const CONVERSATIONS_FOLDER: &str = "./conversations";
+const TOP_K: usize = 4;
+// Long answers are cut so one exchange can't take up the whole context
+const MAX_EXCHANGE_CHARS: usize = 3000;
+
+// Which saved conversations are searched, archived ones are left out unless asked for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ConversationSearchConfig {
+    pub starred_only: bool,
+    pub include_archived: bool,
+}
+
+impl ConversationSearchConfig {
+    pub fn options() -> Vec<Self> {
+        vec![
+            Self {
+                starred_only: false,
+                include_archived: false,
+            },
+            Self {
+                starred_only: true,
+                include_archived: false,
+            },
+            Self {
+                starred_only: false,
+                include_archived: true,
+            },
+        ]
+    }
+
+    pub fn name(&self) -> String {
+        match (self.starred_only, self.include_archived) {
+            (true, _) => String::from("Past conversations (starred)"),
+            (false, true) => String::from("Past conversations (including archived)"),
+            (false, false) => String::from("Past conversations"),
+        }
+    }
+
+    fn includes(&self, saved_conversation: &SavedConversation) -> bool {
+        if self.starred_only {
+            saved_conversation.starred
+        } else {
+            self.include_archived || !saved_conversation.archived
+        }
+    }
+}
+
+fn read_conversation(file_path: &Path) -> Result<SavedConversation, Box<dyn Error>> {
+    Ok(serde_json::from_str(&fs::read_to_string(file_path)?)?)
+}
+
+/*
+One chunk per exchange, a prompt and the answers to it
+- The prompt is taken without any RAG context or attachment it was sent with
+- start_line is the exchange number, used in the result title
+*/
+fn exchange_chunks(file_path: &Path, saved_conversation: &SavedConversation) -> Vec<TextChunk> {
+    let mut chunks: Vec<TextChunk> = vec![];
+    for chat_message in &saved_conversation.conversation {
+        match chat_message.role {
+            Role::User => chunks.push(TextChunk {
+                source: file_path.to_path_buf(),
+                start_line: chunks.len() + 1,
+                text: format!("User: {}", prompt_template::unformat_prompt(chat_message)),
+                end_line: None,
+                symbol: None,
+                title: None,
+            }),
+            Role::Assistant => {
+                if let Some(chunk) = chunks.last_mut() {
+                    chunk
+                        .text
+                        .push_str(&format!("\n\nAssistant: {}", chat_message.content));
+                }
+            }
+            Role::System => {}
+        }
+    }
+    chunks
+        .into_iter()
+        .map(|mut chunk| {
+            chunk.text = truncate_text(&chunk.text, MAX_EXCHANGE_CHARS);
+            chunk
+        })
+        .collect()
+}
+
+// The file name to open in the sidebar when a result comes from a saved conversation
+pub fn conversation_file_name(source: &str) -> Option<PathBuf> {
+    let source_path = Path::new(source);
+    let conversations_folder = get_root_folder().join(CONVERSATIONS_FOLDER);
+    if source_path.parent()? == conversations_folder {
+        source_path.file_name().map(PathBuf::from)
+    } else {
+        None
+    }
+}
+
+/*
+Ranked with BM25 in memory, conversations are small enough that keeping an index isn't worth it
+- The conversation being answered is skipped, it's already in the prompt and would only match itself
+- Every saved conversation is read for each prompt, so it's done on a thread
+*/
+pub async fn retrieve(
+    config: &ConversationSearchConfig,
+    query: &str,
+    current_conversation: Option<&Path>,
+) -> Result<Vec<RetrievedResult>, Box<dyn Error>> {
+    let config = *config;
+    let query = query.to_owned();
+    let current_conversation = current_conversation.map(Path::to_path_buf);
+    rag::run_on_thread(move || {
+        Ok(search_conversations(
+            &config,
+            &query,
+            current_conversation.as_deref(),
+        ))
+    })
+    .await
+}
+
+fn search_conversations(
+    config: &ConversationSearchConfig,
+    query: &str,
+    current_conversation: Option<&Path>,
+) -> Vec<RetrievedResult> {
+    let current_file_name = current_conversation.and_then(Path::file_name);
+    let mut conversation_names = HashMap::new();
+    let mut chunks = vec![];
+    for file_path in get_filenames_from_folder(PathBuf::from(CONVERSATIONS_FOLDER)) {
+        if current_file_name.is_some() && file_path.file_name() == current_file_name {
+            continue;
+        }
+        let saved_conversation = match read_conversation(&file_path) {
+            Ok(saved_conversation) => saved_conversation,
+            Err(err) => {
+                println!("Error reading conversation {:?}: {:?}", file_path, err);
+                continue;
+            }
+        };
+        if !config.includes(&saved_conversation) {
+            continue;
+        }
+        chunks.extend(exchange_chunks(&file_path, &saved_conversation));
+        conversation_names.insert(file_path, saved_conversation.name);
+    }
+    let keyword_index = KeywordIndex::from_chunks(chunks);
+    keyword_index
+        .search(query, TOP_K)
+        .into_iter()
+        .map(|(score, chunk)| RetrievedResult {
+            text: chunk.text.clone(),
+            title: conversation_names
+                .get(&chunk.source)
+                .map(|conversation_name| {
+                    format!("{0}, exchange {1}", conversation_name, chunk.start_line)
+                }),
+            source: Some(chunk.source.to_string_lossy().to_string()),
+            score: Some(score),
+            page: None,
+            line: None,
+            end_line: None,
+        })
+        .collect()
+}
@@ -0,0 +1,610 @@
+use ollama_rs::Ollama;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    error::Error,
+    fs::{self, File},
+    io::{Read, Write},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+    time::{self, SystemTime, UNIX_EPOCH},
+};
+use uuid::Uuid;
+
+use gtk::glib;
+
+use crate::utils::get_root_folder;
+
+use super::{
+    self as rag,
+    chunking::{self, TextChunk},
+    code_chunking,
+    keyword_index::KeywordIndex,
+    local_docs, RetrievedResult,
+};
+
+const INDEX_FOLDER: &str = "./rag_indexes";
+const VECTORS_FOLDER: &str = "vectors";
+const KEYWORDS_FOLDER: &str = "keywords";
+pub const DEFAULT_EMBEDDING_MODEL: &str = "nomic-embed-text";
+pub const DEFAULT_TOP_K: usize = 4;
+
+// Ids of the indexes being built, shared by the index manager and the folder watcher
+static BUILDING_INDEX_IDS: Mutex<BTreeSet<String>> = Mutex::new(BTreeSet::new());
+
+fn default_top_k() -> usize {
+    DEFAULT_TOP_K
+}
+
+fn default_watch_folders() -> bool {
+    true
+}
+
+// Keywords needs no embedding model, so it works without Ollama running
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum SearchMethod {
+    #[default]
+    Embeddings,
+    Keywords,
+}
+
+impl SearchMethod {
+    pub fn label(&self) -> &'static str {
+        match self {
+            SearchMethod::Embeddings => "Embeddings (Ollama)",
+            SearchMethod::Keywords => "Keywords (BM25)",
+        }
+    }
+}
+
+/*
+What the folders hold, which decides how files are found and chunked
+- Repositories respect .gitignore and chunk code along definitions, with line ranges
+- Local documentation reads man pages, info manuals and package docs, cited by page and section
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum IndexKind {
+    #[default]
+    Documents,
+    Repository,
+    LocalDocumentation,
+}
+
+impl IndexKind {
+    pub fn label(&self) -> &'static str {
+        match self {
+            IndexKind::Documents => "Documents",
+            IndexKind::Repository => "Code repository",
+            IndexKind::LocalDocumentation => "Local documentation (man, info, /usr/share/doc)",
+        }
+    }
+}
+
+// The outcome of the last build, kept with the config so the index list can show it
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct IndexStatus {
+    pub documents: usize,
+    pub chunks: usize,
+    pub last_indexed_unix_secs: Option<u64>,
+    pub last_error: Option<String>,
+    // Files the last build couldn't embed, left out so the next update tries them again
+    #[serde(default)]
+    pub failed_files: Vec<PathBuf>,
+}
+
+// What the user chose to index, saved separately from the vectors so it's cheap to list
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct DocumentIndexConfig {
+    pub id: String,
+    pub name: String,
+    pub folders: Vec<PathBuf>,
+    pub embedding_model: String,
+    #[serde(default = "default_top_k")]
+    pub top_k: usize,
+    #[serde(default)]
+    pub search_method: SearchMethod,
+    #[serde(default)]
+    pub status: IndexStatus,
+    #[serde(default = "default_watch_folders")]
+    pub watch_folders: bool,
+    #[serde(default)]
+    pub kind: IndexKind,
+}
+
+impl DocumentIndexConfig {
+    pub fn new(
+        name: String,
+        folders: Vec<PathBuf>,
+        embedding_model: String,
+        search_method: SearchMethod,
+        kind: IndexKind,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4().to_string(),
+            name,
+            folders,
+            embedding_model,
+            top_k: DEFAULT_TOP_K,
+            search_method,
+            status: IndexStatus::default(),
+            watch_folders: true,
+            kind,
+        }
+    }
+
+    fn folder() -> PathBuf {
+        get_root_folder().join(PathBuf::from(INDEX_FOLDER))
+    }
+
+    fn config_path(&self) -> PathBuf {
+        Self::folder().join(format!("{}.json", self.id))
+    }
+
+    fn vectors_path(&self) -> PathBuf {
+        Self::folder()
+            .join(VECTORS_FOLDER)
+            .join(format!("{}.json", self.id))
+    }
+
+    pub fn keyword_index_path(&self) -> PathBuf {
+        Self::folder()
+            .join(KEYWORDS_FOLDER)
+            .join(format!("{}.json", self.id))
+    }
+
+    pub fn list() -> Vec<Self> {
+        let Ok(read_dir) = fs::read_dir(Self::folder()) else {
+            return vec![];
+        };
+        let mut configs = read_dir
+            .filter_map(|dir_entry| dir_entry.ok())
+            .map(|dir_entry| dir_entry.path())
+            .filter(|file_path| file_path.is_file())
+            .filter_map(|file_path| {
+                let json_data = fs::read_to_string(&file_path).ok()?;
+                serde_json::from_str::<Self>(&json_data)
+                    .map_err(|err| println!("Error reading {:?}: {:?}", file_path, err))
+                    .ok()
+            })
+            .collect::<Vec<Self>>();
+        configs.sort_by(|first, second| first.name.cmp(&second.name));
+        configs
+    }
+
+    // The config as last saved, a build can finish after it was edited or deleted
+    pub fn find(id: &str) -> Option<Self> {
+        Self::list().into_iter().find(|config| config.id == id)
+    }
+
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        fs::create_dir_all(Self::folder())?;
+        let mut file = File::create(self.config_path())?;
+        file.write_all(serde_json::to_string(&self)?.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn delete(&self) {
+        [
+            self.config_path(),
+            self.vectors_path(),
+            self.keyword_index_path(),
+        ]
+        .iter()
+        .filter(|file_path| file_path.exists())
+        .for_each(|file_path| {
+            if let Err(err) = fs::remove_file(file_path) {
+                println!("Error removing {:?}: {:?}", file_path, err);
+            }
+        });
+    }
+
+    /*
+    Saves the outcome of a build, as documents, chunks and the files that couldn't be embedded
+    - A failed build keeps the counts from the last one that worked, the old index is still used
+    */
+    pub fn record_build(&mut self, build_result: Result<(usize, usize, Vec<PathBuf>), String>) {
+        match build_result {
+            Ok((documents, chunks, failed_files)) => {
+                self.status = IndexStatus {
+                    documents,
+                    chunks,
+                    last_indexed_unix_secs: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .ok()
+                        .map(|duration| duration.as_secs()),
+                    last_error: None,
+                    failed_files,
+                }
+            }
+            Err(err) => self.status.last_error = Some(err),
+        }
+        if let Err(err) = self.save() {
+            println!("Error saving index status for {}: {:?}", self.name, err);
+        }
+    }
+
+    pub fn collect_files(&self) -> Vec<PathBuf> {
+        match self.kind {
+            IndexKind::Documents => chunking::collect_files(&self.folders),
+            IndexKind::Repository => code_chunking::collect_repository_files(&self.folders),
+            IndexKind::LocalDocumentation => local_docs::collect_files(&self.folders),
+        }
+    }
+
+    pub fn chunk_file(&self, file_path: &PathBuf) -> Vec<TextChunk> {
+        // Man and info pages are usually compressed, so they're read by local_docs
+        if self.kind == IndexKind::LocalDocumentation {
+            return local_docs::chunk_file(file_path);
+        }
+        let Some(document_text) = chunking::read_document(file_path) else {
+            return vec![];
+        };
+        match self.kind {
+            IndexKind::Repository => code_chunking::chunk_source_file(file_path, &document_text),
+            _ => chunking::chunk_text(file_path, &document_text),
+        }
+    }
+
+    // None while the index is already building, whether from the manager or the folder watcher
+    pub fn start_build(&self) -> Option<BuildGuard> {
+        if BUILDING_INDEX_IDS.lock().unwrap().insert(self.id.clone()) {
+            Some(BuildGuard {
+                config_id: self.id.clone(),
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn is_building(&self) -> bool {
+        BUILDING_INDEX_IDS.lock().unwrap().contains(&self.id)
+    }
+
+    pub fn is_built(&self) -> bool {
+        match self.search_method {
+            SearchMethod::Embeddings => self.vectors_path().exists(),
+            SearchMethod::Keywords => self.keyword_index_path().exists(),
+        }
+    }
+}
+
+// Held while an index builds so nothing else writes its files, dropping it lets the next build start
+pub struct BuildGuard {
+    config_id: String,
+}
+
+impl Drop for BuildGuard {
+    fn drop(&mut self) {
+        BUILDING_INDEX_IDS.lock().unwrap().remove(&self.config_id);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct EmbeddedChunk {
+    pub chunk: TextChunk,
+    pub embedding: Vec<f32>,
+}
+
+/*
+Vectors for every chunk of every document in the configured folders
+- Embedded through Ollama's embeddings endpoint with the configured model
+- Searched by cosine similarity against the embedded prompt
+- Each file's modified time is kept so an incremental build only embeds files that changed
+- Each file's content hash is kept too, so a file that was only touched isn't embedded again
+*/
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DocumentIndex {
+    pub embedding_model: String,
+    pub chunks: Vec<EmbeddedChunk>,
+    #[serde(default)]
+    pub files: BTreeMap<PathBuf, u64>,
+    #[serde(default)]
+    pub content_hashes: BTreeMap<PathBuf, String>,
+    #[serde(default)]
+    pub failed_files: Vec<PathBuf>,
+}
+
+pub async fn embed(embedding_model: &str, text: &str) -> Result<Vec<f32>, Box<dyn Error>> {
+    Ok(Ollama::default()
+        .generate_embeddings(embedding_model.to_owned(), text.to_owned(), None)
+        .await?
+        .embeddings
+        .into_iter()
+        .map(|value| value as f32)
+        .collect())
+}
+
+pub fn cosine_similarity(first: &[f32], second: &[f32]) -> f32 {
+    let dot_product = first
+        .iter()
+        .zip(second)
+        .map(|(first_value, second_value)| first_value * second_value)
+        .sum::<f32>();
+    let first_norm = first.iter().map(|value| value * value).sum::<f32>().sqrt();
+    let second_norm = second.iter().map(|value| value * value).sum::<f32>().sqrt();
+    if first_norm == 0.0 || second_norm == 0.0 {
+        0.0
+    } else {
+        dot_product / (first_norm * second_norm)
+    }
+}
+
+// Reported while building, reading covers collecting, chunking and hashing the files
+#[derive(Clone, Copy, Debug)]
+pub enum BuildProgress {
+    Reading {
+        read_files: usize,
+        total_files: usize,
+    },
+    Embedding {
+        embedded_chunks: usize,
+        total_chunks: usize,
+    },
+}
+
+// Everything a build needs before embedding, worked out on a thread
+struct PreparedBuild {
+    files: BTreeMap<PathBuf, u64>,
+    content_hashes: BTreeMap<PathBuf, String>,
+    kept_chunks: Vec<EmbeddedChunk>,
+    text_chunks: Vec<TextChunk>,
+}
+
+enum PrepareMessage {
+    Progress(usize, usize),
+    Done(PreparedBuild),
+}
+
+const POLL_INTERVAL_MILLIS: u64 = 100;
+
+impl DocumentIndex {
+    /*
+    Embeds the configured folders, on_progress is called on the main context as the build goes
+    - Reading, chunking and hashing files and saving the index run on a thread, only embedding is awaited here
+    - An incremental build keeps the vectors of files whose modified time or content hasn't changed
+    - A full build, or a change of embedding model, embeds everything again
+    - A file with a chunk that fails to embed is left out and listed in failed_files, the build goes on
+    - The build only fails when no chunk at all could be embedded, usually as Ollama isn't running
+    */
+    pub async fn build(
+        config: &DocumentIndexConfig,
+        is_full_build: bool,
+        on_progress: impl Fn(BuildProgress),
+    ) -> Result<Self, Box<dyn Error>> {
+        let (prepare_sender, prepare_receiver): (Sender<PrepareMessage>, Receiver<PrepareMessage>) =
+            mpsc::channel();
+        {
+            let config = config.clone();
+            std::thread::spawn(move || {
+                let prepared_build = Self::prepare_build(&config, is_full_build, &prepare_sender);
+                let _ = prepare_sender.send(PrepareMessage::Done(prepared_build));
+            });
+        }
+        let prepared_build = loop {
+            match prepare_receiver.try_recv() {
+                Ok(PrepareMessage::Progress(read_files, total_files)) => {
+                    on_progress(BuildProgress::Reading {
+                        read_files,
+                        total_files,
+                    })
+                }
+                Ok(PrepareMessage::Done(prepared_build)) => break prepared_build,
+                Err(mpsc::TryRecvError::Empty) => {
+                    glib::timeout_future(time::Duration::from_millis(POLL_INTERVAL_MILLIS)).await;
+                }
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Err("The indexing thread stopped unexpectedly".into());
+                }
+            }
+        };
+
+        let mut chunks = prepared_build.kept_chunks;
+        let mut files = prepared_build.files;
+        let mut content_hashes = prepared_build.content_hashes;
+        let mut failed_files: BTreeSet<PathBuf> = BTreeSet::new();
+        let mut embedded_count = 0;
+        let mut last_embed_error = None;
+        let total_chunks = prepared_build.text_chunks.len();
+        for (chunk_index, chunk) in prepared_build.text_chunks.into_iter().enumerate() {
+            if !failed_files.contains(&chunk.source) {
+                match embed(&config.embedding_model, &chunk.text).await {
+                    Ok(embedding) => {
+                        chunks.push(EmbeddedChunk { chunk, embedding });
+                        embedded_count += 1;
+                    }
+                    Err(err) => {
+                        println!("Error embedding {:?}: {:?}", chunk.source, err);
+                        failed_files.insert(chunk.source);
+                        last_embed_error = Some(err);
+                    }
+                }
+            }
+            on_progress(BuildProgress::Embedding {
+                embedded_chunks: chunk_index + 1,
+                total_chunks,
+            });
+        }
+        if let (Some(err), 0) = (last_embed_error, embedded_count) {
+            return Err(err);
+        }
+        // A file's other chunks are dropped too, so it's embedded whole when it's retried
+        chunks.retain(|embedded_chunk| !failed_files.contains(&embedded_chunk.chunk.source));
+        files.retain(|file_path, _| !failed_files.contains(file_path));
+        content_hashes.retain(|file_path, _| !failed_files.contains(file_path));
+        let document_index = Self {
+            embedding_model: config.embedding_model.clone(),
+            chunks,
+            files,
+            content_hashes,
+            failed_files: failed_files.into_iter().collect(),
+        };
+
+        // The index comes back from the thread once it's written
+        let (save_sender, save_receiver): (
+            Sender<(Self, Result<(), String>)>,
+            Receiver<(Self, Result<(), String>)>,
+        ) = mpsc::channel();
+        {
+            let config = config.clone();
+            std::thread::spawn(move || {
+                let save_result = document_index.save(&config).map_err(|err| err.to_string());
+                let _ = save_sender.send((document_index, save_result));
+            });
+        }
+        loop {
+            match save_receiver.try_recv() {
+                Ok((document_index, save_result)) => {
+                    save_result?;
+                    return Ok(document_index);
+                }
+                Err(mpsc::TryRecvError::Empty) => {
+                    glib::timeout_future(time::Duration::from_millis(POLL_INTERVAL_MILLIS)).await;
+                }
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    return Err("The index saving thread stopped unexpectedly".into());
+                }
+            }
+        }
+    }
+
+    fn prepare_build(
+        config: &DocumentIndexConfig,
+        is_full_build: bool,
+        prepare_sender: &Sender<PrepareMessage>,
+    ) -> PreparedBuild {
+        let previous_index = if is_full_build {
+            None
+        } else {
+            Self::load(config)
+                .ok()
+                .filter(|previous_index| previous_index.embedding_model == config.embedding_model)
+        };
+        let mut prepared_build = PreparedBuild {
+            files: BTreeMap::new(),
+            content_hashes: BTreeMap::new(),
+            kept_chunks: vec![],
+            text_chunks: vec![],
+        };
+        let current_files = config.collect_files();
+        let total_files = current_files.len();
+        for (file_index, file_path) in current_files.into_iter().enumerate() {
+            let modified_unix_secs = chunking::modified_unix_secs(&file_path);
+            let previous_modified = previous_index
+                .as_ref()
+                .and_then(|previous_index| previous_index.files.get(&file_path));
+            let previous_hash = previous_index
+                .as_ref()
+                .and_then(|previous_index| previous_index.content_hashes.get(&file_path));
+            let content_hash = if previous_modified == Some(&modified_unix_secs) {
+                previous_hash.cloned()
+            } else {
+                chunking::content_hash(&file_path)
+            };
+            let is_unchanged = previous_modified == Some(&modified_unix_secs)
+                || (content_hash.is_some() && content_hash.as_ref() == previous_hash);
+            prepared_build
+                .files
+                .insert(file_path.clone(), modified_unix_secs);
+            if let Some(content_hash) = content_hash {
+                prepared_build
+                    .content_hashes
+                    .insert(file_path.clone(), content_hash);
+            }
+            if let (true, Some(previous_index)) = (is_unchanged, &previous_index) {
+                prepared_build.kept_chunks.extend(
+                    previous_index
+                        .chunks
+                        .iter()
+                        .filter(|embedded_chunk| embedded_chunk.chunk.source == file_path)
+                        .cloned(),
+                );
+            } else {
+                prepared_build
+                    .text_chunks
+                    .extend(config.chunk_file(&file_path));
+            }
+            let _ = prepare_sender.send(PrepareMessage::Progress(file_index + 1, total_files));
+        }
+        prepared_build
+    }
+
+    pub fn load(config: &DocumentIndexConfig) -> Result<Self, Box<dyn Error>> {
+        let mut index_file = File::open(config.vectors_path())?;
+        let mut json_data = String::new();
+        index_file.read_to_string(&mut json_data)?;
+        Ok(serde_json::from_str(&json_data)?)
+    }
+
+    fn save(&self, config: &DocumentIndexConfig) -> Result<(), Box<dyn Error>> {
+        let vectors_path = config.vectors_path();
+        if let Some(parent) = vectors_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(vectors_path)?;
+        file.write_all(serde_json::to_string(&self)?.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn search(&self, query_embedding: &[f32], top_k: usize) -> Vec<(f32, &TextChunk)> {
+        let mut scored_chunks = self
+            .chunks
+            .iter()
+            .map(|embedded_chunk| {
+                (
+                    cosine_similarity(query_embedding, &embedded_chunk.embedding),
+                    &embedded_chunk.chunk,
+                )
+            })
+            .collect::<Vec<(f32, &TextChunk)>>();
+        scored_chunks.sort_by(|first, second| second.0.total_cmp(&first.0));
+        scored_chunks.into_iter().take(top_k).collect()
+    }
+}
+
+/*
+The top chunks for a prompt, for RagSource::DocumentIndex
+- Indexes can be large, so they're read and ranked on a thread rather than on the main loop
+- The query is embedded with the model the index was built with, in case the config changed since
+*/
+pub async fn retrieve(
+    config: &DocumentIndexConfig,
+    query: &str,
+) -> Result<Vec<RetrievedResult>, Box<dyn Error>> {
+    let config = config.clone();
+    let search_method = config.search_method;
+    match search_method {
+        SearchMethod::Embeddings => {
+            let document_index = {
+                let config = config.clone();
+                rag::run_on_thread(move || {
+                    DocumentIndex::load(&config).map_err(|err| err.to_string())
+                })
+                .await?
+            };
+            let query_embedding = embed(&document_index.embedding_model, query).await?;
+            rag::run_on_thread(move || {
+                Ok(document_index
+                    .search(&query_embedding, config.top_k)
+                    .into_iter()
+                    .map(|(score, chunk)| chunk.to_result(score))
+                    .collect())
+            })
+            .await
+        }
+        SearchMethod::Keywords => {
+            let query = query.to_owned();
+            rag::run_on_thread(move || {
+                let keyword_index = KeywordIndex::load(&config).map_err(|err| err.to_string())?;
+                Ok(keyword_index
+                    .search(&query, config.top_k)
+                    .into_iter()
+                    .map(|(score, chunk)| chunk.to_result(score))
+                    .collect())
+            })
+            .await
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use notify::{
+    event::ModifyKind, recommended_watcher, Event, EventKind, RecommendedWatcher, RecursiveMode,
+    Watcher,
+};
+use std::{
+    collections::HashMap,
+    path::{Component, Path, PathBuf},
+    sync::mpsc::{self, Receiver, Sender},
+    time::{self, Instant},
+};
+
+use gtk::glib;
+
+use super::{
+    chunking,
+    document_index::{
+        BuildGuard, BuildProgress, DocumentIndex, DocumentIndexConfig, IndexKind, SearchMethod,
+    },
+    keyword_index::KeywordIndex,
+    local_docs,
+};
+
+const POLL_INTERVAL_MILLIS: u64 = 500;
+const CONFIG_REFRESH_SECONDS: u64 = 5;
+// Saving a file often fires several events, wait for them to settle before reindexing
+const DEBOUNCE_SECONDS: u64 = 2;
+
+// The same filter the index's collect_files uses, so compressed man and info pages count
+fn is_indexed_file(kind: IndexKind, file_path: &Path) -> bool {
+    match kind {
+        IndexKind::Documents | IndexKind::Repository => chunking::is_supported_file(file_path),
+        IndexKind::LocalDocumentation => local_docs::is_local_doc_file(file_path),
+    }
+}
+
+// Only changes collect_files would pick up, editors' hidden swap files are ignored
+fn is_relevant_change(
+    event: &Event,
+    kind: IndexKind,
+    folders: &[PathBuf],
+    gitignores: &[Gitignore],
+) -> bool {
+    let is_content_change = match event.kind {
+        EventKind::Create(_) | EventKind::Remove(_) => true,
+        EventKind::Modify(ModifyKind::Metadata(_)) => false,
+        EventKind::Modify(_) => true,
+        _ => false,
+    };
+    is_content_change
+        && event.paths.iter().any(|file_path| {
+            !is_hidden(file_path, folders)
+                && (!file_path.is_file() || is_indexed_file(kind, file_path))
+                && !gitignores.iter().any(|gitignore| {
+                    gitignore
+                        .matched_path_or_any_parents(file_path, file_path.is_dir())
+                        .is_ignore()
+                })
+        })
+}
+
+// Only the part below the watched folder counts, which can itself be somewhere like ~/.local/share
+fn is_hidden(file_path: &Path, folders: &[PathBuf]) -> bool {
+    let relative_path = folders
+        .iter()
+        .find_map(|folder| file_path.strip_prefix(folder).ok())
+        .unwrap_or(file_path);
+    relative_path.components().any(|component| match component {
+        Component::Normal(name) => name.to_string_lossy().starts_with('.'),
+        _ => false,
+    })
+}
+
+/*
+Keeps build output like target/ from triggering updates while a repository is being compiled
+- Only the top .gitignore is read, the index itself still honours nested ones when it updates
+*/
+fn root_gitignore(folder: &Path) -> Gitignore {
+    let mut gitignore_builder = GitignoreBuilder::new(folder);
+    if let Some(err) = gitignore_builder.add(folder.join(".gitignore")) {
+        println!("Error reading .gitignore in {:?}: {:?}", folder, err);
+    }
+    gitignore_builder
+        .build()
+        .unwrap_or_else(|_| Gitignore::empty())
+}
+
+fn create_watcher(
+    config: &DocumentIndexConfig,
+    change_sender: Sender<String>,
+) -> Option<RecommendedWatcher> {
+    let config_id = config.id.clone();
+    let kind = config.kind;
+    let folders = config.folders.clone();
+    let gitignores = match config.kind {
+        IndexKind::Documents | IndexKind::LocalDocumentation => vec![],
+        IndexKind::Repository => config
+            .folders
+            .iter()
+            .map(|folder| root_gitignore(folder))
+            .collect(),
+    };
+    let handle_event = move |event_result: notify::Result<Event>| match event_result {
+        Ok(event) if is_relevant_change(&event, kind, &folders, &gitignores) => {
+            let _ = change_sender.send(config_id.clone());
+        }
+        Ok(_) => {}
+        Err(err) => println!("Error watching folders: {:?}", err),
+    };
+    let mut watcher = recommended_watcher(handle_event)
+        .map_err(|err| println!("Error creating watcher for {}: {:?}", config.name, err))
+        .ok()?;
+    config.folders.iter().for_each(|folder| {
+        if let Err(err) = watcher.watch(folder, RecursiveMode::Recursive) {
+            println!("Error watching {:?}: {:?}", folder, err);
+        }
+    });
+    Some(watcher)
+}
+
+/*
+Incremental, so only files whose content changed are read or embedded again
+- Keywords updates run entirely on a thread
+- Embeddings updates read and save on a thread too, only the calls to Ollama are awaited on the main context
+*/
+fn update_index(config: DocumentIndexConfig, build_guard: BuildGuard) {
+    println!("Updating index {} after files changed", config.name);
+    match config.search_method {
+        SearchMethod::Keywords => {
+            std::thread::spawn(move || {
+                let build_result = KeywordIndex::update(&config, false, |_, _| {})
+                    .map(|(keyword_index, _)| {
+                        (
+                            keyword_index.files.len(),
+                            keyword_index.chunk_count(),
+                            vec![],
+                        )
+                    })
+                    .map_err(|err| err.to_string());
+                finish_update(&config, build_result, build_guard);
+            });
+        }
+        SearchMethod::Embeddings => {
+            glib::MainContext::default().spawn_local(async move {
+                let build_result = DocumentIndex::build(&config, false, |build_progress| {
+                    if let BuildProgress::Embedding {
+                        embedded_chunks,
+                        total_chunks,
+                    } = build_progress
+                    {
+                        if embedded_chunks == total_chunks {
+                            println!("Embedded {} changed chunks", total_chunks);
+                        }
+                    }
+                })
+                .await
+                .map(|document_index| {
+                    (
+                        document_index.files.len(),
+                        document_index.chunks.len(),
+                        document_index.failed_files,
+                    )
+                })
+                .map_err(|err| err.to_string());
+                finish_update(&config, build_result, build_guard);
+            });
+        }
+    }
+}
+
+fn finish_update(
+    config: &DocumentIndexConfig,
+    build_result: Result<(usize, usize, Vec<PathBuf>), String>,
+    build_guard: BuildGuard,
+) {
+    if let Err(err) = &build_result {
+        println!("Error updating index {}: {}", config.name, err);
+    }
+    if let Some(mut saved_config) = DocumentIndexConfig::find(&config.id) {
+        saved_config.record_build(build_result);
+    }
+    drop(build_guard);
+}
+
+/*
+Keeps built document indexes up to date while the app runs
+- Watches the folders of every built index that has watch_folders set
+- Rereads the index configs periodically so new, edited and deleted indexes are picked up
+- Changes are debounced per index, then the index gets an incremental update in the background
+- An index already building, here or in the index manager, is left alone, changes made meanwhile trigger another update after it
+*/
+pub fn start() {
+    let (change_sender, change_receiver): (Sender<String>, Receiver<String>) = mpsc::channel();
+    glib::MainContext::default().spawn_local(async move {
+        let mut watchers: HashMap<String, (Vec<PathBuf>, RecommendedWatcher)> = HashMap::new();
+        let mut configs: HashMap<String, DocumentIndexConfig> = HashMap::new();
+        let mut pending_changes: HashMap<String, Instant> = HashMap::new();
+        let mut last_config_refresh: Option<Instant> = None;
+        loop {
+            let needs_config_refresh = last_config_refresh
+                .map(|refreshed_at| refreshed_at.elapsed().as_secs() >= CONFIG_REFRESH_SECONDS)
+                .unwrap_or(true);
+            if needs_config_refresh {
+                configs = DocumentIndexConfig::list()
+                    .into_iter()
+                    .filter(|config| config.watch_folders && config.is_built())
+                    .map(|config| (config.id.clone(), config))
+                    .collect();
+                watchers.retain(|config_id, (folders, _)| {
+                    configs
+                        .get(config_id)
+                        .map(|config| config.folders == *folders)
+                        .unwrap_or(false)
+                });
+                for (config_id, config) in &configs {
+                    if watchers.contains_key(config_id) {
+                        continue;
+                    }
+                    if let Some(watcher) = create_watcher(config, change_sender.clone()) {
+                        watchers.insert(config_id.clone(), (config.folders.clone(), watcher));
+                    }
+                }
+                last_config_refresh = Some(Instant::now());
+            }
+
+            while let Ok(config_id) = change_receiver.try_recv() {
+                pending_changes.insert(config_id, Instant::now());
+            }
+            let settled_ids = pending_changes
+                .iter()
+                .filter(|(_, changed_at)| changed_at.elapsed().as_secs() >= DEBOUNCE_SECONDS)
+                .map(|(config_id, _)| config_id.clone())
+                .collect::<Vec<String>>();
+            for config_id in settled_ids {
+                let Some(config) = configs.get(&config_id) else {
+                    pending_changes.remove(&config_id);
+                    continue;
+                };
+                // Stays pending while the index builds, so the changes are picked up after
+                if let Some(build_guard) = config.start_build() {
+                    pending_changes.remove(&config_id);
+                    update_index(config.clone(), build_guard);
+                }
+            }
+            glib::timeout_future(time::Duration::from_millis(POLL_INTERVAL_MILLIS)).await;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_paths_below_the_watched_folder_count_as_hidden() {
+        let folders = vec![PathBuf::from("/home/user/.local/share/docs")];
+        assert!(!is_hidden(
+            Path::new("/home/user/.local/share/docs/guide.md"),
+            &folders
+        ));
+        assert!(is_hidden(
+            Path::new("/home/user/.local/share/docs/.guide.md.swp"),
+            &folders
+        ));
+        assert!(is_hidden(
+            Path::new("/home/user/.local/share/docs/.git/HEAD"),
+            &folders
+        ));
+    }
+}
@@ -0,0 +1,307 @@
+use rust_stemmers::{Algorithm, Stemmer};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fs::{self, File},
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use super::{
+    chunking::{self, TextChunk},
+    document_index::DocumentIndexConfig,
+};
+
+const BM25_K1: f32 = 1.2;
+const BM25_B: f32 = 0.75;
+const STOP_WORDS: [&str; 35] = [
+    "a", "an", "and", "are", "as", "at", "be", "but", "by", "do", "for", "from", "has", "have",
+    "how", "i", "if", "in", "is", "it", "of", "on", "or", "so", "that", "the", "this", "to", "was",
+    "what", "when", "where", "which", "with", "you",
+];
+
+// Lowercased, stop words dropped and stemmed so "indexing" matches "indexed"
+pub fn tokenise(text: &str) -> Vec<String> {
+    let stemmer = Stemmer::create(Algorithm::English);
+    text.split(|character: char| !character.is_alphanumeric())
+        .map(|word| word.to_lowercase())
+        .filter(|word| word.chars().count() > 1 && !STOP_WORDS.contains(&word.as_str()))
+        .map(|word| stemmer.stem(&word).into_owned())
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct KeywordChunk {
+    pub chunk: TextChunk,
+    pub length: usize,
+    pub term_frequencies: HashMap<String, u32>,
+}
+
+impl KeywordChunk {
+    fn new(chunk: TextChunk) -> Self {
+        let terms = tokenise(&chunk.text);
+        let mut term_frequencies = HashMap::new();
+        terms
+            .iter()
+            .for_each(|term| *term_frequencies.entry(term.clone()).or_insert(0) += 1);
+        Self {
+            chunk,
+            length: terms.len(),
+            term_frequencies,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexedFile {
+    pub modified_unix_secs: u64,
+    #[serde(default)]
+    pub content_hash: Option<String>,
+    pub chunks: Vec<KeywordChunk>,
+}
+
+/*
+Inverted index over the configured folders, ranked with BM25
+- Chunks are stored per file with the file's modified time, so reindexing only reads files that changed
+- A file with a new modified time but the same content hash keeps its chunks
+- The postings list from term to chunks is rebuilt in memory on load rather than saved
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct KeywordIndex {
+    pub files: BTreeMap<PathBuf, IndexedFile>,
+    #[serde(skip)]
+    postings: HashMap<String, Vec<(PathBuf, usize)>>,
+}
+
+impl KeywordIndex {
+    /*
+    Returns the index and how many files had to be read again, a full update rereads every file
+    - on_progress is called with the files checked so far and the total, from the calling thread
+    */
+    pub fn update(
+        config: &DocumentIndexConfig,
+        is_full_update: bool,
+        on_progress: impl Fn(usize, usize),
+    ) -> Result<(Self, usize), Box<dyn Error>> {
+        let mut keyword_index = if is_full_update {
+            Self::default()
+        } else {
+            Self::load(config).unwrap_or_default()
+        };
+        let current_files = config.collect_files();
+        keyword_index
+            .files
+            .retain(|file_path, _| current_files.contains(file_path));
+        let mut updated_files = 0;
+        let total_files = current_files.len();
+        for (file_index, file_path) in current_files.into_iter().enumerate() {
+            on_progress(file_index, total_files);
+            let modified_unix_secs = chunking::modified_unix_secs(&file_path);
+            let indexed_file = keyword_index.files.get_mut(&file_path);
+            if let Some(indexed_file) = &indexed_file {
+                if indexed_file.modified_unix_secs == modified_unix_secs {
+                    continue;
+                }
+            }
+            let content_hash = chunking::content_hash(&file_path);
+            if let Some(indexed_file) = indexed_file {
+                if content_hash.is_some() && indexed_file.content_hash == content_hash {
+                    indexed_file.modified_unix_secs = modified_unix_secs;
+                    continue;
+                }
+            }
+            let chunks = config
+                .chunk_file(&file_path)
+                .into_iter()
+                .map(KeywordChunk::new)
+                .collect();
+            keyword_index.files.insert(
+                file_path,
+                IndexedFile {
+                    modified_unix_secs,
+                    content_hash,
+                    chunks,
+                },
+            );
+            updated_files += 1;
+        }
+        keyword_index.save(config)?;
+        keyword_index.build_postings();
+        Ok((keyword_index, updated_files))
+    }
+
+    // An in-memory index for text that isn't in files, like saved conversations
+    pub fn from_chunks(chunks: Vec<TextChunk>) -> Self {
+        let mut keyword_index = Self::default();
+        for chunk in chunks {
+            keyword_index
+                .files
+                .entry(chunk.source.clone())
+                .or_insert_with(|| IndexedFile {
+                    modified_unix_secs: 0,
+                    content_hash: None,
+                    chunks: vec![],
+                })
+                .chunks
+                .push(KeywordChunk::new(chunk));
+        }
+        keyword_index.build_postings();
+        keyword_index
+    }
+
+    pub fn load(config: &DocumentIndexConfig) -> Result<Self, Box<dyn Error>> {
+        let mut index_file = File::open(config.keyword_index_path())?;
+        let mut json_data = String::new();
+        index_file.read_to_string(&mut json_data)?;
+        let mut keyword_index: Self = serde_json::from_str(&json_data)?;
+        keyword_index.build_postings();
+        Ok(keyword_index)
+    }
+
+    fn save(&self, config: &DocumentIndexConfig) -> Result<(), Box<dyn Error>> {
+        let index_path = config.keyword_index_path();
+        if let Some(parent) = index_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(index_path)?;
+        file.write_all(serde_json::to_string(&self)?.as_bytes())?;
+        Ok(())
+    }
+
+    fn build_postings(&mut self) {
+        self.postings.clear();
+        for (file_path, indexed_file) in &self.files {
+            for (chunk_index, keyword_chunk) in indexed_file.chunks.iter().enumerate() {
+                for term in keyword_chunk.term_frequencies.keys() {
+                    self.postings
+                        .entry(term.clone())
+                        .or_default()
+                        .push((file_path.clone(), chunk_index));
+                }
+            }
+        }
+    }
+
+    pub fn chunk_count(&self) -> usize {
+        self.files
+            .values()
+            .map(|indexed_file| indexed_file.chunks.len())
+            .sum()
+    }
+
+    fn chunk(&self, file_path: &PathBuf, chunk_index: usize) -> Option<&KeywordChunk> {
+        self.files.get(file_path)?.chunks.get(chunk_index)
+    }
+
+    pub fn search(&self, query: &str, top_k: usize) -> Vec<(f32, &TextChunk)> {
+        let chunk_count = self.chunk_count();
+        if chunk_count == 0 {
+            return vec![];
+        }
+        let average_length = self
+            .files
+            .values()
+            .flat_map(|indexed_file| &indexed_file.chunks)
+            .map(|keyword_chunk| keyword_chunk.length)
+            .sum::<usize>() as f32
+            / chunk_count as f32;
+
+        let mut scores: HashMap<(&PathBuf, usize), f32> = HashMap::new();
+        let mut query_terms = tokenise(query);
+        query_terms.sort();
+        query_terms.dedup();
+        for term in query_terms {
+            let Some(postings) = self.postings.get(&term) else {
+                continue;
+            };
+            let document_frequency = postings.len() as f32;
+            let inverse_document_frequency = ((chunk_count as f32 - document_frequency + 0.5)
+                / (document_frequency + 0.5)
+                + 1.0)
+                .ln();
+            for (file_path, chunk_index) in postings {
+                let Some(keyword_chunk) = self.chunk(file_path, *chunk_index) else {
+                    continue;
+                };
+                let term_frequency = keyword_chunk.term_frequencies[&term] as f32;
+                let length_norm =
+                    1.0 - BM25_B + BM25_B * keyword_chunk.length as f32 / average_length.max(1.0);
+                *scores.entry((file_path, *chunk_index)).or_insert(0.0) +=
+                    inverse_document_frequency * term_frequency * (BM25_K1 + 1.0)
+                        / (term_frequency + BM25_K1 * length_norm);
+            }
+        }
+
+        let mut scored_chunks = scores
+            .into_iter()
+            .collect::<Vec<((&PathBuf, usize), f32)>>();
+        scored_chunks.sort_by(|first, second| second.1.total_cmp(&first.1));
+        scored_chunks
+            .into_iter()
+            .take(top_k)
+            .filter_map(|((file_path, chunk_index), score)| {
+                self.chunk(file_path, chunk_index)
+                    .map(|keyword_chunk| (score, &keyword_chunk.chunk))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(source: &str, text: &str) -> TextChunk {
+        TextChunk {
+            source: PathBuf::from(source),
+            start_line: 1,
+            text: String::from(text),
+            end_line: None,
+            symbol: None,
+            title: None,
+        }
+    }
+
+    #[test]
+    fn tokenise_drops_stop_words_and_stems() {
+        assert_eq!(
+            tokenise("What is the Indexing of files?"),
+            vec!["index", "file"]
+        );
+        assert_eq!(tokenise("indexed"), tokenise("indexing"));
+    }
+
+    #[test]
+    fn search_ranks_matching_chunk_first() {
+        let keyword_index = KeywordIndex::from_chunks(vec![
+            chunk("a.md", "The garden has roses and tulips"),
+            chunk("b.md", "Rust compiles the borrow checker rules"),
+            chunk("c.md", "Cooking pasta needs salted water"),
+        ]);
+        let results = keyword_index.search("borrow checker", 3);
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].1.source, PathBuf::from("b.md"));
+        assert!(results[0].0 > 0.0);
+    }
+
+    #[test]
+    fn search_prefers_rarer_terms_and_respects_top_k() {
+        let keyword_index = KeywordIndex::from_chunks(vec![
+            chunk("a.md", "cache cache eviction"),
+            chunk("b.md", "cache layout"),
+            chunk("c.md", "cache warming"),
+        ]);
+        let results = keyword_index.search("cache eviction", 2);
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].1.source, PathBuf::from("a.md"));
+        assert!(results[0].0 > results[1].0);
+    }
+
+    #[test]
+    fn search_on_empty_index_finds_nothing() {
+        let keyword_index = KeywordIndex::from_chunks(vec![]);
+        assert!(keyword_index.search("anything", 5).is_empty());
+        assert_eq!(keyword_index.chunk_count(), 0);
+    }
+}
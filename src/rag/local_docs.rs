@@ -0,0 +1,491 @@
+use flate2::read::GzDecoder;
+use std::{
+    fs::{self, File},
+    io::Read,
+    path::{Path, PathBuf},
+};
+
+use crate::attachments::html::html_to_text;
+
+use super::chunking::{self, TextChunk};
+
+pub const DEFAULT_FOLDERS: [&str; 3] = ["/usr/share/man", "/usr/share/info", "/usr/share/doc"];
+// Licences and packaging history are in every package and would drown out the documentation
+const SKIPPED_DOC_PREFIXES: [&str; 4] = ["changelog", "copyright", "license", "copying"];
+const DOC_NAMES: [&str; 4] = ["readme", "news", "faq", "usage"];
+const DOC_EXTENSIONS: [&str; 5] = ["txt", "md", "html", "htm", "rst"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LocalDocKind {
+    Man,
+    Info,
+    Doc,
+}
+
+// Compressed pages end in .gz, the name without it tells what's inside
+fn uncompressed_name(file_path: &Path) -> String {
+    let file_name = file_path
+        .file_name()
+        .map(|file_name| file_name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    file_name
+        .strip_suffix(".gz")
+        .map(String::from)
+        .unwrap_or(file_name)
+}
+
+/*
+What a file under the documentation folders is, None for anything that isn't indexed
+- Man pages are only read from man/manN, translations in man/de/man1 and the like are skipped
+- Info files can be split into name.info-1, name.info-2 and so on
+*/
+fn local_doc_kind(file_path: &Path) -> Option<LocalDocKind> {
+    let file_name = uncompressed_name(file_path);
+    let parent_name = |levels: usize| {
+        file_path
+            .ancestors()
+            .nth(levels)
+            .and_then(Path::file_name)
+            .map(|folder_name| folder_name.to_string_lossy().to_string())
+            .unwrap_or_default()
+    };
+    if parent_name(1).starts_with("man") && parent_name(2) == "man" {
+        return Some(LocalDocKind::Man);
+    }
+    if parent_name(1) == "info" {
+        return (file_name.ends_with(".info") || file_name.contains(".info-"))
+            .then_some(LocalDocKind::Info);
+    }
+    let is_skipped = SKIPPED_DOC_PREFIXES
+        .iter()
+        .any(|prefix| file_name.starts_with(prefix));
+    let is_doc = DOC_NAMES.iter().any(|name| file_name.starts_with(name))
+        || DOC_EXTENSIONS
+            .iter()
+            .any(|extension| file_name.ends_with(&format!(".{}", extension)));
+    (!is_skipped && is_doc).then_some(LocalDocKind::Doc)
+}
+
+pub fn is_local_doc_file(file_path: &Path) -> bool {
+    local_doc_kind(file_path).is_some()
+}
+
+// Walks the documentation folders for man pages, info files and package docs
+pub fn collect_files(folders: &[PathBuf]) -> Vec<PathBuf> {
+    let mut files = vec![];
+    let mut folders_to_visit = folders.to_vec();
+    while let Some(folder) = folders_to_visit.pop() {
+        let Ok(read_dir) = fs::read_dir(&folder) else {
+            println!("Error reading folder: {:?}", folder);
+            continue;
+        };
+        for dir_entry in read_dir.filter_map(|dir_entry| dir_entry.ok()) {
+            let entry_path = dir_entry.path();
+            match dir_entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => folders_to_visit.push(entry_path),
+                Ok(file_type) if file_type.is_file() && local_doc_kind(&entry_path).is_some() => {
+                    let is_small_enough = dir_entry
+                        .metadata()
+                        .map(|metadata| metadata.len() <= chunking::MAX_FILE_SIZE_BYTES)
+                        .unwrap_or(false);
+                    if is_small_enough {
+                        files.push(entry_path);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+    files.sort();
+    files
+}
+
+fn read_text(file_path: &Path) -> Option<String> {
+    let mut file_bytes = vec![];
+    let read_result = match file_path.extension() {
+        Some(extension) if extension == "gz" => {
+            File::open(file_path).and_then(|file| GzDecoder::new(file).read_to_end(&mut file_bytes))
+        }
+        _ => File::open(file_path).and_then(|mut file| file.read_to_end(&mut file_bytes)),
+    };
+    match read_result {
+        Ok(_) => Some(String::from_utf8_lossy(&file_bytes).to_string()),
+        Err(err) => {
+            println!("Error reading {:?}: {:?}", file_path, err);
+            None
+        }
+    }
+}
+
+// Sections are chunked like any text, every chunk is titled with where it came from
+fn titled_chunks(file_path: &Path, title: &str, text: &str) -> Vec<TextChunk> {
+    chunking::chunk_text(file_path, text)
+        .into_iter()
+        .map(|mut chunk| {
+            // Lines of rendered pages don't match lines in the file, the title says where it is
+            chunk.start_line = 0;
+            chunk.title = Some(title.to_string());
+            chunk
+        })
+        .collect()
+}
+
+pub fn chunk_file(file_path: &Path) -> Vec<TextChunk> {
+    let Some(kind) = local_doc_kind(file_path) else {
+        return vec![];
+    };
+    let Some(text) = read_text(file_path) else {
+        return vec![];
+    };
+    match kind {
+        LocalDocKind::Man => man_page_chunks(file_path, &text),
+        LocalDocKind::Info => info_chunks(file_path, &text),
+        LocalDocKind::Doc => {
+            let package_name = file_path
+                .parent()
+                .and_then(Path::file_name)
+                .map(|package_name| package_name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let file_name = uncompressed_name(file_path);
+            let text = if file_name.ends_with(".html") || file_name.ends_with(".htm") {
+                html_to_text(text.as_bytes())
+            } else {
+                text
+            };
+            titled_chunks(
+                file_path,
+                &format!("{0} documentation, {1}", package_name, file_name),
+                &text,
+            )
+        }
+    }
+}
+
+// Splits a request line into its arguments, double quotes group words like roff does
+fn roff_arguments(arguments: &str) -> Vec<String> {
+    let mut parsed_arguments = vec![];
+    let mut current_argument = String::new();
+    let mut is_quoted = false;
+    for character in arguments.chars() {
+        match character {
+            '"' => is_quoted = !is_quoted,
+            ' ' | '\t' if !is_quoted => {
+                if !current_argument.is_empty() {
+                    parsed_arguments.push(std::mem::take(&mut current_argument));
+                }
+            }
+            _ => current_argument.push(character),
+        }
+    }
+    if !current_argument.is_empty() {
+        parsed_arguments.push(current_argument);
+    }
+    parsed_arguments
+}
+
+/*
+Plain text from a line of roff, font changes and size changes are dropped
+- Named characters like \(em and \(aq become what they print as
+- \" starts a comment, \c and \& print nothing
+*/
+fn unescape_roff(line: &str) -> String {
+    let mut text = String::new();
+    let mut characters = line.chars().peekable();
+    while let Some(character) = characters.next() {
+        if character != '\\' {
+            text.push(character);
+            continue;
+        }
+        match characters.next() {
+            Some('"') => break,
+            Some('f') => match characters.next() {
+                Some('(') => {
+                    characters.next();
+                    characters.next();
+                }
+                Some('[') => {
+                    for font_character in characters.by_ref() {
+                        if font_character == ']' {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            Some('s') => {
+                if matches!(characters.peek(), Some('+') | Some('-')) {
+                    characters.next();
+                }
+                while matches!(characters.peek(), Some(digit) if digit.is_ascii_digit()) {
+                    characters.next();
+                }
+            }
+            Some('(') => {
+                let name = characters.by_ref().take(2).collect::<String>();
+                text.push_str(match name.as_str() {
+                    "em" => "—",
+                    "en" => "–",
+                    "aq" | "cq" | "oq" => "'",
+                    "dq" | "lq" | "rq" => "\"",
+                    "bu" => "•",
+                    "co" => "©",
+                    "hy" | "mi" => "-",
+                    "ti" => "~",
+                    "ha" => "^",
+                    "rs" => "\\",
+                    _ => "",
+                });
+            }
+            Some('*') => match characters.next() {
+                Some('(') => {
+                    characters.next();
+                    characters.next();
+                }
+                Some('[') => {
+                    for string_character in characters.by_ref() {
+                        if string_character == ']' {
+                            break;
+                        }
+                    }
+                }
+                _ => {}
+            },
+            Some('-') => text.push('-'),
+            Some('e') | Some('\\') => text.push('\\'),
+            Some(' ') | Some('~') => text.push(' '),
+            Some('&') | Some('c') | Some('%') | Some('|') | Some('^') | Some(')') => {}
+            Some(other) => text.push(other),
+            None => {}
+        }
+    }
+    text
+}
+
+/*
+Renders a man page to text, split into its sections so each chunk can cite one
+- Handles the man macros most pages use and the common mdoc ones, other requests are dropped
+- Pages that only include another with .so are aliases and give no chunks
+*/
+fn man_page_chunks(file_path: &Path, roff: &str) -> Vec<TextChunk> {
+    let file_name = uncompressed_name(file_path);
+    // ls.1 or ls.1p, used until .TH or .Dt gives the page its proper name
+    let (mut page_name, mut page_section) = match file_name.rsplit_once('.') {
+        Some((page_name, page_section)) => (page_name.to_string(), page_section.to_string()),
+        None => (file_name.clone(), String::new()),
+    };
+    let mut sections: Vec<(String, String)> = vec![];
+    let mut section_heading = String::from("NAME");
+    let mut section_text = String::new();
+    let mut is_in_macro_definition = false;
+    let mut is_heading_next = false;
+
+    for line in roff.lines() {
+        if is_in_macro_definition {
+            is_in_macro_definition = line.trim() != "..";
+            continue;
+        }
+        let Some(request_line) = line.strip_prefix('.').or_else(|| line.strip_prefix('\'')) else {
+            let text_line = unescape_roff(line);
+            if is_heading_next {
+                section_heading = text_line.trim().to_string();
+                is_heading_next = false;
+            } else {
+                section_text.push_str(&text_line);
+                section_text.push('\n');
+            }
+            continue;
+        };
+        let (request, arguments) = request_line
+            .trim_start()
+            .split_once([' ', '\t'])
+            .unwrap_or((request_line.trim_start(), ""));
+        let arguments = roff_arguments(&unescape_roff(arguments));
+        match request {
+            "so" if sections.is_empty() && section_text.trim().is_empty() => return vec![],
+            "de" | "de1" | "am" | "ig" => is_in_macro_definition = true,
+            "TH" | "Dt" => {
+                if let Some(name) = arguments.first() {
+                    page_name = name.to_lowercase();
+                }
+                if let Some(section) = arguments.get(1) {
+                    page_section = section.to_lowercase();
+                }
+            }
+            "SH" | "Sh" => {
+                if !section_text.trim().is_empty() {
+                    sections.push((section_heading.clone(), std::mem::take(&mut section_text)));
+                }
+                section_text.clear();
+                if arguments.is_empty() {
+                    is_heading_next = true;
+                } else {
+                    section_heading = arguments.join(" ");
+                }
+            }
+            "SS" | "Ss" => section_text.push_str(&format!("\n{}\n", arguments.join(" "))),
+            "PP" | "LP" | "P" | "sp" | "Pp" | "TP" => section_text.push('\n'),
+            "br" => {}
+            "IP" => {
+                section_text.push('\n');
+                if let Some(tag) = arguments.first() {
+                    section_text.push_str(&format!("{}\n", tag));
+                }
+            }
+            // Alternating fonts print their arguments without spaces between them
+            "BR" | "RB" | "IR" | "RI" | "BI" | "IB" => {
+                section_text.push_str(&arguments.concat());
+                section_text.push('\n');
+            }
+            "B" | "I" | "SM" | "SB" | "Nm" | "Ar" | "Cm" | "Pa" | "Nd" | "Op" | "It" | "Xr"
+            | "Em" | "Sy" | "Li" | "Dl" | "Ev" | "Va" | "Fn" | "Ic" => {
+                section_text.push_str(&arguments.join(" "));
+                section_text.push('\n');
+            }
+            "Fl" => {
+                section_text.push_str(&format!("-{}", arguments.join(" -")));
+                section_text.push('\n');
+            }
+            _ => {}
+        }
+    }
+    if !section_text.trim().is_empty() {
+        sections.push((section_heading, section_text));
+    }
+
+    let page_title = if page_section.is_empty() {
+        page_name
+    } else {
+        format!("{0}({1})", page_name, page_section)
+    };
+    sections
+        .into_iter()
+        .flat_map(|(section_heading, section_text)| {
+            titled_chunks(
+                file_path,
+                &format!("{0}, {1}", page_title, section_heading),
+                &section_text,
+            )
+        })
+        .collect()
+}
+
+/*
+Splits an info file into its nodes, each node is cited by the manual and node name
+- Nodes start after a 0x1f byte with a line like "File: coreutils.info,  Node: ls invocation,  Next: ..."
+- Tag tables and indirect tables are indexes into the file, not documentation
+*/
+fn info_chunks(file_path: &Path, info_text: &str) -> Vec<TextChunk> {
+    info_text
+        .split('\u{1f}')
+        .filter_map(|node_text| {
+            let node_text = node_text.trim_start_matches('\n');
+            let (header_line, node_body) = node_text.split_once('\n')?;
+            let header_fields = header_line
+                .split(',')
+                .filter_map(|header_field| header_field.trim().split_once(':'))
+                .map(|(field_name, field_value)| (field_name.trim(), field_value.trim()))
+                .collect::<Vec<(&str, &str)>>();
+            let field = |name: &str| {
+                header_fields
+                    .iter()
+                    .find(|(field_name, _)| *field_name == name)
+                    .map(|(_, field_value)| *field_value)
+            };
+            let node_name = field("Node")?;
+            let manual_name = field("File")
+                .map(|info_file| info_file.trim_end_matches(".info"))
+                .unwrap_or("");
+            Some(titled_chunks(
+                file_path,
+                &format!("info {0}, {1}", manual_name, node_name),
+                node_body,
+            ))
+        })
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn titles(chunks: &[TextChunk]) -> Vec<String> {
+        chunks
+            .iter()
+            .map(|chunk| chunk.title.clone().unwrap_or_default())
+            .collect()
+    }
+
+    #[test]
+    fn roff_escapes_become_plain_text() {
+        assert_eq!(
+            unescape_roff(r"\fBls\fR \- list directory contents"),
+            "ls - list directory contents"
+        );
+        assert_eq!(
+            unescape_roff(r#"don\(aqt \(em ok\" a comment"#),
+            "don't — ok"
+        );
+        assert_eq!(
+            unescape_roff(r"\s-2small\s0 \f(CWcode\fP \e"),
+            r"small code \"
+        );
+        assert_eq!(unescape_roff(r"\f[B]bold\f[] a\&.b\c"), "bold a.b");
+    }
+
+    #[test]
+    fn man_pages_are_split_into_their_sections() {
+        let roff = r#".TH LS 1 "2024" "GNU coreutils"
+.de XX
+macro body that isn't printed
+..
+.SH NAME
+ls \- list directory contents
+.SH DESCRIPTION
+List information about the FILEs.
+.SS Sorting
+.B \-t
+sort by time
+.SH "SEE ALSO"
+.BR dir (1)
+"#;
+        let chunks = man_page_chunks(Path::new("/usr/share/man/man1/ls.1.gz"), roff);
+        assert_eq!(
+            titles(&chunks),
+            vec!["ls(1), NAME", "ls(1), DESCRIPTION", "ls(1), SEE ALSO"]
+        );
+        assert_eq!(chunks[0].text.trim(), "ls - list directory contents");
+        assert!(chunks[1].text.contains("\nSorting\n-t\nsort by time"));
+        assert_eq!(chunks[2].text.trim(), "dir(1)");
+        assert!(chunks
+            .iter()
+            .all(|chunk| chunk.start_line == 0 && !chunk.text.contains("macro body")));
+    }
+
+    #[test]
+    fn section_heading_can_be_on_the_next_line() {
+        let roff = ".TH TAR 1\n.SH\nSYNOPSIS\ntar [OPTION...]\n";
+        let chunks = man_page_chunks(Path::new("/usr/share/man/man1/tar.1"), roff);
+        assert_eq!(titles(&chunks), vec!["tar(1), SYNOPSIS"]);
+        assert_eq!(chunks[0].text.trim(), "tar [OPTION...]");
+    }
+
+    #[test]
+    fn man_page_aliases_give_no_chunks() {
+        let roff = ".so man1/gzip.1\n";
+        assert!(man_page_chunks(Path::new("/usr/share/man/man1/gunzip.1.gz"), roff).is_empty());
+    }
+
+    #[test]
+    fn info_files_are_split_at_node_boundaries() {
+        let info_text = "This is coreutils.info, produced by makeinfo.\n\n\u{1f}\nFile: coreutils.info,  Node: Top,  Next: ls invocation,  Up: (dir)\n\nGNU Coreutils\n\nThis manual documents version 9.\n\n\u{1f}\nFile: coreutils.info,  Node: ls invocation,  Prev: Top,  Up: (dir)\n\n'ls' lists information about files.\n\n\u{1f}\nTag Table:\nNode: Top\u{7f}100\nNode: ls invocation\u{7f}300\n\u{1f}\nEnd Tag Table\n";
+        let chunks = info_chunks(Path::new("/usr/share/info/coreutils.info.gz"), info_text);
+        assert_eq!(
+            titles(&chunks),
+            vec!["info coreutils, Top", "info coreutils, ls invocation"]
+        );
+        assert!(chunks[0].text.contains("This manual documents version 9."));
+        assert!(!chunks[0].text.contains("'ls' lists"));
+        assert_eq!(chunks[1].text.trim(), "'ls' lists information about files.");
+    }
+}
@@ -0,0 +1,573 @@
+use gtk::glib;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    env,
+    error::Error,
+    fs,
+    io::{self, Read, Write},
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process::{Child, Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::utils::get_root_folder;
+
+use super::{sandbox::Sandbox, RetrievedResult};
+
+pub const RAG_SOURCES_FOLDER: &str = "./rag_sources";
+const PROMPT_PLACEHOLDER: &str = "{prompt}";
+// Set to the prompt for every source, for scripts that would rather not read stdin
+const PROMPT_ENV_VAR: &str = "COMHRA_PROMPT";
+const DEFAULT_TIMEOUT_SECONDS: u64 = 30;
+const DEFAULT_MAX_OUTPUT_BYTES: usize = 1024 * 1024;
+// Only the end of stderr is shown, that's where the error usually is
+const MAX_STDERR_BYTES: usize = 64 * 1024;
+const STDERR_TAIL_CHARS: usize = 2000;
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+fn default_timeout_seconds() -> u64 {
+    DEFAULT_TIMEOUT_SECONDS
+}
+
+fn default_max_output_bytes() -> usize {
+    DEFAULT_MAX_OUTPUT_BYTES
+}
+
+// Reads up to max_bytes and drains the rest, so a chatty script can't stall on a full pipe
+fn read_capped(mut reader: impl Read, max_bytes: usize) -> (Vec<u8>, bool) {
+    let mut output = vec![];
+    let _ = reader
+        .by_ref()
+        .take(max_bytes as u64)
+        .read_to_end(&mut output);
+    let overflow_bytes = io::copy(&mut reader, &mut io::sink()).unwrap_or(0);
+    (output, overflow_bytes > 0)
+}
+
+// Symlinks are resolved so bwrap mounts the real folder, paths that don't exist yet are only made absolute
+fn absolute_path(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| {
+        env::current_dir()
+            .map(|current_dir| current_dir.join(path))
+            .unwrap_or_else(|_| path.to_path_buf())
+    })
+}
+
+// Stops at max_bytes and flags it if there's more, so the command can be killed instead of drained
+fn read_until_limit(
+    mut reader: impl Read,
+    max_bytes: usize,
+    is_over_limit: &AtomicBool,
+) -> Vec<u8> {
+    let mut output = vec![];
+    let _ = reader
+        .by_ref()
+        .take(max_bytes as u64)
+        .read_to_end(&mut output);
+    if reader.read(&mut [0u8]).unwrap_or(0) > 0 {
+        is_over_limit.store(true, Ordering::Relaxed);
+    }
+    output
+}
+
+fn stderr_tail(stderr: &[u8]) -> String {
+    let stderr = String::from_utf8_lossy(stderr);
+    let stderr = stderr.trim();
+    let skipped_chars = stderr.chars().count().saturating_sub(STDERR_TAIL_CHARS);
+    stderr.chars().skip(skipped_chars).collect()
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputMode {
+    // Whatever the command prints is the context
+    #[default]
+    Text,
+    // The command prints a JsonOutput, see below
+    Json,
+}
+
+/*
+What a source that uses output = "json" reads on stdin and prints on stdout
+- The request is {"version": 1, "query": "..."}, the query is also in COMHRA_PROMPT so simple scripts can ignore stdin
+- The response is {"results": [{"text": "...", "title": "...", "source": "path or URL", "score": 0.8}]}, only text is required
+*/
+#[derive(Serialize, Debug)]
+pub struct JsonRequest<'a> {
+    pub version: u32,
+    pub query: &'a str,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JsonOutput {
+    pub results: Vec<RetrievedResult>,
+}
+
+/*
+A RAG source declared in a TOML file in ./rag_sources
+- The command gets the prompt on stdin and in COMHRA_PROMPT, never in args where other users could see it with ps
+- Relative commands and working dirs are relative to the manifest's folder, so a source can ship its script next to it
+- Executables without a manifest are run as if they had one with just a command, see from_script
+- Commands run in the background and are killed when they time out, are cancelled or print more than max_output_bytes
+- sandbox is one of "none", "minimal" or "bubblewrap", see Sandbox
+
+name = "Project wiki"
+command = "python3"
+args = ["search_wiki.py", "--format", "json"]
+working_dir = "wiki"
+env = { WIKI_URL = "https://wiki.example.com" }
+timeout_seconds = 10
+output = "json"
+sandbox = "bubblewrap"
+allow_network = true
+*/
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RagManifest {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: u64,
+    #[serde(default)]
+    pub output: OutputMode,
+    #[serde(default = "default_max_output_bytes")]
+    pub max_output_bytes: usize,
+    #[serde(default)]
+    pub sandbox: Sandbox,
+    // Only used with bubblewrap, the other modes don't restrict the network
+    #[serde(default)]
+    pub allow_network: bool,
+    #[serde(skip)]
+    pub manifest_path: PathBuf,
+}
+
+impl RagManifest {
+    pub fn load(manifest_path: &Path) -> Result<Self, Box<dyn Error>> {
+        let mut manifest: Self = toml::from_str(&fs::read_to_string(manifest_path)?)?;
+        manifest.manifest_path = manifest_path.to_path_buf();
+        Ok(manifest)
+    }
+
+    pub fn from_script(script_path: &Path) -> Self {
+        let script_path = get_root_folder().join(script_path);
+        Self {
+            name: script_path
+                .file_stem()
+                .map(|file_stem| file_stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+            command: script_path.to_string_lossy().to_string(),
+            args: vec![],
+            working_dir: None,
+            env: BTreeMap::new(),
+            timeout_seconds: DEFAULT_TIMEOUT_SECONDS,
+            output: OutputMode::Text,
+            max_output_bytes: DEFAULT_MAX_OUTPUT_BYTES,
+            sandbox: Sandbox::None,
+            allow_network: false,
+            manifest_path: script_path,
+        }
+    }
+
+    pub fn is_bare_script(&self) -> bool {
+        self.manifest_path
+            .extension()
+            .map(|extension| extension != "toml")
+            .unwrap_or(true)
+    }
+
+    /*
+    Writes the manifest as TOML
+    - A bare script gets a new manifest named after it, which then hides the script from list()
+    - Commands are kept as they are, so relative ones still resolve against ./rag_sources
+    */
+    pub fn save(&mut self) -> Result<(), Box<dyn Error>> {
+        if self.is_bare_script() {
+            let file_stem = self
+                .name
+                .chars()
+                .map(|character| {
+                    if character.is_alphanumeric() {
+                        character
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>();
+            self.manifest_path = get_root_folder()
+                .join(RAG_SOURCES_FOLDER)
+                .join(format!("{}.toml", file_stem));
+        }
+        if let Some(parent) = self.manifest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.manifest_path, toml::to_string(&self)?)?;
+        Ok(())
+    }
+
+    // A script kept in ./rag_sources goes too, as without its manifest it would be listed on its own
+    pub fn delete(&self) -> Result<(), Box<dyn Error>> {
+        let command = self.resolve_command();
+        let rag_sources_folder = get_root_folder().join(RAG_SOURCES_FOLDER);
+        fs::remove_file(&self.manifest_path)?;
+        if !self.is_bare_script() && command.parent() == Some(rag_sources_folder.as_path()) {
+            fs::remove_file(command)?;
+        }
+        Ok(())
+    }
+
+    // Absolute, as the root folder is relative and the sandbox doesn't start where the app runs
+    fn manifest_folder(&self) -> PathBuf {
+        absolute_path(
+            &self
+                .manifest_path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default(),
+        )
+    }
+
+    // Bare names like "python3" are left for PATH to resolve
+    fn resolve_command(&self) -> PathBuf {
+        let command = PathBuf::from(&self.command);
+        if command.is_relative() && command.components().count() > 1 {
+            absolute_path(&self.manifest_folder().join(command))
+        } else if command.is_absolute() {
+            absolute_path(&command)
+        } else {
+            command
+        }
+    }
+
+    fn resolve_working_dir(&self) -> Option<PathBuf> {
+        self.working_dir
+            .as_ref()
+            .map(|working_dir| absolute_path(&self.manifest_folder().join(working_dir)))
+    }
+
+    // Manifests from before the prompt moved to stdin, these are refused rather than run without it
+    fn uses_prompt_placeholder(&self) -> bool {
+        self.args.iter().any(|arg| arg.contains(PROMPT_PLACEHOLDER))
+    }
+
+    // What the bubblewrap sandbox lets the command read, its own folder and the one it runs in
+    fn readable_dirs(&self) -> Vec<PathBuf> {
+        let mut readable_dirs = vec![self.manifest_folder()];
+        let command = self.resolve_command();
+        if command.is_absolute() {
+            if let Some(command_folder) = command.parent() {
+                readable_dirs.push(command_folder.to_path_buf());
+            }
+        }
+        if let Some(working_dir) = self.resolve_working_dir() {
+            readable_dirs.push(working_dir);
+        }
+        readable_dirs.sort();
+        readable_dirs.dedup();
+        readable_dirs
+    }
+
+    pub fn command(&self, prompt: &str) -> Command {
+        let mut command = self.sandbox.command(
+            &self.resolve_command(),
+            &self.readable_dirs(),
+            self.resolve_working_dir().as_deref(),
+            self.allow_network,
+        );
+        command
+            .args(&self.args)
+            .envs(&self.env)
+            .env(PROMPT_ENV_VAR, prompt)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        command
+    }
+
+    // The JSON request for stdin, plain text sources get the prompt as it is
+    pub fn stdin_request(&self, prompt: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(match self.output {
+            OutputMode::Text => prompt.as_bytes().to_vec(),
+            OutputMode::Json => serde_json::to_vec(&JsonRequest {
+                version: 1,
+                query: prompt,
+            })?,
+        })
+    }
+
+    pub fn parse_output(&self, stdout: &[u8]) -> Result<Vec<RetrievedResult>, Box<dyn Error>> {
+        match self.output {
+            OutputMode::Text => Ok(vec![RetrievedResult::from_text(String::from_utf8(
+                stdout.to_vec(),
+            )?)]),
+            OutputMode::Json => Ok(serde_json::from_slice::<JsonOutput>(stdout)
+                .map_err(|err| format!("{} didn't print valid JSON results: {}", self.name, err))?
+                .results),
+        }
+    }
+
+    fn stop(&self, child: &mut Child, reason: &str) -> Box<dyn Error> {
+        if let Err(err) = child.kill().and_then(|_| child.wait()) {
+            println!("Error stopping {}: {:?}", self.name, err);
+        }
+        format!("{0} {1}", self.name, reason).into()
+    }
+
+    /*
+    Runs the command without blocking the main loop
+    - Polls for exit so the timeout and cancel_flag are checked while it runs
+    - stdin is written on a thread, a script that doesn't read it could otherwise block on a full pipe
+    - stdout and stderr are read on threads, past max_output_bytes the command is killed and what was read is kept
+    - Errors carry the end of stderr so they can be shown with the message
+    */
+    pub async fn run(
+        &self,
+        prompt: &str,
+        cancel_flag: &AtomicBool,
+    ) -> Result<Vec<RetrievedResult>, Box<dyn Error>> {
+        if self.uses_prompt_placeholder() {
+            return Err(format!(
+                "{0} puts {1} in its arguments, read the prompt from stdin or {2} instead",
+                self.name, PROMPT_PLACEHOLDER, PROMPT_ENV_VAR
+            )
+            .into());
+        }
+        let stdin_request = self.stdin_request(prompt)?;
+        let mut child = self.command(prompt).spawn().map_err(|err| {
+            format!(
+                "{0} couldn't start {1}: {2}{3}",
+                self.name,
+                self.command,
+                err,
+                self.sandbox.spawn_error_hint()
+            )
+        })?;
+        if let Some(mut stdin) = child.stdin.take() {
+            // A script that doesn't read stdin closes it, which isn't an error
+            thread::spawn(move || {
+                let _ = stdin.write_all(&stdin_request);
+            });
+        }
+        let stdout = child.stdout.take().ok_or("No stdout from the RAG source")?;
+        let stderr = child.stderr.take().ok_or("No stderr from the RAG source")?;
+        let max_output_bytes = self.max_output_bytes;
+        let is_stdout_over_limit = Arc::new(AtomicBool::new(false));
+        let stdout_reader = {
+            let is_stdout_over_limit = Arc::clone(&is_stdout_over_limit);
+            thread::spawn(move || read_until_limit(stdout, max_output_bytes, &is_stdout_over_limit))
+        };
+        let stderr_reader = thread::spawn(move || read_capped(stderr, MAX_STDERR_BYTES));
+
+        // A script can exit while something it started still holds its output open
+        let started_at = Instant::now();
+        let mut exit_status = None;
+        while exit_status.is_none() || !stdout_reader.is_finished() || !stderr_reader.is_finished()
+        {
+            if exit_status.is_none() {
+                exit_status = child.try_wait()?;
+            }
+            if exit_status.is_none() && is_stdout_over_limit.load(Ordering::Relaxed) {
+                // Nothing more it prints is read, so it's stopped rather than left running to the timeout
+                child.kill()?;
+                exit_status = Some(child.wait()?);
+            }
+            if cancel_flag.load(Ordering::Relaxed) {
+                return Err(self.stop(&mut child, "was cancelled"));
+            }
+            if started_at.elapsed() > Duration::from_secs(self.timeout_seconds) {
+                return Err(self.stop(
+                    &mut child,
+                    &format!("timed out after {} seconds", self.timeout_seconds),
+                ));
+            }
+            glib::timeout_future(POLL_INTERVAL).await;
+        }
+        let exit_status = exit_status.ok_or("The RAG source has no exit status")?;
+        let stdout = stdout_reader.join().unwrap_or_default();
+        let (stderr, _) = stderr_reader.join().unwrap_or_default();
+        let is_stdout_truncated = is_stdout_over_limit.load(Ordering::Relaxed);
+        if !exit_status.success() && !is_stdout_truncated {
+            return Err(format!(
+                "{0} exited with {1}:\n{2}",
+                self.name,
+                exit_status,
+                stderr_tail(&stderr)
+            )
+            .into());
+        }
+        if is_stdout_truncated && self.output == OutputMode::Json {
+            return Err(format!(
+                "{0} printed more than max_output_bytes ({1}) of JSON",
+                self.name, self.max_output_bytes
+            )
+            .into());
+        }
+        if is_stdout_truncated {
+            println!(
+                "{0} printed more than {1} bytes, it was stopped and the rest left out",
+                self.name, self.max_output_bytes
+            );
+            return self.parse_output(String::from_utf8_lossy(&stdout).as_bytes());
+        }
+        self.parse_output(&stdout)
+            .map_err(|err| format!("{0}\n{1}", err, stderr_tail(&stderr)).trim().into())
+    }
+}
+
+// Manifests and bare executables in ./rag_sources, sorted by name
+pub fn list() -> Vec<RagManifest> {
+    let Ok(read_dir) = fs::read_dir(get_root_folder().join(RAG_SOURCES_FOLDER)) else {
+        return vec![];
+    };
+    let (manifest_paths, other_paths): (Vec<PathBuf>, Vec<PathBuf>) = read_dir
+        .filter_map(|dir_entry| dir_entry.ok())
+        .map(|dir_entry| dir_entry.path())
+        .filter(|file_path| file_path.is_file())
+        .partition(|file_path| {
+            file_path
+                .extension()
+                .map(|extension| extension == "toml")
+                .unwrap_or(false)
+        });
+    let mut manifests = manifest_paths
+        .iter()
+        .filter_map(|manifest_path| {
+            RagManifest::load(manifest_path)
+                .map_err(|err| println!("Error reading {:?}: {}", manifest_path, err))
+                .ok()
+        })
+        .collect::<Vec<RagManifest>>();
+    // Scripts a manifest runs are usually kept next to it, they shouldn't show up twice
+    let manifest_commands = manifests
+        .iter()
+        .map(|manifest| manifest.resolve_command())
+        .collect::<Vec<PathBuf>>();
+    other_paths
+        .iter()
+        .filter(|file_path| is_executable(file_path) && !manifest_commands.contains(file_path))
+        .for_each(|script_path| manifests.push(RagManifest::from_script(script_path)));
+    manifests.sort_by(|first, second| first.name.cmp(&second.name));
+    manifests
+}
+
+fn is_executable(file_path: &Path) -> bool {
+    fs::metadata(file_path)
+        .map(|metadata| metadata.permissions().mode() & 0o111 != 0)
+        .unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn minimal_manifest_gets_defaults() {
+        let manifest: RagManifest = toml::from_str(
+            r#"
+            name = "Notes"
+            command = "./search.sh"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(manifest.name, "Notes");
+        assert!(manifest.args.is_empty());
+        assert_eq!(manifest.working_dir, None);
+        assert_eq!(manifest.timeout_seconds, DEFAULT_TIMEOUT_SECONDS);
+        assert_eq!(manifest.max_output_bytes, DEFAULT_MAX_OUTPUT_BYTES);
+        assert_eq!(manifest.output, OutputMode::Text);
+        assert_eq!(manifest.sandbox, Sandbox::None);
+        assert!(!manifest.allow_network);
+    }
+
+    #[test]
+    fn full_manifest_is_parsed() {
+        let mut manifest: RagManifest = toml::from_str(
+            r#"
+            name = "Project wiki"
+            command = "python3"
+            args = ["search_wiki.py", "--format", "json"]
+            working_dir = "wiki"
+            env = { WIKI_URL = "https://wiki.example.com" }
+            timeout_seconds = 10
+            output = "json"
+            sandbox = "bubblewrap"
+            allow_network = true
+            "#,
+        )
+        .unwrap();
+        manifest.manifest_path = PathBuf::from("/sources/wiki.toml");
+        assert_eq!(manifest.args, vec!["search_wiki.py", "--format", "json"]);
+        assert_eq!(manifest.env["WIKI_URL"], "https://wiki.example.com");
+        assert_eq!(manifest.timeout_seconds, 10);
+        assert_eq!(manifest.output, OutputMode::Json);
+        assert_eq!(manifest.sandbox, Sandbox::Bubblewrap);
+        assert!(manifest.allow_network);
+        assert!(!manifest.is_bare_script());
+        assert!(!manifest.uses_prompt_placeholder());
+        assert_eq!(
+            manifest.readable_dirs(),
+            vec![PathBuf::from("/sources"), PathBuf::from("/sources/wiki")]
+        );
+    }
+
+    #[test]
+    fn unknown_output_mode_is_rejected() {
+        let manifest = toml::from_str::<RagManifest>(
+            r#"
+            name = "Notes"
+            command = "./search.sh"
+            output = "xml"
+            "#,
+        );
+        assert!(manifest.is_err());
+    }
+
+    #[test]
+    fn prompt_placeholder_in_args_is_detected() {
+        let manifest: RagManifest = toml::from_str(
+            r#"
+            name = "Old source"
+            command = "grep"
+            args = ["-r", "{prompt}"]
+            "#,
+        )
+        .unwrap();
+        assert!(manifest.uses_prompt_placeholder());
+    }
+
+    #[test]
+    fn json_output_is_parsed() {
+        let manifest: RagManifest = toml::from_str(
+            r#"
+            name = "Notes"
+            command = "./search.sh"
+            output = "json"
+            "#,
+        )
+        .unwrap();
+        let stdin_request: serde_json::Value =
+            serde_json::from_slice(&manifest.stdin_request("what's new").unwrap()).unwrap();
+        assert_eq!(
+            stdin_request,
+            serde_json::json!({"version": 1, "query": "what's new"})
+        );
+        let results = manifest
+            .parse_output(br#"{"results": [{"text": "Release notes", "score": 0.8}]}"#)
+            .unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!(results[0].text, "Release notes");
+        assert_eq!(results[0].score, Some(0.8));
+        assert!(manifest.parse_output(b"not json").is_err());
+    }
+}
@@ -0,0 +1,146 @@
+use gtk::glib;
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    path::Path,
+    sync::{atomic::AtomicBool, mpsc},
+    thread,
+    time::Duration,
+};
+
+use crate::RagSource;
+
+pub mod chunking;
+pub mod code_chunking;
+pub mod conversation_search;
+pub mod document_index;
+pub mod index_watcher;
+pub mod keyword_index;
+pub mod local_docs;
+pub mod manifest;
+pub mod pipeline;
+pub mod sandbox;
+
+const THREAD_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Runs blocking work like reading an index on a thread, polled so the window keeps responding
+pub async fn run_on_thread<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, String> + Send + 'static,
+) -> Result<T, Box<dyn Error>> {
+    let (result_sender, result_receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = result_sender.send(work());
+    });
+    loop {
+        match result_receiver.try_recv() {
+            Ok(result) => return Ok(result?),
+            Err(mpsc::TryRecvError::Empty) => glib::timeout_future(THREAD_POLL_INTERVAL).await,
+            Err(mpsc::TryRecvError::Disconnected) => {
+                return Err("The retrieval thread stopped unexpectedly".into())
+            }
+        }
+    }
+}
+
+// One piece of retrieved context, scripts using the JSON protocol can fill in everything but text
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct RetrievedResult {
+    pub text: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    // A file path or URL
+    #[serde(default)]
+    pub source: Option<String>,
+    #[serde(default)]
+    pub score: Option<f32>,
+    // Where in the source the text starts, pages are for PDFs
+    #[serde(default)]
+    pub page: Option<usize>,
+    #[serde(default)]
+    pub line: Option<usize>,
+    #[serde(default)]
+    pub end_line: Option<usize>,
+}
+
+impl RetrievedResult {
+    pub fn from_text(text: String) -> Self {
+        Self {
+            text,
+            title: None,
+            source: None,
+            score: None,
+            page: None,
+            line: None,
+            end_line: None,
+        }
+    }
+
+    pub fn is_url(&self) -> bool {
+        self.source
+            .as_ref()
+            .map(|source| source.starts_with("http://") || source.starts_with("https://"))
+            .unwrap_or(false)
+    }
+
+    // e.g. "notes.pdf, page 3", used in the prompt and the source list under answers
+    pub fn label(&self) -> String {
+        let name = self.title.clone().or_else(|| {
+            self.source.as_ref().map(|source| {
+                if self.is_url() {
+                    source.clone()
+                } else {
+                    Path::new(source)
+                        .file_name()
+                        .map(|file_name| file_name.to_string_lossy().to_string())
+                        .unwrap_or_else(|| source.clone())
+                }
+            })
+        });
+        let location = match (self.page, self.line) {
+            (Some(page), _) => Some(format!("page {}", page)),
+            (None, Some(line)) => match self.end_line {
+                Some(end_line) if end_line > line => Some(format!("lines {0}-{1}", line, end_line)),
+                _ => Some(format!("line {}", line)),
+            },
+            (None, None) => None,
+        };
+        match (name, location) {
+            (Some(name), Some(location)) => format!("{0}, {1}", name, location),
+            (Some(name), None) => name,
+            (None, _) => String::from("Retrieved text"),
+        }
+    }
+}
+
+// Numbered so the model can cite them with [n], matching the source list shown under the answer
+pub fn format_results(results: &[RetrievedResult]) -> String {
+    results
+        .iter()
+        .enumerate()
+        .map(|(result_index, result)| {
+            let mut header = format!("[{0}] {1}", result_index + 1, result.label());
+            if let Some(source) = &result.source {
+                header.push_str(&format!(" ({})", source));
+            }
+            format!("{0}\n{1}", header, result.text.trim())
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n---\n\n")
+}
+
+// The results for a query from any kind of source, used for prompts and the test query in Preferences
+pub async fn retrieve(
+    rag_source: &RagSource,
+    query: &str,
+    current_conversation: Option<&Path>,
+    cancel_flag: &AtomicBool,
+) -> Result<Vec<RetrievedResult>, Box<dyn Error>> {
+    match rag_source {
+        RagSource::NoRag => Ok(vec![]),
+        RagSource::Script(manifest) => manifest.run(query, cancel_flag).await,
+        RagSource::DocumentIndex(config) => document_index::retrieve(config, query).await,
+        RagSource::Conversations(config) => {
+            conversation_search::retrieve(config, query, current_conversation).await
+        }
+    }
+}
@@ -0,0 +1,275 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    path::Path,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+};
+
+use crate::{
+    models::{model_registry::ModelRegistry, prompt_template, Message, Role, SavedModel},
+    settings::Settings,
+    RagSource,
+};
+
+use super::RetrievedResult;
+
+// Enough of the conversation to resolve "it" or "the second one" without a long request
+const REWRITE_HISTORY_MESSAGES: usize = 6;
+const REWRITE_HISTORY_MESSAGE_CHARS: usize = 500;
+const RERANK_PASSAGE_CHARS: usize = 600;
+// Document indexes fetch this many times their top_k so re-ranking has something to choose from
+const RERANK_CANDIDATE_FACTOR: usize = 2;
+
+const REWRITE_INSTRUCTION: &str = "Rewrite the latest message into a standalone search query for a document search. Use the conversation to resolve references like \"it\" or \"the second one\". Reply with the query only, without quotes or explanation.";
+const RERANK_INSTRUCTION: &str = "Rank the passages by how well they answer the query. Reply with the passage numbers only, most relevant first, separated by commas.";
+
+// Optional steps around a source's retrieval, off unless turned on for that source in Preferences
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub struct RetrievalPipeline {
+    #[serde(default)]
+    pub rewrite_query: bool,
+    #[serde(default)]
+    pub rerank: bool,
+}
+
+impl RetrievalPipeline {
+    pub fn for_source(rag_source: &RagSource) -> Self {
+        Settings::load()
+            .rag_pipelines
+            .get(&rag_source.key())
+            .copied()
+            .unwrap_or_default()
+    }
+
+    pub fn save_for_source(&self, rag_source: &RagSource) {
+        let mut settings = Settings::load();
+        if *self == Self::default() {
+            settings.rag_pipelines.remove(&rag_source.key());
+        } else {
+            settings.rag_pipelines.insert(rag_source.key(), *self);
+        }
+        settings.save();
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.rewrite_query || self.rerank
+    }
+}
+
+pub struct PipelineResults {
+    pub results: Vec<RetrievedResult>,
+    // Only set when the query was rewritten into something other than the prompt
+    pub rewritten_query: Option<String>,
+}
+
+// The helper model from Settings, or the model answering the prompt when there's none
+pub fn helper_model(active_model_name: Option<&str>) -> Option<SavedModel> {
+    Settings::load()
+        .rag_helper_model
+        .or_else(|| active_model_name.and_then(ModelRegistry::find_cached))
+}
+
+fn user_message(content: String) -> Message {
+    Message {
+        role: Role::User,
+        content,
+        images: None,
+        model_name: None,
+        request_id: None,
+        original_content: None,
+        rag_context: None,
+        sources: None,
+        rag_error: None,
+        rag_query: None,
+        attachments: None,
+    }
+}
+
+// A one-off question to a model with no history, its streamed output isn't shown anywhere
+async fn complete(saved_model: &SavedModel, prompt: String) -> Result<String, Box<dyn Error>> {
+    let mut helper_model = saved_model.load_model(vec![])?;
+    let (list_sender, _list_receiver) = mpsc::channel();
+    helper_model
+        .ask(user_message(prompt), list_sender)
+        .await
+        .map_err(|err| err.to_string())?;
+    helper_model
+        .get_conversation()
+        .into_iter()
+        .rev()
+        .find(|chat_message| matches!(chat_message.role, Role::Assistant))
+        .map(|chat_message| chat_message.content)
+        .ok_or_else(|| format!("{} gave no answer", saved_model.display_name()).into())
+}
+
+fn truncate_chars(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        text.to_string()
+    } else {
+        format!("{}...", text.chars().take(max_chars).collect::<String>())
+    }
+}
+
+pub async fn rewrite_query(
+    saved_model: &SavedModel,
+    conversation: &[Message],
+    prompt: &str,
+) -> Result<String, Box<dyn Error>> {
+    let history = conversation
+        .iter()
+        .filter_map(|chat_message| match chat_message.role {
+            Role::User => Some(format!(
+                "User: {}",
+                prompt_template::unformat_prompt(chat_message)
+            )),
+            Role::Assistant => Some(format!("Assistant: {}", chat_message.content)),
+            Role::System => None,
+        })
+        .map(|history_line| truncate_chars(&history_line, REWRITE_HISTORY_MESSAGE_CHARS))
+        .collect::<Vec<String>>();
+    let recent_history = &history[history.len().saturating_sub(REWRITE_HISTORY_MESSAGES)..];
+    let rewrite_prompt = format!(
+        "{0}\n\nConversation:\n{1}\n\nLatest message:\n{2}",
+        REWRITE_INSTRUCTION,
+        recent_history.join("\n"),
+        prompt
+    );
+    let response = complete(saved_model, rewrite_prompt).await?;
+    // Small models sometimes explain themselves anyway, the query is the first line
+    let query = response
+        .lines()
+        .map(|line| {
+            line.trim()
+                .trim_matches(|character| character == '"' || character == '\'')
+        })
+        .find(|line| !line.is_empty())
+        .unwrap_or("")
+        .to_string();
+    if query.is_empty() {
+        Err("The rewritten query was empty".into())
+    } else {
+        Ok(query)
+    }
+}
+
+/*
+Orders the results by how relevant the model thinks they are
+- The model answers with passage numbers, anything it leaves out keeps its place after those it ranked
+- A passage number out of range or repeated is ignored
+*/
+pub async fn rerank(
+    saved_model: &SavedModel,
+    query: &str,
+    results: Vec<RetrievedResult>,
+) -> Result<Vec<RetrievedResult>, Box<dyn Error>> {
+    if results.len() < 2 {
+        return Ok(results);
+    }
+    let passages = results
+        .iter()
+        .enumerate()
+        .map(|(result_index, result)| {
+            format!(
+                "[{0}] {1}",
+                result_index + 1,
+                truncate_chars(result.text.trim(), RERANK_PASSAGE_CHARS)
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n\n");
+    let rerank_prompt = format!(
+        "{0}\n\nQuery: {1}\n\nPassages:\n{2}",
+        RERANK_INSTRUCTION, query, passages
+    );
+    let response = complete(saved_model, rerank_prompt).await?;
+    let mut ranked_indexes = vec![];
+    response
+        .split(|character: char| !character.is_ascii_digit())
+        .filter_map(|number| number.parse::<usize>().ok())
+        .filter(|passage_number| (1..=results.len()).contains(passage_number))
+        .for_each(|passage_number| {
+            if !ranked_indexes.contains(&(passage_number - 1)) {
+                ranked_indexes.push(passage_number - 1);
+            }
+        });
+    if ranked_indexes.is_empty() {
+        return Err("The ranking had no passage numbers".into());
+    }
+    (0..results.len()).for_each(|result_index| {
+        if !ranked_indexes.contains(&result_index) {
+            ranked_indexes.push(result_index);
+        }
+    });
+    Ok(ranked_indexes
+        .into_iter()
+        .map(|result_index| results[result_index].clone())
+        .collect())
+}
+
+/*
+Retrieves with the source's pipeline, see rag::retrieve for the retrieval itself
+- Rewriting turns the latest prompt into a standalone query using the conversation before it
+- current_conversation is the file the conversation is saved to, past conversation search skips it
+- Re-ranking orders the candidates, document indexes fetch extra candidates and keep their top_k
+- A step that fails is skipped and the raw prompt or original order is used, retrieval still runs
+*/
+pub async fn retrieve(
+    rag_source: &RagSource,
+    prompt: &str,
+    conversation: &[Message],
+    current_conversation: Option<&Path>,
+    active_model_name: Option<&str>,
+    cancel_flag: &AtomicBool,
+) -> Result<PipelineResults, Box<dyn Error>> {
+    let pipeline = RetrievalPipeline::for_source(rag_source);
+    let saved_model = if pipeline.is_enabled() {
+        let saved_model = helper_model(active_model_name);
+        if saved_model.is_none() {
+            println!("No model to rewrite or re-rank with, retrieving with the prompt");
+        }
+        saved_model
+    } else {
+        None
+    };
+
+    let mut rewritten_query = None;
+    if let (true, Some(saved_model)) = (pipeline.rewrite_query, &saved_model) {
+        match rewrite_query(saved_model, conversation, prompt).await {
+            Ok(query) if query != prompt.trim() => rewritten_query = Some(query),
+            Ok(_) => {}
+            Err(err) => println!("Error rewriting the query, using the prompt: {:?}", err),
+        }
+    }
+    if cancel_flag.load(Ordering::Relaxed) {
+        return Err("Retrieval was cancelled".into());
+    }
+    let query = rewritten_query.as_deref().unwrap_or(prompt);
+
+    let (candidate_source, kept_results) = match (pipeline.rerank, rag_source) {
+        (true, RagSource::DocumentIndex(config)) => {
+            let mut candidate_config = config.clone();
+            candidate_config.top_k = config.top_k * RERANK_CANDIDATE_FACTOR;
+            (RagSource::DocumentIndex(candidate_config), config.top_k)
+        }
+        _ => (rag_source.clone(), usize::MAX),
+    };
+    let mut results =
+        super::retrieve(&candidate_source, query, current_conversation, cancel_flag).await?;
+    if let (true, Some(saved_model)) = (pipeline.rerank, &saved_model) {
+        if cancel_flag.load(Ordering::Relaxed) {
+            return Err("Retrieval was cancelled".into());
+        }
+        match rerank(saved_model, query, results.clone()).await {
+            Ok(reranked_results) => results = reranked_results,
+            Err(err) => println!("Error re-ranking, keeping the original order: {:?}", err),
+        }
+    }
+    results.truncate(kept_results);
+    Ok(PipelineResults {
+        results,
+        rewritten_query,
+    })
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    env,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+const BUBBLEWRAP_COMMAND: &str = "bwrap";
+// All a script usually needs to find its tools and print UTF-8
+const MINIMAL_ENV_VARS: [&str; 4] = ["PATH", "HOME", "LANG", "LC_ALL"];
+// Programs, libraries and what they read from /etc to resolve names and check certificates
+const BUBBLEWRAP_SYSTEM_PATHS: [&str; 16] = [
+    "/usr",
+    "/bin",
+    "/sbin",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/etc/alternatives",
+    "/etc/ld.so.cache",
+    "/etc/ld.so.conf",
+    "/etc/localtime",
+    "/etc/resolv.conf",
+    "/etc/hosts",
+    "/etc/nsswitch.conf",
+    "/etc/ssl",
+    "/etc/ca-certificates",
+    "/etc/pki",
+];
+
+/*
+How much of the system a RAG source's command can reach
+- None runs it like any other program the user starts
+- Minimal clears the environment so tokens and keys in it aren't passed on
+- Bubblewrap also hides the rest of the filesystem, needs bwrap installed
+    - Only the system paths above and the source's own folders are mounted, read-only
+    - $HOME and /tmp are empty and private, so the credential vault and ~/.ssh aren't there
+    - No network unless the source allows it
+*/
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Sandbox {
+    #[default]
+    None,
+    Minimal,
+    Bubblewrap,
+}
+
+impl Sandbox {
+    /*
+    The command to spawn, wrapping the source's own command when it runs under bwrap
+    - Paths should be absolute, bwrap's own working directory isn't mounted inside the sandbox
+    - The working dir is set with --chdir under bwrap, as the sandbox starts in an empty $HOME
+    */
+    pub fn command(
+        &self,
+        program: &Path,
+        readable_dirs: &[PathBuf],
+        working_dir: Option<&Path>,
+        allow_network: bool,
+    ) -> Command {
+        let mut command = match self {
+            Sandbox::Bubblewrap => {
+                let mut command = Command::new(BUBBLEWRAP_COMMAND);
+                BUBBLEWRAP_SYSTEM_PATHS.iter().for_each(|system_path| {
+                    command.args(["--ro-bind-try", system_path, system_path]);
+                });
+                command.args(["--dev", "/dev", "--proc", "/proc", "--tmpfs", "/tmp"]);
+                if let Some(home_dir) = env::var_os("HOME") {
+                    command.arg("--tmpfs").arg(home_dir);
+                }
+                // After the tmpfs mounts, as the folders are usually somewhere under $HOME
+                readable_dirs.iter().for_each(|readable_dir| {
+                    command.arg("--ro-bind").arg(readable_dir).arg(readable_dir);
+                });
+                command.args(["--unshare-all", "--die-with-parent", "--new-session"]);
+                if allow_network {
+                    command.arg("--share-net");
+                }
+                if let Some(working_dir) = working_dir {
+                    command.arg("--chdir").arg(working_dir);
+                }
+                command.arg("--").arg(program);
+                command
+            }
+            _ => {
+                let mut command = Command::new(program);
+                if let Some(working_dir) = working_dir {
+                    command.current_dir(working_dir);
+                }
+                command
+            }
+        };
+        if *self != Sandbox::None {
+            command.env_clear();
+            MINIMAL_ENV_VARS.iter().for_each(|env_var| {
+                if let Ok(value) = env::var(env_var) {
+                    command.env(env_var, value);
+                }
+            });
+        }
+        command
+    }
+
+    pub fn spawn_error_hint(&self) -> &'static str {
+        match self {
+            Sandbox::Bubblewrap => ", is bubblewrap (bwrap) installed?",
+            _ => "",
+        }
+    }
+}
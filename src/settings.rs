@@ -0,0 +1,97 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{Read, Write},
+    path::PathBuf,
+};
+
+use crate::{
+    models::SavedModel, rag::pipeline::RetrievalPipeline, speech::WhisperModelSize,
+    utils::get_root_folder,
+};
+
+fn default_fallback_retry_attempts() -> u32 {
+    3
+}
+
+// Big enough for vision models to read text in a screenshot, small enough to keep requests light
+fn default_image_max_dimension() -> u32 {
+    1536
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Settings {
+    // Tried in order when the selected model fails, unless a conversation has its own list
+    #[serde(default)]
+    pub fallback_models: Vec<SavedModel>,
+    #[serde(default = "default_fallback_retry_attempts")]
+    pub fallback_retry_attempts: u32,
+    // Keyed by RagSource::key, sources without an entry retrieve with the raw prompt
+    #[serde(default)]
+    pub rag_pipelines: BTreeMap<String, RetrievalPipeline>,
+    // Rewrites queries and re-ranks results, the model answering the prompt does it when unset
+    #[serde(default)]
+    pub rag_helper_model: Option<SavedModel>,
+    // Attached images are scaled down so their longest side is at most this many pixels
+    #[serde(default = "default_image_max_dimension")]
+    pub image_max_dimension: u32,
+    // Used for voice input and audio attachments, once it's downloaded
+    #[serde(default)]
+    pub whisper_model_size: WhisperModelSize,
+    // Saves each request and answer for the request inspector, off as the records hold whole conversations
+    #[serde(default)]
+    pub record_requests: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            fallback_models: vec![],
+            fallback_retry_attempts: default_fallback_retry_attempts(),
+            rag_pipelines: BTreeMap::new(),
+            rag_helper_model: None,
+            image_max_dimension: default_image_max_dimension(),
+            whisper_model_size: WhisperModelSize::default(),
+            record_requests: false,
+        }
+    }
+}
+
+impl Settings {
+    fn file_path() -> PathBuf {
+        get_root_folder().join(PathBuf::from("./settings.json"))
+    }
+
+    pub fn load() -> Self {
+        let settings_file_path = Self::file_path();
+        if settings_file_path.exists() {
+            let mut settings_file = File::open(&settings_file_path).expect("Could not open file");
+
+            let mut json_data = String::new();
+            settings_file
+                .read_to_string(&mut json_data)
+                .expect("Failed to read data from file");
+
+            serde_json::from_str(&json_data).unwrap_or_else(|err| {
+                println!("Error reading settings, using defaults: {:?}", err);
+                Settings::default()
+            })
+        } else {
+            Settings::default()
+        }
+    }
+
+    pub fn save(&self) {
+        let settings_file_path = Self::file_path();
+        if let Some(parent) = settings_file_path.parent() {
+            fs::create_dir_all(parent).expect("Failed to create parent directories");
+        }
+        let serialised_settings =
+            serde_json::to_string(&self).expect("Error converting settings to JSON");
+        let mut file = File::create(settings_file_path).expect("Failed to create file");
+
+        file.write_all(serialised_settings.as_bytes())
+            .expect("Failed to write data to file");
+    }
+}
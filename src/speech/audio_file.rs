@@ -0,0 +1,68 @@
+use std::{error::Error, ffi::OsStr, fs::File, io::ErrorKind, path::PathBuf};
+use symphonia::core::{
+    audio::SampleBuffer, codecs::DecoderOptions, errors::Error as SymphoniaError,
+    formats::FormatOptions, io::MediaSourceStream, meta::MetadataOptions, probe::Hint,
+};
+
+use super::resample;
+
+/*
+Decodes a WAV, MP3 or OGG Vorbis file into mono samples at the rate whisper takes
+- Channels are averaged
+- A packet that fails to decode is skipped rather than failing the whole file
+*/
+pub fn read_samples(file_path: &PathBuf) -> Result<Vec<f32>, Box<dyn Error>> {
+    let media_source_stream =
+        MediaSourceStream::new(Box::new(File::open(file_path)?), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = file_path.extension().and_then(OsStr::to_str) {
+        hint.with_extension(extension);
+    }
+    let probed = symphonia::default::get_probe().format(
+        &hint,
+        media_source_stream,
+        &FormatOptions::default(),
+        &MetadataOptions::default(),
+    )?;
+    let mut format_reader = probed.format;
+    let track = format_reader
+        .default_track()
+        .ok_or("the file has no audio track")?;
+    let track_id = track.id;
+    let mut sample_rate = track.codec_params.sample_rate.unwrap_or(super::SAMPLE_RATE);
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+
+    let mut samples = vec![];
+    loop {
+        let packet = match format_reader.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded_buffer = match decoder.decode(&packet) {
+            Ok(decoded_buffer) => decoded_buffer,
+            Err(SymphoniaError::DecodeError(err)) => {
+                println!("Skipping undecodable audio in {:?}: {}", file_path, err);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let signal_spec = *decoded_buffer.spec();
+        sample_rate = signal_spec.rate;
+        let channel_count = signal_spec.channels.count().max(1);
+        let mut sample_buffer =
+            SampleBuffer::<f32>::new(decoded_buffer.capacity() as u64, signal_spec);
+        sample_buffer.copy_interleaved_ref(decoded_buffer);
+        samples.extend(
+            sample_buffer
+                .samples()
+                .chunks(channel_count)
+                .map(|frame| frame.iter().sum::<f32>() / channel_count as f32),
+        );
+    }
+    Ok(resample(&samples, sample_rate))
+}
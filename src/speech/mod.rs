@@ -0,0 +1,195 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs::{self, File},
+    io::Write,
+    path::PathBuf,
+    sync::Mutex,
+    thread,
+};
+use whisper_rs::{FullParams, SamplingStrategy, WhisperContext, WhisperContextParameters};
+
+use crate::{rag::chunking, settings::Settings, utils::get_root_folder};
+
+pub mod audio_file;
+pub mod recorder;
+
+// Whisper only takes mono audio at this rate, everything is resampled to it first
+pub const SAMPLE_RATE: u32 = 16000;
+
+const MODEL_DOWNLOAD_URL: &str = "https://huggingface.co/ggerganov/whisper.cpp/resolve/main";
+
+// Keyed by the audio file's content hash, so a file taken out of the tray and added again isn't done twice
+static TRANSCRIPTS: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+pub enum WhisperModelSize {
+    Tiny,
+    #[default]
+    Base,
+    Small,
+    Medium,
+}
+
+pub const WHISPER_MODEL_SIZES: [WhisperModelSize; 4] = [
+    WhisperModelSize::Tiny,
+    WhisperModelSize::Base,
+    WhisperModelSize::Small,
+    WhisperModelSize::Medium,
+];
+
+impl WhisperModelSize {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Tiny => "Tiny, 75 MB, fastest",
+            Self::Base => "Base, 142 MB",
+            Self::Small => "Small, 466 MB",
+            Self::Medium => "Medium, 1.5 GB, most accurate",
+        }
+    }
+
+    fn file_name(&self) -> &'static str {
+        match self {
+            Self::Tiny => "ggml-tiny.bin",
+            Self::Base => "ggml-base.bin",
+            Self::Small => "ggml-small.bin",
+            Self::Medium => "ggml-medium.bin",
+        }
+    }
+
+    pub fn file_path(&self) -> PathBuf {
+        get_root_folder().join("speech").join(self.file_name())
+    }
+
+    pub fn is_downloaded(&self) -> bool {
+        self.file_path().exists()
+    }
+
+    /*
+    Downloads the model once, this is the only time speech input uses the network
+    - Written to a .part file first, so a cancelled download never looks like a downloaded model
+    */
+    pub async fn download(
+        &self,
+        download_progress_bar: &gtk::ProgressBar,
+    ) -> Result<(), Box<dyn Error>> {
+        let model_path = self.file_path();
+        if let Some(parent) = model_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let partial_path = model_path.with_extension("bin.part");
+        let mut response = reqwest::get(format!("{0}/{1}", MODEL_DOWNLOAD_URL, self.file_name()))
+            .await?
+            .error_for_status()?;
+        let total_bytes = response.content_length();
+        let mut downloaded_bytes = 0;
+        let mut partial_file = File::create(&partial_path)?;
+        download_progress_bar.show();
+        while let Some(chunk) = response.chunk().await? {
+            partial_file.write_all(&chunk)?;
+            downloaded_bytes += chunk.len() as u64;
+            if let Some(total_bytes) = total_bytes {
+                let fraction = downloaded_bytes as f64 / total_bytes as f64;
+                download_progress_bar.set_fraction(fraction);
+                download_progress_bar.set_text(Some(&format!(
+                    "Downloading speech model: {0:.1}%",
+                    fraction * 100.0
+                )));
+            }
+        }
+        download_progress_bar.hide();
+        fs::rename(partial_path, model_path)?;
+        Ok(())
+    }
+
+    pub fn delete(&self) -> Result<(), Box<dyn Error>> {
+        fs::remove_file(self.file_path())?;
+        Ok(())
+    }
+}
+
+pub fn selected_model_size() -> WhisperModelSize {
+    Settings::load().whisper_model_size
+}
+
+/*
+Turns mono 16 kHz samples into text with the selected model, on the CPU
+- Slow for long audio with the bigger models, so call it off the main thread
+- The language is detected from the audio
+*/
+pub fn transcribe(samples: &[f32]) -> Result<String, Box<dyn Error>> {
+    let model_size = selected_model_size();
+    if !model_size.is_downloaded() {
+        return Err("no speech model is downloaded, download one in Preferences".into());
+    }
+    let model_path = model_size.file_path();
+    let whisper_context = WhisperContext::new_with_params(
+        &model_path.to_string_lossy(),
+        WhisperContextParameters::default(),
+    )
+    .map_err(|err| format!("the speech model couldn't be loaded ({:?})", err))?;
+    let mut whisper_state = whisper_context
+        .create_state()
+        .map_err(|err| format!("{:?}", err))?;
+    let mut full_params = FullParams::new(SamplingStrategy::Greedy { best_of: 1 });
+    full_params.set_language(Some("auto"));
+    full_params.set_n_threads(
+        thread::available_parallelism()
+            .map(|thread_count| thread_count.get() as i32)
+            .unwrap_or(4),
+    );
+    full_params.set_print_progress(false);
+    full_params.set_print_realtime(false);
+    full_params.set_print_special(false);
+    full_params.set_print_timestamps(false);
+    whisper_state
+        .full(full_params, samples)
+        .map_err(|err| format!("transcription failed ({:?})", err))?;
+    let segment_count = whisper_state
+        .full_n_segments()
+        .map_err(|err| format!("{:?}", err))?;
+    let transcript = (0..segment_count)
+        .filter_map(|segment_index| whisper_state.full_get_segment_text(segment_index).ok())
+        .collect::<Vec<String>>()
+        .join("");
+    Ok(transcript.trim().to_string())
+}
+
+pub fn transcribe_file(file_path: &PathBuf) -> Result<String, Box<dyn Error>> {
+    let content_hash = chunking::content_hash(file_path);
+    if let Some(content_hash) = &content_hash {
+        if let Some(transcript) = TRANSCRIPTS.lock().unwrap().get(content_hash) {
+            return Ok(transcript.clone());
+        }
+    }
+    let samples = audio_file::read_samples(file_path)?;
+    let transcript = transcribe(&samples)?;
+    if let Some(content_hash) = content_hash {
+        TRANSCRIPTS
+            .lock()
+            .unwrap()
+            .insert(content_hash, transcript.clone());
+    }
+    Ok(transcript)
+}
+
+// Linear interpolation, good enough for speech
+pub fn resample(samples: &[f32], sample_rate: u32) -> Vec<f32> {
+    if sample_rate == SAMPLE_RATE || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let step = sample_rate as f64 / SAMPLE_RATE as f64;
+    let resampled_length = (samples.len() as f64 / step) as usize;
+    (0..resampled_length)
+        .map(|resampled_index| {
+            let position = resampled_index as f64 * step;
+            let sample_index = position as usize;
+            let next_sample = samples
+                .get(sample_index + 1)
+                .unwrap_or(&samples[sample_index]);
+            let weight = (position - sample_index as f64) as f32;
+            samples[sample_index] * (1.0 - weight) + next_sample * weight
+        })
+        .collect()
+}
@@ -0,0 +1,122 @@
+use cpal::{
+    traits::{DeviceTrait, HostTrait, StreamTrait},
+    FromSample, SampleFormat, SizedSample, Stream, StreamConfig,
+};
+use std::{
+    error::Error,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+};
+
+use super::resample;
+
+/*
+Records from the default microphone until stopped
+- The stream lives on its own thread, as audio streams can't be moved between threads
+- Samples are kept in memory and only ever handed to the local model
+*/
+pub struct Recording {
+    stop_sender: Sender<()>,
+    samples_receiver: Receiver<Vec<f32>>,
+}
+
+impl Recording {
+    pub fn start() -> Result<Self, String> {
+        let (ready_sender, ready_receiver): (
+            Sender<Result<(), String>>,
+            Receiver<Result<(), String>>,
+        ) = mpsc::channel();
+        let (stop_sender, stop_receiver): (Sender<()>, Receiver<()>) = mpsc::channel();
+        let (samples_sender, samples_receiver): (Sender<Vec<f32>>, Receiver<Vec<f32>>) =
+            mpsc::channel();
+        std::thread::spawn(move || {
+            let recorded_samples = Arc::new(Mutex::new(vec![]));
+            let (stream, sample_rate) = match open_input_stream(Arc::clone(&recorded_samples)) {
+                Ok(input_stream) => input_stream,
+                Err(err) => {
+                    let _ = ready_sender.send(Err(err.to_string()));
+                    return;
+                }
+            };
+            let _ = ready_sender.send(Ok(()));
+            // Dropping the sender stops the recording too, e.g. if the window closes
+            let _ = stop_receiver.recv();
+            drop(stream);
+            let recorded_samples = recorded_samples.lock().unwrap().clone();
+            let _ = samples_sender.send(resample(&recorded_samples, sample_rate));
+        });
+        ready_receiver
+            .recv()
+            .map_err(|_| "The recording thread stopped unexpectedly".to_string())??;
+        Ok(Self {
+            stop_sender,
+            samples_receiver,
+        })
+    }
+
+    // Mono samples at the rate whisper takes
+    pub fn stop(self) -> Result<Vec<f32>, String> {
+        let _ = self.stop_sender.send(());
+        self.samples_receiver
+            .recv()
+            .map_err(|_| "The recording thread stopped unexpectedly".to_string())
+    }
+}
+
+fn open_input_stream(
+    recorded_samples: Arc<Mutex<Vec<f32>>>,
+) -> Result<(Stream, u32), Box<dyn Error>> {
+    let input_device = cpal::default_host()
+        .default_input_device()
+        .ok_or("No microphone was found")?;
+    let supported_config = input_device.default_input_config()?;
+    let sample_rate = supported_config.sample_rate().0;
+    let sample_format = supported_config.sample_format();
+    let stream_config: StreamConfig = supported_config.into();
+    let stream = match sample_format {
+        SampleFormat::F32 => build_stream::<f32>(&input_device, &stream_config, recorded_samples)?,
+        SampleFormat::I16 => build_stream::<i16>(&input_device, &stream_config, recorded_samples)?,
+        SampleFormat::U16 => build_stream::<u16>(&input_device, &stream_config, recorded_samples)?,
+        SampleFormat::I32 => build_stream::<i32>(&input_device, &stream_config, recorded_samples)?,
+        other_format => {
+            return Err(format!(
+                "The microphone's sample format {:?} isn't supported",
+                other_format
+            )
+            .into())
+        }
+    };
+    stream.play()?;
+    Ok((stream, sample_rate))
+}
+
+// Channels are averaged into one as they arrive
+fn build_stream<T>(
+    input_device: &cpal::Device,
+    stream_config: &StreamConfig,
+    recorded_samples: Arc<Mutex<Vec<f32>>>,
+) -> Result<Stream, Box<dyn Error>>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channel_count = (stream_config.channels as usize).max(1);
+    let stream = input_device.build_input_stream(
+        stream_config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            let mut recorded_samples = recorded_samples.lock().unwrap();
+            data.chunks(channel_count).for_each(|frame| {
+                let frame_sum = frame
+                    .iter()
+                    .map(|sample| f32::from_sample(*sample))
+                    .sum::<f32>();
+                recorded_samples.push(frame_sum / channel_count as f32);
+            });
+        },
+        |err| println!("Error recording audio: {:?}", err),
+        None,
+    )?;
+    Ok(stream)
+}
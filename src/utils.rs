@@ -1,13 +1,13 @@
 use base64::prelude::*;
-use std::{
-    env,
-    fs::{self, File},
-    io::Read,
-    path::PathBuf,
-    process::Command,
+use image::{
+    codecs::jpeg::JpegEncoder, imageops::FilterType, DynamicImage, ImageDecoder, ImageFormat,
+    ImageReader,
 };
+use std::{env, error::Error, fs, io::Cursor, path::PathBuf};
 use uuid::Uuid;
 
+use crate::attachments::pdf::PdfDocument;
+
 pub fn generate_unique_filename(extension: &str) -> PathBuf {
     let uuid = Uuid::new_v4();
 
@@ -44,25 +44,41 @@ pub fn get_filenames_from_folder(folder: PathBuf) -> Vec<PathBuf> {
     }
 }
 
-pub fn run_bash_search_script(script: PathBuf, prompt: &str) -> String {
-    let script = get_root_folder().join(script);
-    let output = Command::new(script)
-        .arg(prompt)
-        .output()
-        .expect("Failed to execute command")
-        .stdout;
-    String::from_utf8(output).unwrap_or(String::from("error converting output to string"))
-}
-
+// Every page with page markers, empty when the PDF can't be read
 pub fn pdf_to_string(file_path: &PathBuf) -> String {
-    String::from("")
+    match PdfDocument::open(file_path) {
+        Ok(pdf_document) => pdf_document.to_prompt_text(None, usize::MAX),
+        Err(err) => {
+            println!("Error reading PDF {:?}: {:?}", file_path, err);
+            String::new()
+        }
+    }
 }
 
-pub fn image_to_b64(file_path: &PathBuf) -> String {
-    let mut image_file = File::open(file_path).expect("Failed to open file");
-    let mut buffer = Vec::new();
-    image_file
-        .read_to_end(&mut buffer)
-        .expect("Failed to read file");
-    BASE64_STANDARD.encode(buffer)
+const JPEG_QUALITY: u8 = 90;
+
+/*
+Reads an image into the base64 that's sent to a model
+- Turned upright using its EXIF orientation, as models ignore EXIF
+- Scaled down so its longest side is at most max_dimension, smaller images keep their size
+- Always re-encoded, PNG when it has transparency and JPEG otherwise, which every backend reads
+*/
+pub fn image_to_b64(file_path: &PathBuf, max_dimension: u32) -> Result<String, Box<dyn Error>> {
+    let mut image_decoder = ImageReader::open(file_path)?
+        .with_guessed_format()?
+        .into_decoder()?;
+    let orientation = image_decoder.orientation()?;
+    let mut image = DynamicImage::from_decoder(image_decoder)?;
+    image.apply_orientation(orientation);
+    if image.width().max(image.height()) > max_dimension {
+        image = image.resize(max_dimension, max_dimension, FilterType::Lanczos3);
+    }
+    let mut buffer = Cursor::new(Vec::new());
+    if image.color().has_alpha() {
+        image.write_to(&mut buffer, ImageFormat::Png)?;
+    } else {
+        let jpeg_encoder = JpegEncoder::new_with_quality(&mut buffer, JPEG_QUALITY);
+        image.to_rgb8().write_with_encoder(jpeg_encoder)?;
+    }
+    Ok(BASE64_STANDARD.encode(buffer.into_inner()))
 }
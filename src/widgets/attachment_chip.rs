@@ -0,0 +1,77 @@
+use adw::prelude::*;
+
+use gtk::{gio, glib};
+
+use crate::attachments::Attachment;
+
+/*
+- Icon for the file's type
+- File name, ellipsized in the middle so the extension stays visible
+- Detail label with the size, or what went wrong reading the file
+- Optional remove button, only shown while the file is waiting to be sent
+*/
+#[derive(Clone)]
+pub struct AttachmentChip {
+    pub main_box: gtk::Box,
+    detail_label: gtk::Label,
+    pub remove_button: gtk::Button,
+}
+
+impl AttachmentChip {
+    pub fn new(attachment: &Attachment, is_removable: bool) -> Self {
+        let icon = gio::content_type_get_symbolic_icon(&attachment.content_type);
+        let type_image = gtk::Image::from_gicon(&icon);
+        let name_label = gtk::Label::builder()
+            .label(&attachment.file_name)
+            .tooltip_text(attachment.file_path.to_string_lossy().as_ref())
+            .ellipsize(gtk::pango::EllipsizeMode::Middle)
+            .max_width_chars(24)
+            .build();
+        let detail_label = gtk::Label::builder()
+            .label(glib::format_size(attachment.size_bytes).as_str())
+            .css_classes(["dim-label", "caption"])
+            .build();
+        let remove_button = gtk::Button::builder()
+            .icon_name("window-close-symbolic")
+            .tooltip_text("Remove attachment")
+            .css_classes(["flat", "circular"])
+            .visible(is_removable)
+            .build();
+        let main_box = gtk::Box::builder()
+            .orientation(gtk::Orientation::Horizontal)
+            .spacing(6)
+            .css_classes(["attachment-chip"])
+            .build();
+        main_box.append(&type_image);
+        main_box.append(&name_label);
+        main_box.append(&detail_label);
+        main_box.append(&remove_button);
+
+        let attachment_chip = Self {
+            main_box,
+            detail_label,
+            remove_button,
+        };
+        if let Some(err) = &attachment.error {
+            attachment_chip.show_warning("couldn't be read", err);
+        }
+        attachment_chip
+    }
+
+    // Placed after the detail label, e.g. the pages to send from a PDF
+    pub fn add_entry(&self, entry: &gtk::Entry) {
+        self.main_box
+            .insert_child_after(entry, Some(&self.detail_label));
+    }
+
+    pub fn show_detail(&self, detail: &str) {
+        self.detail_label.set_text(detail);
+    }
+
+    pub fn show_warning(&self, detail: &str, explanation: &str) {
+        self.detail_label.set_text(detail);
+        self.detail_label.remove_css_class("dim-label");
+        self.detail_label.add_css_class("error");
+        self.main_box.set_tooltip_text(Some(explanation));
+    }
+}
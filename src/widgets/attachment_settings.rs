@@ -0,0 +1,59 @@
+use adw::prelude::*;
+
+use crate::settings::Settings;
+
+const MIN_IMAGE_DIMENSION: f64 = 256.0;
+const MAX_IMAGE_DIMENSION: f64 = 8192.0;
+
+/*
+- Spin button for the longest side attached images are scaled down to
+- Saved to Settings as soon as it changes, images already sent keep the size they were sent at
+*/
+pub struct AttachmentSettingsWidget {
+    pub main_box: gtk::Box,
+}
+
+impl AttachmentSettingsWidget {
+    pub fn new() -> Self {
+        let main_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        let info_label = gtk::Label::builder()
+            .label("Images are turned upright and scaled down before they're sent, smaller images use less of the model's context.")
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        let image_size_box = gtk::Box::builder()
+            .spacing(4)
+            .orientation(gtk::Orientation::Horizontal)
+            .build();
+        let image_size_label = gtk::Label::builder()
+            .label("Largest image side in pixels")
+            .hexpand(true)
+            .xalign(0.0)
+            .build();
+        let image_size_spin_button =
+            gtk::SpinButton::with_range(MIN_IMAGE_DIMENSION, MAX_IMAGE_DIMENSION, 64.0);
+        image_size_spin_button.set_value(Settings::load().image_max_dimension as f64);
+        image_size_box.append(&image_size_label);
+        image_size_box.append(&image_size_spin_button);
+
+        main_box.append(&info_label);
+        main_box.append(&image_size_box);
+
+        image_size_spin_button.connect_value_changed(|image_size_spin_button| {
+            let mut settings = Settings::load();
+            settings.image_max_dimension = image_size_spin_button.value() as u32;
+            settings.save();
+        });
+
+        Self { main_box }
+    }
+}
+
+impl Default for AttachmentSettingsWidget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,25 +1,60 @@
 use adw::prelude::*;
 
-use crate::models::Message;
+use crate::{
+    attachments::Attachment,
+    models::{request_inspector::RequestRecord, B64Image, Message},
+    rag::{conversation_search, RetrievedResult},
+};
 use arboard::Clipboard;
+use gtk::{gdk, glib};
+use std::{
+    path::PathBuf,
+    sync::{mpsc::Sender, Arc, Mutex},
+};
+
+use super::{attachment_chip::AttachmentChip, request_inspector::RequestInspectorWidget};
+
+const CITATION_TAG: &str = "citation";
+const SNIPPET_CHARS: usize = 160;
+const THUMBNAIL_SIZE: i32 = 160;
+const IMAGE_VIEWER_MAX_SIZE: i32 = 900;
 
 /*
 - Editable field/label for text
 - Label for user/assistant
-- Button to show rag content
-- Collapsible label for rag content
+- Chips for the files attached to a prompt, clicking one opens the file if it's still there
+- Thumbnails of the images sent with a prompt, clicking one shows it full size
+- Label for the model that answered
+- Button to show rag content, or why retrieval failed
+- Collapsible label for rag content, with the query it was retrieved with when the prompt was rewritten
 - Button for copy
 - Button for edit
 - Button for regenerate
+- Button to inspect the raw request behind an answer
+- List of sources under answers that used retrieved context, [n] citations in the text link to them
+- Sources from past conversations open in the app rather than in another program
 */
 pub struct ChatMessageListItem {
     pub main_box: gtk::Box,
     pub content_textbox: gtk::TextView,
     role_label: gtk::Label,
+    model_label: gtk::Label,
+    inspect_button: gtk::Button,
+    request_id: Arc<Mutex<Option<String>>>,
+    rag_button: gtk::ToggleButton,
+    rag_content_label: gtk::Label,
+    attachments_box: gtk::FlowBox,
+    images_box: gtk::FlowBox,
+    sources_box: gtk::Box,
+    sources: Arc<Mutex<Vec<RetrievedResult>>>,
+    conversation_file_option_sender: Sender<Option<PathBuf>>,
 }
 
 impl ChatMessageListItem {
-    pub fn new(chat_message_option: Option<Message>) -> Self {
+    pub fn new(
+        chat_message_option: Option<Message>,
+        conversation_file_option_sender: Sender<Option<PathBuf>>,
+    ) -> Self {
         let chat_message_box = gtk::Box::builder()
             .orientation(gtk::Orientation::Horizontal)
             .spacing(10)
@@ -38,13 +73,51 @@ impl ChatMessageListItem {
             .halign(gtk::Align::Center)
             .width_chars(12)
             .build();
+        let chat_model_label = gtk::Label::builder()
+            .label("")
+            .halign(gtk::Align::Center)
+            .width_chars(12)
+            .max_width_chars(12)
+            .ellipsize(gtk::pango::EllipsizeMode::End)
+            .css_classes(["dim-label", "caption"])
+            .visible(false)
+            .build();
+        let inspect_button = gtk::Button::builder()
+            .icon_name("dialog-information-symbolic")
+            .tooltip_text("Inspect request")
+            .visible(false)
+            .build();
+        let request_id: Arc<Mutex<Option<String>>> = Arc::new(Mutex::new(None));
+        let rag_button = gtk::ToggleButton::builder()
+            .icon_name("edit-find-symbolic")
+            .tooltip_text("Show retrieved context")
+            .visible(false)
+            .build();
         let chat_message_side_box = gtk::Box::builder()
             .orientation(gtk::Orientation::Vertical)
             .spacing(5)
             .build();
         chat_message_side_box.append(&chat_role_label);
+        chat_message_side_box.append(&chat_model_label);
         chat_message_side_box.append(&edit_button);
         chat_message_side_box.append(&copy_button);
+        chat_message_side_box.append(&rag_button);
+        chat_message_side_box.append(&inspect_button);
+
+        {
+            let request_id = Arc::clone(&request_id);
+            inspect_button.connect_clicked(move |_| {
+                let Some(request_id) = request_id.lock().unwrap().clone() else {
+                    return;
+                };
+                match RequestRecord::load(&request_id) {
+                    Some(request_record) => {
+                        RequestInspectorWidget::new(request_record).dialog.present()
+                    }
+                    None => println!("No request record found for {}", request_id),
+                }
+            });
+        }
 
         let chat_content_buffer = gtk::TextBuffer::builder()
             .enable_undo(true)
@@ -57,6 +130,19 @@ impl ChatMessageListItem {
             .hexpand(true)
             .vexpand(true)
             .build();
+        let sources: Arc<Mutex<Vec<RetrievedResult>>> = Arc::new(Mutex::new(vec![]));
+        chat_content_buffer.create_tag(
+            Some(CITATION_TAG),
+            &[
+                ("foreground", &"#3584e4"),
+                ("underline", &gtk::pango::Underline::Single),
+            ],
+        );
+        Self::connect_citation_clicks(
+            &chat_content_textbox,
+            Arc::clone(&sources),
+            conversation_file_option_sender.clone(),
+        );
 
         copy_button.connect_clicked(move |_| {
             let mut clipboard = Clipboard::new().unwrap();
@@ -73,12 +159,65 @@ impl ChatMessageListItem {
                 .unwrap();
         });
 
+        let rag_content_label = gtk::Label::builder()
+            .selectable(true)
+            .wrap(true)
+            .xalign(0.0)
+            .css_classes(["dim-label"])
+            .build();
+        let rag_content_revealer = gtk::Revealer::builder()
+            .transition_type(gtk::RevealerTransitionType::SlideDown)
+            .child(&rag_content_label)
+            .build();
+        rag_button
+            .bind_property("active", &rag_content_revealer, "reveal-child")
+            .build();
+        let chat_content_box = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(5)
+            .hexpand(true)
+            .build();
+        let sources_box = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(2)
+            .visible(false)
+            .build();
+        let attachments_box = gtk::FlowBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .column_spacing(4)
+            .row_spacing(4)
+            .max_children_per_line(4)
+            .visible(false)
+            .build();
+        let images_box = gtk::FlowBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .column_spacing(4)
+            .row_spacing(4)
+            .max_children_per_line(4)
+            .visible(false)
+            .build();
+        chat_content_box.append(&attachments_box);
+        chat_content_box.append(&images_box);
+        chat_content_box.append(&chat_content_textbox);
+        chat_content_box.append(&sources_box);
+        chat_content_box.append(&rag_content_revealer);
+
         chat_message_box.append(&chat_message_side_box);
-        chat_message_box.append(&chat_content_textbox);
+        chat_message_box.append(&chat_content_box);
         let mut chat_message_list_item = Self {
             main_box: chat_message_box,
             content_textbox: chat_content_textbox,
             role_label: chat_role_label,
+            model_label: chat_model_label,
+            inspect_button,
+            request_id,
+            rag_button,
+            rag_content_label,
+            attachments_box,
+            images_box,
+            sources_box,
+            sources,
+            conversation_file_option_sender,
         };
         if let Some(chat_message) = chat_message_option {
             chat_message_list_item.update_message(chat_message);
@@ -87,6 +226,33 @@ impl ChatMessageListItem {
     }
 
     pub fn update_message(&mut self, chat_message: Message) {
+        if let Some(model_name) = &chat_message.model_name {
+            self.model_label.set_text(model_name);
+            self.model_label.set_tooltip_text(Some(model_name));
+            self.model_label.show();
+        }
+        if let Some(request_id) = &chat_message.request_id {
+            *self.request_id.lock().unwrap() = Some(request_id.clone());
+            self.inspect_button.show();
+        }
+        if let Some(rag_context) = &chat_message.rag_context {
+            let rag_details = match &chat_message.rag_query {
+                Some(rag_query) => {
+                    format!("Searched for: {0}\n\n{1}", rag_query, rag_context.trim())
+                }
+                None => rag_context.trim().to_string(),
+            };
+            self.rag_content_label.set_text(&rag_details);
+            self.rag_button.show();
+        }
+        if let Some(rag_error) = &chat_message.rag_error {
+            self.rag_content_label
+                .set_text(&format!("Retrieval failed: {}", rag_error.trim()));
+            self.rag_button.set_icon_name("dialog-warning-symbolic");
+            self.rag_button
+                .set_tooltip_text(Some("Show why no context was retrieved"));
+            self.rag_button.show();
+        }
         match chat_message.role {
             crate::models::Role::User => {
                 self.role_label.add_css_class("user-label");
@@ -94,6 +260,12 @@ impl ChatMessageListItem {
                 self.content_textbox
                     .buffer()
                     .set_text(&chat_message.content);
+                if let Some(attachments) = &chat_message.attachments {
+                    self.show_attachments(attachments);
+                }
+                if let Some(images) = &chat_message.images {
+                    self.show_images(images);
+                }
             }
             crate::models::Role::Assistant => {
                 self.role_label.add_css_class("assistant-label");
@@ -101,6 +273,10 @@ impl ChatMessageListItem {
                 self.content_textbox
                     .buffer()
                     .set_text(&chat_message.content);
+                if let Some(sources) = &chat_message.sources {
+                    self.show_sources(sources);
+                }
+                self.tag_citations();
             }
             crate::models::Role::System => {
                 self.role_label.add_css_class("system-label");
@@ -111,4 +287,264 @@ impl ChatMessageListItem {
             }
         }
     }
+
+    fn show_attachments(&self, attachments: &[Attachment]) {
+        while let Some(child) = self.attachments_box.first_child() {
+            self.attachments_box.remove(&child);
+        }
+        attachments.iter().for_each(|attachment| {
+            let attachment_chip = AttachmentChip::new(attachment, false);
+            let click_gesture = gtk::GestureClick::new();
+            let file_path = attachment.file_path.clone();
+            click_gesture.connect_released(move |_, _, _, _| {
+                if !file_path.exists() {
+                    println!("Attachment {:?} no longer exists", file_path);
+                    return;
+                }
+                if let Err(err) = open::that(&file_path) {
+                    println!("Error opening attachment {:?}: {:?}", file_path, err);
+                }
+            });
+            attachment_chip.main_box.add_controller(click_gesture);
+            self.attachments_box.append(&attachment_chip.main_box);
+        });
+        self.attachments_box.set_visible(!attachments.is_empty());
+    }
+
+    fn show_images(&self, images: &[B64Image]) {
+        while let Some(child) = self.images_box.first_child() {
+            self.images_box.remove(&child);
+        }
+        images.iter().for_each(|image| {
+            let texture_result = image
+                .to_bytes()
+                .ok_or_else(|| "the image isn't valid base64".to_string())
+                .and_then(|image_bytes| {
+                    gdk::Texture::from_bytes(&glib::Bytes::from_owned(image_bytes))
+                        .map_err(|err| err.to_string())
+                });
+            let texture = match texture_result {
+                Ok(texture) => texture,
+                Err(err) => {
+                    println!("Error showing image: {}", err);
+                    return;
+                }
+            };
+            let thumbnail_picture = gtk::Picture::builder()
+                .paintable(&texture)
+                .content_fit(gtk::ContentFit::Cover)
+                .width_request(THUMBNAIL_SIZE)
+                .height_request(THUMBNAIL_SIZE)
+                .tooltip_text("Show full size")
+                .build();
+            let click_gesture = gtk::GestureClick::new();
+            click_gesture.connect_released(move |click_gesture, _, _, _| {
+                let parent_window = click_gesture.widget().root().and_downcast::<gtk::Window>();
+                show_image_viewer(&texture, parent_window.as_ref());
+            });
+            thumbnail_picture.add_controller(click_gesture);
+            self.images_box.append(&thumbnail_picture);
+        });
+        self.images_box.set_visible(!images.is_empty());
+    }
+
+    // Only rebuilt when the sources change, as this runs for every streamed chunk
+    fn show_sources(&self, sources: &[RetrievedResult]) {
+        if *self.sources.lock().unwrap() == sources {
+            return;
+        }
+        *self.sources.lock().unwrap() = sources.to_vec();
+        while let Some(child) = self.sources_box.first_child() {
+            self.sources_box.remove(&child);
+        }
+        let heading_label = gtk::Label::builder()
+            .label("Sources")
+            .xalign(0.0)
+            .css_classes(["heading"])
+            .build();
+        self.sources_box.append(&heading_label);
+        sources
+            .iter()
+            .enumerate()
+            .for_each(|(source_index, source)| {
+                let mut source_markup = format!(
+                    "<a href=\"{0}\">[{0}] {1}</a>",
+                    source_index + 1,
+                    glib::markup_escape_text(&source.label())
+                );
+                if let Some(score) = source.score {
+                    source_markup.push_str(&format!(" <small>score {:.2}</small>", score));
+                }
+                let snippet = source
+                    .text
+                    .split_whitespace()
+                    .collect::<Vec<&str>>()
+                    .join(" ")
+                    .chars()
+                    .take(SNIPPET_CHARS)
+                    .collect::<String>();
+                source_markup.push_str(&format!(
+                    "\n<span alpha=\"70%\">{}</span>",
+                    glib::markup_escape_text(&snippet)
+                ));
+                let source_label = gtk::Label::builder()
+                    .label(source_markup)
+                    .use_markup(true)
+                    .wrap(true)
+                    .xalign(0.0)
+                    .tooltip_text(source.source.clone().unwrap_or_default())
+                    .build();
+                let source = source.clone();
+                let conversation_file_option_sender = self.conversation_file_option_sender.clone();
+                source_label.connect_activate_link(move |_, _| {
+                    open_source(&source, &conversation_file_option_sender);
+                    glib::Propagation::Stop
+                });
+                self.sources_box.append(&source_label);
+            });
+        self.sources_box.set_visible(!sources.is_empty());
+    }
+
+    // Marks [n] where n is one of the sources, other bracketed numbers are left alone
+    fn tag_citations(&self) {
+        let source_count = self.sources.lock().unwrap().len();
+        if source_count == 0 {
+            return;
+        }
+        let buffer = self.content_textbox.buffer();
+        let content = buffer.text(&buffer.start_iter(), &buffer.end_iter(), false);
+        let content_chars = content.chars().collect::<Vec<char>>();
+        let mut char_index = 0;
+        while char_index < content_chars.len() {
+            if content_chars[char_index] != '[' {
+                char_index += 1;
+                continue;
+            }
+            let digits = content_chars[char_index + 1..]
+                .iter()
+                .take_while(|character| character.is_ascii_digit())
+                .collect::<String>();
+            let closing_index = char_index + 1 + digits.len();
+            let is_citation = content_chars.get(closing_index) == Some(&']')
+                && digits
+                    .parse::<usize>()
+                    .map(|source_number| source_number >= 1 && source_number <= source_count)
+                    .unwrap_or(false);
+            if is_citation {
+                buffer.apply_tag_by_name(
+                    CITATION_TAG,
+                    &buffer.iter_at_offset(char_index as i32),
+                    &buffer.iter_at_offset(closing_index as i32 + 1),
+                );
+                char_index = closing_index;
+            }
+            char_index += 1;
+        }
+    }
+
+    fn connect_citation_clicks(
+        content_textbox: &gtk::TextView,
+        sources: Arc<Mutex<Vec<RetrievedResult>>>,
+        conversation_file_option_sender: Sender<Option<PathBuf>>,
+    ) {
+        let click_gesture = gtk::GestureClick::new();
+        let content_textbox_for_closure = content_textbox.clone();
+        click_gesture.connect_released(move |_, _, x, y| {
+            let content_textbox = &content_textbox_for_closure;
+            let (buffer_x, buffer_y) = content_textbox.window_to_buffer_coords(
+                gtk::TextWindowType::Widget,
+                x as i32,
+                y as i32,
+            );
+            let Some(clicked_iter) = content_textbox.iter_at_location(buffer_x, buffer_y) else {
+                return;
+            };
+            let buffer = content_textbox.buffer();
+            let Some(citation_tag) = buffer.tag_table().lookup(CITATION_TAG) else {
+                return;
+            };
+            if !clicked_iter.has_tag(&citation_tag) {
+                return;
+            }
+            let mut citation_start = clicked_iter.clone();
+            if !citation_start.starts_tag(Some(&citation_tag)) {
+                citation_start.backward_to_tag_toggle(Some(&citation_tag));
+            }
+            let mut citation_end = clicked_iter;
+            citation_end.forward_to_tag_toggle(Some(&citation_tag));
+            let citation = buffer.text(&citation_start, &citation_end, false);
+            let source_number = citation
+                .trim_matches(|character| character == '[' || character == ']')
+                .parse::<usize>()
+                .unwrap_or(0);
+            if let Some(source) = sources.lock().unwrap().get(source_number.wrapping_sub(1)) {
+                open_source(source, &conversation_file_option_sender);
+            }
+        });
+        content_textbox.add_controller(click_gesture);
+    }
+}
+
+/*
+Opens a source in the default app for it
+- URLs go to the browser
+- PDF pages are opened with a #page= fragment, which most PDF viewers follow and the rest ignore
+- Saved conversations are opened in the app, the same as picking them in the sidebar
+*/
+fn open_source(
+    source: &RetrievedResult,
+    conversation_file_option_sender: &Sender<Option<PathBuf>>,
+) {
+    let Some(source_location) = &source.source else {
+        return;
+    };
+    if let Some(conversation_file_name) =
+        conversation_search::conversation_file_name(source_location)
+    {
+        conversation_file_option_sender
+            .send(Some(conversation_file_name))
+            .expect("Conversation file channel needs to be open.");
+        return;
+    }
+    let open_result = match source.page {
+        Some(page) if !source.is_url() => {
+            let file_uri = gtk::gio::File::for_path(source_location).uri();
+            open::that(format!("{0}#page={1}", file_uri, page))
+                .or_else(|_| open::that(source_location))
+        }
+        _ => open::that(source_location),
+    };
+    if let Err(err) = open_result {
+        println!("Error opening {}: {:?}", source_location, err);
+    }
+}
+
+// The image at full size, scaled down to fit when it's bigger than the viewer, Escape closes it
+fn show_image_viewer(texture: &gdk::Texture, parent_window: Option<&gtk::Window>) {
+    let image_picture = gtk::Picture::builder()
+        .paintable(texture)
+        .content_fit(gtk::ContentFit::Contain)
+        .build();
+    let viewer_window = gtk::Window::builder()
+        .title("Image")
+        .modal(true)
+        .default_width(texture.width().min(IMAGE_VIEWER_MAX_SIZE))
+        .default_height(texture.height().min(IMAGE_VIEWER_MAX_SIZE))
+        .child(&image_picture)
+        .build();
+    viewer_window.set_transient_for(parent_window);
+    let key_controller = gtk::EventControllerKey::new();
+    {
+        let viewer_window = viewer_window.clone();
+        key_controller.connect_key_pressed(move |_, key, _, _| {
+            if key == gdk::Key::Escape {
+                viewer_window.close();
+                glib::Propagation::Stop
+            } else {
+                glib::Propagation::Proceed
+            }
+        });
+    }
+    viewer_window.add_controller(key_controller);
+    viewer_window.present();
 }
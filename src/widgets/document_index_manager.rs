@@ -0,0 +1,836 @@
+use std::{
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time,
+};
+
+use adw::prelude::*;
+use gtk::glib;
+
+use crate::rag::{
+    document_index::{
+        BuildGuard, BuildProgress, DocumentIndex, DocumentIndexConfig, IndexKind, IndexStatus,
+        SearchMethod, DEFAULT_EMBEDDING_MODEL, DEFAULT_TOP_K,
+    },
+    keyword_index::KeywordIndex,
+    local_docs,
+};
+
+const SEARCH_METHODS: [SearchMethod; 2] = [SearchMethod::Embeddings, SearchMethod::Keywords];
+const INDEX_KINDS: [IndexKind; 3] = [
+    IndexKind::Documents,
+    IndexKind::Repository,
+    IndexKind::LocalDocumentation,
+];
+const MAX_TOP_K: f64 = 20.0;
+
+/*
+Manages the local document indexes used as RAG sources
+- List of indexes with their status, each with update, rebuild, edit and delete buttons
+- Form to create or edit an index from a name, folders, a search method and how many chunks to retrieve
+- Code repositories skip what .gitignore lists and are chunked along functions and classes
+- Local documentation fills in the system's documentation folders and keyword search, so it works offline
+- Embeddings need an Ollama embedding model, keywords need nothing
+- Progress bar and status label while an index builds
+- One index builds at a time, builds asked for meanwhile are queued and start when it's done
+- Deleting asks for confirmation and isn't possible while the index builds
+- Updates only reread files that changed since the last build, rebuilds start from scratch
+- Calls on_change when the list of indexes changes so the RAG dropdown can update
+*/
+#[derive(Clone)]
+pub struct DocumentIndexManagerWidget {
+    pub main_box: gtk::Box,
+    index_list_box: gtk::ListBox,
+    name_entry: gtk::Entry,
+    folders_label: gtk::Label,
+    selected_folders: Arc<Mutex<Vec<PathBuf>>>,
+    kind_dropdown: gtk::DropDown,
+    search_method_dropdown: gtk::DropDown,
+    embedding_model_entry: gtk::Entry,
+    top_k_spin_button: gtk::SpinButton,
+    watch_folders_check_button: gtk::CheckButton,
+    save_button: gtk::Button,
+    cancel_edit_button: gtk::Button,
+    editing_config: Arc<Mutex<Option<DocumentIndexConfig>>>,
+    progress_bar: gtk::ProgressBar,
+    status_label: gtk::Label,
+    is_building: Arc<Mutex<bool>>,
+    // Configs waiting to build and whether each is a full rebuild
+    queued_builds: Arc<Mutex<Vec<(DocumentIndexConfig, bool)>>>,
+    is_waiting_for_watcher: Arc<Mutex<bool>>,
+    on_change: Arc<dyn Fn()>,
+}
+
+impl DocumentIndexManagerWidget {
+    pub fn new(on_change: Arc<dyn Fn()>) -> Self {
+        let selected_folders: Arc<Mutex<Vec<PathBuf>>> = Arc::new(Mutex::new(vec![]));
+
+        let main_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        let index_list_box = gtk::ListBox::builder().hexpand(true).build();
+        let scroll_window = gtk::ScrolledWindow::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .child(&index_list_box)
+            .build();
+
+        let name_entry = gtk::Entry::builder().placeholder_text("Index name").build();
+        let folders_label = gtk::Label::builder()
+            .label("No folders selected")
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        let add_folder_button = gtk::Button::builder()
+            .icon_name("folder-new-symbolic")
+            .tooltip_text("Add folder")
+            .build();
+        let clear_folders_button = gtk::Button::builder()
+            .icon_name("edit-clear-symbolic")
+            .tooltip_text("Clear folders")
+            .build();
+        let folders_box = gtk::Box::builder()
+            .spacing(4)
+            .orientation(gtk::Orientation::Horizontal)
+            .build();
+        folders_label.set_hexpand(true);
+        folders_box.append(&folders_label);
+        folders_box.append(&add_folder_button);
+        folders_box.append(&clear_folders_button);
+        let kind_dropdown = gtk::DropDown::from_strings(
+            &INDEX_KINDS
+                .iter()
+                .map(|index_kind| index_kind.label())
+                .collect::<Vec<&str>>(),
+        );
+        kind_dropdown.set_tooltip_text(Some("What the folders contain"));
+        let search_method_dropdown = gtk::DropDown::from_strings(
+            &SEARCH_METHODS
+                .iter()
+                .map(|search_method| search_method.label())
+                .collect::<Vec<&str>>(),
+        );
+        search_method_dropdown.set_tooltip_text(Some("Search method"));
+        let embedding_model_entry = gtk::Entry::builder()
+            .text(DEFAULT_EMBEDDING_MODEL)
+            .placeholder_text("Ollama embedding model")
+            .tooltip_text("Ollama embedding model")
+            .build();
+        let top_k_spin_button = gtk::SpinButton::with_range(1.0, MAX_TOP_K, 1.0);
+        top_k_spin_button.set_value(DEFAULT_TOP_K as f64);
+        top_k_spin_button.set_tooltip_text(Some("Chunks to retrieve for each prompt"));
+        let watch_folders_check_button = gtk::CheckButton::builder()
+            .label("Update when files in these folders change")
+            .active(true)
+            .build();
+        let save_button = gtk::Button::builder().label("Create index").build();
+        let cancel_edit_button = gtk::Button::builder()
+            .label("Cancel")
+            .visible(false)
+            .build();
+        let form_buttons_box = gtk::Box::builder()
+            .spacing(4)
+            .homogeneous(true)
+            .orientation(gtk::Orientation::Horizontal)
+            .build();
+        form_buttons_box.append(&save_button);
+        form_buttons_box.append(&cancel_edit_button);
+        let progress_bar = gtk::ProgressBar::builder()
+            .show_text(true)
+            .visible(false)
+            .build();
+        let status_label = gtk::Label::builder()
+            .label("")
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+
+        main_box.append(&scroll_window);
+        main_box.append(&name_entry);
+        main_box.append(&folders_box);
+        main_box.append(&kind_dropdown);
+        main_box.append(&search_method_dropdown);
+        main_box.append(&embedding_model_entry);
+        main_box.append(&top_k_spin_button);
+        main_box.append(&watch_folders_check_button);
+        main_box.append(&form_buttons_box);
+        main_box.append(&progress_bar);
+        main_box.append(&status_label);
+
+        let document_index_manager = Self {
+            main_box,
+            index_list_box,
+            name_entry,
+            folders_label,
+            selected_folders,
+            kind_dropdown,
+            search_method_dropdown,
+            embedding_model_entry,
+            top_k_spin_button,
+            watch_folders_check_button,
+            save_button,
+            cancel_edit_button,
+            editing_config: Arc::new(Mutex::new(None)),
+            progress_bar,
+            status_label,
+            is_building: Arc::new(Mutex::new(false)),
+            queued_builds: Arc::new(Mutex::new(vec![])),
+            is_waiting_for_watcher: Arc::new(Mutex::new(false)),
+            on_change,
+        };
+        document_index_manager.refresh_index_list();
+
+        let folder_chooser = Self::create_folder_chooser(
+            Arc::clone(&document_index_manager.selected_folders),
+            document_index_manager.folders_label.clone(),
+        );
+        add_folder_button.connect_clicked(move |_| {
+            folder_chooser.show();
+        });
+        {
+            let document_index_manager = document_index_manager.clone();
+            clear_folders_button.connect_clicked(move |_| {
+                document_index_manager.set_folders(vec![]);
+            });
+        }
+        {
+            let document_index_manager_for_closure = document_index_manager.clone();
+            document_index_manager
+                .kind_dropdown
+                .connect_selected_notify(move |kind_dropdown| {
+                    if INDEX_KINDS[kind_dropdown.selected() as usize]
+                        == IndexKind::LocalDocumentation
+                    {
+                        document_index_manager_for_closure.fill_local_documentation_form();
+                    }
+                });
+        }
+        {
+            let embedding_model_entry = document_index_manager.embedding_model_entry.clone();
+            document_index_manager
+                .search_method_dropdown
+                .connect_selected_notify(move |search_method_dropdown| {
+                    embedding_model_entry.set_sensitive(
+                        SEARCH_METHODS[search_method_dropdown.selected() as usize]
+                            == SearchMethod::Embeddings,
+                    );
+                });
+        }
+        {
+            let document_index_manager_for_closure = document_index_manager.clone();
+            document_index_manager
+                .save_button
+                .connect_clicked(move |_| {
+                    document_index_manager_for_closure.save_form();
+                });
+        }
+        {
+            let document_index_manager_for_closure = document_index_manager.clone();
+            document_index_manager
+                .cancel_edit_button
+                .connect_clicked(move |_| {
+                    document_index_manager_for_closure.reset_form();
+                });
+        }
+
+        document_index_manager
+    }
+
+    fn create_folder_chooser(
+        selected_folders: Arc<Mutex<Vec<PathBuf>>>,
+        folders_label: gtk::Label,
+    ) -> gtk::FileChooserNative {
+        let folder_chooser = gtk::FileChooserNative::builder()
+            .title("Select a folder to index")
+            .action(gtk::FileChooserAction::SelectFolder)
+            .build();
+        folder_chooser.connect_response(move |folder_chooser, response| {
+            if response == gtk::ResponseType::Accept {
+                if let Some(folder) = folder_chooser.file().and_then(|file| file.path()) {
+                    let mut selected_folders = selected_folders.lock().unwrap();
+                    if !selected_folders.contains(&folder) {
+                        selected_folders.push(folder);
+                    }
+                    folders_label.set_text(&Self::folders_text(&selected_folders));
+                }
+            }
+        });
+        folder_chooser
+    }
+
+    fn folders_text(folders: &[PathBuf]) -> String {
+        folders
+            .iter()
+            .map(|folder| folder.to_string_lossy().to_string())
+            .collect::<Vec<String>>()
+            .join(", ")
+    }
+
+    fn set_folders(&self, folders: Vec<PathBuf>) {
+        if folders.is_empty() {
+            self.folders_label.set_text("No folders selected");
+        } else {
+            self.folders_label.set_text(&Self::folders_text(&folders));
+        }
+        *self.selected_folders.lock().unwrap() = folders;
+    }
+
+    // e.g. "12 documents, 340 chunks, indexed 2026-10-19 14:02"
+    fn status_text(status: &IndexStatus) -> String {
+        let Some(last_indexed_unix_secs) = status.last_indexed_unix_secs else {
+            return String::from("Not indexed yet");
+        };
+        let last_indexed = glib::DateTime::from_unix_local(last_indexed_unix_secs as i64)
+            .and_then(|date_time| date_time.format("%Y-%m-%d %H:%M"))
+            .map(|date_time| date_time.to_string())
+            .unwrap_or_default();
+        format!(
+            "{0} documents, {1} chunks, indexed {2}",
+            status.documents, status.chunks, last_indexed
+        )
+    }
+
+    fn refresh_index_list(&self) {
+        while let Some(row) = self.index_list_box.first_child() {
+            self.index_list_box.remove(&row);
+        }
+        let is_building = *self.is_building.lock().unwrap();
+        let queued_ids = self
+            .queued_builds
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(queued_config, _)| queued_config.id.clone())
+            .collect::<Vec<String>>();
+        DocumentIndexConfig::list().into_iter().for_each(|config| {
+            let row_box = gtk::Box::builder()
+                .spacing(5)
+                .orientation(gtk::Orientation::Horizontal)
+                .build();
+            let search_method_text = match config.search_method {
+                SearchMethod::Embeddings => format!("Embedding model: {}", config.embedding_model),
+                SearchMethod::Keywords => config.search_method.label().to_string(),
+            };
+            let labels_box = gtk::Box::builder()
+                .orientation(gtk::Orientation::Vertical)
+                .hexpand(true)
+                .tooltip_text(format!(
+                    "{0}: {1}\n{2}\n{3} chunks per prompt",
+                    config.kind.label(),
+                    Self::folders_text(&config.folders),
+                    search_method_text,
+                    config.top_k
+                ))
+                .build();
+            let name_label = gtk::Label::builder()
+                .label(&config.name)
+                .xalign(0.0)
+                .build();
+            let status_text = if queued_ids.contains(&config.id) {
+                format!("{}, waiting to build", Self::status_text(&config.status))
+            } else {
+                Self::status_text(&config.status)
+            };
+            let status_label = gtk::Label::builder()
+                .label(status_text)
+                .wrap(true)
+                .xalign(0.0)
+                .css_classes(["dim-label", "caption"])
+                .build();
+            labels_box.append(&name_label);
+            labels_box.append(&status_label);
+            if let Some(last_error) = &config.status.last_error {
+                let error_label = gtk::Label::builder()
+                    .label(format!("Last build failed: {}", last_error))
+                    .wrap(true)
+                    .xalign(0.0)
+                    .css_classes(["error", "caption"])
+                    .build();
+                labels_box.append(&error_label);
+            }
+            if !config.status.failed_files.is_empty() {
+                let failed_files_label = gtk::Label::builder()
+                    .label(format!(
+                        "{} files couldn't be embedded",
+                        config.status.failed_files.len()
+                    ))
+                    .tooltip_text(Self::folders_text(&config.status.failed_files))
+                    .wrap(true)
+                    .xalign(0.0)
+                    .css_classes(["warning", "caption"])
+                    .build();
+                labels_box.append(&failed_files_label);
+            }
+            let update_button = gtk::Button::builder()
+                .icon_name("view-refresh-symbolic")
+                .tooltip_text("Update index, only changed files are read")
+                .build();
+            let rebuild_button = gtk::Button::builder()
+                .icon_name("edit-clear-all-symbolic")
+                .tooltip_text("Rebuild index from scratch")
+                .build();
+            let edit_button = gtk::Button::builder()
+                .icon_name("document-edit-symbolic")
+                .tooltip_text("Edit index")
+                .build();
+            let delete_button = gtk::Button::builder()
+                .icon_name("user-trash-symbolic")
+                .tooltip_text("Delete index")
+                .sensitive(!is_building && !config.is_building())
+                .build();
+            row_box.append(&labels_box);
+            row_box.append(&update_button);
+            row_box.append(&rebuild_button);
+            row_box.append(&edit_button);
+            row_box.append(&delete_button);
+            self.index_list_box.append(&row_box);
+
+            {
+                let document_index_manager = self.clone();
+                let config = config.clone();
+                update_button.connect_clicked(move |_| {
+                    document_index_manager.build_index(config.clone(), false);
+                });
+            }
+            {
+                let document_index_manager = self.clone();
+                let config = config.clone();
+                rebuild_button.connect_clicked(move |_| {
+                    document_index_manager.build_index(config.clone(), true);
+                });
+            }
+            {
+                let document_index_manager = self.clone();
+                let config = config.clone();
+                edit_button.connect_clicked(move |_| {
+                    document_index_manager.edit_config(config.clone());
+                });
+            }
+            {
+                let document_index_manager = self.clone();
+                delete_button.connect_clicked(move |delete_button| {
+                    document_index_manager.confirm_delete(delete_button, config.clone());
+                });
+            }
+        });
+    }
+
+    fn confirm_delete(&self, delete_button: &gtk::Button, config: DocumentIndexConfig) {
+        let message_dialog = adw::MessageDialog::builder()
+            .heading("Delete Index?")
+            .body(format!(
+                "{} and its indexed files will be deleted, the documents in its folders are kept.",
+                config.name
+            ))
+            .modal(true)
+            .build();
+        if let Some(window) = delete_button.root().and_downcast::<gtk::Window>() {
+            message_dialog.set_transient_for(Some(&window));
+        }
+        message_dialog.add_response("cancel", "Cancel");
+        message_dialog.add_response("delete", "Delete");
+        message_dialog.set_response_appearance("delete", adw::ResponseAppearance::Destructive);
+        message_dialog.set_default_response(Some("cancel"));
+        message_dialog.set_close_response("cancel");
+        let document_index_manager = self.clone();
+        message_dialog.connect_response(None, move |_, response| {
+            if response != "delete" {
+                return;
+            }
+            // A build may have started while the dialog was open, deleting now would leave its files behind
+            if *document_index_manager.is_building.lock().unwrap() || config.is_building() {
+                document_index_manager.status_label.set_text(&format!(
+                    "{} can't be deleted while an index builds, try again when it's done.",
+                    config.name
+                ));
+                return;
+            }
+            document_index_manager
+                .queued_builds
+                .lock()
+                .unwrap()
+                .retain(|(queued_config, _)| queued_config.id != config.id);
+            config.delete();
+            document_index_manager.refresh_index_list();
+            (document_index_manager.on_change)();
+        });
+        message_dialog.present();
+    }
+
+    fn edit_config(&self, config: DocumentIndexConfig) {
+        // Set first so the kind dropdown doesn't fill in defaults over the saved values
+        *self.editing_config.lock().unwrap() = Some(config.clone());
+        self.name_entry.set_text(&config.name);
+        self.set_folders(config.folders.clone());
+        let search_method_index = SEARCH_METHODS
+            .iter()
+            .position(|search_method| *search_method == config.search_method)
+            .unwrap_or(0);
+        self.search_method_dropdown
+            .set_selected(search_method_index as u32);
+        let kind_index = INDEX_KINDS
+            .iter()
+            .position(|index_kind| *index_kind == config.kind)
+            .unwrap_or(0);
+        self.kind_dropdown.set_selected(kind_index as u32);
+        self.embedding_model_entry.set_text(&config.embedding_model);
+        self.top_k_spin_button.set_value(config.top_k as f64);
+        self.watch_folders_check_button
+            .set_active(config.watch_folders);
+        self.save_button.set_label("Save index");
+        self.cancel_edit_button.show();
+    }
+
+    /*
+    Sensible defaults for a new local documentation index, only filled where the form is still empty
+    - Keywords need no Ollama, which matters on machines without a network
+    - Watching is off, the folders hold thousands of directories and only change on package updates
+    */
+    fn fill_local_documentation_form(&self) {
+        if self.editing_config.lock().unwrap().is_some() {
+            return;
+        }
+        if self.name_entry.text().trim().is_empty() {
+            self.name_entry.set_text("Local documentation");
+        }
+        if self.selected_folders.lock().unwrap().is_empty() {
+            self.set_folders(
+                local_docs::DEFAULT_FOLDERS
+                    .iter()
+                    .map(PathBuf::from)
+                    .filter(|folder| folder.is_dir())
+                    .collect(),
+            );
+        }
+        let keywords_index = SEARCH_METHODS
+            .iter()
+            .position(|search_method| *search_method == SearchMethod::Keywords)
+            .unwrap_or(0);
+        self.search_method_dropdown
+            .set_selected(keywords_index as u32);
+        self.watch_folders_check_button.set_active(false);
+    }
+
+    fn reset_form(&self) {
+        self.name_entry.set_text("");
+        self.set_folders(vec![]);
+        self.kind_dropdown.set_selected(0);
+        self.search_method_dropdown.set_selected(0);
+        self.embedding_model_entry.set_text(DEFAULT_EMBEDDING_MODEL);
+        self.top_k_spin_button.set_value(DEFAULT_TOP_K as f64);
+        self.watch_folders_check_button.set_active(true);
+        self.save_button.set_label("Create index");
+        self.cancel_edit_button.hide();
+        *self.editing_config.lock().unwrap() = None;
+    }
+
+    /*
+    Creates an index or saves the one being edited
+    - A new kind, search method or embedding model means the old index can't be reused, so it's rebuilt
+    - New folders only need an update, files already indexed are kept
+    */
+    fn save_form(&self) {
+        let name = self.name_entry.text().trim().to_string();
+        let folders = self.selected_folders.lock().unwrap().clone();
+        let search_method = SEARCH_METHODS[self.search_method_dropdown.selected() as usize];
+        let kind = INDEX_KINDS[self.kind_dropdown.selected() as usize];
+        let embedding_model = self.embedding_model_entry.text().trim().to_string();
+        if name.is_empty()
+            || folders.is_empty()
+            || (search_method == SearchMethod::Embeddings && embedding_model.is_empty())
+        {
+            self.status_label
+                .set_text("Enter a name, at least one folder and an embedding model.");
+            return;
+        }
+        let editing_config = self.editing_config.lock().unwrap().clone();
+        let (mut config, is_full_build, needs_build) = match editing_config {
+            Some(previous_config) => {
+                let is_full_build = previous_config.kind != kind
+                    || previous_config.search_method != search_method
+                    || previous_config.embedding_model != embedding_model;
+                let needs_build = is_full_build || previous_config.folders != folders;
+                (previous_config, is_full_build, needs_build)
+            }
+            None => (
+                DocumentIndexConfig::new(
+                    name.clone(),
+                    folders.clone(),
+                    embedding_model.clone(),
+                    search_method,
+                    kind,
+                ),
+                true,
+                true,
+            ),
+        };
+        config.name = name;
+        config.folders = folders;
+        config.kind = kind;
+        config.search_method = search_method;
+        config.embedding_model = embedding_model;
+        config.top_k = self.top_k_spin_button.value_as_int() as usize;
+        config.watch_folders = self.watch_folders_check_button.is_active();
+        if let Err(err) = config.save() {
+            self.status_label
+                .set_text(&format!("Error saving index: {}", err));
+            return;
+        }
+        self.reset_form();
+        self.refresh_index_list();
+        (self.on_change)();
+        if needs_build {
+            self.build_index(config, is_full_build);
+        }
+    }
+
+    fn build_index(&self, config: DocumentIndexConfig, is_full_build: bool) {
+        if *self.is_building.lock().unwrap() {
+            self.queue_build(config, is_full_build);
+            return;
+        }
+        // The folder watcher may be updating this index, both writing its files would corrupt them
+        let Some(build_guard) = config.start_build() else {
+            self.queue_build(config, is_full_build);
+            return;
+        };
+        *self.is_building.lock().unwrap() = true;
+        self.refresh_index_list();
+        if config.search_method == SearchMethod::Keywords {
+            self.update_keyword_index(config, is_full_build, build_guard);
+            return;
+        }
+        self.progress_bar.set_fraction(0.0);
+        self.progress_bar.set_text(Some("Reading documents"));
+        self.progress_bar.show();
+        self.status_label
+            .set_text(&format!("Building {}", config.name));
+        let document_index_manager = self.clone();
+        glib::MainContext::default().spawn_local(async move {
+            let progress_bar = document_index_manager.progress_bar.clone();
+            let build_result = DocumentIndex::build(&config, is_full_build, |build_progress| {
+                let (progress_text, fraction) = match build_progress {
+                    BuildProgress::Reading {
+                        read_files,
+                        total_files,
+                    } => (
+                        format!("Read {0} of {1} files", read_files, total_files),
+                        read_files as f64 / total_files.max(1) as f64,
+                    ),
+                    BuildProgress::Embedding {
+                        embedded_chunks,
+                        total_chunks,
+                    } => (
+                        format!(
+                            "Embedded {0} of {1} changed chunks",
+                            embedded_chunks, total_chunks
+                        ),
+                        embedded_chunks as f64 / total_chunks.max(1) as f64,
+                    ),
+                };
+                progress_bar.set_fraction(fraction);
+                progress_bar.set_text(Some(&progress_text));
+            })
+            .await
+            .map(|document_index| {
+                (
+                    document_index.files.len(),
+                    document_index.chunks.len(),
+                    document_index.failed_files,
+                )
+            })
+            .map_err(|err| err.to_string());
+            let status_text = match &build_result {
+                Ok((_, chunk_count, failed_files)) if failed_files.is_empty() => {
+                    format!("Built {0} with {1} chunks", config.name, chunk_count)
+                }
+                Ok((_, chunk_count, failed_files)) => format!(
+                    "Built {0} with {1} chunks, {2} files couldn't be embedded and will be tried again on the next update",
+                    config.name,
+                    chunk_count,
+                    failed_files.len()
+                ),
+                Err(err) => format!(
+                    "Error building {0}: {1}\nCheck that {2} is pulled in Ollama.",
+                    config.name, err, config.embedding_model
+                ),
+            };
+            document_index_manager.finish_build(config, build_result, &status_text, build_guard);
+        });
+    }
+
+    // A config already queued keeps its place, it's rebuilt from scratch if either build asked for it
+    fn queue_build(&self, config: DocumentIndexConfig, is_full_build: bool) {
+        {
+            let mut queued_builds = self.queued_builds.lock().unwrap();
+            match queued_builds
+                .iter_mut()
+                .find(|(queued_config, _)| queued_config.id == config.id)
+            {
+                Some(queued_build) => queued_build.1 |= is_full_build,
+                None => queued_builds.push((config.clone(), is_full_build)),
+            }
+        }
+        self.status_label.set_text(&format!(
+            "{} will build when the current build is done.",
+            config.name
+        ));
+        self.refresh_index_list();
+        if !*self.is_building.lock().unwrap() {
+            self.wait_for_watcher();
+        }
+    }
+
+    /*
+    Starts the first queued build that isn't blocked by the folder watcher
+    - Configs are reread so edits saved while waiting are built and deleted ones are dropped
+    - If the watcher holds every queued index, this checks again until it lets go
+    */
+    fn start_next_queued_build(&self) {
+        if *self.is_building.lock().unwrap() {
+            return;
+        }
+        let next_build = {
+            let mut queued_builds = self.queued_builds.lock().unwrap();
+            queued_builds.retain(|(queued_config, _)| {
+                DocumentIndexConfig::find(&queued_config.id).is_some()
+            });
+            queued_builds
+                .iter()
+                .position(|(queued_config, _)| !queued_config.is_building())
+                .map(|position| queued_builds.remove(position))
+        };
+        match next_build {
+            Some((queued_config, is_full_build)) => {
+                if let Some(config) = DocumentIndexConfig::find(&queued_config.id) {
+                    self.build_index(config, is_full_build);
+                }
+            }
+            None if !self.queued_builds.lock().unwrap().is_empty() => self.wait_for_watcher(),
+            None => {}
+        }
+    }
+
+    // The watcher doesn't report back here, so its builds finishing is polled for
+    fn wait_for_watcher(&self) {
+        {
+            let mut is_waiting_for_watcher = self.is_waiting_for_watcher.lock().unwrap();
+            if *is_waiting_for_watcher {
+                return;
+            }
+            *is_waiting_for_watcher = true;
+        }
+        let document_index_manager = self.clone();
+        glib::MainContext::default().spawn_local(async move {
+            glib::timeout_future(time::Duration::from_secs(1)).await;
+            *document_index_manager
+                .is_waiting_for_watcher
+                .lock()
+                .unwrap() = false;
+            document_index_manager.start_next_queued_build();
+        });
+    }
+
+    fn update_keyword_index(
+        &self,
+        config: DocumentIndexConfig,
+        is_full_update: bool,
+        build_guard: BuildGuard,
+    ) {
+        self.progress_bar.set_text(Some(if is_full_update {
+            "Indexing all documents"
+        } else {
+            "Indexing changed documents"
+        }));
+        self.progress_bar.show();
+        self.status_label
+            .set_text(&format!("Updating {}", config.name));
+        let (update_sender, update_receiver): (
+            Sender<Result<(usize, usize, usize), String>>,
+            Receiver<Result<(usize, usize, usize), String>>,
+        ) = mpsc::channel();
+        // Files checked and the total, written by the thread and shown by the loop below
+        let update_progress: Arc<Mutex<Option<(usize, usize)>>> = Arc::new(Mutex::new(None));
+        {
+            let config = config.clone();
+            let update_progress = Arc::clone(&update_progress);
+            std::thread::spawn(move || {
+                let on_progress = |checked_files, total_files| {
+                    *update_progress.lock().unwrap() = Some((checked_files, total_files));
+                };
+                let update_result = KeywordIndex::update(&config, is_full_update, on_progress)
+                    .map(|(keyword_index, updated_files)| {
+                        (
+                            keyword_index.files.len(),
+                            keyword_index.chunk_count(),
+                            updated_files,
+                        )
+                    })
+                    .map_err(|err| err.to_string());
+                update_sender.send(update_result).unwrap();
+            });
+        }
+        let document_index_manager = self.clone();
+        glib::MainContext::default().spawn_local(async move {
+            let update_result = loop {
+                match update_receiver.try_recv() {
+                    Ok(update_result) => break update_result,
+                    Err(mpsc::TryRecvError::Empty) => {
+                        match *update_progress.lock().unwrap() {
+                            Some((checked_files, total_files)) => {
+                                document_index_manager
+                                    .progress_bar
+                                    .set_fraction(checked_files as f64 / total_files.max(1) as f64);
+                                document_index_manager.progress_bar.set_text(Some(&format!(
+                                    "Checked {0} of {1} files",
+                                    checked_files, total_files
+                                )));
+                            }
+                            None => document_index_manager.progress_bar.pulse(),
+                        }
+                        glib::timeout_future(time::Duration::from_millis(100)).await;
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        break Err("The indexing thread stopped unexpectedly".to_string());
+                    }
+                }
+            };
+            let status_text = match &update_result {
+                Ok((_, chunk_count, updated_files)) => format!(
+                    "Updated {0}, reread {1} changed files, {2} chunks in total",
+                    config.name, updated_files, chunk_count
+                ),
+                Err(err) => format!("Error updating {0}: {1}", config.name, err),
+            };
+            let build_result = update_result
+                .map(|(document_count, chunk_count, _)| (document_count, chunk_count, vec![]));
+            document_index_manager.finish_build(config, build_result, &status_text, build_guard);
+        });
+    }
+
+    fn finish_build(
+        &self,
+        config: DocumentIndexConfig,
+        build_result: Result<(usize, usize, Vec<PathBuf>), String>,
+        status_text: &str,
+        build_guard: BuildGuard,
+    ) {
+        // Reread as it may have been edited or deleted while this was building
+        match DocumentIndexConfig::find(&config.id) {
+            Some(mut saved_config) => saved_config.record_build(build_result),
+            // Its files were written after the config was deleted, nothing else would remove them
+            None => config.delete(),
+        }
+        drop(build_guard);
+        self.status_label.set_text(status_text);
+        self.progress_bar.hide();
+        *self.is_building.lock().unwrap() = false;
+        self.refresh_index_list();
+        (self.on_change)();
+        self.start_next_queued_build();
+    }
+}
@@ -0,0 +1,211 @@
+use core::time;
+use std::sync::{
+    mpsc::{self, Receiver},
+    Arc, Mutex,
+};
+
+use adw::prelude::*;
+use gtk::glib;
+
+use crate::models::{
+    model_registry::{ModelRegistry, ModelRegistryUpdate},
+    SavedModel,
+};
+
+/*
+Editor for an ordered list of fallback models
+- List of the models in order, each with move up, move down and remove buttons
+- Dropdown of known models with an add button
+- Calls on_change with the new list after every edit
+*/
+pub struct FallbackChainWidget {
+    pub main_box: gtk::Box,
+}
+
+impl FallbackChainWidget {
+    pub fn new(
+        fallback_models: Vec<SavedModel>,
+        model_registry: &ModelRegistry,
+        on_change: Arc<dyn Fn(Vec<SavedModel>)>,
+    ) -> Self {
+        let fallback_models = Arc::new(Mutex::new(fallback_models));
+        let available_models = Arc::new(Mutex::new(model_registry.models()));
+
+        let main_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        let info_label = gtk::Label::builder()
+            .label(
+                "If the selected model fails, these are tried in order with the same conversation.",
+            )
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        let chain_list_box = gtk::ListBox::builder().hexpand(true).build();
+        let scroll_window = gtk::ScrolledWindow::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .child(&chain_list_box)
+            .build();
+        let add_box = gtk::Box::builder()
+            .spacing(4)
+            .orientation(gtk::Orientation::Horizontal)
+            .build();
+        let available_models_list = gtk::StringList::from_iter(
+            available_models
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|saved_model| saved_model.display_name()),
+        );
+        let available_models_dropdown = gtk::DropDown::builder()
+            .model(&available_models_list)
+            .hexpand(true)
+            .build();
+        let add_button = gtk::Button::builder()
+            .icon_name("list-add-symbolic")
+            .tooltip_text("Add fallback model")
+            .build();
+        add_box.append(&available_models_dropdown);
+        add_box.append(&add_button);
+
+        main_box.append(&info_label);
+        main_box.append(&scroll_window);
+        main_box.append(&add_box);
+
+        Self::refresh_chain_list(&chain_list_box, &fallback_models, &on_change);
+
+        {
+            let fallback_models = Arc::clone(&fallback_models);
+            let available_models = Arc::clone(&available_models);
+            let chain_list_box = chain_list_box.clone();
+            let on_change = Arc::clone(&on_change);
+            add_button.connect_clicked(move |_| {
+                let Some(saved_model) = available_models
+                    .lock()
+                    .unwrap()
+                    .get(available_models_dropdown.selected() as usize)
+                    .cloned()
+                else {
+                    return;
+                };
+                if fallback_models.lock().unwrap().contains(&saved_model) {
+                    return;
+                }
+                fallback_models.lock().unwrap().push(saved_model);
+                on_change(fallback_models.lock().unwrap().clone());
+                Self::refresh_chain_list(&chain_list_box, &fallback_models, &on_change);
+            });
+        }
+
+        Self::create_model_list_listener_thread(
+            model_registry.subscribe(),
+            available_models,
+            available_models_list,
+        );
+
+        Self { main_box }
+    }
+
+    fn create_model_list_listener_thread(
+        model_update_receiver: Receiver<ModelRegistryUpdate>,
+        available_models: Arc<Mutex<Vec<SavedModel>>>,
+        available_models_list: gtk::StringList,
+    ) {
+        glib::MainContext::default().spawn_local(async move {
+            loop {
+                match model_update_receiver.try_recv() {
+                    Ok(model_update) => {
+                        let display_names = model_update
+                            .models
+                            .iter()
+                            .map(|saved_model| saved_model.display_name())
+                            .collect::<Vec<String>>();
+                        available_models_list.splice(
+                            0,
+                            available_models_list.n_items(),
+                            &display_names
+                                .iter()
+                                .map(|display_name| display_name.as_str())
+                                .collect::<Vec<&str>>(),
+                        );
+                        *available_models.lock().unwrap() = model_update.models;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {
+                        // No update available yet, wait a bit before checking again.
+                        glib::timeout_future(time::Duration::from_millis(500)).await;
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        println!("The model registry channel is disconnected.");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    fn refresh_chain_list(
+        chain_list_box: &gtk::ListBox,
+        fallback_models: &Arc<Mutex<Vec<SavedModel>>>,
+        on_change: &Arc<dyn Fn(Vec<SavedModel>)>,
+    ) {
+        while let Some(row) = chain_list_box.first_child() {
+            chain_list_box.remove(&row);
+        }
+        let models_count = fallback_models.lock().unwrap().len();
+        fallback_models
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .for_each(|(index, saved_model)| {
+                let row_box = gtk::Box::builder()
+                    .spacing(5)
+                    .orientation(gtk::Orientation::Horizontal)
+                    .build();
+                let name_label = gtk::Label::builder()
+                    .label(format!("{0}. {1}", index + 1, saved_model.display_name()))
+                    .hexpand(true)
+                    .xalign(0.0)
+                    .build();
+                let up_button = gtk::Button::builder()
+                    .icon_name("go-up-symbolic")
+                    .tooltip_text("Try earlier")
+                    .sensitive(index > 0)
+                    .build();
+                let down_button = gtk::Button::builder()
+                    .icon_name("go-down-symbolic")
+                    .tooltip_text("Try later")
+                    .sensitive(index + 1 < models_count)
+                    .build();
+                let remove_button = gtk::Button::builder()
+                    .icon_name("user-trash-symbolic")
+                    .tooltip_text("Remove")
+                    .build();
+                row_box.append(&name_label);
+                row_box.append(&up_button);
+                row_box.append(&down_button);
+                row_box.append(&remove_button);
+                chain_list_box.append(&row_box);
+
+                let buttons_and_edits: [(gtk::Button, fn(&mut Vec<SavedModel>, usize)); 3] = [
+                    (up_button, |models, index| models.swap(index - 1, index)),
+                    (down_button, |models, index| models.swap(index, index + 1)),
+                    (remove_button, |models, index| {
+                        models.remove(index);
+                    }),
+                ];
+                buttons_and_edits.into_iter().for_each(|(button, edit)| {
+                    let chain_list_box = chain_list_box.clone();
+                    let fallback_models = Arc::clone(fallback_models);
+                    let on_change = Arc::clone(on_change);
+                    button.connect_clicked(move |_| {
+                        edit(&mut fallback_models.lock().unwrap(), index);
+                        on_change(fallback_models.lock().unwrap().clone());
+                        Self::refresh_chain_list(&chain_list_box, &fallback_models, &on_change);
+                    });
+                });
+            });
+    }
+}
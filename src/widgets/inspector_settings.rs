@@ -0,0 +1,46 @@
+use adw::prelude::*;
+
+use crate::settings::Settings;
+
+/*
+- Check button for saving requests so answers can be opened in the request inspector
+- Saved to Settings as soon as it changes, answers sent while it was off can't be inspected
+*/
+pub struct InspectorSettingsWidget {
+    pub main_box: gtk::Box,
+}
+
+impl InspectorSettingsWidget {
+    pub fn new() -> Self {
+        let main_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        let info_label = gtk::Label::builder()
+            .label("Recorded requests are saved with the whole conversation that was sent, API keys are redacted and images left out. The last 100 are kept.")
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        let record_requests_check_button = gtk::CheckButton::builder()
+            .label("Record requests for the inspector")
+            .active(Settings::load().record_requests)
+            .build();
+
+        main_box.append(&info_label);
+        main_box.append(&record_requests_check_button);
+
+        record_requests_check_button.connect_toggled(|record_requests_check_button| {
+            let mut settings = Settings::load();
+            settings.record_requests = record_requests_check_button.is_active();
+            settings.save();
+        });
+
+        Self { main_box }
+    }
+}
+
+impl Default for InspectorSettingsWidget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
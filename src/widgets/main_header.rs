@@ -1,45 +1,98 @@
-use crate::models::api_model::ApiModel;
-use crate::models::ollama_model::OllamaModel;
+use crate::models::model_registry::{ModelRegistry, ModelRegistryUpdate};
 use crate::models::{CoreLLM, SavedModel};
-use crate::utils::get_filenames_from_folder;
+use crate::rag::{
+    conversation_search::ConversationSearchConfig, document_index::DocumentIndexConfig, manifest,
+};
 use crate::RagSource;
 use adw::prelude::*;
+use core::time;
+use gtk::glib;
 
 use std::path::PathBuf;
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{self, Receiver, Sender};
 use std::sync::{Arc, Mutex};
 
 use super::preferences::PreferencesWidget;
 
 #[derive(Clone, Debug)]
 pub struct RagDropdown {
-    pub rag_options: Vec<RagSource>,
+    pub rag_options: Arc<Mutex<Vec<RagSource>>>,
     pub dropdown: gtk::DropDown,
+    option_list: gtk::StringList,
 }
 
 impl RagDropdown {
     pub fn new() -> Self {
-        let rag_source_scripts = get_filenames_from_folder(PathBuf::from("./rag_sources"));
-        let mut rag_options = vec![RagSource::NoRag];
-        rag_source_scripts
-            .iter()
-            .for_each(|source| rag_options.push(RagSource::BashScript(source.clone())));
+        let rag_options = Self::load_rag_options();
         let option_list =
             gtk::StringList::from_iter(rag_options.iter().map(|rag_source| rag_source.name()));
 
         let dropdown = gtk::DropDown::builder().model(&option_list).build();
 
-        dropdown.connect_selected_notify(move |drop_down| {
-            let selected_index = drop_down.selected();
+        {
+            let option_list = option_list.clone();
+            dropdown.connect_selected_notify(move |drop_down| {
+                let selected_index = drop_down.selected();
 
-            let selected_text = option_list.string(selected_index).unwrap();
-            println!("Selected: {}", selected_text);
-        });
+                if let Some(selected_text) = option_list.string(selected_index) {
+                    println!("Selected: {}", selected_text);
+                }
+            });
+        }
         Self {
-            rag_options,
+            rag_options: Arc::new(Mutex::new(rag_options)),
             dropdown,
+            option_list,
         }
     }
+
+    fn load_rag_options() -> Vec<RagSource> {
+        let mut rag_options = vec![RagSource::NoRag];
+        manifest::list()
+            .into_iter()
+            .for_each(|manifest| rag_options.push(RagSource::Script(manifest)));
+        DocumentIndexConfig::list()
+            .into_iter()
+            .filter(|config| config.is_built())
+            .for_each(|config| rag_options.push(RagSource::DocumentIndex(config)));
+        ConversationSearchConfig::options()
+            .into_iter()
+            .for_each(|config| rag_options.push(RagSource::Conversations(config)));
+        rag_options
+    }
+
+    pub fn selected_rag_source(&self) -> RagSource {
+        self.rag_options
+            .lock()
+            .unwrap()
+            .get(self.dropdown.selected() as usize)
+            .cloned()
+            .unwrap_or(RagSource::NoRag)
+    }
+
+    // Reloads the sources, keeping the selection if it still exists
+    pub fn refresh(&self) {
+        let selected_name = self.selected_rag_source().name();
+        let rag_options = Self::load_rag_options();
+        let option_names = rag_options
+            .iter()
+            .map(|rag_source| rag_source.name())
+            .collect::<Vec<String>>();
+        let selected_index = option_names
+            .iter()
+            .position(|option_name| *option_name == selected_name)
+            .unwrap_or(0);
+        *self.rag_options.lock().unwrap() = rag_options;
+        self.option_list.splice(
+            0,
+            self.option_list.n_items(),
+            &option_names
+                .iter()
+                .map(|option_name| option_name.as_str())
+                .collect::<Vec<&str>>(),
+        );
+        self.dropdown.set_selected(selected_index as u32);
+    }
 }
 
 impl Default for RagDropdown {
@@ -50,59 +103,156 @@ impl Default for RagDropdown {
 
 #[derive(Clone, Debug)]
 pub struct ModelDropdown {
-    pub model_list: Vec<SavedModel>,
+    pub model_list: Arc<Mutex<Vec<SavedModel>>>,
     pub dropdown: gtk::DropDown,
 }
 
 impl ModelDropdown {
-    pub fn new(chat_model: Arc<Mutex<Box<dyn CoreLLM>>>) -> Self {
-        let model_list = SavedModel::get_all();
-
-        let option_list = gtk::StringList::from_iter(model_list.iter().map(|local_model| {
-            format!("{0}: {1}", local_model.model_type.name(), &local_model.name)
-        }));
+    pub fn new(chat_model: Arc<Mutex<Box<dyn CoreLLM>>>, model_registry: &ModelRegistry) -> Self {
+        let model_list = Arc::new(Mutex::new(model_registry.models()));
+        let is_updating_list = Arc::new(Mutex::new(false));
+
+        let option_list = gtk::StringList::from_iter(
+            model_list
+                .lock()
+                .unwrap()
+                .iter()
+                .map(|local_model| local_model.display_name()),
+        );
 
         let dropdown = gtk::DropDown::builder().model(&option_list).build();
 
-        let model_list_for_closure = model_list.clone();
         dropdown.connect_selected_notify(Self::dropdown_on_selected(
-            option_list,
-            model_list_for_closure,
+            option_list.clone(),
+            Arc::clone(&model_list),
             chat_model,
+            Arc::clone(&is_updating_list),
         ));
+        Self::create_model_list_listener_thread(
+            model_registry.subscribe(),
+            dropdown.clone(),
+            option_list,
+            Arc::clone(&model_list),
+            is_updating_list,
+        );
         Self {
             dropdown,
             model_list,
         }
     }
 
+    fn create_model_list_listener_thread(
+        model_update_receiver: Receiver<ModelRegistryUpdate>,
+        dropdown: gtk::DropDown,
+        option_list: gtk::StringList,
+        model_list: Arc<Mutex<Vec<SavedModel>>>,
+        is_updating_list: Arc<Mutex<bool>>,
+    ) {
+        glib::MainContext::default().spawn_local(async move {
+            loop {
+                match model_update_receiver.try_recv() {
+                    Ok(model_update) => {
+                        let selected_model = model_list
+                            .lock()
+                            .unwrap()
+                            .get(dropdown.selected() as usize)
+                            .cloned();
+                        let new_selected_index =
+                            selected_model.as_ref().and_then(|selected_model| {
+                                model_update
+                                    .models
+                                    .iter()
+                                    .position(|saved_model| saved_model == selected_model)
+                            });
+                        // Replacing the list changes the selection, which mustn't switch the chat model over
+                        *is_updating_list.lock().unwrap() = true;
+                        *model_list.lock().unwrap() = model_update.models.clone();
+                        let display_names = model_update
+                            .models
+                            .iter()
+                            .map(|saved_model| saved_model.display_name())
+                            .collect::<Vec<String>>();
+                        option_list.splice(
+                            0,
+                            option_list.n_items(),
+                            &display_names
+                                .iter()
+                                .map(|display_name| display_name.as_str())
+                                .collect::<Vec<&str>>(),
+                        );
+                        match (new_selected_index, selected_model) {
+                            (Some(new_selected_index), _) => {
+                                dropdown.set_selected(new_selected_index as u32)
+                            }
+                            (None, Some(removed_model)) => {
+                                dropdown.set_selected(gtk::INVALID_LIST_POSITION);
+                                Self::show_model_removed(&dropdown, &removed_model);
+                            }
+                            (None, None) => {}
+                        }
+                        *is_updating_list.lock().unwrap() = false;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {
+                        // No update available yet, wait a bit before checking again.
+                        glib::timeout_future(time::Duration::from_millis(500)).await;
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        println!("The model registry channel is disconnected.");
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    // The chat model is left as it was, so nothing changes until another model is picked
+    fn show_model_removed(dropdown: &gtk::DropDown, removed_model: &SavedModel) {
+        let message_dialog = adw::MessageDialog::builder()
+            .heading("Model Removed")
+            .body(format!(
+                "{} is no longer in the model list. Pick another model to keep chatting.",
+                removed_model.display_name()
+            ))
+            .modal(true)
+            .build();
+        if let Some(window) = dropdown.root().and_downcast::<gtk::Window>() {
+            message_dialog.set_transient_for(Some(&window));
+        }
+        message_dialog.add_response("ok", "OK");
+        message_dialog.present();
+    }
+
     fn dropdown_on_selected(
         option_list: gtk::StringList,
-        model_list_for_closure: Vec<SavedModel>,
+        model_list: Arc<Mutex<Vec<SavedModel>>>,
         chat_model: Arc<Mutex<Box<dyn CoreLLM>>>,
+        is_updating_list: Arc<Mutex<bool>>,
     ) -> impl Fn(&gtk::DropDown) {
         move |drop_down| {
+            if *is_updating_list.lock().unwrap() {
+                return;
+            }
             let selected_index = drop_down.selected();
-            let selected_text = option_list.string(selected_index).unwrap();
-            let saved_model = model_list_for_closure.get(selected_index as usize).unwrap();
+            let Some(selected_text) = option_list.string(selected_index) else {
+                return;
+            };
+            let Some(saved_model) = model_list
+                .lock()
+                .unwrap()
+                .get(selected_index as usize)
+                .cloned()
+            else {
+                return;
+            };
 
             let current_conversation = chat_model.lock().unwrap().get_conversation();
-            *chat_model.lock().unwrap() = match &saved_model.model_type {
-                crate::models::ModelType::Ollama => {
-                    Box::new(OllamaModel::new_from_conversation_and_model_name(
-                        current_conversation,
-                        saved_model.name.clone(),
-                    ))
-                }
-                crate::models::ModelType::Api(api_key, api_type) => {
-                    Box::new(ApiModel::new_from_conversation_and_model_name(
-                        current_conversation,
-                        saved_model.name.clone(),
-                        api_key.clone(),
-                        api_type.clone(),
-                    ))
+            match saved_model.load_model(current_conversation) {
+                Ok(new_chat_model) => *chat_model.lock().unwrap() = new_chat_model,
+                Err(err) => {
+                    println!("Error loading {}: {:?}", saved_model.name, err);
+                    return;
                 }
-            };
+            }
             println!("Selected: {}", selected_text);
         }
     }
@@ -128,13 +278,15 @@ impl HeaderWidget {
         main_content_box: gtk::Box,
         conversation_file_option_sender: Sender<Option<PathBuf>>,
         chat_model: Arc<Mutex<Box<dyn CoreLLM>>>,
+        model_registry: &ModelRegistry,
     ) -> Self {
         // Create new chat button, this restarts the conversation, saves the current one, and clears the conversation list
         let new_chat_button = Self::create_new_chat_button(conversation_file_option_sender);
         let sidebar_toggle_button =
             Self::create_sidebar_toggle_button(sidebar_widget, main_content_box);
 
-        let menu_popover = Self::create_menu_popover();
+        let rag_dropdown = RagDropdown::new();
+        let menu_popover = Self::create_menu_popover(model_registry, &rag_dropdown);
         let menu_button = gtk::Button::builder()
             .icon_name("open-menu-symbolic")
             .build();
@@ -143,8 +295,7 @@ impl HeaderWidget {
             menu_popover.popup();
         });
 
-        let rag_dropdown = RagDropdown::new();
-        let model_dropdown = ModelDropdown::new(chat_model);
+        let model_dropdown = ModelDropdown::new(chat_model, model_registry);
 
         let main_bar = gtk::HeaderBar::builder().show_title_buttons(true).build();
         main_bar.pack_start(&sidebar_toggle_button);
@@ -202,7 +353,10 @@ impl HeaderWidget {
             .build()
     }
 
-    fn create_menu_popover() -> gtk::Popover {
+    fn create_menu_popover(
+        model_registry: &ModelRegistry,
+        rag_dropdown: &RagDropdown,
+    ) -> gtk::Popover {
         let menu_box = gtk::Box::builder()
             .orientation(gtk::Orientation::Vertical)
             .spacing(4)
@@ -227,7 +381,7 @@ impl HeaderWidget {
             about_dialog.grab_focus();
         });
 
-        let preferences_widget = PreferencesWidget::new();
+        let preferences_widget = PreferencesWidget::new(model_registry, rag_dropdown);
         preferences_button.connect_clicked(move |_| {
             preferences_widget.dialog.show();
             preferences_widget.dialog.grab_focus();
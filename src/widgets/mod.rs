@@ -1,6 +1,17 @@
+pub mod attachment_chip;
+pub mod attachment_settings;
 pub mod chat_list_item;
+pub mod document_index_manager;
+pub mod fallback_chain;
+pub mod inspector_settings;
 pub mod main_header;
 pub mod model_manager;
 pub mod preferences;
 pub mod prompt_entry;
+pub mod rag_sources;
+pub mod remote_model_manager;
+pub mod request_inspector;
+pub mod script_source_manager;
 pub mod sidebar;
+pub mod speech_settings;
+pub mod vault_unlock;
@@ -4,7 +4,10 @@ use std::{
     io::Read,
     path::PathBuf,
     process::Command,
-    sync::mpsc::{self, Receiver, Sender},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
 };
 
 use adw::prelude::*;
@@ -13,35 +16,47 @@ use ollama_rs::Ollama;
 
 use crate::{
     models::{
+        model_registry::{ModelRegistry, ModelRegistryUpdate},
         ollama_model::{ModelInfo, OllamaModel},
-        ModelType, UtilsLLM,
+        ModelType, SavedModel, UtilsLLM,
     },
     utils::get_root_folder,
 };
+
+use super::remote_model_manager::RemoteModelManagerWidget;
 /*
 two tabs
     Local
-        On open, list models, if fail, have a dialog for selecting a different ollama url or installing ollama
+        On open, show the model registry's last known list, if Ollama isn't reachable, have a dialog for selecting a different ollama url or installing ollama
             Entry for new url
             Button for install ollama
                 Show output from installation process
         Open list of all available ollama models
         If downloaded, have delete button
         If not downloaded, have download button
+        If downloaded but the registry has a newer version, show an update badge and update button
+        Update all button for every outdated model
+        Download and update errors are shown in the model's row
         Have name, short description, and size
     Remote
-        Will work on this later
+        See RemoteModelManagerWidget
 Start on Local
 */
 
+#[derive(Clone)]
 struct ModelListItem {
     button: gtk::Button,
+    update_button: gtk::Button,
+    update_badge_label: gtk::Label,
+    model_download_progress_bar: gtk::ProgressBar,
+    error_label: gtk::Label,
     main_box: gtk::Box,
     model_info: ModelInfo,
+    is_downloaded: Arc<Mutex<bool>>,
 }
 
 impl ModelListItem {
-    pub fn new(model_info: ModelInfo) -> Self {
+    pub fn new(model_info: ModelInfo, model_registry: &ModelRegistry) -> Self {
         let detail_box = gtk::Box::builder()
             .spacing(5)
             .hexpand(true)
@@ -67,104 +82,202 @@ impl ModelListItem {
             .wrap(true)
             .label(&model_info.description)
             .build();
+        let update_badge_label = gtk::Label::builder()
+            .selectable(false)
+            .label("Update available")
+            .css_classes(["update-badge"])
+            .visible(model_info.is_outdated)
+            .build();
         let model_download_progress_bar = gtk::ProgressBar::builder()
             .text(format!("Downloading model: {}", model_info.download_name))
             .visible(false)
             .show_text(true)
             .build();
+        let error_label = gtk::Label::builder()
+            .selectable(true)
+            .wrap(true)
+            .css_classes(["error"])
+            .visible(false)
+            .build();
         detail_box.append(&display_name_label);
         detail_box.append(&download_name_label);
         detail_box.append(&size_in_b_label);
         detail_box.append(&description_label);
+        detail_box.append(&update_badge_label);
         detail_box.append(&model_download_progress_bar);
+        detail_box.append(&error_label);
         let main_box = gtk::Box::builder()
             .spacing(5)
             .orientation(gtk::Orientation::Horizontal)
             .build();
-        let button = if model_info.is_downloaded {
-            let temp_button = gtk::Button::builder()
-                .icon_name("user-trash-symbolic")
-                .css_classes(["is-downloaded-button"])
-                .build();
-            {
-                let download_name = model_info.download_name.clone();
-                let model_download_progress_bar = model_download_progress_bar.clone();
-                temp_button.connect_clicked(move |button| {
-                    ModelListItem::delete_button(
-                        button,
-                        download_name.clone(),
-                        model_download_progress_bar.clone(),
-                    )
-                });
-            }
-            temp_button
-        } else {
-            let temp_button = gtk::Button::builder()
-                .icon_name("document-save-symbolic")
-                .css_classes(["not-downloaded-button"])
-                .build();
-            {
-                let download_name = model_info.download_name.clone();
-                let model_download_progress_bar = model_download_progress_bar.clone();
-                temp_button.connect_clicked(move |button| {
+        let is_downloaded = Arc::new(Mutex::new(model_info.is_downloaded));
+        let button = gtk::Button::new();
+        ModelListItem::set_button_state(&button, model_info.is_downloaded);
+        {
+            let download_name = model_info.download_name.clone();
+            let model_download_progress_bar = model_download_progress_bar.clone();
+            let error_label = error_label.clone();
+            let is_downloaded = Arc::clone(&is_downloaded);
+            let model_registry = model_registry.clone();
+            button.connect_clicked(move |button| {
+                let was_downloaded = *is_downloaded.lock().unwrap();
+                if was_downloaded {
+                    ModelListItem::delete_button(button, download_name.clone(), &model_registry);
+                } else {
                     ModelListItem::download_button(
                         button,
                         download_name.clone(),
                         model_download_progress_bar.clone(),
-                    )
-                });
-            }
-            temp_button
-        };
+                        error_label.clone(),
+                        &model_registry,
+                    );
+                }
+                *is_downloaded.lock().unwrap() = !was_downloaded;
+            });
+        }
+        let update_button = gtk::Button::builder()
+            .icon_name("software-update-available-symbolic")
+            .tooltip_text("Update model")
+            .visible(model_info.is_outdated)
+            .build();
+        if model_info.is_downloaded {
+            ModelListItem::check_for_update(
+                model_info.download_name.clone(),
+                update_button.clone(),
+                update_badge_label.clone(),
+            );
+        }
         main_box.append(&detail_box);
+        main_box.append(&update_button);
         main_box.append(&button);
-        Self {
+        let model_list_item = Self {
             button,
+            update_button,
+            update_badge_label,
+            model_download_progress_bar,
+            error_label,
             main_box,
             model_info,
+            is_downloaded,
+        };
+        {
+            let model_list_item_for_update = model_list_item.clone();
+            model_list_item.update_button.connect_clicked(move |_| {
+                model_list_item_for_update.update();
+            });
         }
+        model_list_item
     }
 
-    fn delete_button(
-        button: &gtk::Button,
-        download_name: String,
-        model_download_progress_bar: gtk::ProgressBar,
-    ) {
-        glib::MainContext::default().spawn_local(OllamaModel::delete_model(download_name.clone()));
-        button.set_icon_name("document-save-symbolic");
-        button.set_css_classes(&["not-downloaded-button"]);
-        button.connect_clicked(move |button| {
-            ModelListItem::download_button(
-                button,
+    // Shared by the row's update button and the update all button
+    fn update(&self) {
+        if !self.update_button.is_visible() || !self.update_button.is_sensitive() {
+            return;
+        }
+        self.update_button.set_sensitive(false);
+        self.error_label.hide();
+        let model_list_item = self.clone();
+        glib::MainContext::default().spawn_local(async move {
+            let download_name = model_list_item.model_info.download_name.clone();
+            let update_result = OllamaModel::update_model(
                 download_name.clone(),
-                model_download_progress_bar.clone(),
+                &model_list_item.model_download_progress_bar,
             )
+            .await;
+            model_list_item.update_button.set_sensitive(true);
+            match update_result {
+                Ok(()) => {
+                    model_list_item.update_button.hide();
+                    model_list_item.update_badge_label.hide();
+                }
+                Err(err) => {
+                    println!("Error updating {}: {:?}", download_name, err);
+                    model_list_item
+                        .error_label
+                        .set_text(&format!("Couldn't update: {}", err));
+                    model_list_item.error_label.show();
+                }
+            }
         });
     }
 
+    // Called when the model registry finds a model was pulled or deleted elsewhere
+    fn set_downloaded(&self, is_downloaded: bool) {
+        if *self.is_downloaded.lock().unwrap() == is_downloaded {
+            return;
+        }
+        *self.is_downloaded.lock().unwrap() = is_downloaded;
+        ModelListItem::set_button_state(&self.button, is_downloaded);
+        if is_downloaded {
+            ModelListItem::check_for_update(
+                self.model_info.download_name.clone(),
+                self.update_button.clone(),
+                self.update_badge_label.clone(),
+            );
+        } else {
+            self.update_button.hide();
+            self.update_badge_label.hide();
+        }
+    }
+
+    fn set_button_state(button: &gtk::Button, is_downloaded: bool) {
+        if is_downloaded {
+            button.set_icon_name("user-trash-symbolic");
+            button.set_css_classes(&["is-downloaded-button"]);
+        } else {
+            button.set_icon_name("document-save-symbolic");
+            button.set_css_classes(&["not-downloaded-button"]);
+        }
+    }
+
+    fn check_for_update(
+        download_name: String,
+        update_button: gtk::Button,
+        update_badge_label: gtk::Label,
+    ) {
+        glib::MainContext::default().spawn_local(async move {
+            match OllamaModel::is_model_outdated(download_name.clone()).await {
+                Ok(is_outdated) => {
+                    update_button.set_visible(is_outdated);
+                    update_badge_label.set_visible(is_outdated);
+                }
+                Err(err) => {
+                    println!("Error checking for update to {}: {:?}", download_name, err);
+                }
+            }
+        });
+    }
+
+    fn delete_button(button: &gtk::Button, download_name: String, model_registry: &ModelRegistry) {
+        let model_registry = model_registry.clone();
+        glib::MainContext::default().spawn_local(async move {
+            OllamaModel::delete_model(download_name).await;
+            model_registry.refresh();
+        });
+        ModelListItem::set_button_state(button, false);
+    }
+
     fn download_button(
         button: &gtk::Button,
         download_name: String,
         model_download_progress_bar: gtk::ProgressBar,
+        error_label: gtk::Label,
+        model_registry: &ModelRegistry,
     ) {
-        let model_download_progress_bar_for_thread = model_download_progress_bar.clone();
-        let download_name_for_thread = download_name.clone();
+        let model_registry = model_registry.clone();
+        error_label.hide();
         glib::MainContext::default().spawn_local(async move {
-            OllamaModel::pull_model(
-                download_name_for_thread,
-                &model_download_progress_bar_for_thread,
-            )
-            .await;
-        });
-        button.set_icon_name("user-trash-symbolic");
-        button.set_css_classes(&["is-downloaded-button"]);
-        button.connect_clicked(move |button| {
-            ModelListItem::delete_button(
-                button,
-                download_name.clone(),
-                model_download_progress_bar.clone(),
-            )
+            if let Err(err) =
+                OllamaModel::pull_model(download_name.clone(), &model_download_progress_bar).await
+            {
+                println!("Error downloading {}: {:?}", download_name, err);
+                error_label.set_text(&format!("Couldn't download: {}", err));
+                error_label.show();
+            }
+            // Puts the button back to download if the pull failed
+            model_registry.refresh();
         });
+        ModelListItem::set_button_state(button, true);
     }
 }
 
@@ -174,7 +287,7 @@ pub struct ModelManagerWidget {
 }
 
 impl ModelManagerWidget {
-    pub fn new() -> Self {
+    pub fn new(model_registry: &ModelRegistry) -> Self {
         let ollama_model_list_path =
             get_root_folder().join(PathBuf::from("./ollama_model_list.json"));
         let mut ollama_model_list = if ollama_model_list_path.exists() {
@@ -191,61 +304,138 @@ impl ModelManagerWidget {
         } else {
             vec![]
         };
-        let main_box = gtk::Box::builder()
+        let local_box = gtk::Box::builder()
             .spacing(5)
             .orientation(gtk::Orientation::Vertical)
             .build();
-        if let Ok(saved_models) = OllamaModel::list_models() {
-            let scroll_window = gtk::ScrolledWindow::builder()
-                .hexpand(true)
-                .vexpand(true)
-                .build();
-            let list_widget = gtk::ListBox::builder().hexpand(true).vexpand(true).build();
-            let saved_models_names = saved_models
-                .iter()
-                .filter(|saved_model| matches!(saved_model.model_type, ModelType::Ollama))
-                .map(|saved_model| saved_model.name.clone())
-                .collect::<Vec<String>>();
-            ollama_model_list.iter_mut().for_each(|model_info| {
-                model_info.is_downloaded = saved_models_names.contains(&model_info.download_name);
-                let model_list_item_box = ModelListItem::new(model_info.clone()).main_box;
-                list_widget.append(&model_list_item_box);
-            });
-            scroll_window.set_child(Some(&list_widget));
-            main_box.append(&scroll_window);
-        } else {
-            let default_ollama_uri = Ollama::default().uri();
-            let error_label = gtk::Label::builder()
-                .label(format!(
+
+        // Shown while Ollama can't be reached
+        let ollama_missing_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .visible(!model_registry.ollama_available())
+            .build();
+        let default_ollama_uri = Ollama::default().uri();
+        let error_label = gtk::Label::builder()
+            .label(format!(
                 "Ollama was not found at {} Either enter the URI you're using or install Ollama.",
                 default_ollama_uri
             ))
-                .wrap(true)
-                .build();
-            main_box.append(&error_label);
-            let install_ollama_button = gtk::Button::builder().label("Install Ollama").build();
-            let installation_progress_spinner = gtk::Spinner::new();
-            main_box.append(&install_ollama_button);
-            main_box.append(&installation_progress_spinner);
-            install_ollama_button.connect_clicked(move |_| {
-                let installation_progress_spinner = installation_progress_spinner.clone();
-                let error_label = error_label.clone();
-                glib::MainContext::default().spawn_local(async move {
-                    execute_command(&installation_progress_spinner, &error_label).await;
-                });
-                println!("TEST3");
+            .wrap(true)
+            .build();
+        let install_ollama_button = gtk::Button::builder().label("Install Ollama").build();
+        let installation_progress_spinner = gtk::Spinner::new();
+        ollama_missing_box.append(&error_label);
+        ollama_missing_box.append(&install_ollama_button);
+        ollama_missing_box.append(&installation_progress_spinner);
+        install_ollama_button.connect_clicked(move |_| {
+            let installation_progress_spinner = installation_progress_spinner.clone();
+            let error_label = error_label.clone();
+            glib::MainContext::default().spawn_local(async move {
+                execute_command(&installation_progress_spinner, &error_label).await;
+            });
+        });
+
+        // Shown once Ollama is running
+        let model_list_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .visible(model_registry.ollama_available())
+            .build();
+        let scroll_window = gtk::ScrolledWindow::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .build();
+        let list_widget = gtk::ListBox::builder().hexpand(true).vexpand(true).build();
+        let saved_models_names = Self::downloaded_model_names(&model_registry.models());
+        let mut list_items = vec![];
+        ollama_model_list.iter_mut().for_each(|model_info| {
+            model_info.is_downloaded = saved_models_names.contains(&model_info.download_name);
+            let model_list_item = ModelListItem::new(model_info.clone(), model_registry);
+            list_widget.append(&model_list_item.main_box);
+            list_items.push(model_list_item);
+        });
+        let update_all_button = gtk::Button::builder()
+            .label("Update all")
+            .tooltip_text("Update all outdated models")
+            .build();
+        {
+            let list_items = list_items.clone();
+            update_all_button.connect_clicked(move |_| {
+                list_items.iter().for_each(ModelListItem::update);
             });
         }
+        scroll_window.set_child(Some(&list_widget));
+        model_list_box.append(&update_all_button);
+        model_list_box.append(&scroll_window);
+
+        local_box.append(&ollama_missing_box);
+        local_box.append(&model_list_box);
+
+        Self::create_model_list_listener_thread(
+            model_registry.subscribe(),
+            list_items,
+            ollama_missing_box,
+            model_list_box,
+        );
+
+        let remote_model_manager_widget = RemoteModelManagerWidget::new(model_registry);
+        let notebook = gtk::Notebook::builder().vexpand(true).build();
+        notebook.append_page(&local_box, Some(&gtk::Label::new(Some("Local"))));
+        notebook.append_page(
+            &remote_model_manager_widget.main_box,
+            Some(&gtk::Label::new(Some("Remote"))),
+        );
+        let main_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        main_box.append(&notebook);
         Self {
             main_box,
             ollama_model_list,
         }
     }
-}
 
-impl Default for ModelManagerWidget {
-    fn default() -> Self {
-        Self::new()
+    fn downloaded_model_names(saved_models: &[SavedModel]) -> Vec<String> {
+        saved_models
+            .iter()
+            .filter(|saved_model| matches!(saved_model.model_type, ModelType::Ollama))
+            .map(|saved_model| saved_model.name.clone())
+            .collect::<Vec<String>>()
+    }
+
+    fn create_model_list_listener_thread(
+        model_update_receiver: Receiver<ModelRegistryUpdate>,
+        list_items: Vec<ModelListItem>,
+        ollama_missing_box: gtk::Box,
+        model_list_box: gtk::Box,
+    ) {
+        glib::MainContext::default().spawn_local(async move {
+            loop {
+                match model_update_receiver.try_recv() {
+                    Ok(model_update) => {
+                        ollama_missing_box.set_visible(!model_update.ollama_available);
+                        model_list_box.set_visible(model_update.ollama_available);
+                        let saved_models_names = Self::downloaded_model_names(&model_update.models);
+                        list_items.iter().for_each(|model_list_item| {
+                            model_list_item.set_downloaded(
+                                saved_models_names
+                                    .contains(&model_list_item.model_info.download_name),
+                            )
+                        });
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {
+                        // No update available yet, wait a bit before checking again.
+                        glib::timeout_future(time::Duration::from_millis(500)).await;
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        println!("The model registry channel is disconnected.");
+                        break;
+                    }
+                }
+            }
+        });
     }
 }
 
@@ -1,19 +1,68 @@
+use std::sync::Arc;
+
 use adw::prelude::*;
 
+use crate::models::model_registry::ModelRegistry;
+use crate::settings::Settings;
+
+use super::attachment_settings::AttachmentSettingsWidget;
+use super::fallback_chain::FallbackChainWidget;
+use super::inspector_settings::InspectorSettingsWidget;
+use super::main_header::RagDropdown;
 use super::model_manager::ModelManagerWidget;
+use super::rag_sources::RagSourcesWidget;
+use super::speech_settings::SpeechSettingsWidget;
 
 pub struct PreferencesWidget {
     pub dialog: gtk::Dialog,
 }
 
 impl PreferencesWidget {
-    pub fn new() -> Self {
-        let model_manager_widget = ModelManagerWidget::new();
+    pub fn new(model_registry: &ModelRegistry, rag_dropdown: &RagDropdown) -> Self {
+        let model_manager_widget = ModelManagerWidget::new(model_registry);
+        let fallback_chain_widget = FallbackChainWidget::new(
+            Settings::load().fallback_models,
+            model_registry,
+            Arc::new(|fallback_models| {
+                let mut settings = Settings::load();
+                settings.fallback_models = fallback_models;
+                settings.save();
+            }),
+        );
+        let rag_sources_widget = RagSourcesWidget::new(rag_dropdown, model_registry);
+        let attachment_settings_widget = AttachmentSettingsWidget::new();
+        let speech_settings_widget = SpeechSettingsWidget::new();
+        let inspector_settings_widget = InspectorSettingsWidget::new();
+        let preferences_notebook = gtk::Notebook::new();
+        preferences_notebook.append_page(
+            &model_manager_widget.main_box,
+            Some(&gtk::Label::new(Some("Models"))),
+        );
+        preferences_notebook.append_page(
+            &fallback_chain_widget.main_box,
+            Some(&gtk::Label::new(Some("Fallback"))),
+        );
+        preferences_notebook.append_page(
+            &rag_sources_widget.main_box,
+            Some(&gtk::Label::new(Some("RAG sources"))),
+        );
+        preferences_notebook.append_page(
+            &attachment_settings_widget.main_box,
+            Some(&gtk::Label::new(Some("Attachments"))),
+        );
+        preferences_notebook.append_page(
+            &speech_settings_widget.main_box,
+            Some(&gtk::Label::new(Some("Speech"))),
+        );
+        preferences_notebook.append_page(
+            &inspector_settings_widget.main_box,
+            Some(&gtk::Label::new(Some("Inspector"))),
+        );
         let dialog = gtk::Dialog::builder()
             .title("Preferences")
             .default_height(300)
             .default_width(300)
-            .child(&model_manager_widget.main_box)
+            .child(&preferences_notebook)
             .build();
         Self { dialog }
     }
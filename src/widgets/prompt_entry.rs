@@ -1,16 +1,35 @@
 use adw::prelude::*;
 
-use gtk::glib;
+use gtk::{gdk, gio, glib};
 
 use std::{
+    fs,
     path::PathBuf,
-    sync::{Arc, Mutex},
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Arc, Mutex,
+    },
+    time,
 };
+
+use crate::{
+    attachments::{
+        self, audio,
+        pdf::{PageRange, PdfDocument},
+        Attachment, PreparedContent,
+    },
+    speech::{self, recorder::Recording},
+};
+
+use super::attachment_chip::AttachmentChip;
 /*
 - Prompt entry text field
 - Collapsible RAG search query text field
 - Submit prompt/stop generating button
 - Open file button
+- Microphone button that records, then transcribes into the entry with the local speech model
+- Attachment tray above the entry for the files to send
+- Files dropped on the widget and files or images pasted into the entry go to the tray
 */
 pub struct PromptEntryWidget {
     pub main_box: gtk::Box,
@@ -19,7 +38,7 @@ pub struct PromptEntryWidget {
     pub submit_button: gtk::Button,
     pub submit_button_signal_id: Arc<Mutex<Option<glib::SignalHandlerId>>>,
     pub prompt_entry_signal_id: Arc<Mutex<Option<glib::SignalHandlerId>>>,
-    pub selected_file: Arc<Mutex<Option<PathBuf>>>,
+    pub attachment_tray: AttachmentTray,
 }
 impl PromptEntryWidget {
     pub fn new() -> Self {
@@ -28,7 +47,6 @@ impl PromptEntryWidget {
             Arc::new(Mutex::new(None));
         let prompt_button_signal_id: Arc<Mutex<Option<glib::SignalHandlerId>>> =
             Arc::new(Mutex::new(None));
-        let selected_file = Arc::new(Mutex::new(None));
 
         // Create the widgets
         let prompt_entry_buffer = gtk::EntryBuffer::builder().text("").build();
@@ -46,12 +64,21 @@ impl PromptEntryWidget {
             .tooltip_text("Add file")
             .icon_name("document-open-symbolic")
             .build();
+        let voice_button = gtk::Button::builder()
+            .tooltip_text("Record voice input")
+            .icon_name("audio-input-microphone-symbolic")
+            .build();
         let prompt_box = gtk::Box::builder()
             .orientation(gtk::Orientation::Horizontal)
             .spacing(4)
             .build();
+        let main_box = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(4)
+            .build();
+        let attachment_tray = AttachmentTray::new();
 
-        let file_chooser = Self::create_file_chooser(Arc::clone(&selected_file));
+        let file_chooser = Self::create_file_chooser(attachment_tray.clone());
 
         add_file_button.connect_clicked(move |_| {
             file_chooser.show();
@@ -59,7 +86,13 @@ impl PromptEntryWidget {
 
         prompt_box.append(&prompt_entry);
         prompt_box.append(&add_file_button);
+        prompt_box.append(&voice_button);
         prompt_box.append(&prompt_button);
+        main_box.append(&attachment_tray.main_box);
+        main_box.append(&prompt_box);
+        attachment_tray.accept_drops(&main_box);
+        Self::connect_paste(&prompt_entry, attachment_tray.clone());
+        Self::connect_voice_input(&voice_button, &prompt_entry, attachment_tray.clone());
 
         PromptEntryWidget {
             prompt_entry,
@@ -67,38 +100,136 @@ impl PromptEntryWidget {
             submit_button: prompt_button,
             submit_button_signal_id: prompt_button_signal_id,
             prompt_entry_signal_id,
-            main_box: prompt_box,
-            selected_file,
+            main_box,
+            attachment_tray,
         }
     }
 
-    fn create_file_chooser(selected_file: Arc<Mutex<Option<PathBuf>>>) -> gtk::FileChooserNative {
+    fn create_file_chooser(attachment_tray: AttachmentTray) -> gtk::FileChooserNative {
         let file_filter = gtk::FileFilter::new();
-        file_filter.add_mime_type("image/png");
-        file_filter.add_mime_type("image/jpeg");
-        file_filter.add_mime_type("text/*");
-        file_filter.add_mime_type("application/pdf");
+        attachments::add_to_file_filter(&file_filter);
 
         let file_chooser = gtk::FileChooserNative::builder()
-            .title("Select a file")
+            .title("Select files")
             .action(gtk::FileChooserAction::Open)
+            .select_multiple(true)
             .filter(&file_filter)
             .build();
         file_chooser.connect_response(move |file_chooser, response| {
             if response == gtk::ResponseType::Accept {
-                if let Some(file) = file_chooser.file() {
-                    if let Some(name) = file.path() {
-                        if let Some(name_str) = name.to_str() {
-                            println!("Selected file: {}", name_str);
-                        }
-                        *selected_file.lock().unwrap() = Some(name);
-                    }
-                }
+                let files = file_chooser.files();
+                (0..files.n_items())
+                    .filter_map(|file_index| files.item(file_index))
+                    .filter_map(|file| file.downcast::<gio::File>().ok())
+                    .filter_map(|file| file.path())
+                    .for_each(|file_path| {
+                        println!("Selected file: {:?}", file_path);
+                        attachment_tray.add(file_path);
+                    });
             }
             file_chooser.hide();
         });
         file_chooser
     }
+
+    // Ctrl+V and Shift+Insert are caught before the entry pastes, so files and images can go to the tray
+    fn connect_paste(prompt_entry: &gtk::Entry, attachment_tray: AttachmentTray) {
+        let key_controller = gtk::EventControllerKey::new();
+        key_controller.set_propagation_phase(gtk::PropagationPhase::Capture);
+        let prompt_entry_for_closure = prompt_entry.clone();
+        key_controller.connect_key_pressed(move |_, key, _, modifier_state| {
+            let is_paste = (modifier_state.contains(gdk::ModifierType::CONTROL_MASK)
+                && matches!(key, gdk::Key::v | gdk::Key::V))
+                || (modifier_state.contains(gdk::ModifierType::SHIFT_MASK)
+                    && key == gdk::Key::Insert);
+            if is_paste {
+                attachment_tray.paste_clipboard(&prompt_entry_for_closure)
+            } else {
+                glib::Propagation::Proceed
+            }
+        });
+        prompt_entry.add_controller(key_controller);
+    }
+}
+
+impl PromptEntryWidget {
+    /*
+    The first click starts recording, the second stops it and transcribes on a thread
+    - The transcript is put in the entry at the cursor, so it can be checked before sending
+    - Nothing is recorded until a speech model is downloaded, as there'd be nothing to transcribe with
+    */
+    fn connect_voice_input(
+        voice_button: &gtk::Button,
+        prompt_entry: &gtk::Entry,
+        attachment_tray: AttachmentTray,
+    ) {
+        let recording: Arc<Mutex<Option<Recording>>> = Arc::new(Mutex::new(None));
+        let prompt_entry = prompt_entry.clone();
+        voice_button.connect_clicked(move |voice_button| {
+            let active_recording = recording.lock().unwrap().take();
+            let Some(active_recording) = active_recording else {
+                if !speech::selected_model_size().is_downloaded() {
+                    attachment_tray
+                        .show_error("Download a speech model in Preferences to use voice input");
+                    return;
+                }
+                match Recording::start() {
+                    Ok(new_recording) => {
+                        *recording.lock().unwrap() = Some(new_recording);
+                        voice_button.set_icon_name("media-playback-stop-symbolic");
+                        voice_button.set_tooltip_text(Some("Stop recording and transcribe"));
+                        voice_button.add_css_class("destructive-action");
+                    }
+                    Err(err) => attachment_tray.show_error(&format!("Recording failed: {}", err)),
+                }
+                return;
+            };
+            voice_button.remove_css_class("destructive-action");
+            voice_button.set_icon_name("emblem-synchronizing-symbolic");
+            voice_button.set_tooltip_text(Some("Transcribing"));
+            voice_button.set_sensitive(false);
+            let (transcript_sender, transcript_receiver): (
+                Sender<Result<String, String>>,
+                Receiver<Result<String, String>>,
+            ) = mpsc::channel();
+            std::thread::spawn(move || {
+                let transcript_result = active_recording.stop().and_then(|samples| {
+                    speech::transcribe(&samples).map_err(|err| err.to_string())
+                });
+                transcript_sender.send(transcript_result).unwrap();
+            });
+            let voice_button = voice_button.clone();
+            let prompt_entry = prompt_entry.clone();
+            let attachment_tray = attachment_tray.clone();
+            glib::MainContext::default().spawn_local(async move {
+                loop {
+                    match transcript_receiver.try_recv() {
+                        Ok(Ok(transcript)) => {
+                            if !transcript.is_empty() {
+                                insert_at_cursor(&prompt_entry, &transcript);
+                            }
+                            break;
+                        }
+                        Ok(Err(err)) => {
+                            attachment_tray.show_error(&format!("Transcription failed: {}", err));
+                            break;
+                        }
+                        Err(mpsc::TryRecvError::Empty) => {
+                            glib::timeout_future(time::Duration::from_millis(100)).await;
+                        }
+                        Err(mpsc::TryRecvError::Disconnected) => {
+                            println!("The transcription channel is disconnected.");
+                            break;
+                        }
+                    }
+                }
+                voice_button.set_icon_name("audio-input-microphone-symbolic");
+                voice_button.set_tooltip_text(Some("Record voice input"));
+                voice_button.set_sensitive(true);
+                prompt_entry.grab_focus();
+            });
+        });
+    }
 }
 
 impl Default for PromptEntryWidget {
@@ -106,3 +237,491 @@ impl Default for PromptEntryWidget {
         Self::new()
     }
 }
+
+// Files that are slow to read are read as soon as they're added, sending waits for them
+#[derive(Clone)]
+enum Preparation {
+    NotNeeded,
+    // What's being done, for telling the user why the prompt can't be sent yet
+    Running(&'static str),
+    Done(PreparedContent),
+}
+
+// A file waiting in the tray, PDFs carry the entry for the pages to send
+struct PendingAttachment {
+    file_path: PathBuf,
+    page_range_entry: Option<gtk::Entry>,
+    chip: AttachmentChip,
+    preparation: Arc<Mutex<Preparation>>,
+}
+
+/*
+Shows the files that will be sent with the next prompt, as chips that can be removed
+- PDFs get a page range entry and are read on a thread to count pages, the text is kept for sending
+- Scanned PDFs with no text layer get a warning, as there's nothing to send without OCR
+- Audio is transcribed and other files are read on threads, the prompt can't be sent until every file is read
+- Adding a file that's already in the tray does nothing
+*/
+#[derive(Clone)]
+pub struct AttachmentTray {
+    pub main_box: gtk::Box,
+    chips_box: gtk::FlowBox,
+    error_label: gtk::Label,
+    pending_attachments: Arc<Mutex<Vec<PendingAttachment>>>,
+}
+
+impl AttachmentTray {
+    fn new() -> Self {
+        let chips_box = gtk::FlowBox::builder()
+            .selection_mode(gtk::SelectionMode::None)
+            .column_spacing(4)
+            .row_spacing(4)
+            .max_children_per_line(4)
+            .build();
+        let error_label = gtk::Label::builder()
+            .xalign(0.0)
+            .wrap(true)
+            .css_classes(["error"])
+            .visible(false)
+            .build();
+        let main_box = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(4)
+            .visible(false)
+            .build();
+        main_box.append(&chips_box);
+        main_box.append(&error_label);
+        Self {
+            main_box,
+            chips_box,
+            error_label,
+            pending_attachments: Arc::new(Mutex::new(vec![])),
+        }
+    }
+
+    pub fn add(&self, file_path: PathBuf) {
+        let is_already_added = self
+            .pending_attachments
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|pending_attachment| pending_attachment.file_path == file_path);
+        if is_already_added {
+            return;
+        }
+        let is_pdf = file_path
+            .extension()
+            .map(|extension| extension.eq_ignore_ascii_case("pdf"))
+            .unwrap_or(false);
+        let chip = AttachmentChip::new(&Attachment::new(&file_path), true);
+        let preparation = Arc::new(Mutex::new(Preparation::NotNeeded));
+        let page_range_entry = if is_pdf {
+            let page_range_entry = gtk::Entry::builder()
+                .placeholder_text("Pages, e.g. 1-3, 7")
+                .tooltip_text("Pages to attach, all pages when empty")
+                .width_chars(12)
+                .build();
+            chip.add_entry(&page_range_entry);
+            Self::read_pdf(&file_path, chip.clone(), Arc::clone(&preparation));
+            Some(page_range_entry)
+        } else {
+            if audio::is_audio_file(&file_path) {
+                Self::transcribe_audio(&file_path, chip.clone(), Arc::clone(&preparation));
+            } else {
+                Self::read_file(&file_path, chip.clone(), Arc::clone(&preparation));
+            }
+            None
+        };
+        {
+            let attachment_tray = self.clone();
+            let file_path = file_path.clone();
+            chip.remove_button.connect_clicked(move |_| {
+                attachment_tray.remove(&file_path);
+            });
+        }
+        self.chips_box.append(&chip.main_box);
+        self.pending_attachments
+            .lock()
+            .unwrap()
+            .push(PendingAttachment {
+                file_path,
+                page_range_entry,
+                chip,
+                preparation,
+            });
+        self.error_label.hide();
+        self.main_box.show();
+    }
+
+    fn remove(&self, file_path: &PathBuf) {
+        let mut pending_attachments = self.pending_attachments.lock().unwrap();
+        pending_attachments.retain(|pending_attachment| {
+            if pending_attachment.file_path == *file_path {
+                self.chips_box.remove(&pending_attachment.chip.main_box);
+                false
+            } else {
+                true
+            }
+        });
+        if pending_attachments.is_empty() {
+            self.error_label.hide();
+            self.main_box.hide();
+        }
+    }
+
+    fn read_pdf(file_path: &PathBuf, chip: AttachmentChip, preparation: Arc<Mutex<Preparation>>) {
+        *preparation.lock().unwrap() = Preparation::Running("read");
+        let (pdf_sender, pdf_receiver): (
+            Sender<Result<PdfDocument, String>>,
+            Receiver<Result<PdfDocument, String>>,
+        ) = mpsc::channel();
+        {
+            let file_path = file_path.clone();
+            std::thread::spawn(move || {
+                let pdf_result = PdfDocument::open(&file_path).map_err(|err| err.to_string());
+                pdf_sender.send(pdf_result).unwrap();
+            });
+        }
+        chip.show_detail("reading");
+        let file_name = attachments::file_name(file_path);
+        glib::MainContext::default().spawn_local(async move {
+            loop {
+                match pdf_receiver.try_recv() {
+                    Ok(Ok(pdf_document)) => {
+                        if pdf_document.has_text_layer() {
+                            chip.show_detail(&format!("{} pages", pdf_document.pages.len()));
+                        } else {
+                            chip.show_warning(
+                                "no text layer",
+                                &format!(
+                                    "{} looks scanned, run it through OCR to send its text",
+                                    file_name
+                                ),
+                            );
+                        }
+                        *preparation.lock().unwrap() =
+                            Preparation::Done(PreparedContent::Pdf(pdf_document));
+                        break;
+                    }
+                    Ok(Err(err)) => {
+                        chip.show_warning("couldn't be read", &err);
+                        *preparation.lock().unwrap() =
+                            Preparation::Done(PreparedContent::Failed(err));
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {
+                        glib::timeout_future(time::Duration::from_millis(100)).await;
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        println!("The PDF reader channel is disconnected.");
+                        *preparation.lock().unwrap() = Preparation::Done(PreparedContent::Failed(
+                            String::from("reading the PDF stopped"),
+                        ));
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    // Documents, text files and images, what's read is kept for when it's sent
+    fn read_file(file_path: &PathBuf, chip: AttachmentChip, preparation: Arc<Mutex<Preparation>>) {
+        *preparation.lock().unwrap() = Preparation::Running("read");
+        let (file_sender, file_receiver): (Sender<PreparedContent>, Receiver<PreparedContent>) =
+            mpsc::channel();
+        {
+            let file_path = file_path.clone();
+            std::thread::spawn(move || {
+                file_sender.send(attachments::prepare(&file_path)).unwrap();
+            });
+        }
+        chip.show_detail("reading");
+        let size_text = glib::format_size(Attachment::new(file_path).size_bytes);
+        glib::MainContext::default().spawn_local(async move {
+            loop {
+                match file_receiver.try_recv() {
+                    Ok(prepared_content) => {
+                        match &prepared_content {
+                            PreparedContent::Failed(err) => {
+                                chip.show_warning("couldn't be read", err)
+                            }
+                            _ => chip.show_detail(&size_text),
+                        }
+                        *preparation.lock().unwrap() = Preparation::Done(prepared_content);
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {
+                        glib::timeout_future(time::Duration::from_millis(100)).await;
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        println!("The file reader channel is disconnected.");
+                        *preparation.lock().unwrap() = Preparation::Done(PreparedContent::Failed(
+                            String::from("reading the file stopped"),
+                        ));
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    // Done ahead of sending so the prompt isn't held up, the transcript is kept for when it's sent
+    fn transcribe_audio(
+        file_path: &PathBuf,
+        chip: AttachmentChip,
+        preparation: Arc<Mutex<Preparation>>,
+    ) {
+        if !speech::selected_model_size().is_downloaded() {
+            let explanation =
+                "Download a speech model in Preferences to send audio as a transcript";
+            chip.show_warning("no speech model", explanation);
+            *preparation.lock().unwrap() =
+                Preparation::Done(PreparedContent::Failed(explanation.to_string()));
+            return;
+        }
+        *preparation.lock().unwrap() = Preparation::Running("transcribed");
+        let (transcript_sender, transcript_receiver): (
+            Sender<Result<String, String>>,
+            Receiver<Result<String, String>>,
+        ) = mpsc::channel();
+        {
+            let file_path = file_path.clone();
+            std::thread::spawn(move || {
+                let transcript_result =
+                    speech::transcribe_file(&file_path).map_err(|err| err.to_string());
+                transcript_sender.send(transcript_result).unwrap();
+            });
+        }
+        chip.show_detail("transcribing");
+        glib::MainContext::default().spawn_local(async move {
+            loop {
+                match transcript_receiver.try_recv() {
+                    Ok(Ok(transcript)) => {
+                        chip.show_detail("transcribed");
+                        *preparation.lock().unwrap() =
+                            Preparation::Done(PreparedContent::Transcript(transcript));
+                        break;
+                    }
+                    Ok(Err(err)) => {
+                        chip.show_warning("couldn't be transcribed", &err);
+                        *preparation.lock().unwrap() =
+                            Preparation::Done(PreparedContent::Failed(err));
+                        break;
+                    }
+                    Err(mpsc::TryRecvError::Empty) => {
+                        glib::timeout_future(time::Duration::from_millis(100)).await;
+                    }
+                    Err(mpsc::TryRecvError::Disconnected) => {
+                        println!("The transcription channel is disconnected.");
+                        *preparation.lock().unwrap() = Preparation::Done(PreparedContent::Failed(
+                            String::from("transcription stopped"),
+                        ));
+                        break;
+                    }
+                }
+            }
+        });
+    }
+
+    /*
+    Each file with the pages to send and what was read ahead of time
+    - The page range is None when every page should be sent
+    - Errors when a page range is invalid or a file is still being read, so the prompt isn't sent yet
+    */
+    pub fn attachments(
+        &self,
+    ) -> Result<Vec<(PathBuf, Option<PageRange>, Option<PreparedContent>)>, String> {
+        self.pending_attachments
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|pending_attachment| {
+                let prepared_content = match &*pending_attachment.preparation.lock().unwrap() {
+                    Preparation::NotNeeded => None,
+                    Preparation::Running(activity) => {
+                        return Err(format!(
+                            "{0} is still being {1}, send the prompt once it's done",
+                            attachments::file_name(&pending_attachment.file_path),
+                            activity
+                        ));
+                    }
+                    Preparation::Done(prepared_content) => Some(prepared_content.clone()),
+                };
+                let page_range = match &pending_attachment.page_range_entry {
+                    Some(page_range_entry) if !page_range_entry.text().trim().is_empty() => {
+                        Some(PageRange::parse(&page_range_entry.text()).map_err(|err| {
+                            format!(
+                                "{0}: {1}",
+                                attachments::file_name(&pending_attachment.file_path),
+                                err
+                            )
+                        })?)
+                    }
+                    _ => None,
+                };
+                Ok((
+                    pending_attachment.file_path.clone(),
+                    page_range,
+                    prepared_content,
+                ))
+            })
+            .collect()
+    }
+
+    // Files from a file manager and images dragged from other apps, e.g. a browser
+    pub fn accept_drops(&self, widget: &impl IsA<gtk::Widget>) {
+        let drop_target = gtk::DropTarget::new(glib::Type::INVALID, gdk::DragAction::COPY);
+        drop_target.set_types(&[gdk::FileList::static_type(), gdk::Texture::static_type()]);
+        let attachment_tray = self.clone();
+        drop_target.connect_drop(move |_, value, _, _| {
+            if let Ok(file_list) = value.get::<gdk::FileList>() {
+                file_list
+                    .files()
+                    .iter()
+                    .filter_map(|file| file.path())
+                    .for_each(|file_path| attachment_tray.add(file_path));
+                true
+            } else if let Ok(texture) = value.get::<gdk::Texture>() {
+                attachment_tray.add_texture(&texture);
+                true
+            } else {
+                false
+            }
+        });
+        widget.add_controller(drop_target);
+    }
+
+    /*
+    Pastes from the clipboard, checked in order as copying can offer several formats at once
+    - Copied files are attached
+    - Images, e.g. screenshots, are saved as PNGs and attached
+    - Text longer than LONG_PASTE_CHARS is offered as an attachment, shorter text goes in the entry
+    */
+    fn paste_clipboard(&self, prompt_entry: &gtk::Entry) -> glib::Propagation {
+        let clipboard = prompt_entry.clipboard();
+        // Includes what the offered MIME types can be read as, e.g. image/png as a texture
+        let formats = clipboard.formats().union_deserialize_gtypes();
+        let attachment_tray = self.clone();
+        if formats.contain_gtype(gdk::FileList::static_type()) {
+            clipboard.read_value_async(
+                gdk::FileList::static_type(),
+                glib::Priority::DEFAULT,
+                None::<&gio::Cancellable>,
+                move |value_result| match value_result
+                    .map_err(|err| err.to_string())
+                    .and_then(|value| value.get::<gdk::FileList>().map_err(|err| err.to_string()))
+                {
+                    Ok(file_list) => file_list
+                        .files()
+                        .iter()
+                        .filter_map(|file| file.path())
+                        .for_each(|file_path| attachment_tray.add(file_path)),
+                    Err(err) => println!("Error pasting files: {:?}", err),
+                },
+            );
+            glib::Propagation::Stop
+        } else if formats.contain_gtype(gdk::Texture::static_type()) {
+            clipboard.read_texture_async(None::<&gio::Cancellable>, move |texture_result| {
+                match texture_result {
+                    Ok(Some(texture)) => attachment_tray.add_texture(&texture),
+                    Ok(None) => println!("The clipboard had no image to paste"),
+                    Err(err) => println!("Error pasting image: {:?}", err),
+                }
+            });
+            glib::Propagation::Stop
+        } else if formats.contain_gtype(glib::GString::static_type()) {
+            let prompt_entry = prompt_entry.clone();
+            clipboard.read_text_async(None::<&gio::Cancellable>, move |text_result| {
+                match text_result {
+                    Ok(Some(text)) if text.chars().count() > attachments::LONG_PASTE_CHARS => {
+                        attachment_tray.offer_text_attachment(&prompt_entry, text.to_string())
+                    }
+                    Ok(Some(text)) => insert_at_cursor(&prompt_entry, &text),
+                    Ok(None) => {}
+                    Err(err) => println!("Error pasting text: {:?}", err),
+                }
+            });
+            glib::Propagation::Stop
+        } else {
+            glib::Propagation::Proceed
+        }
+    }
+
+    fn add_texture(&self, texture: &gdk::Texture) {
+        let save_result =
+            attachments::pasted_file_path("Pasted image", "png").and_then(|file_path| {
+                texture.save_to_png(&file_path)?;
+                Ok(file_path)
+            });
+        match save_result {
+            Ok(file_path) => self.add(file_path),
+            Err(err) => {
+                println!("Error saving pasted image: {:?}", err);
+                self.show_error(&format!("The pasted image couldn't be saved: {}", err));
+            }
+        }
+    }
+
+    fn offer_text_attachment(&self, prompt_entry: &gtk::Entry, text: String) {
+        let message_dialog = adw::MessageDialog::builder()
+            .heading("Attach Pasted Text?")
+            .body(format!(
+                "The pasted text is {} characters long. Attaching it as a file keeps the prompt short, and it's still sent with the prompt.",
+                text.chars().count()
+            ))
+            .modal(true)
+            .build();
+        if let Some(window) = prompt_entry.root().and_downcast::<gtk::Window>() {
+            message_dialog.set_transient_for(Some(&window));
+        }
+        message_dialog.add_response("inline", "Paste Inline");
+        message_dialog.add_response("attach", "Attach as File");
+        message_dialog.set_response_appearance("attach", adw::ResponseAppearance::Suggested);
+        message_dialog.set_default_response(Some("attach"));
+        message_dialog.set_close_response("inline");
+        let attachment_tray = self.clone();
+        let prompt_entry = prompt_entry.clone();
+        message_dialog.connect_response(None, move |_, response| {
+            if response != "attach" {
+                insert_at_cursor(&prompt_entry, &text);
+                return;
+            }
+            let save_result =
+                attachments::pasted_file_path("Pasted text", "txt").and_then(|file_path| {
+                    fs::write(&file_path, &text)?;
+                    Ok(file_path)
+                });
+            match save_result {
+                Ok(file_path) => attachment_tray.add(file_path),
+                Err(err) => {
+                    println!("Error saving pasted text: {:?}", err);
+                    insert_at_cursor(&prompt_entry, &text);
+                }
+            }
+        });
+        message_dialog.present();
+    }
+
+    pub fn show_error(&self, error: &str) {
+        self.error_label.set_text(error);
+        self.error_label.show();
+        self.main_box.show();
+    }
+
+    pub fn clear(&self) {
+        self.pending_attachments.lock().unwrap().clear();
+        while let Some(chip) = self.chips_box.first_child() {
+            self.chips_box.remove(&chip);
+        }
+        self.error_label.hide();
+        self.main_box.hide();
+    }
+}
+
+// What the entry would have done with the paste, replacing any selection
+fn insert_at_cursor(prompt_entry: &gtk::Entry, text: &str) {
+    prompt_entry.delete_selection();
+    let mut position = prompt_entry.position();
+    prompt_entry.insert_text(text, &mut position);
+    prompt_entry.set_position(position);
+}
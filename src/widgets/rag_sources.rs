@@ -0,0 +1,264 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Mutex,
+};
+
+use adw::prelude::*;
+use gtk::glib;
+
+use crate::{
+    models::{model_registry::ModelRegistry, SavedModel},
+    rag::{
+        self,
+        pipeline::{self, RetrievalPipeline},
+    },
+    settings::Settings,
+    RagSource,
+};
+
+use super::{
+    document_index_manager::DocumentIndexManagerWidget, main_header::RagDropdown,
+    script_source_manager::ScriptSourceManagerWidget,
+};
+
+/*
+Preferences page for everything the RAG dropdown offers
+- Scripts and manifests in ./rag_sources
+- Document indexes with their status
+- Test query that shows what a source retrieves, nothing is sent to a model unless the source rewrites or re-ranks
+- Query rewriting and re-ranking for the source picked for the test query, and the model that does them
+- Refreshes the header's RAG dropdown whenever a source is added, changed or removed
+*/
+pub struct RagSourcesWidget {
+    pub main_box: gtk::Box,
+}
+
+impl RagSourcesWidget {
+    pub fn new(rag_dropdown: &RagDropdown, model_registry: &ModelRegistry) -> Self {
+        let test_rag_dropdown = RagDropdown::new();
+        let on_change: Arc<dyn Fn()> = {
+            let rag_dropdown = rag_dropdown.clone();
+            let test_rag_dropdown = test_rag_dropdown.clone();
+            Arc::new(move || {
+                rag_dropdown.refresh();
+                test_rag_dropdown.refresh();
+            })
+        };
+        let script_source_manager_widget = ScriptSourceManagerWidget::new(Arc::clone(&on_change));
+        let document_index_manager_widget = DocumentIndexManagerWidget::new(on_change);
+
+        let stack = gtk::Stack::builder().vexpand(true).build();
+        stack.add_titled(
+            &script_source_manager_widget.main_box,
+            Some("scripts"),
+            "Scripts",
+        );
+        stack.add_titled(
+            &document_index_manager_widget.main_box,
+            Some("documents"),
+            "Documents",
+        );
+        stack.add_titled(
+            &Self::create_test_query_box(test_rag_dropdown, model_registry.models()),
+            Some("test"),
+            "Test query",
+        );
+        let stack_switcher = gtk::StackSwitcher::builder()
+            .stack(&stack)
+            .halign(gtk::Align::Center)
+            .build();
+
+        let main_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        main_box.append(&stack_switcher);
+        main_box.append(&stack);
+        Self { main_box }
+    }
+
+    /*
+    Toggles for the selected source's pipeline, saved as soon as they change
+    - The helper model applies to every source, without one the model answering the prompt is used
+    */
+    fn create_pipeline_box(test_rag_dropdown: &RagDropdown, models: Vec<SavedModel>) -> gtk::Box {
+        let rewrite_check_button = gtk::CheckButton::builder()
+            .label("Rewrite the prompt into a standalone search query")
+            .build();
+        let rerank_check_button = gtk::CheckButton::builder()
+            .label("Re-rank the results")
+            .build();
+        let helper_model_names = std::iter::once(String::from("Model answering the prompt"))
+            .chain(models.iter().map(|saved_model| saved_model.display_name()))
+            .collect::<Vec<String>>();
+        let helper_model_dropdown = gtk::DropDown::from_strings(
+            &helper_model_names
+                .iter()
+                .map(|model_name| model_name.as_str())
+                .collect::<Vec<&str>>(),
+        );
+        helper_model_dropdown.set_tooltip_text(Some("Model that rewrites and re-ranks"));
+        let helper_model_index = Settings::load()
+            .rag_helper_model
+            .and_then(|helper_model| {
+                models
+                    .iter()
+                    .position(|saved_model| *saved_model == helper_model)
+            })
+            .map(|model_index| model_index + 1)
+            .unwrap_or(0);
+        helper_model_dropdown.set_selected(helper_model_index as u32);
+        let pipeline_box = gtk::Box::builder()
+            .spacing(4)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        pipeline_box.append(&rewrite_check_button);
+        pipeline_box.append(&rerank_check_button);
+        pipeline_box.append(&helper_model_dropdown);
+
+        // Set while the toggles are loaded for another source, so that doesn't save over it
+        let is_loading = Arc::new(Mutex::new(false));
+        let load_pipeline = {
+            let test_rag_dropdown = test_rag_dropdown.clone();
+            let rewrite_check_button = rewrite_check_button.clone();
+            let rerank_check_button = rerank_check_button.clone();
+            let is_loading = Arc::clone(&is_loading);
+            move || {
+                let rag_source = test_rag_dropdown.selected_rag_source();
+                let retrieval_pipeline = RetrievalPipeline::for_source(&rag_source);
+                let has_source = !matches!(rag_source, RagSource::NoRag);
+                *is_loading.lock().unwrap() = true;
+                rewrite_check_button.set_active(retrieval_pipeline.rewrite_query);
+                rerank_check_button.set_active(retrieval_pipeline.rerank);
+                rewrite_check_button.set_sensitive(has_source);
+                rerank_check_button.set_sensitive(has_source);
+                *is_loading.lock().unwrap() = false;
+            }
+        };
+        load_pipeline();
+        test_rag_dropdown
+            .dropdown
+            .connect_selected_notify(move |_| load_pipeline());
+        let save_pipeline = {
+            let test_rag_dropdown = test_rag_dropdown.clone();
+            let rewrite_check_button = rewrite_check_button.clone();
+            let rerank_check_button = rerank_check_button.clone();
+            move || {
+                if *is_loading.lock().unwrap() {
+                    return;
+                }
+                RetrievalPipeline {
+                    rewrite_query: rewrite_check_button.is_active(),
+                    rerank: rerank_check_button.is_active(),
+                }
+                .save_for_source(&test_rag_dropdown.selected_rag_source());
+            }
+        };
+        let save_pipeline = Arc::new(save_pipeline);
+        {
+            let save_pipeline = Arc::clone(&save_pipeline);
+            rewrite_check_button.connect_toggled(move |_| save_pipeline());
+        }
+        rerank_check_button.connect_toggled(move |_| save_pipeline());
+        helper_model_dropdown.connect_selected_notify(move |helper_model_dropdown| {
+            let mut settings = Settings::load();
+            settings.rag_helper_model = (helper_model_dropdown.selected() as usize)
+                .checked_sub(1)
+                .and_then(|model_index| models.get(model_index).cloned());
+            settings.save();
+        });
+        pipeline_box
+    }
+
+    // The test button cancels while a query runs, like the prompt button does
+    fn create_test_query_box(test_rag_dropdown: RagDropdown, models: Vec<SavedModel>) -> gtk::Box {
+        let query_entry = gtk::Entry::builder()
+            .placeholder_text("Test query")
+            .hexpand(true)
+            .build();
+        let test_button = gtk::Button::builder().label("Test").build();
+        let query_box = gtk::Box::builder()
+            .spacing(4)
+            .orientation(gtk::Orientation::Horizontal)
+            .build();
+        query_box.append(&test_rag_dropdown.dropdown);
+        query_box.append(&query_entry);
+        query_box.append(&test_button);
+        let results_buffer = gtk::TextBuffer::builder().text("").build();
+        let results_textview = gtk::TextView::builder()
+            .buffer(&results_buffer)
+            .editable(false)
+            .wrap_mode(gtk::WrapMode::WordChar)
+            .build();
+        let scroll_window = gtk::ScrolledWindow::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .child(&results_textview)
+            .build();
+        let main_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        main_box.append(&query_box);
+        main_box.append(&Self::create_pipeline_box(&test_rag_dropdown, models));
+        main_box.append(&scroll_window);
+
+        let is_running = Arc::new(Mutex::new(false));
+        let cancel_flag = Arc::new(AtomicBool::new(false));
+        let query_entry_for_closure = query_entry.clone();
+        let test_button_for_closure = test_button.clone();
+        let run_test_query = move || {
+            let query_entry = &query_entry_for_closure;
+            if *is_running.lock().unwrap() {
+                cancel_flag.store(true, Ordering::Relaxed);
+                return;
+            }
+            let query = query_entry.text().trim().to_string();
+            if query.is_empty() {
+                return;
+            }
+            *is_running.lock().unwrap() = true;
+            cancel_flag.store(false, Ordering::Relaxed);
+            test_button_for_closure.set_label("Cancel");
+            let rag_source = test_rag_dropdown.selected_rag_source();
+            results_buffer.set_text(&format!("Searching {}", rag_source.name()));
+            let is_running = Arc::clone(&is_running);
+            let cancel_flag = Arc::clone(&cancel_flag);
+            let test_button = test_button_for_closure.clone();
+            let results_buffer = results_buffer.clone();
+            glib::MainContext::default().spawn_local(async move {
+                // There's no conversation here, so a rewrite only rephrases the query
+                let pipeline_results =
+                    pipeline::retrieve(&rag_source, &query, &[], None, None, &cancel_flag).await;
+                let results_text = match pipeline_results {
+                    Ok(pipeline_results) => {
+                        let searched_for = pipeline_results
+                            .rewritten_query
+                            .map(|rewritten_query| format!("Searched for: {}\n\n", rewritten_query))
+                            .unwrap_or_default();
+                        if pipeline_results.results.is_empty() {
+                            format!("{}Nothing was retrieved.", searched_for)
+                        } else {
+                            format!(
+                                "{0}{1}",
+                                searched_for,
+                                rag::format_results(&pipeline_results.results)
+                            )
+                        }
+                    }
+                    Err(err) => format!("Retrieval failed: {}", err),
+                };
+                results_buffer.set_text(&results_text);
+                test_button.set_label("Test");
+                *is_running.lock().unwrap() = false;
+            });
+        };
+        let run_test_query = Arc::new(run_test_query);
+        {
+            let run_test_query = Arc::clone(&run_test_query);
+            test_button.connect_clicked(move |_| run_test_query());
+        }
+        query_entry.connect_activate(move |_| run_test_query());
+        main_box
+    }
+}
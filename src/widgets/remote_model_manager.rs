@@ -0,0 +1,572 @@
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+use adw::prelude::*;
+use gtk::glib;
+
+use crate::models::{
+    api_model::{ApiModel, ApiTypeForSaving},
+    credential_store::{ApiKeyRef, CredentialStore},
+    model_registry::ModelRegistry,
+    ModelType, SavedModel, UtilsLLM,
+};
+use crate::widgets::vault_unlock::VaultUnlockWidget;
+
+/*
+Remote tab of the model manager
+- List of saved API models, each with edit and remove buttons
+- Form for adding or editing a model
+    - Provider dropdown, OpenAI or any OpenAI compatible server
+    - Base URL entry for compatible servers
+    - API key entry, saved to the credential store
+    - Model name entry, with a button to fill a dropdown from the provider's models endpoint
+    - Test connection button
+    - Save and clear buttons
+- Status label for errors and test results
+*/
+const PROVIDER_OPENAI_INDEX: u32 = 0;
+const PROVIDER_GENERIC_INDEX: u32 = 1;
+
+#[derive(Clone)]
+struct RemoteModelForm {
+    provider_dropdown: gtk::DropDown,
+    base_url_entry: gtk::Entry,
+    api_key_entry: gtk::PasswordEntry,
+    model_name_entry: gtk::Entry,
+    available_models_dropdown: gtk::DropDown,
+    available_models_list: gtk::StringList,
+    status_label: gtk::Label,
+}
+
+impl RemoteModelForm {
+    fn api_type(&self) -> ApiTypeForSaving {
+        if self.provider_dropdown.selected() == PROVIDER_GENERIC_INDEX {
+            ApiTypeForSaving::Generic(self.base_url_entry.text().trim().to_string())
+        } else {
+            ApiTypeForSaving::OpenAI
+        }
+    }
+
+    fn set_status(&self, status: &str) {
+        self.status_label.set_text(status);
+    }
+
+    // Keys can't be stored or deleted until the encrypted file is unlocked, or created
+    fn prompt_vault_unlock(&self, on_unlocked: impl Fn() + 'static) {
+        self.set_status("Unlock the credential vault to continue");
+        if let Some(parent_window) = self.status_label.root().and_downcast::<gtk::Window>() {
+            VaultUnlockWidget::new(&parent_window, on_unlocked)
+                .dialog
+                .present();
+        }
+    }
+
+    fn clear(&self) {
+        self.provider_dropdown.set_selected(PROVIDER_OPENAI_INDEX);
+        self.base_url_entry.set_text("");
+        self.api_key_entry.set_text("");
+        self.model_name_entry.set_text("");
+        self.available_models_list
+            .splice(0, self.available_models_list.n_items(), &[]);
+        self.available_models_dropdown.hide();
+    }
+
+    fn fill_from_saved_model(&self, saved_model: &SavedModel) {
+        self.clear();
+        self.model_name_entry.set_text(&saved_model.name);
+        if let ModelType::Api(_, api_type) = &saved_model.model_type {
+            match api_type {
+                ApiTypeForSaving::OpenAI => {
+                    self.provider_dropdown.set_selected(PROVIDER_OPENAI_INDEX)
+                }
+                ApiTypeForSaving::Generic(base_url) => {
+                    self.provider_dropdown.set_selected(PROVIDER_GENERIC_INDEX);
+                    self.base_url_entry.set_text(base_url);
+                }
+            }
+        }
+        self.api_key_entry
+            .set_placeholder_text(Some("Leave blank to keep the saved key"));
+    }
+}
+
+pub struct RemoteModelManagerWidget {
+    pub main_box: gtk::Box,
+}
+
+impl RemoteModelManagerWidget {
+    pub fn new(model_registry: &ModelRegistry) -> Self {
+        let api_models = Arc::new(Mutex::new(ApiModel::list_models().unwrap_or_else(|err| {
+            println!("Error: {:?}", err);
+            vec![]
+        })));
+        let editing_index: Arc<Mutex<Option<usize>>> = Arc::new(Mutex::new(None));
+
+        let main_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        let saved_list_box = gtk::ListBox::builder().hexpand(true).build();
+        let scroll_window = gtk::ScrolledWindow::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .child(&saved_list_box)
+            .build();
+
+        let form = Self::create_form();
+        let form_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        let model_name_box = gtk::Box::builder()
+            .spacing(4)
+            .orientation(gtk::Orientation::Horizontal)
+            .build();
+        let fetch_models_button = gtk::Button::builder()
+            .icon_name("view-refresh-symbolic")
+            .tooltip_text("List the provider's models")
+            .build();
+        model_name_box.append(&form.model_name_entry);
+        model_name_box.append(&fetch_models_button);
+        let action_box = gtk::Box::builder()
+            .spacing(4)
+            .homogeneous(true)
+            .orientation(gtk::Orientation::Horizontal)
+            .build();
+        let test_button = gtk::Button::builder().label("Test connection").build();
+        let save_button = gtk::Button::builder().label("Save").build();
+        let clear_button = gtk::Button::builder().label("Clear").build();
+        action_box.append(&test_button);
+        action_box.append(&save_button);
+        action_box.append(&clear_button);
+
+        form_box.append(&form.provider_dropdown);
+        form_box.append(&form.base_url_entry);
+        form_box.append(&form.api_key_entry);
+        form_box.append(&model_name_box);
+        form_box.append(&form.available_models_dropdown);
+        form_box.append(&action_box);
+        form_box.append(&form.status_label);
+
+        main_box.append(&scroll_window);
+        main_box.append(&form_box);
+
+        Self::refresh_saved_list(
+            &saved_list_box,
+            &api_models,
+            &editing_index,
+            &form,
+            model_registry,
+        );
+
+        {
+            let form = form.clone();
+            let api_models = Arc::clone(&api_models);
+            let editing_index = Arc::clone(&editing_index);
+            fetch_models_button.connect_clicked(move |_| {
+                let saved_api_key_ref = Self::editing_api_key_ref(&api_models, &editing_index);
+                Self::fetch_available_models(&form, &form_api_key(&form, saved_api_key_ref));
+            });
+        }
+        {
+            let form = form.clone();
+            let api_models = Arc::clone(&api_models);
+            let editing_index = Arc::clone(&editing_index);
+            test_button.connect_clicked(move |_| {
+                let saved_api_key_ref = Self::editing_api_key_ref(&api_models, &editing_index);
+                Self::test_connection(&form, &form_api_key(&form, saved_api_key_ref));
+            });
+        }
+        {
+            let form = form.clone();
+            let api_models = Arc::clone(&api_models);
+            let editing_index = Arc::clone(&editing_index);
+            let saved_list_box = saved_list_box.clone();
+            let model_registry = model_registry.clone();
+            save_button.connect_clicked(move |_| {
+                Self::save_model(
+                    &form,
+                    &api_models,
+                    &editing_index,
+                    &saved_list_box,
+                    &model_registry,
+                );
+            });
+        }
+        {
+            let form = form.clone();
+            let editing_index = Arc::clone(&editing_index);
+            clear_button.connect_clicked(move |_| {
+                *editing_index.lock().unwrap() = None;
+                form.clear();
+                form.api_key_entry.set_placeholder_text(Some("API key"));
+                form.set_status("");
+            });
+        }
+
+        Self { main_box }
+    }
+
+    fn create_form() -> RemoteModelForm {
+        let provider_dropdown = gtk::DropDown::builder()
+            .model(&gtk::StringList::new(&[
+                ApiTypeForSaving::OpenAI.name(),
+                ApiTypeForSaving::Generic(String::new()).name(),
+            ]))
+            .build();
+        let base_url_entry = gtk::Entry::builder()
+            .placeholder_text("Base URL, e.g. http://localhost:8000/v1")
+            .visible(false)
+            .build();
+        {
+            let base_url_entry = base_url_entry.clone();
+            provider_dropdown.connect_selected_notify(move |provider_dropdown| {
+                base_url_entry.set_visible(provider_dropdown.selected() == PROVIDER_GENERIC_INDEX);
+            });
+        }
+        let api_key_entry = gtk::PasswordEntry::builder()
+            .show_peek_icon(true)
+            .placeholder_text("API key")
+            .build();
+        let model_name_entry = gtk::Entry::builder()
+            .hexpand(true)
+            .placeholder_text("Model name")
+            .build();
+        let available_models_list = gtk::StringList::new(&[]);
+        let available_models_dropdown = gtk::DropDown::builder()
+            .model(&available_models_list)
+            .enable_search(true)
+            .visible(false)
+            .build();
+        {
+            let model_name_entry = model_name_entry.clone();
+            let available_models_list = available_models_list.clone();
+            available_models_dropdown.connect_selected_notify(move |available_models_dropdown| {
+                if let Some(model_name) =
+                    available_models_list.string(available_models_dropdown.selected())
+                {
+                    model_name_entry.set_text(&model_name);
+                }
+            });
+        }
+        let status_label = gtk::Label::builder()
+            .label("")
+            .wrap(true)
+            .selectable(true)
+            .build();
+        RemoteModelForm {
+            provider_dropdown,
+            base_url_entry,
+            api_key_entry,
+            model_name_entry,
+            available_models_dropdown,
+            available_models_list,
+            status_label,
+        }
+    }
+
+    fn refresh_saved_list(
+        saved_list_box: &gtk::ListBox,
+        api_models: &Arc<Mutex<Vec<SavedModel>>>,
+        editing_index: &Arc<Mutex<Option<usize>>>,
+        form: &RemoteModelForm,
+        model_registry: &ModelRegistry,
+    ) {
+        while let Some(row) = saved_list_box.first_child() {
+            saved_list_box.remove(&row);
+        }
+        api_models
+            .lock()
+            .unwrap()
+            .iter()
+            .enumerate()
+            .for_each(|(index, saved_model)| {
+                let provider_name = match &saved_model.model_type {
+                    ModelType::Api(_, api_type) => api_type.name().to_owned(),
+                    ModelType::Ollama => String::from("Ollama"),
+                };
+                let row_box = gtk::Box::builder()
+                    .spacing(5)
+                    .orientation(gtk::Orientation::Horizontal)
+                    .build();
+                let name_label = gtk::Label::builder()
+                    .label(format!("{0} ({1})", saved_model.name, provider_name))
+                    .hexpand(true)
+                    .xalign(0.0)
+                    .build();
+                let edit_button = gtk::Button::builder()
+                    .icon_name("document-edit-symbolic")
+                    .tooltip_text("Edit")
+                    .build();
+                let remove_button = gtk::Button::builder()
+                    .icon_name("user-trash-symbolic")
+                    .tooltip_text("Remove")
+                    .build();
+                row_box.append(&name_label);
+                row_box.append(&edit_button);
+                row_box.append(&remove_button);
+                saved_list_box.append(&row_box);
+
+                {
+                    let form = form.clone();
+                    let saved_model = saved_model.clone();
+                    let editing_index = Arc::clone(editing_index);
+                    edit_button.connect_clicked(move |_| {
+                        *editing_index.lock().unwrap() = Some(index);
+                        form.fill_from_saved_model(&saved_model);
+                        form.set_status(&format!("Editing {}", saved_model.name));
+                    });
+                }
+                {
+                    let form = form.clone();
+                    let api_models = Arc::clone(api_models);
+                    let editing_index = Arc::clone(editing_index);
+                    let saved_list_box = saved_list_box.clone();
+                    let model_registry = model_registry.clone();
+                    remove_button.connect_clicked(move |_| {
+                        Self::remove_model(
+                            index,
+                            &api_models,
+                            &editing_index,
+                            &form,
+                            &saved_list_box,
+                            &model_registry,
+                        );
+                    });
+                }
+            });
+    }
+
+    fn editing_api_key_ref(
+        api_models: &Arc<Mutex<Vec<SavedModel>>>,
+        editing_index: &Arc<Mutex<Option<usize>>>,
+    ) -> Option<ApiKeyRef> {
+        let index = (*editing_index.lock().unwrap())?;
+        match &api_models.lock().unwrap().get(index)?.model_type {
+            ModelType::Api(api_key_ref, _) => Some(api_key_ref.clone()),
+            ModelType::Ollama => None,
+        }
+    }
+
+    fn fetch_available_models(form: &RemoteModelForm, api_key: &Option<String>) {
+        let Some(api_key) = api_key.clone() else {
+            form.set_status("Enter an API key first");
+            return;
+        };
+        form.set_status("Fetching models...");
+        let form = form.clone();
+        glib::MainContext::default().spawn_local(async move {
+            match ApiModel::list_available_models(api_key, form.api_type()).await {
+                Ok(model_names) => {
+                    let model_names = model_names
+                        .iter()
+                        .map(|model_name| model_name.as_str())
+                        .collect::<Vec<&str>>();
+                    form.available_models_list.splice(
+                        0,
+                        form.available_models_list.n_items(),
+                        &model_names,
+                    );
+                    form.available_models_dropdown
+                        .set_visible(!model_names.is_empty());
+                    form.set_status(&format!("Found {} models", model_names.len()));
+                }
+                Err(err) => form.set_status(&format!("Error listing models: {}", err)),
+            }
+        });
+    }
+
+    fn test_connection(form: &RemoteModelForm, api_key: &Option<String>) {
+        let Some(api_key) = api_key.clone() else {
+            form.set_status("Enter an API key first");
+            return;
+        };
+        let model_name = form.model_name_entry.text().trim().to_string();
+        if model_name.is_empty() {
+            form.set_status("Enter a model name first");
+            return;
+        }
+        form.set_status("Testing connection...");
+        let form = form.clone();
+        glib::MainContext::default().spawn_local(async move {
+            match ApiModel::test_connection(model_name, api_key, form.api_type()).await {
+                Ok(reply) => {
+                    form.set_status(&format!("Connection works, model replied: {}", reply))
+                }
+                Err(err) => form.set_status(&format!("Connection failed: {}", err)),
+            }
+        });
+    }
+
+    fn save_model(
+        form: &RemoteModelForm,
+        api_models: &Arc<Mutex<Vec<SavedModel>>>,
+        editing_index: &Arc<Mutex<Option<usize>>>,
+        saved_list_box: &gtk::ListBox,
+        model_registry: &ModelRegistry,
+    ) {
+        let model_name = form.model_name_entry.text().trim().to_string();
+        if model_name.is_empty() {
+            form.set_status("Enter a model name first");
+            return;
+        }
+        let api_type = form.api_type();
+        if let ApiTypeForSaving::Generic(base_url) = &api_type {
+            if base_url.is_empty() {
+                form.set_status("Enter the server's base URL first");
+                return;
+            }
+        }
+        let new_api_key = form.api_key_entry.text().to_string();
+        if CredentialStore::needs_passphrase() {
+            // Picks up where this left off once the vault is open
+            let form_for_retry = form.clone();
+            let api_models = Arc::clone(api_models);
+            let editing_index = Arc::clone(editing_index);
+            let saved_list_box = saved_list_box.clone();
+            let model_registry = model_registry.clone();
+            form.prompt_vault_unlock(move || {
+                Self::save_model(
+                    &form_for_retry,
+                    &api_models,
+                    &editing_index,
+                    &saved_list_box,
+                    &model_registry,
+                );
+            });
+            return;
+        }
+        let credential_store = match CredentialStore::open() {
+            Ok(credential_store) => credential_store,
+            Err(err) => {
+                form.set_status(&format!("Error opening credential store: {}", err));
+                return;
+            }
+        };
+        let editing_index_value = *editing_index.lock().unwrap();
+        let existing_api_key_ref = Self::editing_api_key_ref(api_models, editing_index);
+
+        let api_key_ref = match (existing_api_key_ref, new_api_key.is_empty()) {
+            (Some(existing_api_key_ref), true) => existing_api_key_ref,
+            (Some(ApiKeyRef::Stored { credential_id }), false) => {
+                if let Err(err) = credential_store.store(&credential_id, &new_api_key) {
+                    form.set_status(&format!("Error saving API key: {}", err));
+                    return;
+                }
+                ApiKeyRef::Stored { credential_id }
+            }
+            (_, false) => match credential_store.store_new(&new_api_key) {
+                Ok(api_key_ref) => api_key_ref,
+                Err(err) => {
+                    form.set_status(&format!("Error saving API key: {}", err));
+                    return;
+                }
+            },
+            (None, true) => {
+                form.set_status("Enter an API key first");
+                return;
+            }
+        };
+
+        let saved_model = SavedModel {
+            name: model_name.clone(),
+            model_type: ModelType::Api(api_key_ref, api_type),
+        };
+        {
+            let mut api_models = api_models.lock().unwrap();
+            match editing_index_value {
+                Some(index) if index < api_models.len() => api_models[index] = saved_model,
+                _ => api_models.push(saved_model),
+            }
+            SavedModel::write_to_file(PathBuf::from("api_models.json"), api_models.clone());
+        }
+        *editing_index.lock().unwrap() = None;
+        form.clear();
+        form.api_key_entry.set_placeholder_text(Some("API key"));
+        form.set_status(&format!("Saved {}", model_name));
+        Self::refresh_saved_list(
+            saved_list_box,
+            api_models,
+            editing_index,
+            form,
+            model_registry,
+        );
+        model_registry.refresh();
+    }
+
+    fn remove_model(
+        index: usize,
+        api_models: &Arc<Mutex<Vec<SavedModel>>>,
+        editing_index: &Arc<Mutex<Option<usize>>>,
+        form: &RemoteModelForm,
+        saved_list_box: &gtk::ListBox,
+        model_registry: &ModelRegistry,
+    ) {
+        {
+            let mut api_models_guard = api_models.lock().unwrap();
+            let Some(saved_model) = api_models_guard.get(index) else {
+                return;
+            };
+            if let ModelType::Api(ApiKeyRef::Stored { credential_id }, _) = &saved_model.model_type
+            {
+                // Keep the model listed rather than leave its key behind in the vault
+                if CredentialStore::needs_passphrase() {
+                    let form_for_retry = form.clone();
+                    let api_models = Arc::clone(api_models);
+                    let editing_index = Arc::clone(editing_index);
+                    let saved_list_box = saved_list_box.clone();
+                    let model_registry = model_registry.clone();
+                    form.prompt_vault_unlock(move || {
+                        Self::remove_model(
+                            index,
+                            &api_models,
+                            &editing_index,
+                            &form_for_retry,
+                            &saved_list_box,
+                            &model_registry,
+                        );
+                    });
+                    return;
+                }
+                match CredentialStore::open() {
+                    Ok(credential_store) => {
+                        if let Err(err) = credential_store.delete(credential_id) {
+                            println!("Error deleting API key for {}: {:?}", saved_model.name, err);
+                        }
+                    }
+                    Err(err) => println!("Error opening credential store: {}", err),
+                }
+            }
+            api_models_guard.remove(index);
+            SavedModel::write_to_file(PathBuf::from("api_models.json"), api_models_guard.clone());
+        }
+        *editing_index.lock().unwrap() = None;
+        form.clear();
+        form.set_status("Model removed");
+        Self::refresh_saved_list(
+            saved_list_box,
+            api_models,
+            editing_index,
+            form,
+            model_registry,
+        );
+        model_registry.refresh();
+    }
+}
+
+// The key typed into the form, or the saved one when editing without changing it
+fn form_api_key(form: &RemoteModelForm, saved_api_key_ref: Option<ApiKeyRef>) -> Option<String> {
+    let typed_api_key = form.api_key_entry.text().to_string();
+    if !typed_api_key.is_empty() {
+        return Some(typed_api_key);
+    }
+    saved_api_key_ref.and_then(|api_key_ref| match api_key_ref.resolve() {
+        Ok(api_key) => Some(api_key),
+        Err(err) => {
+            println!("Error loading API key: {:?}", err);
+            None
+        }
+    })
+}
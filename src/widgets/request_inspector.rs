@@ -0,0 +1,108 @@
+use adw::prelude::*;
+
+use crate::models::request_inspector::RequestRecord;
+
+/*
+Developer view of what was sent to a provider for one answer
+- Summary of provider, endpoint, model and timings
+- Full redacted record as JSON, with the messages, parameters and raw chunks
+- Export button to save the record for bug reports
+*/
+pub struct RequestInspectorWidget {
+    pub dialog: gtk::Dialog,
+}
+
+impl RequestInspectorWidget {
+    pub fn new(request_record: RequestRecord) -> Self {
+        let summary_label = gtk::Label::builder()
+            .label(Self::summary(&request_record))
+            .selectable(true)
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        let record_buffer = gtk::TextBuffer::builder()
+            .text(request_record.to_pretty_json())
+            .build();
+        let record_textbox = gtk::TextView::builder()
+            .editable(false)
+            .monospace(true)
+            .buffer(&record_buffer)
+            .hexpand(true)
+            .vexpand(true)
+            .build();
+        let record_scroll_window = gtk::ScrolledWindow::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .child(&record_textbox)
+            .build();
+        let export_button = gtk::Button::builder()
+            .label("Export JSON")
+            .halign(gtk::Align::End)
+            .build();
+
+        let main_box = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(10)
+            .margin_top(10)
+            .margin_bottom(10)
+            .margin_start(10)
+            .margin_end(10)
+            .build();
+        main_box.append(&summary_label);
+        main_box.append(&record_scroll_window);
+        main_box.append(&export_button);
+
+        let export_file_chooser = Self::create_export_file_chooser(request_record);
+        export_button.connect_clicked(move |_| {
+            export_file_chooser.show();
+        });
+
+        let dialog = gtk::Dialog::builder()
+            .title("Request inspector")
+            .default_height(500)
+            .default_width(700)
+            .child(&main_box)
+            .build();
+        Self { dialog }
+    }
+
+    fn summary(request_record: &RequestRecord) -> String {
+        let format_ms = |duration_ms: Option<u128>| {
+            duration_ms
+                .map(|duration_ms| format!("{} ms", duration_ms))
+                .unwrap_or(String::from("-"))
+        };
+        let mut summary = format!(
+            "{0} {1}\nEndpoint: {2}\nFirst chunk: {3}, total: {4}, {5} chunks",
+            request_record.provider,
+            request_record.model_name,
+            request_record.endpoint,
+            format_ms(request_record.first_chunk_ms),
+            format_ms(request_record.total_ms),
+            request_record.chunks.len()
+        );
+        if let Some(error) = &request_record.error {
+            summary.push_str(&format!("\nError: {}", error));
+        }
+        summary
+    }
+
+    fn create_export_file_chooser(request_record: RequestRecord) -> gtk::FileChooserNative {
+        let file_chooser = gtk::FileChooserNative::builder()
+            .title("Export request")
+            .action(gtk::FileChooserAction::Save)
+            .build();
+        file_chooser.set_current_name(&format!("request-{}.json", request_record.id));
+        file_chooser.connect_response(move |file_chooser, response| {
+            if response == gtk::ResponseType::Accept {
+                if let Some(file_path) = file_chooser.file().and_then(|file| file.path()) {
+                    match request_record.export(&file_path) {
+                        Ok(()) => println!("Exported request to {:?}", file_path),
+                        Err(err) => println!("Error exporting request: {:?}", err),
+                    }
+                }
+            }
+        });
+        file_chooser
+    }
+}
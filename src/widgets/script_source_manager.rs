@@ -0,0 +1,301 @@
+use std::sync::{Arc, Mutex};
+
+use adw::prelude::*;
+
+use crate::rag::{
+    manifest::{self, OutputMode, RagManifest},
+    sandbox::Sandbox,
+};
+
+const OUTPUT_MODES: [OutputMode; 2] = [OutputMode::Text, OutputMode::Json];
+const SANDBOXES: [Sandbox; 3] = [Sandbox::None, Sandbox::Minimal, Sandbox::Bubblewrap];
+const MAX_TIMEOUT_SECONDS: f64 = 600.0;
+
+/*
+Manages the scripts and manifests in ./rag_sources
+- List of sources with the command they run, each with edit and remove buttons
+- Add button to pick a script anywhere on disk, which gets a manifest pointing at it
+- Form for the options of the source being edited, saved as its TOML manifest
+- Calls on_change when the list of sources changes so the RAG dropdown can update
+*/
+#[derive(Clone)]
+pub struct ScriptSourceManagerWidget {
+    pub main_box: gtk::Box,
+    source_list_box: gtk::ListBox,
+    form_box: gtk::Box,
+    name_entry: gtk::Entry,
+    args_entry: gtk::Entry,
+    timeout_spin_button: gtk::SpinButton,
+    output_dropdown: gtk::DropDown,
+    sandbox_dropdown: gtk::DropDown,
+    allow_network_check_button: gtk::CheckButton,
+    editing_manifest: Arc<Mutex<Option<RagManifest>>>,
+    status_label: gtk::Label,
+    on_change: Arc<dyn Fn()>,
+}
+
+impl ScriptSourceManagerWidget {
+    pub fn new(on_change: Arc<dyn Fn()>) -> Self {
+        let main_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        let source_list_box = gtk::ListBox::builder().hexpand(true).build();
+        let scroll_window = gtk::ScrolledWindow::builder()
+            .hexpand(true)
+            .vexpand(true)
+            .child(&source_list_box)
+            .build();
+        let add_button = gtk::Button::builder().label("Add script").build();
+
+        let name_entry = gtk::Entry::builder()
+            .placeholder_text("Source name")
+            .build();
+        let args_entry = gtk::Entry::builder()
+            .placeholder_text("Arguments")
+            .tooltip_text("Arguments, the prompt is sent on stdin and in COMHRA_PROMPT")
+            .build();
+        let timeout_spin_button = gtk::SpinButton::with_range(1.0, MAX_TIMEOUT_SECONDS, 1.0);
+        timeout_spin_button.set_tooltip_text(Some("Timeout in seconds"));
+        let output_dropdown = gtk::DropDown::from_strings(&["Plain text output", "JSON output"]);
+        output_dropdown.set_tooltip_text(Some("What the script prints"));
+        let sandbox_dropdown = gtk::DropDown::from_strings(&[
+            "No sandbox",
+            "Minimal environment",
+            "Bubblewrap sandbox",
+        ]);
+        sandbox_dropdown.set_tooltip_text(Some("What the script can reach while it runs"));
+        let allow_network_check_button = gtk::CheckButton::builder()
+            .label("Allow network access in the sandbox")
+            .build();
+        let save_button = gtk::Button::builder().label("Save source").build();
+        let cancel_edit_button = gtk::Button::builder().label("Cancel").build();
+        let form_buttons_box = gtk::Box::builder()
+            .spacing(4)
+            .homogeneous(true)
+            .orientation(gtk::Orientation::Horizontal)
+            .build();
+        form_buttons_box.append(&save_button);
+        form_buttons_box.append(&cancel_edit_button);
+        let form_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .visible(false)
+            .build();
+        form_box.append(&name_entry);
+        form_box.append(&args_entry);
+        form_box.append(&timeout_spin_button);
+        form_box.append(&output_dropdown);
+        form_box.append(&sandbox_dropdown);
+        form_box.append(&allow_network_check_button);
+        form_box.append(&form_buttons_box);
+        let status_label = gtk::Label::builder()
+            .label("")
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+
+        main_box.append(&scroll_window);
+        main_box.append(&add_button);
+        main_box.append(&form_box);
+        main_box.append(&status_label);
+
+        let script_source_manager = Self {
+            main_box,
+            source_list_box,
+            form_box,
+            name_entry,
+            args_entry,
+            timeout_spin_button,
+            output_dropdown,
+            sandbox_dropdown,
+            allow_network_check_button,
+            editing_manifest: Arc::new(Mutex::new(None)),
+            status_label,
+            on_change,
+        };
+        script_source_manager.refresh_source_list();
+
+        let script_chooser = script_source_manager.create_script_chooser();
+        add_button.connect_clicked(move |_| {
+            script_chooser.show();
+        });
+        {
+            let allow_network_check_button =
+                script_source_manager.allow_network_check_button.clone();
+            script_source_manager
+                .sandbox_dropdown
+                .connect_selected_notify(move |sandbox_dropdown| {
+                    allow_network_check_button.set_sensitive(
+                        SANDBOXES[sandbox_dropdown.selected() as usize] == Sandbox::Bubblewrap,
+                    );
+                });
+        }
+        {
+            let script_source_manager = script_source_manager.clone();
+            save_button.connect_clicked(move |_| {
+                script_source_manager.save_form();
+            });
+        }
+        {
+            let script_source_manager = script_source_manager.clone();
+            cancel_edit_button.connect_clicked(move |_| {
+                *script_source_manager.editing_manifest.lock().unwrap() = None;
+                script_source_manager.form_box.hide();
+            });
+        }
+
+        script_source_manager
+    }
+
+    // The script stays where it is, only a manifest pointing at it is added to ./rag_sources
+    fn create_script_chooser(&self) -> gtk::FileChooserNative {
+        let script_chooser = gtk::FileChooserNative::builder()
+            .title("Select a RAG script")
+            .action(gtk::FileChooserAction::Open)
+            .build();
+        let script_source_manager = self.clone();
+        script_chooser.connect_response(move |script_chooser, response| {
+            if response != gtk::ResponseType::Accept {
+                return;
+            }
+            let Some(script_path) = script_chooser.file().and_then(|file| file.path()) else {
+                return;
+            };
+            let mut rag_manifest = RagManifest::from_script(&script_path);
+            match rag_manifest.save() {
+                Ok(()) => {
+                    script_source_manager.refresh_source_list();
+                    (script_source_manager.on_change)();
+                    script_source_manager.edit_manifest(rag_manifest);
+                }
+                Err(err) => script_source_manager
+                    .status_label
+                    .set_text(&format!("Error adding {:?}: {}", script_path, err)),
+            }
+        });
+        script_chooser
+    }
+
+    fn refresh_source_list(&self) {
+        while let Some(row) = self.source_list_box.first_child() {
+            self.source_list_box.remove(&row);
+        }
+        manifest::list().into_iter().for_each(|rag_manifest| {
+            let row_box = gtk::Box::builder()
+                .spacing(5)
+                .orientation(gtk::Orientation::Horizontal)
+                .build();
+            let labels_box = gtk::Box::builder()
+                .orientation(gtk::Orientation::Vertical)
+                .hexpand(true)
+                .tooltip_text(rag_manifest.manifest_path.to_string_lossy().to_string())
+                .build();
+            let name_label = gtk::Label::builder()
+                .label(&rag_manifest.name)
+                .xalign(0.0)
+                .build();
+            let command_label = gtk::Label::builder()
+                .label(format!(
+                    "{0} {1}",
+                    rag_manifest.command,
+                    rag_manifest.args.join(" ")
+                ))
+                .ellipsize(gtk::pango::EllipsizeMode::Middle)
+                .xalign(0.0)
+                .css_classes(["dim-label", "caption"])
+                .build();
+            labels_box.append(&name_label);
+            labels_box.append(&command_label);
+            let edit_button = gtk::Button::builder()
+                .icon_name("document-edit-symbolic")
+                .tooltip_text("Edit source")
+                .build();
+            let remove_button = gtk::Button::builder()
+                .icon_name("user-trash-symbolic")
+                .tooltip_text("Remove source, scripts kept in rag_sources are deleted with it")
+                .build();
+            row_box.append(&labels_box);
+            row_box.append(&edit_button);
+            row_box.append(&remove_button);
+            self.source_list_box.append(&row_box);
+
+            {
+                let script_source_manager = self.clone();
+                let rag_manifest = rag_manifest.clone();
+                edit_button.connect_clicked(move |_| {
+                    script_source_manager.edit_manifest(rag_manifest.clone());
+                });
+            }
+            {
+                let script_source_manager = self.clone();
+                remove_button.connect_clicked(move |_| {
+                    if let Err(err) = rag_manifest.delete() {
+                        script_source_manager
+                            .status_label
+                            .set_text(&format!("Error removing {}: {}", rag_manifest.name, err));
+                    }
+                    script_source_manager.refresh_source_list();
+                    (script_source_manager.on_change)();
+                });
+            }
+        });
+    }
+
+    fn edit_manifest(&self, rag_manifest: RagManifest) {
+        self.name_entry.set_text(&rag_manifest.name);
+        self.args_entry.set_text(&rag_manifest.args.join(" "));
+        self.timeout_spin_button
+            .set_value(rag_manifest.timeout_seconds as f64);
+        let output_index = OUTPUT_MODES
+            .iter()
+            .position(|output_mode| *output_mode == rag_manifest.output)
+            .unwrap_or(0);
+        self.output_dropdown.set_selected(output_index as u32);
+        let sandbox_index = SANDBOXES
+            .iter()
+            .position(|sandbox| *sandbox == rag_manifest.sandbox)
+            .unwrap_or(0);
+        self.sandbox_dropdown.set_selected(sandbox_index as u32);
+        self.allow_network_check_button
+            .set_active(rag_manifest.allow_network);
+        self.allow_network_check_button
+            .set_sensitive(rag_manifest.sandbox == Sandbox::Bubblewrap);
+        self.form_box.show();
+        *self.editing_manifest.lock().unwrap() = Some(rag_manifest);
+    }
+
+    // Arguments are split on whitespace, manifests edited by hand can quote them in the TOML
+    fn save_form(&self) {
+        let Some(mut rag_manifest) = self.editing_manifest.lock().unwrap().clone() else {
+            return;
+        };
+        let name = self.name_entry.text().trim().to_string();
+        if name.is_empty() {
+            self.status_label.set_text("Enter a name for the source.");
+            return;
+        }
+        rag_manifest.name = name;
+        rag_manifest.args = self
+            .args_entry
+            .text()
+            .split_whitespace()
+            .map(String::from)
+            .collect();
+        rag_manifest.timeout_seconds = self.timeout_spin_button.value_as_int() as u64;
+        rag_manifest.output = OUTPUT_MODES[self.output_dropdown.selected() as usize];
+        rag_manifest.sandbox = SANDBOXES[self.sandbox_dropdown.selected() as usize];
+        rag_manifest.allow_network = self.allow_network_check_button.is_active();
+        if let Err(err) = rag_manifest.save() {
+            self.status_label
+                .set_text(&format!("Error saving {}: {}", rag_manifest.name, err));
+            return;
+        }
+        self.status_label
+            .set_text(&format!("Saved {}", rag_manifest.name));
+        *self.editing_manifest.lock().unwrap() = None;
+        self.form_box.hide();
+        self.refresh_source_list();
+        (self.on_change)();
+    }
+}
@@ -6,8 +6,11 @@ use std::path::PathBuf;
 use std::sync::mpsc::Sender;
 use std::sync::{Arc, Mutex};
 
-use crate::models::SavedConversation;
+use crate::models::model_registry::ModelRegistry;
+use crate::models::{SavedConversation, SavedModel};
 use crate::utils;
+
+use super::fallback_chain::FallbackChainWidget;
 /*
 - Button to filter list to show/hide archived
 - Button to filter list to show only starred
@@ -21,7 +24,10 @@ enum SideBarFilterState {
     JustFavourite,
 }
 
-pub fn create_sidebar(conversation_file_option_sender: Sender<Option<PathBuf>>) -> gtk::Box {
+pub fn create_sidebar(
+    conversation_file_option_sender: Sender<Option<PathBuf>>,
+    model_registry: &ModelRegistry,
+) -> gtk::Box {
     let filter_state = Arc::new(Mutex::new(SideBarFilterState::NoFilter));
     let main_box = gtk::Box::builder()
         .orientation(gtk::Orientation::Vertical)
@@ -58,6 +64,7 @@ pub fn create_sidebar(conversation_file_option_sender: Sender<Option<PathBuf>>)
         let sidebar_list_item = SideBarListItem::new(
             file_path_for_button,
             conversation_file_option_sender_for_button,
+            model_registry,
         );
         conversation_list_box.append(&sidebar_list_item.main_box);
         list_items.push(sidebar_list_item);
@@ -190,6 +197,7 @@ impl SideBarListItem {
     pub fn new(
         file_path: PathBuf,
         conversation_file_option_sender: Sender<Option<PathBuf>>,
+        model_registry: &ModelRegistry,
     ) -> Self {
         let (permanent_state, conversation_name) = Self::initialise_state_and_name(&file_path);
         let main_box = gtk::Box::builder()
@@ -222,6 +230,7 @@ impl SideBarListItem {
             &file_path,
             conversation_name,
             &open_button,
+            model_registry,
         );
         menu_popover.set_parent(&menu_button);
         menu_button.connect_clicked(move |_| {
@@ -410,6 +419,7 @@ impl SideBarListItem {
         file_path: &PathBuf,
         conversation_name: String,
         open_button: &gtk::Button,
+        model_registry: &ModelRegistry,
     ) -> gtk::Popover {
         let delete_button = gtk::Button::builder()
             .icon_name("edit-delete-symbolic")
@@ -425,7 +435,13 @@ impl SideBarListItem {
             .spacing(2)
             .orientation(gtk::Orientation::Horizontal)
             .build();
+        let fallback_button = gtk::Button::builder()
+            .icon_name("view-list-ordered-symbolic")
+            .has_tooltip(true)
+            .tooltip_text("Fallback models")
+            .build();
         menu_box.append(&rename_button);
+        menu_box.append(&fallback_button);
         menu_box.append(&delete_button);
 
         let menu_popover = gtk::Popover::builder().autohide(true).build();
@@ -452,6 +468,16 @@ impl SideBarListItem {
             menu_popover_for_rename.set_child(Some(&rename_box));
         });
 
+        {
+            let menu_popover = menu_popover.clone();
+            let file_path = file_path.clone();
+            let model_registry = model_registry.clone();
+            fallback_button.connect_clicked(move |_| {
+                menu_popover.popdown();
+                Self::create_fallback_dialog(&file_path, &model_registry).present();
+            });
+        }
+
         let menu_popover_for_delete = menu_popover.clone();
         delete_button.connect_clicked(move |_| {
             menu_popover_for_delete.set_child(Some(&delete_box));
@@ -459,6 +485,85 @@ impl SideBarListItem {
         menu_popover
     }
 
+    // The conversation either follows the global list from Preferences or keeps its own
+    fn create_fallback_dialog(file_path: &PathBuf, model_registry: &ModelRegistry) -> gtk::Dialog {
+        let conversation_fallback_models = SavedConversation::load(file_path)
+            .and_then(|saved_conversation| saved_conversation.fallback_models);
+        let use_global_list = conversation_fallback_models.is_none();
+
+        let use_global_switch = gtk::Switch::builder()
+            .active(use_global_list)
+            .valign(gtk::Align::Center)
+            .build();
+        let use_global_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Horizontal)
+            .build();
+        use_global_box.append(
+            &gtk::Label::builder()
+                .label("Use the fallback models from Preferences")
+                .hexpand(true)
+                .xalign(0.0)
+                .build(),
+        );
+        use_global_box.append(&use_global_switch);
+
+        let edited_fallback_models = Arc::new(Mutex::new(
+            conversation_fallback_models.clone().unwrap_or_default(),
+        ));
+        let fallback_chain_widget = {
+            let file_path = file_path.clone();
+            let edited_fallback_models = Arc::clone(&edited_fallback_models);
+            FallbackChainWidget::new(
+                conversation_fallback_models.unwrap_or_default(),
+                model_registry,
+                Arc::new(move |fallback_models| {
+                    *edited_fallback_models.lock().unwrap() = fallback_models.clone();
+                    Self::save_fallback_models(&file_path, Some(fallback_models));
+                }),
+            )
+        };
+        fallback_chain_widget
+            .main_box
+            .set_sensitive(!use_global_list);
+        {
+            let file_path = file_path.clone();
+            let chain_box = fallback_chain_widget.main_box.clone();
+            use_global_switch.connect_active_notify(move |use_global_switch| {
+                let use_global_list = use_global_switch.is_active();
+                chain_box.set_sensitive(!use_global_list);
+                Self::save_fallback_models(
+                    &file_path,
+                    (!use_global_list).then(|| edited_fallback_models.lock().unwrap().clone()),
+                );
+            });
+        }
+
+        let dialog_box = gtk::Box::builder()
+            .spacing(10)
+            .margin_top(10)
+            .margin_bottom(10)
+            .margin_start(10)
+            .margin_end(10)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        dialog_box.append(&use_global_box);
+        dialog_box.append(&fallback_chain_widget.main_box);
+        gtk::Dialog::builder()
+            .title("Fallback models")
+            .default_height(300)
+            .default_width(300)
+            .child(&dialog_box)
+            .build()
+    }
+
+    fn save_fallback_models(file_path: &PathBuf, fallback_models: Option<Vec<SavedModel>>) {
+        if let Some(mut loaded_conversation) = SavedConversation::load(file_path) {
+            loaded_conversation.fallback_models = fallback_models;
+            loaded_conversation.save(file_path);
+        }
+    }
+
     fn create_rename_box(
         conversation_name: String,
         menu_popover: &gtk::Popover,
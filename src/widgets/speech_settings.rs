@@ -0,0 +1,162 @@
+use adw::prelude::*;
+use gtk::glib;
+
+use crate::{
+    settings::Settings,
+    speech::{self, WhisperModelSize, WHISPER_MODEL_SIZES},
+};
+
+/*
+- Dropdown of whisper model sizes, the selected one is used for voice input and audio attachments
+- Status of the selected size, with a download or delete button
+- Progress bar while downloading, transcription itself never uses the network
+*/
+pub struct SpeechSettingsWidget {
+    pub main_box: gtk::Box,
+}
+
+impl SpeechSettingsWidget {
+    pub fn new() -> Self {
+        let main_box = gtk::Box::builder()
+            .spacing(5)
+            .orientation(gtk::Orientation::Vertical)
+            .build();
+        let info_label = gtk::Label::builder()
+            .label("Voice input and audio attachments are transcribed on this computer with a whisper model. Bigger models are more accurate but slower.")
+            .wrap(true)
+            .xalign(0.0)
+            .build();
+        let model_size_dropdown = gtk::DropDown::from_strings(
+            &WHISPER_MODEL_SIZES
+                .iter()
+                .map(|model_size| model_size.label())
+                .collect::<Vec<&str>>(),
+        );
+        let selected_model_size = speech::selected_model_size();
+        if let Some(selected_index) = WHISPER_MODEL_SIZES
+            .iter()
+            .position(|model_size| *model_size == selected_model_size)
+        {
+            model_size_dropdown.set_selected(selected_index as u32);
+        }
+        let status_label = gtk::Label::builder()
+            .hexpand(true)
+            .xalign(0.0)
+            .css_classes(["dim-label"])
+            .build();
+        let download_button = gtk::Button::builder()
+            .icon_name("folder-download-symbolic")
+            .tooltip_text("Download model")
+            .build();
+        let delete_button = gtk::Button::builder()
+            .icon_name("user-trash-symbolic")
+            .tooltip_text("Delete model")
+            .build();
+        let status_box = gtk::Box::builder()
+            .spacing(4)
+            .orientation(gtk::Orientation::Horizontal)
+            .build();
+        let download_progress_bar = gtk::ProgressBar::builder()
+            .show_text(true)
+            .visible(false)
+            .build();
+        status_box.append(&status_label);
+        status_box.append(&download_button);
+        status_box.append(&delete_button);
+
+        main_box.append(&info_label);
+        main_box.append(&model_size_dropdown);
+        main_box.append(&status_box);
+        main_box.append(&download_progress_bar);
+
+        Self::refresh_status(
+            selected_model_size,
+            &status_label,
+            &download_button,
+            &delete_button,
+        );
+
+        {
+            let status_label = status_label.clone();
+            let download_button = download_button.clone();
+            let delete_button = delete_button.clone();
+            model_size_dropdown.connect_selected_notify(move |model_size_dropdown| {
+                let Some(model_size) = WHISPER_MODEL_SIZES
+                    .get(model_size_dropdown.selected() as usize)
+                    .copied()
+                else {
+                    return;
+                };
+                let mut settings = Settings::load();
+                settings.whisper_model_size = model_size;
+                settings.save();
+                Self::refresh_status(model_size, &status_label, &download_button, &delete_button);
+            });
+        }
+
+        {
+            let status_label = status_label.clone();
+            let delete_button = delete_button.clone();
+            download_button.connect_clicked(move |download_button| {
+                let model_size = speech::selected_model_size();
+                download_button.set_sensitive(false);
+                let status_label = status_label.clone();
+                let download_button = download_button.clone();
+                let delete_button = delete_button.clone();
+                let download_progress_bar = download_progress_bar.clone();
+                glib::MainContext::default().spawn_local(async move {
+                    if let Err(err) = model_size.download(&download_progress_bar).await {
+                        println!("Error downloading speech model: {:?}", err);
+                        download_progress_bar.hide();
+                        status_label.set_text(&format!("Download failed: {}", err));
+                        download_button.set_sensitive(true);
+                        return;
+                    }
+                    download_button.set_sensitive(true);
+                    Self::refresh_status(
+                        model_size,
+                        &status_label,
+                        &download_button,
+                        &delete_button,
+                    );
+                });
+            });
+        }
+
+        {
+            let status_label = status_label.clone();
+            let download_button = download_button.clone();
+            delete_button.connect_clicked(move |delete_button| {
+                let model_size = speech::selected_model_size();
+                if let Err(err) = model_size.delete() {
+                    println!("Error deleting speech model: {:?}", err);
+                }
+                Self::refresh_status(model_size, &status_label, &download_button, delete_button);
+            });
+        }
+
+        Self { main_box }
+    }
+
+    fn refresh_status(
+        model_size: WhisperModelSize,
+        status_label: &gtk::Label,
+        download_button: &gtk::Button,
+        delete_button: &gtk::Button,
+    ) {
+        let is_downloaded = model_size.is_downloaded();
+        status_label.set_text(if is_downloaded {
+            "Downloaded"
+        } else {
+            "Not downloaded, voice input is off until it is"
+        });
+        download_button.set_visible(!is_downloaded);
+        delete_button.set_visible(is_downloaded);
+    }
+}
+
+impl Default for SpeechSettingsWidget {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,136 @@
+use adw::prelude::*;
+
+use crate::models::{
+    credential_store::{CredentialStore, EncryptedFileBackend},
+    SavedModel,
+};
+
+/*
+Shown when there's no Secret Service to keep API keys in, on startup or before saving a key
+- Passphrase entry for the encrypted credentials file
+- Confirmation entry when the file doesn't exist yet and is being created
+- Unlock button, migrates any plain text keys once unlocked then runs the caller's follow up
+- Error label for a wrong or mismatched passphrase
+*/
+pub struct VaultUnlockWidget {
+    pub dialog: gtk::Dialog,
+}
+
+impl VaultUnlockWidget {
+    pub fn new(parent_window: &impl IsA<gtk::Window>, on_unlocked: impl Fn() + 'static) -> Self {
+        let is_creating_vault = !EncryptedFileBackend::vault_path().exists();
+        let main_box = gtk::Box::builder()
+            .orientation(gtk::Orientation::Vertical)
+            .spacing(5)
+            .margin_top(10)
+            .margin_bottom(10)
+            .margin_start(10)
+            .margin_end(10)
+            .build();
+        let info_label = gtk::Label::builder()
+            .label(if is_creating_vault {
+                "No Secret Service was found, so API keys are kept in a file encrypted with a passphrase. Choose one to create it, it can't be recovered if forgotten."
+            } else {
+                "No Secret Service was found, so API keys are kept in a file encrypted with your passphrase. Enter it to unlock your API models."
+            })
+            .wrap(true)
+            .build();
+        let passphrase_entry = gtk::PasswordEntry::builder()
+            .show_peek_icon(true)
+            .placeholder_text("Passphrase")
+            .build();
+        let confirm_entry = gtk::PasswordEntry::builder()
+            .show_peek_icon(true)
+            .placeholder_text("Confirm passphrase")
+            .visible(is_creating_vault)
+            .build();
+        let error_label = gtk::Label::builder()
+            .label("")
+            .wrap(true)
+            .visible(false)
+            .build();
+        let unlock_button = gtk::Button::builder()
+            .label(if is_creating_vault {
+                "Create"
+            } else {
+                "Unlock"
+            })
+            .build();
+        main_box.append(&info_label);
+        main_box.append(&passphrase_entry);
+        main_box.append(&confirm_entry);
+        main_box.append(&error_label);
+        main_box.append(&unlock_button);
+
+        let dialog = gtk::Dialog::builder()
+            .title(if is_creating_vault {
+                "Create credential vault"
+            } else {
+                "Unlock API keys"
+            })
+            .transient_for(parent_window)
+            .modal(true)
+            .default_width(300)
+            .child(&main_box)
+            .build();
+
+        {
+            let dialog = dialog.clone();
+            let passphrase_entry = passphrase_entry.clone();
+            let confirm_entry = confirm_entry.clone();
+            unlock_button.connect_clicked(move |_| {
+                Self::unlock(
+                    &dialog,
+                    &passphrase_entry,
+                    &confirm_entry,
+                    &error_label,
+                    &on_unlocked,
+                );
+            });
+        }
+        {
+            let unlock_button = unlock_button.clone();
+            passphrase_entry.connect_activate(move |_| {
+                unlock_button.emit_clicked();
+            });
+        }
+        {
+            let unlock_button = unlock_button.clone();
+            confirm_entry.connect_activate(move |_| {
+                unlock_button.emit_clicked();
+            });
+        }
+        Self { dialog }
+    }
+
+    fn unlock(
+        dialog: &gtk::Dialog,
+        passphrase_entry: &gtk::PasswordEntry,
+        confirm_entry: &gtk::PasswordEntry,
+        error_label: &gtk::Label,
+        on_unlocked: &impl Fn(),
+    ) {
+        let passphrase = passphrase_entry.text().to_string();
+        if passphrase.is_empty() {
+            return;
+        }
+        if confirm_entry.is_visible() && confirm_entry.text() != passphrase {
+            error_label.set_text("The passphrases don't match");
+            error_label.show();
+            confirm_entry.set_text("");
+            return;
+        }
+        match CredentialStore::unlock(passphrase) {
+            Ok(()) => {
+                SavedModel::migrate_plaintext_api_keys();
+                dialog.close();
+                on_unlocked();
+            }
+            Err(err) => {
+                error_label.set_text(&err.to_string());
+                error_label.show();
+                passphrase_entry.set_text("");
+            }
+        }
+    }
+}
@@ -1,10 +1,17 @@
+use crate::attachments::{self, AttachmentOptions};
+use crate::models::api_model::ApiModel;
+use crate::models::credential_store::CredentialStore;
+use crate::models::fallback::{ask_with_fallback, fallback_models_for_conversation};
+use crate::models::model_registry::ModelRegistry;
 use crate::models::ollama_model::OllamaModel;
-use crate::models::{CoreLLM, Message, UtilsLLM};
+use crate::models::{CoreLLM, Message, SavedModel, UtilsLLM};
+use crate::rag::index_watcher;
 use crate::utils::generate_unique_filename;
 use crate::widgets::chat_list_item::ChatMessageListItem;
 use crate::widgets::main_header::{HeaderWidget, RagDropdown};
-use crate::widgets::prompt_entry::PromptEntryWidget;
+use crate::widgets::prompt_entry::{AttachmentTray, PromptEntryWidget};
 use crate::widgets::sidebar::create_sidebar;
+use crate::widgets::vault_unlock::VaultUnlockWidget;
 use crate::{ModelMessageState, RagSource};
 use adw::{gdk, prelude::*};
 use core::time;
@@ -12,7 +19,10 @@ use gtk::{glib, ApplicationWindow};
 use std::path::PathBuf;
 
 use std::sync::mpsc::{Receiver, Sender};
-use std::sync::{mpsc, Arc, Mutex};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    mpsc, Arc, Mutex,
+};
 
 fn process_user_prompt(
     prompt_entry_buffer: &gtk::EntryBuffer,
@@ -20,15 +30,31 @@ fn process_user_prompt(
     list_sender: &Sender<Message>,
     model_sender: &Sender<Message>,
     is_processing: &Arc<Mutex<ModelMessageState>>,
-    prompt_selected_file: &Arc<Mutex<Option<PathBuf>>>,
+    attachment_tray: &AttachmentTray,
+    chat_model: &Arc<Mutex<Box<dyn CoreLLM>>>,
+    rag_dropdown: &RagDropdown,
+    rag_cancel_flag: &Arc<AtomicBool>,
+    conversation_file_path_arc: &Arc<Mutex<PathBuf>>,
 ) {
     let mut model_message_state = is_processing.lock().unwrap().clone();
+    // While a RAG source runs the prompt button cancels it
+    if let ModelMessageState::RetrievingContext = model_message_state {
+        rag_cancel_flag.store(true, Ordering::Relaxed);
+        return;
+    }
     if let ModelMessageState::FinishedAssistant = model_message_state {
         *is_processing.lock().unwrap() = ModelMessageState::UserTurn;
         model_message_state = is_processing.lock().unwrap().clone();
     }
     if let ModelMessageState::UserTurn = model_message_state {
         let text = prompt_entry_buffer.text().to_string();
+        let pending_attachments = match attachment_tray.attachments() {
+            Ok(pending_attachments) => pending_attachments,
+            Err(err) => {
+                attachment_tray.show_error(&err);
+                return;
+            }
+        };
         if !text.is_empty() {
             prompt_button.set_icon_name("emblem-synchronizing-symbolic");
             prompt_entry_buffer.set_text("");
@@ -36,18 +62,83 @@ fn process_user_prompt(
                 role: crate::models::Role::User,
                 content: text.clone(),
                 images: None,
+                model_name: None,
+                request_id: None,
+                original_content: None,
+                rag_context: None,
+                sources: None,
+                rag_error: None,
+                rag_query: None,
+                attachments: None,
+            };
+            attachment_tray.clear();
+            let rag_source = rag_dropdown.selected_rag_source();
+            let conversation_file_path = conversation_file_path_arc.lock().unwrap().clone();
+            let (conversation, model_name, answering_model) = {
+                let mut chat_model = chat_model.lock().unwrap();
+                (
+                    chat_model.get_conversation(),
+                    chat_model.model_name(),
+                    chat_model.clone_box(),
+                )
             };
-            let file_path_option = (*prompt_selected_file.lock().unwrap()).clone();
-            *prompt_selected_file.lock().unwrap() = None;
-            if let Some(file_path) = file_path_option {
-                chat_message = OllamaModel::process_file_for_prompt(chat_message, file_path);
+            if !matches!(rag_source, RagSource::NoRag) {
+                prompt_button.set_icon_name("process-stop-symbolic");
+                prompt_button.set_tooltip_text(Some("Cancel retrieval"));
             }
-            list_sender
-                .send(chat_message.clone())
-                .expect("List channel needs to be open.");
-            model_sender
-                .send(chat_message)
-                .expect("Model channel needs to be open.");
+            *is_processing.lock().unwrap() = ModelMessageState::RetrievingContext;
+            rag_cancel_flag.store(false, Ordering::Relaxed);
+            let list_sender = list_sender.clone();
+            let model_sender = model_sender.clone();
+            let prompt_entry_buffer = prompt_entry_buffer.clone();
+            let prompt_button = prompt_button.clone();
+            let is_processing = Arc::clone(is_processing);
+            let rag_cancel_flag = Arc::clone(rag_cancel_flag);
+            // Context is retrieved before the message is listed so it can be shown with it
+            glib::MainContext::default().spawn_local(async move {
+                if !pending_attachments.is_empty() {
+                    // The attachments share the budget, so several files still fit the context
+                    let context_window_tokens = answering_model.context_window_tokens().await;
+                    let max_chars = attachments::max_attachment_chars(context_window_tokens)
+                        / pending_attachments.len();
+                    for (file_path, page_range, prepared_content) in pending_attachments {
+                        let attachment_options = AttachmentOptions {
+                            page_range,
+                            max_chars,
+                            prepared_content,
+                        };
+                        chat_message = OllamaModel::process_file_for_prompt(
+                            chat_message,
+                            file_path,
+                            &attachment_options,
+                        );
+                    }
+                }
+                let chat_message = OllamaModel::format_prompt(
+                    chat_message,
+                    rag_source,
+                    conversation,
+                    conversation_file_path,
+                    model_name,
+                    &rag_cancel_flag,
+                )
+                .await;
+                *is_processing.lock().unwrap() = ModelMessageState::UserTurn;
+                prompt_button.set_tooltip_text(Some("Send prompt"));
+                if rag_cancel_flag.load(Ordering::Relaxed) {
+                    // Put the prompt back so it can be sent again, with another source or none
+                    prompt_entry_buffer.set_text(text);
+                    prompt_button.set_icon_name("emblem-ok-symbolic");
+                    return;
+                }
+                prompt_button.set_icon_name("emblem-synchronizing-symbolic");
+                list_sender
+                    .send(chat_message.clone())
+                    .expect("List channel needs to be open.");
+                model_sender
+                    .send(chat_message)
+                    .expect("Model channel needs to be open.");
+            });
         }
     } else {
         println!(
@@ -62,27 +153,46 @@ fn create_model_caller_thread(
     model_receiver: Receiver<Message>,
     list_sender: Sender<Message>,
     is_processing: &Arc<Mutex<ModelMessageState>>,
-    rag_dropdown: RagDropdown,
+    conversation_file_path_arc: Arc<Mutex<PathBuf>>,
 ) {
     let is_processing = Arc::clone(is_processing);
     glib::MainContext::default().spawn_local(async move {
         loop {
             match model_receiver.try_recv() {
-                Ok(mut chat_message) => {
+                Ok(chat_message) => {
                     *is_processing.lock().unwrap() = ModelMessageState::StartAssistant;
-                    chat_message.content = OllamaModel::format_prompt(
-                        &chat_message.content,
-                        rag_dropdown
-                            .rag_options
-                            .get(rag_dropdown.dropdown.selected() as usize)
-                            .unwrap_or(&RagSource::NoRag)
-                            .clone(),
+                    let fallback_models = fallback_models_for_conversation(
+                        &conversation_file_path_arc.lock().unwrap(),
                     );
-                    chat_model
-                        .lock()
-                        .unwrap()
-                        .ask(chat_message, list_sender.clone())
-                        .await;
+                    match ask_with_fallback(
+                        &chat_model,
+                        chat_message,
+                        list_sender.clone(),
+                        fallback_models,
+                    )
+                    .await
+                    {
+                        Ok(answering_model_name) => {
+                            println!("Answered by {}", answering_model_name);
+                        }
+                        Err(errors) => {
+                            list_sender
+                                .send(Message {
+                                    role: crate::models::Role::System,
+                                    content: format!("Every model failed to answer:\n{}", errors),
+                                    images: None,
+                                    model_name: None,
+                                    request_id: None,
+                                    original_content: None,
+                                    rag_context: None,
+                                    sources: None,
+                                    rag_error: None,
+                                    rag_query: None,
+                                    attachments: None,
+                                })
+                                .expect("List channel needs to be open.");
+                        }
+                    }
                     *is_processing.lock().unwrap() = ModelMessageState::FinishedAssistant;
                 }
                 Err(mpsc::TryRecvError::Empty) => {
@@ -104,9 +214,11 @@ fn create_list_manager_thread(
     conversation_list_box: gtk::ListBox,
     prompt_button: gtk::Button,
     is_processing: Arc<Mutex<ModelMessageState>>,
+    conversation_file_option_sender: Sender<Option<PathBuf>>,
 ) {
     glib::MainContext::default().spawn_local(async move {
-        let mut chat_message_list_item = ChatMessageListItem::new(None);
+        let mut chat_message_list_item =
+            ChatMessageListItem::new(None, conversation_file_option_sender.clone());
         let mut last_model_message_state = ModelMessageState::UserTurn;
         loop {
             match list_receiver.try_recv() {
@@ -116,23 +228,35 @@ fn create_list_manager_thread(
                     match model_message_state {
                         ModelMessageState::UserTurn => {
                             println!("User message: {:?}", chat_message.clone());
-                            let current_user_list_item =
-                                ChatMessageListItem::new(Some(chat_message.clone()));
+                            let current_user_list_item = ChatMessageListItem::new(
+                                Some(chat_message.clone()),
+                                conversation_file_option_sender.clone(),
+                            );
                             conversation_list_box.append(&current_user_list_item.main_box);
                         }
+                        ModelMessageState::RetrievingContext => {
+                            println!(
+                                "Message listed while retrieving context: {:?}",
+                                chat_message
+                            );
+                        }
                         ModelMessageState::RunningAssistant => {
                             chat_message_list_item.update_message(chat_message);
                         }
                         ModelMessageState::StartAssistant => {
-                            chat_message_list_item =
-                                ChatMessageListItem::new(Some(chat_message.clone()));
+                            chat_message_list_item = ChatMessageListItem::new(
+                                Some(chat_message.clone()),
+                                conversation_file_option_sender.clone(),
+                            );
                             conversation_list_box.append(&chat_message_list_item.main_box);
                             *is_processing.lock().unwrap() = ModelMessageState::RunningAssistant;
                             last_model_message_state = ModelMessageState::RunningAssistant;
                         }
                         ModelMessageState::LoadingFromFile => {
-                            let current_list_item =
-                                ChatMessageListItem::new(Some(chat_message.clone()));
+                            let current_list_item = ChatMessageListItem::new(
+                                Some(chat_message.clone()),
+                                conversation_file_option_sender.clone(),
+                            );
                             conversation_list_box.append(&current_list_item.main_box);
                         }
                         ModelMessageState::FinishedAssistant => {
@@ -162,6 +286,7 @@ fn create_list_manager_thread(
 
 fn create_conversation_file_manager_thread(
     conversation_file_option_receiver: Receiver<Option<PathBuf>>,
+    conversation_file_option_sender: Sender<Option<PathBuf>>,
     chat_model: &Arc<Mutex<Box<dyn CoreLLM>>>,
     prompt_entry_widget: PromptEntryWidget,
     conversation_scroll_window: gtk::ScrolledWindow,
@@ -187,6 +312,7 @@ fn create_conversation_file_manager_thread(
                         conversation_file_option,
                         &conversation_file_path_arc,
                         rag_dropdown.clone(),
+                        conversation_file_option_sender.clone(),
                     );
                 }
 
@@ -211,6 +337,7 @@ fn create_chat_context(
     new_conversation_filepath_option: Option<PathBuf>,
     current_conversation_file_path_arc: &Arc<Mutex<PathBuf>>,
     rag_dropdown: RagDropdown,
+    conversation_file_option_sender: Sender<Option<PathBuf>>,
 ) {
     // Initialise all the async
     let chat_model_for_thread = Arc::clone(chat_model);
@@ -221,6 +348,7 @@ fn create_chat_context(
     let prompt_button_signal_id_for_closure =
         Arc::clone(&prompt_entry_widget.submit_button_signal_id);
     let is_processing = Arc::new(Mutex::new(ModelMessageState::UserTurn));
+    let rag_cancel_flag = Arc::new(AtomicBool::new(false));
 
     // Create fresh ListBox
     let conversation_list_box = gtk::ListBox::builder().vexpand(true).build();
@@ -230,11 +358,15 @@ fn create_chat_context(
     {
         // Clone vars for closure
         let prompt_entry_buffer = prompt_entry_widget.prompt_entry_buffer.clone();
-        let prompt_selected_file = Arc::clone(&prompt_entry_widget.selected_file);
+        let attachment_tray = prompt_entry_widget.attachment_tray.clone();
+        let chat_model = Arc::clone(chat_model);
         let prompt_button = prompt_entry_widget.submit_button.clone();
         let list_sender = list_sender.clone();
         let model_sender = model_sender.clone();
         let is_processing = Arc::clone(&is_processing);
+        let rag_dropdown = rag_dropdown.clone();
+        let rag_cancel_flag = Arc::clone(&rag_cancel_flag);
+        let conversation_file_path_arc = Arc::clone(current_conversation_file_path_arc);
 
         // Disconnect the exisiting signal from the entry
         if prompt_entry_signal_id_for_closure.lock().unwrap().is_some() {
@@ -254,7 +386,11 @@ fn create_chat_context(
                 &list_sender,
                 &model_sender,
                 &is_processing,
-                &prompt_selected_file,
+                &attachment_tray,
+                &chat_model,
+                &rag_dropdown,
+                &rag_cancel_flag,
+                &conversation_file_path_arc,
             );
         });
 
@@ -269,11 +405,15 @@ fn create_chat_context(
     {
         // Clone vars for closure
         let prompt_entry_buffer = prompt_entry_widget.prompt_entry_buffer.clone();
-        let prompt_selected_file = Arc::clone(&prompt_entry_widget.selected_file);
+        let attachment_tray = prompt_entry_widget.attachment_tray.clone();
+        let chat_model = Arc::clone(chat_model);
         let prompt_button = prompt_entry_widget.submit_button.clone();
         let list_sender = list_sender.clone();
         let model_sender = model_sender.clone();
         let is_processing = Arc::clone(&is_processing);
+        let rag_dropdown = rag_dropdown.clone();
+        let rag_cancel_flag = Arc::clone(&rag_cancel_flag);
+        let conversation_file_path_arc = Arc::clone(current_conversation_file_path_arc);
 
         // Disconnect the exisiting signal from the button
         if prompt_button_signal_id_for_closure
@@ -297,7 +437,11 @@ fn create_chat_context(
                 &list_sender,
                 &model_sender,
                 &is_processing,
-                &prompt_selected_file,
+                &attachment_tray,
+                &chat_model,
+                &rag_dropdown,
+                &rag_cancel_flag,
+                &conversation_file_path_arc,
             );
         });
 
@@ -315,7 +459,7 @@ fn create_chat_context(
         model_receiver,
         list_sender_for_model_caller,
         &is_processing,
-        rag_dropdown,
+        Arc::clone(current_conversation_file_path_arc),
     );
 
     // Spawn a thread which listens for items to add to the conversation list
@@ -324,6 +468,7 @@ fn create_chat_context(
         conversation_list_box,
         prompt_entry_widget.submit_button.clone(),
         Arc::clone(&is_processing),
+        conversation_file_option_sender,
     );
     if let Some(conversation_filepath) = new_conversation_filepath_option {
         chat_model
@@ -350,7 +495,7 @@ fn create_chat_context(
             println!("{:?}", chat_message);
             let mut message_to_send = chat_message.clone();
             if let crate::models::Role::User = message_to_send.role {
-                message_to_send.content = OllamaModel::unformat_prompt(&chat_message.content);
+                message_to_send.content = OllamaModel::unformat_prompt(chat_message);
             }
             list_sender.send(message_to_send).unwrap();
         });
@@ -370,6 +515,7 @@ pub fn build_ui(app: &adw::Application) {
         Receiver<Option<PathBuf>>,
     ) = mpsc::channel();
     let conversation_file_path_arc = Arc::new(Mutex::new(generate_unique_filename("json")));
+    let model_registry = ModelRegistry::new();
 
     let prompt_entry_widget = PromptEntryWidget::new();
 
@@ -380,10 +526,13 @@ pub fn build_ui(app: &adw::Application) {
         .spacing(5)
         .build();
     main_content_box.append(&conversation_scroll_window);
+    prompt_entry_widget
+        .attachment_tray
+        .accept_drops(&conversation_scroll_window);
     main_content_box.append(&prompt_entry_widget.main_box);
 
     let paned_main = gtk::Paned::new(gtk::Orientation::Horizontal);
-    let sidebar_widget = create_sidebar(conversation_file_option_sender.clone());
+    let sidebar_widget = create_sidebar(conversation_file_option_sender.clone(), &model_registry);
 
     paned_main.set_start_child(Some(&sidebar_widget));
     paned_main.set_end_child(Some(&main_content_box));
@@ -395,10 +544,12 @@ pub fn build_ui(app: &adw::Application) {
         main_content_box.clone(),
         conversation_file_option_sender.clone(),
         Arc::clone(&chat_model),
+        &model_registry,
     );
 
     create_conversation_file_manager_thread(
         conversation_file_option_receiver,
+        conversation_file_option_sender.clone(),
         &chat_model,
         prompt_entry_widget,
         conversation_scroll_window,
@@ -424,6 +575,17 @@ pub fn build_ui(app: &adw::Application) {
     .system-label {
       background-color: #f9f06b;
       color: black;
+    }
+    .attachment-chip {
+      background-color: alpha(currentColor, 0.08);
+      border-radius: 6px;
+      padding: 2px 6px;
+    }
+    .update-badge {
+      background-color: #ffbe6f;
+      color: black;
+      border-radius: 6px;
+      padding: 2px 6px;
     }
         ",
     );
@@ -458,6 +620,20 @@ pub fn build_ui(app: &adw::Application) {
 
     window.present();
 
+    // Look for models once the window is up, the cached list is shown until then
+    model_registry.start();
+    index_watcher::start();
+
+    // API keys need the credential store, which may have to be unlocked first
+    if !ApiModel::list_models().unwrap_or_default().is_empty()
+        && CredentialStore::needs_passphrase()
+    {
+        let vault_unlock_widget = VaultUnlockWidget::new(&window, || {});
+        vault_unlock_widget.dialog.present();
+    } else {
+        SavedModel::migrate_plaintext_api_keys();
+    }
+
     // Initialise chat context
     conversation_file_option_sender.send(None).unwrap();
 }